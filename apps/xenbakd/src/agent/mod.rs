@@ -0,0 +1,267 @@
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{AppConfig, DifferentialConfig, JobConfig},
+    storage::{restore_point::RestorePoint, BackupObject, BackupObjectFilter, StorageHandler},
+};
+
+use self::protocol::{
+    read_frame, read_message, write_message, AgentOperation, AgentRequest, AgentResponse,
+    FrameKind, DATA_FRAME_SIZE,
+};
+
+pub mod protocol;
+pub mod tls;
+
+/// runs the agent, which executes storage operations for remote daemons on the storages
+/// configured locally
+pub async fn run_agent(config: AppConfig) -> eyre::Result<()> {
+    if config.agent.token.is_empty() {
+        return Err(eyre::eyre!(
+            "No agent token configured, refusing to accept connections without one"
+        ));
+    }
+
+    let acceptor = tls::acceptor(&config.agent.tls_cert, &config.agent.tls_key)?;
+    let listener = TcpListener::bind(&config.agent.listen).await?;
+    info!("Agent listening on {}", config.agent.listen);
+
+    let config = Arc::new(config);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+        let config = config.clone();
+
+        tokio::spawn(async move {
+            let result = async {
+                let mut stream = acceptor.accept(stream).await?;
+                handle_connection(&config, &mut stream).await
+            }
+            .await;
+
+            if let Err(e) = result {
+                warn!("Agent connection from {} failed: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    config: &AppConfig,
+    stream: &mut S,
+) -> eyre::Result<()> {
+    let request: AgentRequest = read_message(stream).await?;
+
+    if !tokens_match(&request.token, &config.agent.token) {
+        write_message(stream, &AgentResponse::Error("Invalid token".into())).await?;
+        return Err(eyre::eyre!("Client sent an invalid token"));
+    }
+
+    let response = match handle_request(config, request, stream).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Agent request failed: {:#}", e);
+            AgentResponse::Error(format!("{:#}", e))
+        }
+    };
+
+    write_message(stream, &response).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+async fn handle_request<S: AsyncRead + Unpin + Send>(
+    config: &AppConfig,
+    request: AgentRequest,
+    stream: &mut S,
+) -> eyre::Result<AgentResponse> {
+    // the job name ends up in the paths of the storages
+    if !is_valid_job_name(&request.job) {
+        return Err(eyre::eyre!("Invalid job name '{}'", request.job));
+    }
+
+    // resolve the requested storage from the agent's own config
+    let job = JobConfig {
+        name: request.job,
+        storages: vec![request.storage.clone()],
+        differential: request.differential.then(DifferentialConfig::default),
+        ..JobConfig::default()
+    };
+    let storage_handler = job
+        .get_storages(config.storage.clone())
+        .into_iter()
+        .next()
+        .ok_or_else(|| {
+            eyre::eyre!(
+                "Storage '{}' is not configured on the agent",
+                request.storage
+            )
+        })?;
+
+    debug!(
+        "Handling {:?} for job '{}' on storage '{}'",
+        request.operation, job.name, request.storage
+    );
+
+    match request.operation {
+        AgentOperation::Status => Ok(AgentResponse::Status(storage_handler.status().await?)),
+        AgentOperation::Initialize => {
+            storage_handler.initialize().await?;
+            Ok(AgentResponse::Initialized {
+                retention_count: storage_handler.get_retention_count(),
+                delete_protection_days: storage_handler.get_delete_protection_days(),
+            })
+        }
+        AgentOperation::Probe => {
+            storage_handler.probe().await?;
+            Ok(AgentResponse::Done)
+        }
+        AgentOperation::List { filter } => {
+            Ok(AgentResponse::List(storage_handler.list(filter).await?))
+        }
+        AgentOperation::Rotate {
+            filter,
+            retention,
+            simulate,
+        } => {
+            let deleted = storage_handler
+                .rotate(filter, retention, simulate.into())
+                .await?;
+            Ok(AgentResponse::Rotated(deleted))
+        }
+        AgentOperation::Verify { restore_point } => {
+            let restore_point =
+                resolve_restore_point(storage_handler.as_ref(), &restore_point).await?;
+            storage_handler.verify(&restore_point).await?;
+            Ok(AgentResponse::Done)
+        }
+        AgentOperation::Delete { restore_point } => {
+            let restore_point =
+                resolve_restore_point(storage_handler.as_ref(), &restore_point).await?;
+            storage_handler.delete(&restore_point).await?;
+            Ok(AgentResponse::Done)
+        }
+        AgentOperation::SetPinned {
+            restore_point,
+            pinned,
+        } => {
+            let restore_point =
+                resolve_restore_point(storage_handler.as_ref(), &restore_point).await?;
+            storage_handler.set_pinned(&restore_point, pinned).await?;
+            Ok(AgentResponse::Done)
+        }
+        AgentOperation::Store { backup_object } => {
+            receive_export(storage_handler, backup_object, stream).await?;
+            Ok(AgentResponse::Done)
+        }
+    }
+}
+
+/// compares the hashes of both tokens in constant time, the time taken doesn't tell how much of
+/// the token or its length was right
+fn tokens_match(a: &str, b: &str) -> bool {
+    Sha256::digest(a.as_bytes())
+        .iter()
+        .zip(Sha256::digest(b.as_bytes()).iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// job names may only consist of `[A-Za-z0-9._-]` and must not be `.` or `..`
+fn is_valid_job_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// looks up the restore point sent by the client on the agent's own storage. only the artifact
+/// locations found by the agent are used, the client could send any path otherwise
+async fn resolve_restore_point(
+    storage_handler: &dyn StorageHandler,
+    restore_point: &RestorePoint,
+) -> eyre::Result<RestorePoint> {
+    storage_handler
+        .list(BackupObjectFilter::from_backup_object(
+            restore_point.backup_object.clone(),
+        ))
+        .await?
+        .into_iter()
+        .find(|x| x.id == restore_point.id)
+        .ok_or_else(|| {
+            eyre::eyre!(
+                "Restore point '{}' not found on storage '{}'",
+                restore_point.id,
+                storage_handler.get_name()
+            )
+        })
+}
+
+/// feeds the frames of an export stream into the storage handler
+async fn receive_export<S: AsyncRead + Unpin + Send>(
+    storage_handler: Arc<dyn StorageHandler>,
+    backup_object: BackupObject,
+    stream: &mut S,
+) -> eyre::Result<()> {
+    let (stdout_writer, stdout_reader) = tokio::io::duplex(DATA_FRAME_SIZE * 4);
+    let (mut stderr_writer, stderr_reader) = tokio::io::duplex(64 * 1024);
+
+    let handler = storage_handler.handle_stdio_stream(
+        backup_object,
+        Box::new(stdout_reader),
+        Box::new(stderr_reader),
+    );
+
+    let demux = async move {
+        let mut stdout_writer = Some(stdout_writer);
+        let result = forward_frames(stream, &mut stdout_writer).await;
+        drop(stdout_writer);
+
+        // an interrupted stream is reported as stderr output, so the handler discards the backup
+        let stderr = match &result {
+            Ok(stderr) => stderr.clone(),
+            Err(e) => format!("Export stream from daemon was interrupted: {:#}", e).into_bytes(),
+        };
+        let _ = stderr_writer.write_all(&stderr).await;
+        drop(stderr_writer);
+
+        result.map(|_| ())
+    };
+
+    let (handler_result, demux_result) = tokio::join!(handler, demux);
+    demux_result?;
+    handler_result
+}
+
+/// writes data frames to the handler's stdout until the stream ends, returns the received stderr
+async fn forward_frames<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    stdout_writer: &mut Option<DuplexStream>,
+) -> eyre::Result<Vec<u8>> {
+    loop {
+        match read_frame(stream).await? {
+            (FrameKind::Data, payload) => {
+                // if the handler stopped reading, the rest of the stream is drained
+                if let Some(writer) = stdout_writer {
+                    if writer.write_all(&payload).await.is_err() {
+                        *stdout_writer = None;
+                    }
+                }
+            }
+            (FrameKind::Stderr, payload) => return Ok(payload),
+            (FrameKind::End, _) => return Ok(vec![]),
+            (FrameKind::Message, _) => {
+                return Err(eyre::eyre!("Unexpected message during export stream"))
+            }
+        }
+    }
+}
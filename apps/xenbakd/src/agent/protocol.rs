@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+// the agent speaks the same framing as storage plugins
+pub use xenbak_storage_plugin::frame::{
+    read_frame, read_message, write_frame, write_message, FrameKind, DATA_FRAME_SIZE,
+};
+
+use crate::storage::{
+    restore_point::RestorePoint, BackupObject, BackupObjectFilter, StorageStatus,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentRequest {
+    pub token: String,
+    /// name of the storage configured on the agent
+    pub storage: String,
+    /// name of the job, the storages keep the backups of every job in a directory of its own
+    pub job: String,
+    /// differential jobs store archives instead of XVAs, the only job setting a storage needs
+    #[serde(default)]
+    pub differential: bool,
+    pub operation: AgentOperation,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AgentOperation {
+    Status,
+    Initialize,
+    Probe,
+    List {
+        filter: BackupObjectFilter,
+    },
+    Rotate {
+        filter: BackupObjectFilter,
+        /// older xenbakd versions don't send it
+        #[serde(default)]
+        retention: Option<u32>,
+        simulate: bool,
+    },
+    Verify {
+        restore_point: RestorePoint,
+    },
+    Delete {
+        restore_point: RestorePoint,
+    },
+    SetPinned {
+        restore_point: RestorePoint,
+        pinned: bool,
+    },
+    /// followed by data frames, terminated by an end frame or a stderr frame if the export failed
+    Store {
+        backup_object: BackupObject,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AgentResponse {
+    Status(StorageStatus),
+    Initialized {
+        retention_count: u32,
+        #[serde(default)]
+        delete_protection_days: Option<u32>,
+    },
+    List(Vec<RestorePoint>),
+    /// ids of the restore points deleted by a rotation, or which would be on a dry run
+    Rotated(Vec<String>),
+    Done,
+    Error(String),
+}
@@ -0,0 +1,59 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use eyre::Context;
+use tokio_rustls::{
+    rustls::{self, Certificate, PrivateKey, RootCertStore},
+    TlsAcceptor, TlsConnector,
+};
+
+fn load_certs(path: &str) -> eyre::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(
+        File::open(path).wrap_err_with(|| format!("Failed to open certificate {}", path))?,
+    );
+    Ok(rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect())
+}
+
+fn load_key(path: &str) -> eyre::Result<PrivateKey> {
+    let mut reader = BufReader::new(
+        File::open(path).wrap_err_with(|| format!("Failed to open private key {}", path))?,
+    );
+
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+
+    Err(eyre::eyre!("No private key found in {}", path))
+}
+
+/// creates the acceptor used by the agent to terminate TLS connections
+pub fn acceptor(cert_path: &str, key_path: &str) -> eyre::Result<TlsAcceptor> {
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// creates a connector which only trusts agents signed by the given CA (or self-signed certificate)
+pub fn connector(ca_cert_path: &str) -> eyre::Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_cert_path)? {
+        roots.add(&cert)?;
+    }
+
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
@@ -3,9 +3,17 @@ use clap::Parser;
 #[derive(Parser)]
 #[command(version, about, long_about)]
 pub struct XenbakdCli {
-    /// Sets a custom config file
+    /// Sets a custom config file, can be given multiple times (merged in order)
+    #[clap(short, long, required = true)]
+    pub config: Vec<String>,
+    /// Applies the overrides of the given `[profiles.<name>]` section
     #[clap(short, long)]
-    pub config: String,
+    pub profile: Option<String>,
+    /// Never deletes backups, the rotation of every job only logs what it would delete. Runs
+    /// (`run --dry-run`) additionally only print what the jobs would back up, without
+    /// snapshotting or exporting anything
+    #[clap(long, global = true)]
+    pub dry_run: bool,
     #[clap(subcommand)]
     pub subcmd: SubCommand,
 }
@@ -16,13 +24,296 @@ pub enum SubCommand {
     Daemon(DaemonSubCommand),
     #[clap(name = "run", about = "Runs jobs once")]
     Run(RunSubCommand),
+    #[clap(
+        name = "agent",
+        about = "Runs as agent, handling the configured storages for remote daemons"
+    )]
+    Agent(AgentSubCommand),
+    #[clap(name = "config", about = "Inspects the loaded configuration")]
+    Config(ConfigSubCommand),
+    #[clap(
+        name = "debug",
+        about = "Inspects a running daemon via its metrics endpoint"
+    )]
+    Debug(DebugSubCommand),
+    #[clap(
+        name = "pin",
+        about = "Pins a restore point, so it is never deleted by the rotation"
+    )]
+    Pin(PinSubCommand),
+    #[clap(name = "unpin", about = "Unpins a restore point")]
+    Unpin(PinSubCommand),
+    #[clap(name = "restore", about = "Restores a VM from a restore point")]
+    Restore(RestoreSubCommand),
+    #[clap(
+        name = "extract-disk",
+        about = "Extracts a single disk of a restore point as raw image, without importing the VM"
+    )]
+    ExtractDisk(ExtractDiskSubCommand),
+    #[clap(
+        name = "convert",
+        about = "Converts the disks of a restore point into raw or qcow2 images, e.g. for KVM or Proxmox"
+    )]
+    Convert(ConvertSubCommand),
+    #[clap(name = "history", about = "Lists the recorded runs of the jobs")]
+    History(HistorySubCommand),
+    #[clap(
+        name = "coverage",
+        about = "Lists the VMs of every pool which no enabled job backs up"
+    )]
+    Coverage(CoverageSubCommand),
+    #[clap(
+        name = "quarantine",
+        about = "Lists or releases VMs quarantined after failing too many runs in a row"
+    )]
+    Quarantine(QuarantineSubCommand),
+    #[clap(
+        name = "jobs",
+        about = "Lists, pauses or resumes the jobs of a running daemon via its metrics endpoint"
+    )]
+    Jobs(JobsSubCommand),
 }
 
 #[derive(Parser)]
 pub struct DaemonSubCommand {}
 
+#[derive(Parser)]
+pub struct AgentSubCommand {}
+
+#[derive(Parser)]
+pub struct ConfigSubCommand {
+    #[clap(subcommand)]
+    pub subcmd: ConfigCommand,
+}
+
+#[derive(Parser)]
+pub enum ConfigCommand {
+    #[clap(
+        name = "show",
+        about = "Prints the merged configuration (config files and profile) as TOML"
+    )]
+    Show(ConfigShowSubCommand),
+}
+
+#[derive(Parser)]
+pub struct ConfigShowSubCommand {
+    /// Resolves job defaults, templates and pool defaults into the final jobs and xen hosts
+    #[clap(long)]
+    pub resolved: bool,
+}
+
+#[derive(Parser)]
+pub struct DebugSubCommand {
+    #[clap(subcommand)]
+    pub subcmd: DebugCommand,
+}
+
+#[derive(Parser)]
+pub enum DebugCommand {
+    #[clap(
+        name = "dump-tasks",
+        about = "Prints the live tasks and spans of the running daemon"
+    )]
+    DumpTasks,
+}
+
+#[derive(Parser)]
+pub struct PinSubCommand {
+    /// Id of the restore point, as shown in the job stats and logs
+    pub restore_point: String,
+    /// Only searches the storages of the given job
+    #[clap(short, long)]
+    pub job: Option<String>,
+    /// Only searches the given storage
+    #[clap(short, long)]
+    pub storage: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct RestoreSubCommand {
+    /// Id of the restore point, as shown in the job stats and logs
+    pub restore_point: String,
+    /// Name of the xen host (`[[xen]]`) the VM is imported on
+    #[clap(short, long)]
+    pub xen_host: String,
+    /// Only searches the storages of the given job
+    #[clap(short, long)]
+    pub job: Option<String>,
+    /// Only searches the given storage
+    #[clap(short, long)]
+    pub storage: Option<String>,
+    /// Maps a source SR to a target SR as `<source>=<target>` (uuid or name-label), can be given multiple times
+    #[clap(long = "map-sr", value_parser = parse_mapping)]
+    pub sr_map: Vec<(String, String)>,
+    /// Maps a source network to a target network as `<source>=<target>` (uuid or name-label), can be given multiple times
+    #[clap(long = "map-network", value_parser = parse_mapping)]
+    pub network_map: Vec<(String, String)>,
+    /// Gives the VIFs new MAC addresses instead of the ones of the backup
+    #[clap(long)]
+    pub regenerate_macs: bool,
+    /// Imports all disks into the given SR (uuid or name-label), regardless of the SR mapping
+    #[clap(long)]
+    pub sr: Option<String>,
+    /// Name-label of the restored VM, defaults to `restored-<name>-<date of the backup>`
+    #[clap(long, conflicts_with = "keep_name")]
+    pub name: Option<String>,
+    /// Keeps the name-label of the backed up VM, it may collide with the original VM
+    #[clap(long)]
+    pub keep_name: bool,
+}
+
+#[derive(Parser)]
+pub struct ExtractDiskSubCommand {
+    /// Id of the restore point, as shown in the job stats and logs
+    pub restore_point: String,
+    /// Userdevice of the disk, e.g. `0` for the first disk
+    #[clap(short, long)]
+    pub disk: String,
+    /// Path of the raw image, the disk is written sparse
+    #[clap(short, long)]
+    pub output: String,
+    /// Only searches the storages of the given job
+    #[clap(short, long)]
+    pub job: Option<String>,
+    /// Only searches the given storage
+    #[clap(short, long)]
+    pub storage: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct ConvertSubCommand {
+    /// Id of the restore point, as shown in the job stats and logs
+    pub restore_point: String,
+    /// Directory the images are written to, as `<vm>-<userdevice>.<format>`
+    #[clap(short, long)]
+    pub output_dir: String,
+    /// Format of the images, qcow2 needs `qemu-img`
+    #[clap(short, long, value_enum, default_value_t = ImageFormat::Qcow2)]
+    pub format: ImageFormat,
+    /// Only converts the disk with the given userdevice, can be given multiple times
+    #[clap(short, long = "disk")]
+    pub disks: Vec<String>,
+    /// Only searches the storages of the given job
+    #[clap(short, long)]
+    pub job: Option<String>,
+    /// Only searches the given storage
+    #[clap(short, long)]
+    pub storage: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+}
+
+fn parse_mapping(mapping: &str) -> Result<(String, String), String> {
+    mapping
+        .split_once('=')
+        .map(|(source, target)| (source.to_string(), target.to_string()))
+        .ok_or_else(|| format!("expected <source>=<target>, got '{}'", mapping))
+}
+
+#[derive(Parser)]
+pub struct HistorySubCommand {
+    /// Only lists the runs of the given job
+    #[clap(short, long)]
+    pub job: Option<String>,
+    /// Number of runs listed, the newest ones
+    #[clap(short, long, default_value_t = 20)]
+    pub limit: usize,
+    /// Lists the result of every VM of the runs
+    #[clap(short, long)]
+    pub details: bool,
+    /// Prints the runs with their stats as JSON lines
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Parser)]
+pub struct CoverageSubCommand {
+    /// Prints every pool with its protected, unprotected and ignored VMs as JSON lines
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Parser)]
+pub struct QuarantineSubCommand {
+    #[clap(subcommand)]
+    pub subcmd: QuarantineCommand,
+}
+
+#[derive(Parser)]
+pub enum QuarantineCommand {
+    #[clap(
+        name = "list",
+        about = "Lists the quarantined VMs and the VMs failing in a row"
+    )]
+    List(QuarantineListSubCommand),
+    #[clap(
+        name = "clear",
+        about = "Releases quarantined VMs, their next backup starts counting failures anew"
+    )]
+    Clear(QuarantineClearSubCommand),
+}
+
+#[derive(Parser)]
+pub struct QuarantineListSubCommand {
+    /// Only lists the VMs of the given job
+    #[clap(short, long)]
+    pub job: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct QuarantineClearSubCommand {
+    /// Name-label or uuid of the VM, releases all quarantined VMs if not given
+    pub vm: Option<String>,
+    /// Only releases the VMs of the given job
+    #[clap(short, long)]
+    pub job: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct JobsSubCommand {
+    #[clap(subcommand)]
+    pub subcmd: JobsCommand,
+}
+
+#[derive(Parser)]
+pub enum JobsCommand {
+    #[clap(
+        name = "status",
+        about = "Lists the jobs with their state, next run and last run"
+    )]
+    Status(JobsStatusSubCommand),
+    #[clap(
+        name = "pause",
+        about = "Pauses a job, its scheduled runs are skipped until it is resumed or the daemon restarts"
+    )]
+    Pause(JobsControlSubCommand),
+    #[clap(name = "resume", about = "Resumes a paused job")]
+    Resume(JobsControlSubCommand),
+}
+
+#[derive(Parser)]
+pub struct JobsStatusSubCommand {
+    /// Prints the status of every job as JSON lines
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Parser)]
+pub struct JobsControlSubCommand {
+    /// Name of the job
+    pub job: String,
+}
+
 #[derive(Parser)]
 pub struct RunSubCommand {
     #[clap(short, long)]
     pub jobs: Vec<String>,
+    /// Only backs up the xen hosts of the given pools, runs all enabled jobs covering them if no
+    /// jobs are given. Can be given multiple times
+    #[clap(long = "pool")]
+    pub pools: Vec<String>,
 }
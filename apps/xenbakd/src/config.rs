@@ -1,13 +1,28 @@
 #![allow(dead_code)]
+use figment::{
+    providers::{Format, Serialized, Toml},
+    value::{Dict, Value},
+    Figment,
+};
 use serde::{de::IntoDeserializer, Deserialize, Serialize};
-use std::sync::Arc;
+use std::{collections::BTreeMap, str::FromStr, sync::Arc};
 
+use crate::jobs::{
+    cold_backup::ColdBackupMode,
+    guest_hooks::GuestHookMethod,
+    guest_quiesce::GuestQuiesceMethod,
+    hooks::HookFailureAction,
+    window::{self, TimeWindow},
+    JobType,
+};
 use crate::storage::{
     self,
     borg::{BorgCompressionType, BorgEncryptionType, BorgStorageRetention},
-    local::LocalCompressionType,
+    local::{LocalCompressionType, LocalEncryptionType, LocalStorageLayout},
     StorageHandler,
 };
+use crate::xapi::{ExportCompression, PowerState, SnapshotType, VM};
+use tracing::warn;
 
 pub fn deserialize_option_enum<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
 where
@@ -24,15 +39,53 @@ where
     }
 }
 
+/// job types are given by their short name in the config, e.g. `vdi`
+pub fn deserialize_job_type<'de, D>(deserializer: D) -> Result<JobType, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: String = serde::Deserialize::deserialize(deserializer)?;
+    JobType::from_str(&s).map_err(serde::de::Error::custom)
+}
+
+pub fn serialize_job_type<S>(job_type: &JobType, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_str(job_type)
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct GeneralConfig {
     pub log_level: String,
+    /// seconds the clock of a xen host may differ from the local one before a warning is logged
+    #[serde(default = "default_clock_skew_threshold")]
+    pub clock_skew_threshold: i64,
+    /// backups running at the same time over all jobs, on top of the `concurrency` of each job.
+    /// unlimited if unset
+    #[serde(default)]
+    pub max_concurrent_backups: Option<u32>,
+    /// seconds running VM backups may take to finish when the daemon is stopped, they are
+    /// cancelled afterwards
+    #[serde(default = "default_shutdown_grace_period")]
+    pub shutdown_grace_period: u64,
+}
+
+fn default_clock_skew_threshold() -> i64 {
+    30
+}
+
+fn default_shutdown_grace_period() -> u64 {
+    600
 }
 
 impl Default for GeneralConfig {
     fn default() -> GeneralConfig {
         GeneralConfig {
             log_level: "info".into(),
+            clock_skew_threshold: default_clock_skew_threshold(),
+            max_concurrent_backups: None,
+            shutdown_grace_period: default_shutdown_grace_period(),
         }
     }
 }
@@ -44,6 +97,19 @@ pub struct LocalStorageConfig {
     pub path: String,
     #[serde(deserialize_with = "deserialize_option_enum")]
     pub compression: Option<LocalCompressionType>,
+    /// compression level, defaults to the codec's default level
+    #[serde(default)]
+    pub compression_level: Option<i32>,
+    /// number of zstd worker threads, compression runs on the calling thread if unset
+    #[serde(default)]
+    pub compression_threads: Option<u32>,
+    /// backups younger than this are never deleted by the rotation
+    #[serde(default)]
+    pub delete_protection_days: Option<u32>,
+    #[serde(default)]
+    pub encryption: Option<LocalEncryptionType>,
+    #[serde(default)]
+    pub layout: LocalStorageLayout,
     pub retention: u32,
 }
 
@@ -54,6 +120,11 @@ impl Default for LocalStorageConfig {
             name: String::default(),
             path: String::default(),
             compression: None,
+            compression_level: None,
+            compression_threads: None,
+            delete_protection_days: None,
+            encryption: None,
+            layout: LocalStorageLayout::Flat,
             retention: 7,
         }
     }
@@ -68,10 +139,35 @@ pub struct BorgStorageConfig {
     pub ssh_key_path: Option<String>,
     #[serde(deserialize_with = "deserialize_option_enum")]
     pub encryption: Option<BorgEncryptionType>,
+    /// passphrase of an encrypted repository, takes precedence over `passphrase_file`
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    /// file containing the passphrase of an encrypted repository
+    #[serde(default)]
+    pub passphrase_file: Option<String>,
+    /// key file of a repository using keyfile encryption, instead of borg's default location
+    #[serde(default)]
+    pub key_file: Option<String>,
+    /// runs `borg break-lock` when the repository is locked, no xenbakd run owns it and the
+    /// holder of the lock was a process of this host which is gone
+    #[serde(default)]
+    pub break_stale_locks: bool,
+    /// borg binary on the remote host (`--remote-path`), e.g. `borg1` on rsync.net
+    #[serde(default)]
+    pub remote_path: Option<String>,
+    /// the repository only accepts new archives, rotation and deletion are left to a trusted client
+    #[serde(default)]
+    pub append_only: bool,
+    /// checks and compacts the repository on its own schedule, independent of the backup jobs
+    #[serde(default)]
+    pub maintenance: Option<BorgMaintenanceConfig>,
     #[serde(deserialize_with = "deserialize_option_enum")]
     pub compression: Option<BorgCompressionType>,
     pub retention: BorgStorageRetention,
     pub temp_dir: String,
+    /// backups younger than this are never deleted by the rotation
+    #[serde(default)]
+    pub delete_protection_days: Option<u32>,
 }
 
 impl Default for BorgStorageConfig {
@@ -83,6 +179,13 @@ impl Default for BorgStorageConfig {
             ssh_key_path: None,
             repository: String::default(),
             encryption: None,
+            passphrase: None,
+            passphrase_file: None,
+            key_file: None,
+            break_stale_locks: false,
+            remote_path: None,
+            append_only: false,
+            maintenance: None,
             compression: None,
             retention: BorgStorageRetention {
                 daily: 7,
@@ -91,14 +194,143 @@ impl Default for BorgStorageConfig {
                 yearly: 1,
             },
             temp_dir: "/tmp/xenbakd".into(),
+            delete_protection_days: None,
         }
     }
 }
 
+/// a storage handled by an agent (`xenbakd agent`) running on another machine
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RemoteStorageConfig {
+    pub enabled: bool,
+    pub name: String,
+    /// `host:port` of the agent
+    pub address: String,
+    /// name to validate the agent's certificate against, defaults to the host of `address`
+    pub server_name: Option<String>,
+    /// CA (or self-signed) certificate the agent's certificate is validated with
+    pub ca_cert: String,
+    pub token: String,
+    /// name of the storage configured on the agent
+    pub remote_storage: String,
+}
+
+/// a content-addressed chunk store, chunks shared by backups of all jobs are stored only once
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ChunkedStorageConfig {
+    pub enabled: bool,
+    pub name: String,
+    pub path: String,
+    pub retention: u32,
+    /// average size of the content-defined chunks in bytes
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u32,
+    /// compresses every chunk with zstd
+    #[serde(default = "default_chunk_compress")]
+    pub compress: bool,
+    /// backups younger than this are never deleted by the rotation
+    #[serde(default)]
+    pub delete_protection_days: Option<u32>,
+}
+
+/// scheduled `borg check`/`borg compact` of a repository
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BorgMaintenanceConfig {
+    /// cron expression, like the schedule of jobs
+    pub schedule: String,
+    /// runs `borg check`, read-only (without `--repair`)
+    #[serde(default = "default_maintenance_enabled")]
+    pub check: bool,
+    /// lets `borg check` read and verify all data, which reads the whole repository
+    #[serde(default)]
+    pub verify_data: bool,
+    /// runs `borg compact`, skipped for append-only repositories
+    #[serde(default = "default_maintenance_enabled")]
+    pub compact: bool,
+}
+
+fn default_maintenance_enabled() -> bool {
+    true
+}
+
+fn default_chunk_size() -> u32 {
+    1024 * 1024
+}
+
+fn default_chunk_compress() -> bool {
+    true
+}
+
+/// a storage handled by an external plugin executable
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PluginStorageConfig {
+    pub enabled: bool,
+    pub name: String,
+    /// path to the plugin executable
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub retention: u32,
+    /// backups younger than this are never deleted by the rotation
+    #[serde(default)]
+    pub delete_protection_days: Option<u32>,
+    /// passed to the plugin as is
+    #[serde(default)]
+    pub options: serde_json::Value,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct StorageConfig {
     pub local: Vec<LocalStorageConfig>,
     pub borg: Vec<BorgStorageConfig>,
+    #[serde(default)]
+    pub chunked: Vec<ChunkedStorageConfig>,
+    #[serde(default)]
+    pub remote: Vec<RemoteStorageConfig>,
+    #[serde(default)]
+    pub plugin: Vec<PluginStorageConfig>,
+}
+
+impl StorageConfig {
+    /// returns the config of the storage with the given name as JSON, secrets redacted
+    pub fn snapshot(&self, name: &str) -> Option<serde_json::Value> {
+        let serde_json::Value::Object(storage_types) = serde_json::to_value(self).ok()? else {
+            return None;
+        };
+        let mut storage = storage_types
+            .into_iter()
+            .map(|(_, x)| x)
+            .filter_map(|x| match x {
+                serde_json::Value::Array(storages) => Some(storages),
+                _ => None,
+            })
+            .flatten()
+            .find(|x| x["name"] == name && x["enabled"] == true)?;
+        redact_secrets(&mut storage);
+        Some(storage)
+    }
+}
+
+/// replaces passwords, passphrases, tokens and keys, so configs can be stored alongside backups.
+/// any key naming one of them is redacted, plugin options like `secret_access_key` included
+pub fn redact_secrets(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_lowercase();
+                let is_secret = ["secret", "key", "pass", "token", "credential"]
+                    .iter()
+                    .any(|x| key.contains(x));
+                if is_secret && !value.is_null() {
+                    *value = serde_json::Value::String("<redacted>".into());
+                } else {
+                    redact_secrets(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_secrets),
+        _ => {}
+    }
 }
 
 impl Default for StorageConfig {
@@ -106,10 +338,271 @@ impl Default for StorageConfig {
         StorageConfig {
             local: vec![LocalStorageConfig::default()],
             borg: vec![BorgStorageConfig::default()],
+            chunked: vec![],
+            remote: vec![],
+            plugin: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AgentConfig {
+    pub listen: String,
+    pub tls_cert: String,
+    pub tls_key: String,
+    pub token: String,
+}
+
+impl Default for AgentConfig {
+    fn default() -> AgentConfig {
+        AgentConfig {
+            listen: "127.0.0.1:7070".into(),
+            tls_cert: String::default(),
+            tls_key: String::default(),
+            token: String::default(),
         }
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// address the prometheus endpoint listens on
+    pub listen: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> MetricsConfig {
+        MetricsConfig {
+            enabled: false,
+            listen: "127.0.0.1:9477".into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport {
+    /// datagram socket of the local syslog daemon
+    #[default]
+    Unix,
+    Udp,
+    /// octet-counted frames, see RFC 6587
+    Tcp,
+}
+
+/// RFC 5424 messages to a syslog server, next to the output on stdout
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SyslogConfig {
+    pub enabled: bool,
+    #[serde(default)]
+    pub transport: SyslogTransport,
+    /// path of the socket or `host:port` of the server
+    #[serde(default = "default_syslog_address")]
+    pub address: String,
+    /// kern, user, mail, daemon, auth, syslog, lpr, news, uucp, cron, authpriv, ftp or local0 to
+    /// local7
+    #[serde(default = "default_syslog_facility")]
+    pub facility: String,
+    #[serde(default = "default_syslog_app_name")]
+    pub app_name: String,
+    /// log level of the messages sent to syslog, `general.log_level` if unset
+    #[serde(default)]
+    pub log_level: Option<String>,
+}
+
+fn default_syslog_address() -> String {
+    "/dev/log".into()
+}
+
+fn default_syslog_facility() -> String {
+    "daemon".into()
+}
+
+fn default_syslog_app_name() -> String {
+    "xenbakd".into()
+}
+
+impl Default for SyslogConfig {
+    fn default() -> SyslogConfig {
+        SyslogConfig {
+            enabled: false,
+            transport: SyslogTransport::default(),
+            address: default_syslog_address(),
+            facility: default_syslog_facility(),
+            app_name: default_syslog_app_name(),
+            log_level: None,
+        }
+    }
+}
+
+/// native journald messages, the fields of the events and their spans (job, VM, xen host)
+/// become fields of the journal entries
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JournaldConfig {
+    pub enabled: bool,
+    /// prefix of the fields, e.g. `XENBAKD_JOB`
+    #[serde(default = "default_journald_field_prefix")]
+    pub field_prefix: String,
+    #[serde(default = "default_journald_syslog_identifier")]
+    pub syslog_identifier: String,
+    /// log level of the messages sent to journald, `general.log_level` if unset
+    #[serde(default)]
+    pub log_level: Option<String>,
+}
+
+fn default_journald_field_prefix() -> String {
+    "XENBAKD".into()
+}
+
+fn default_journald_syslog_identifier() -> String {
+    "xenbakd".into()
+}
+
+impl Default for JournaldConfig {
+    fn default() -> JournaldConfig {
+        JournaldConfig {
+            enabled: false,
+            field_prefix: default_journald_field_prefix(),
+            syslog_identifier: default_journald_syslog_identifier(),
+            log_level: None,
+        }
+    }
+}
+
+/// outputs of the log besides stdout
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub syslog: SyslogConfig,
+    #[serde(default)]
+    pub journald: JournaldConfig,
+}
+
+/// on-disk history of the finished job runs, read by `xenbakd history`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    /// JSON lines file the runs are appended to
+    #[serde(default = "default_history_path")]
+    pub path: String,
+    /// number of runs kept, the oldest ones are dropped
+    #[serde(default = "default_history_max_entries")]
+    pub max_entries: usize,
+}
+
+fn default_history_path() -> String {
+    "/var/lib/xenbakd/history.jsonl".into()
+}
+
+fn default_history_max_entries() -> usize {
+    10000
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            enabled: false,
+            path: default_history_path(),
+            max_entries: default_history_max_entries(),
+        }
+    }
+}
+
+/// VMs which failed several runs of a job in a row, see `quarantine_after` of the jobs
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct QuarantineConfig {
+    /// JSON file the consecutive failures and quarantined VMs are kept in
+    #[serde(default = "default_quarantine_path")]
+    pub path: String,
+}
+
+fn default_quarantine_path() -> String {
+    "/var/lib/xenbakd/quarantine.json".into()
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> QuarantineConfig {
+        QuarantineConfig {
+            path: default_quarantine_path(),
+        }
+    }
+}
+
+/// scheduled check for VMs which aren't backed up by any enabled job, e.g. new VMs nobody tagged
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CoverageConfig {
+    pub enabled: bool,
+    #[serde(default = "default_coverage_schedule")]
+    pub schedule: String,
+    /// reports the unprotected VMs as failed objects, so the run sends a warning notification
+    #[serde(default)]
+    pub notify: bool,
+    /// name-labels or uuids of VMs which are deliberately not backed up
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// VMs with any of these tags are deliberately not backed up
+    #[serde(default)]
+    pub ignore_tags: Vec<String>,
+    /// JSON file the VMs seen by the last check are kept in, to tell new VMs apart
+    #[serde(default = "default_known_vms_path")]
+    pub known_vms_path: String,
+    /// reports new unprotected VMs as failed objects, once. unlike `notify`, VMs which were
+    /// unprotected before don't send a warning on every check
+    #[serde(default)]
+    pub notify_new: bool,
+    /// job new unprotected VMs are added to, by tagging them with the first tag of its
+    /// `tag_filter`
+    #[serde(default)]
+    pub default_job: Option<String>,
+}
+
+fn default_known_vms_path() -> String {
+    "/var/lib/xenbakd/known_vms.json".into()
+}
+
+fn default_coverage_schedule() -> String {
+    "0 0 8 * * *".into()
+}
+
+impl CoverageConfig {
+    pub fn is_ignored(&self, vm: &VM) -> bool {
+        self.ignore
+            .iter()
+            .any(|x| x == &vm.name_label || x == &vm.uuid)
+            || self.ignore_tags.iter().any(|x| vm.tags.contains(x))
+    }
+}
+
+impl Default for CoverageConfig {
+    fn default() -> CoverageConfig {
+        CoverageConfig {
+            enabled: false,
+            schedule: default_coverage_schedule(),
+            notify: false,
+            ignore: vec![],
+            ignore_tags: vec![],
+            known_vms_path: default_known_vms_path(),
+            notify_new: false,
+            default_job: None,
+        }
+    }
+}
+
+/// mappings applied when restoring into a pool whose SRs and networks differ from the source pool
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RestoreConfig {
+    /// source SR (uuid or name-label) -> target SR (uuid or name-label)
+    #[serde(default)]
+    pub sr_map: BTreeMap<String, String>,
+    /// source network (uuid or name-label) -> target network (uuid or name-label)
+    #[serde(default)]
+    pub network_map: BTreeMap<String, String>,
+    /// gives the restored VIFs new MAC addresses instead of the ones of the backup
+    #[serde(default)]
+    pub regenerate_macs: bool,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct HealthchecksConfig {
     pub enabled: bool,
@@ -157,32 +650,627 @@ impl Default for MailConfig {
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ExecNotifierConfig {
+    pub enabled: bool,
+    pub name: String,
+    /// executable run for every event, receives the event as JSON on stdin
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// seconds after which the command is killed
+    #[serde(default = "default_exec_notifier_timeout")]
+    pub timeout: u64,
+}
+
+fn default_exec_notifier_timeout() -> u64 {
+    30
+}
+
+/// messages of a telegram bot to a chat, group or channel
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TelegramConfig {
+    pub enabled: bool,
+    pub bot_token: String,
+    /// id of the chat, or `@<name>` of a public channel
+    pub chat_id: String,
+    #[serde(default = "default_telegram_api_url")]
+    pub api_url: String,
+    /// errors listed in a message, the rest is only counted
+    #[serde(default = "default_telegram_max_errors")]
+    pub max_errors: usize,
+}
+
+fn default_telegram_api_url() -> String {
+    "https://api.telegram.org".into()
+}
+
+fn default_telegram_max_errors() -> usize {
+    10
+}
+
+impl Default for TelegramConfig {
+    fn default() -> TelegramConfig {
+        TelegramConfig {
+            enabled: false,
+            bot_token: String::default(),
+            chat_id: String::default(),
+            api_url: default_telegram_api_url(),
+            max_errors: default_telegram_max_errors(),
+        }
+    }
+}
+
+/// alerts of failed runs in opsgenie, one per job which is closed by its next successful run
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OpsgenieConfig {
+    pub enabled: bool,
+    /// key of an api integration
+    pub api_key: String,
+    /// `https://api.eu.opsgenie.com` for accounts in the EU
+    #[serde(default = "default_opsgenie_api_url")]
+    pub api_url: String,
+    /// priority of the alerts of failed runs, P1 to P5
+    #[serde(default = "default_opsgenie_priority")]
+    pub priority: String,
+    /// raise alerts for runs with failed objects within their failure threshold as well
+    #[serde(default = "default_opsgenie_alert_on_warning")]
+    pub alert_on_warning: bool,
+    #[serde(default = "default_opsgenie_warning_priority")]
+    pub warning_priority: String,
+    #[serde(default = "default_opsgenie_tags")]
+    pub tags: Vec<String>,
+}
+
+fn default_opsgenie_api_url() -> String {
+    "https://api.opsgenie.com".into()
+}
+
+fn default_opsgenie_priority() -> String {
+    "P3".into()
+}
+
+fn default_opsgenie_alert_on_warning() -> bool {
+    true
+}
+
+fn default_opsgenie_warning_priority() -> String {
+    "P4".into()
+}
+
+fn default_opsgenie_tags() -> Vec<String> {
+    vec!["xenbakd".into()]
+}
+
+impl Default for OpsgenieConfig {
+    fn default() -> OpsgenieConfig {
+        OpsgenieConfig {
+            enabled: false,
+            api_key: String::default(),
+            api_url: default_opsgenie_api_url(),
+            priority: default_opsgenie_priority(),
+            alert_on_warning: default_opsgenie_alert_on_warning(),
+            warning_priority: default_opsgenie_warning_priority(),
+            tags: default_opsgenie_tags(),
+        }
+    }
+}
+
+/// measurements of every run and its VMs written to an influxdb v2 bucket
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct InfluxDbConfig {
+    pub enabled: bool,
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    /// api token with write access to the bucket
+    pub token: String,
+    /// the measurements are named `<prefix>_job` and `<prefix>_vm`
+    #[serde(default = "default_influxdb_measurement_prefix")]
+    pub measurement_prefix: String,
+    /// seconds a write may take
+    #[serde(default = "default_influxdb_timeout")]
+    pub timeout: u64,
+}
+
+fn default_influxdb_measurement_prefix() -> String {
+    "xenbakd".into()
+}
+
+fn default_influxdb_timeout() -> u64 {
+    30
+}
+
+impl Default for InfluxDbConfig {
+    fn default() -> InfluxDbConfig {
+        InfluxDbConfig {
+            enabled: false,
+            url: String::default(),
+            org: String::default(),
+            bucket: String::default(),
+            token: String::default(),
+            measurement_prefix: default_influxdb_measurement_prefix(),
+            timeout: default_influxdb_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdProtocol {
+    /// statsd over udp
+    #[default]
+    Statsd,
+    /// graphite's plaintext protocol over tcp
+    Graphite,
+}
+
+/// metrics of every run and its VMs sent to statsd or graphite
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StatsdConfig {
+    pub enabled: bool,
+    #[serde(default)]
+    pub protocol: StatsdProtocol,
+    /// `host:port` of the statsd daemon or the graphite carbon receiver
+    pub address: String,
+    /// the metrics are named `<prefix>.jobs.<job>.<metric>` and `<prefix>.vms.<job>.<vm>.<metric>`
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: String,
+}
+
+fn default_statsd_prefix() -> String {
+    "xenbakd".into()
+}
+
+impl Default for StatsdConfig {
+    fn default() -> StatsdConfig {
+        StatsdConfig {
+            enabled: false,
+            protocol: StatsdProtocol::default(),
+            address: "127.0.0.1:8125".into(),
+            prefix: default_statsd_prefix(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct MonitoringConfig {
     pub mail: MailConfig,
     pub healthchecks: HealthchecksConfig,
+    #[serde(default)]
+    pub exec: Vec<ExecNotifierConfig>,
+    #[serde(default)]
+    pub telegram: TelegramConfig,
+    #[serde(default)]
+    pub opsgenie: OpsgenieConfig,
+    #[serde(default)]
+    pub influxdb: InfluxDbConfig,
+    #[serde(default)]
+    pub statsd: StatsdConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GuestQuiesceConfig {
+    pub vm_name: String,
+    #[serde(default = "default_guest_quiesce_timeout")]
+    pub timeout: u64,
+    #[serde(flatten)]
+    pub method: GuestQuiesceMethod,
+}
+
+/// runs commands in or against the guests of VMs with one of the tags, right before their
+/// snapshot and after their export, e.g. to dump a database into the snapshot
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GuestHookConfig {
+    pub tags: Vec<String>,
+    /// maximum seconds a hook may take
+    #[serde(default = "default_guest_hook_timeout")]
+    pub timeout: u64,
+    #[serde(flatten)]
+    pub method: GuestHookMethod,
+}
+
+fn default_guest_hook_timeout() -> u64 {
+    300
+}
+
+/// settings of a job which differ for some of its VMs, matched by name-label, uuid or tag. unset
+/// settings are left as configured for the job, later overrides take precedence
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct VmOverrideConfig {
+    /// name-labels or uuids of the VMs
+    #[serde(default)]
+    pub vms: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub export_compression: Option<ExportCompression>,
+    #[serde(default)]
+    pub snapshot_type: Option<SnapshotType>,
+    /// number of restore points kept per storage, replacing the retention of the storages
+    #[serde(default)]
+    pub retention: Option<u32>,
+    /// only snapshot the VM and keep the snapshot on the host, nothing is exported
+    #[serde(default)]
+    pub skip_export: bool,
+    /// cron schedule of the VM's backups, the next run of the job after it fired backs the VM up.
+    /// it can only make the backups rarer than the job's own schedule
+    #[serde(default)]
+    pub schedule: Option<String>,
+}
+
+/// prefix of the VM tags overriding settings of the job, e.g. `xenbakd:retention=30`
+pub const TAG_OVERRIDE_PREFIX: &str = "xenbakd:";
+
+impl VmOverrideConfig {
+    pub fn matches(&self, vm: &VM) -> bool {
+        self.vms
+            .iter()
+            .any(|x| x == &vm.name_label || x == &vm.uuid)
+            || self.tags.iter().any(|tag| vm.tags.contains(tag))
+    }
+
+    /// reads the overrides from `xenbakd:<setting>=<value>` tags of the VM. the owner of a VM can
+    /// set `schedule`, `retention`, `snapshot_type` and `export_compression`, invalid tags are
+    /// ignored with a warning
+    pub fn from_tags(vm: &VM) -> VmOverrideConfig {
+        let mut vm_override = VmOverrideConfig::default();
+        for tag in &vm.tags {
+            let Some((key, value)) = tag
+                .strip_prefix(TAG_OVERRIDE_PREFIX)
+                .and_then(|x| x.split_once('='))
+            else {
+                continue;
+            };
+            let value = value.trim();
+            let result = match key.trim() {
+                "schedule" => window::parse_schedule(value)
+                    .map(|_| vm_override.schedule = Some(value.to_string())),
+                "retention" => value
+                    .parse()
+                    .map(|x| vm_override.retention = Some(x))
+                    .map_err(|e| eyre::eyre!("{}", e)),
+                "snapshot_type" => SnapshotType::deserialize(value.into_deserializer())
+                    .map(|x| vm_override.snapshot_type = Some(x))
+                    .map_err(|e: serde::de::value::Error| eyre::eyre!("{}", e)),
+                "export_compression" => ExportCompression::deserialize(value.into_deserializer())
+                    .map(|x| vm_override.export_compression = Some(x))
+                    .map_err(|e: serde::de::value::Error| eyre::eyre!("{}", e)),
+                _ => Err(eyre::eyre!("unknown setting")),
+            };
+            if let Err(e) = result {
+                warn!("Ignoring tag '{}' of VM '{}': {}", tag, vm.name_label, e);
+            }
+        }
+        vm_override
+    }
+
+    /// returns the job's configuration with the overridden settings applied
+    pub fn apply(&self, job_config: &JobConfig) -> JobConfig {
+        let mut job_config = job_config.clone();
+        if let Some(export_compression) = self.export_compression {
+            job_config.export_compression = Some(export_compression);
+        }
+        if let Some(snapshot_type) = &self.snapshot_type {
+            job_config.snapshot_type = snapshot_type.clone();
+        }
+        // the snapshot is the only restore point, so it is kept instead of being a differential base
+        if self.skip_export {
+            job_config.differential = None;
+            job_config.keep_snapshots = job_config.keep_snapshots.max(1);
+        }
+        job_config
+    }
+}
+
+/// powers VMs down for their snapshot, for workloads which need fully consistent backups. the
+/// VM is powered on again as soon as the snapshot exists, the export runs from the snapshot
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ColdBackupConfig {
+    #[serde(default)]
+    pub mode: ColdBackupMode,
+    /// only VMs with any of these tags are powered down, all VMs of the job if empty
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// seconds to wait for the VM to power down or up again
+    #[serde(default = "default_cold_backup_timeout")]
+    pub timeout: u64,
+}
+
+fn default_cold_backup_timeout() -> u64 {
+    600
+}
+
+/// what happens when a new backup would exceed a storage quota
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum QuotaAction {
+    /// delete the oldest backups of the VM until the new backup fits
+    #[default]
+    #[serde(rename = "rotate")]
+    Rotate,
+    /// fail the backup of the VM
+    #[serde(rename = "fail")]
+    Fail,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QuotaConfig {
+    /// name of the storage the quota applies to
+    pub storage: String,
+    /// maximum bytes the backups of a single VM may use
+    #[serde(default)]
+    pub vm_limit: Option<u64>,
+    /// maximum bytes the backups of all VMs of the job may use
+    #[serde(default)]
+    pub job_limit: Option<u64>,
+    #[serde(default)]
+    pub action: QuotaAction,
+}
+
+/// differential backups export the disks as VHD, relative to a snapshot of the previous backup
+/// which is kept on the host. works without changed block tracking
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DifferentialConfig {
+    /// a full backup is made once the chain of a VM reaches this length, including the full backup
+    #[serde(default = "default_full_interval")]
+    pub full_interval: u32,
+}
+
+fn default_full_interval() -> u32 {
+    7
 }
 
-impl Default for MonitoringConfig {
-    fn default() -> MonitoringConfig {
-        MonitoringConfig {
-            mail: MailConfig::default(),
-            healthchecks: HealthchecksConfig::default(),
+impl Default for DifferentialConfig {
+    fn default() -> DifferentialConfig {
+        DifferentialConfig {
+            full_interval: default_full_interval(),
         }
     }
 }
 
+/// imports the newest backup of a VM job into a sandbox SR every `interval` runs and boots it,
+/// to prove the backups can actually be restored. the test VM gets no network and is destroyed
+/// afterwards
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TestRestoreConfig {
+    /// SR (uuid or name-label) the test VM is imported into
+    pub sr: String,
+    /// xen host the test VM is imported on, the host the VM was backed up from if unset
+    #[serde(default)]
+    pub xen_host: Option<String>,
+    /// test restore every Nth run of the job
+    #[serde(default = "default_test_restore_interval")]
+    pub interval: u32,
+    /// seconds the test VM has to keep running after its start
+    #[serde(default = "default_test_restore_boot_time")]
+    pub boot_time: u64,
+    /// the guest agent of the test VM has to report in within `boot_time`, instead of the VM
+    /// merely running
+    #[serde(default)]
+    pub guest_agent: bool,
+}
+
+fn default_test_restore_interval() -> u32 {
+    1
+}
+
+fn default_test_restore_boot_time() -> u64 {
+    120
+}
+
+/// replication jobs copy VMs into another pool instead of a storage, keeping a rolling set of
+/// halted replicas there which can be started if the source pool is lost
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReplicationConfig {
+    /// name of the `[[xen]]` host of the destination pool
+    pub target: String,
+    /// SR (uuid or name-label) of the destination pool the replicas are imported into, its
+    /// default SR if unset
+    #[serde(default)]
+    pub sr: Option<String>,
+    /// name-label of the replicas, `{vm}` is replaced by the name-label of the VM and
+    /// `{timestamp}` by the time of its snapshot
+    #[serde(default = "default_replica_name_format")]
+    pub name_format: String,
+    /// number of replicas per VM kept on the destination pool, the oldest ones are deleted once
+    /// there are more
+    #[serde(default = "default_replica_keep")]
+    pub keep: u32,
+}
+
+fn default_replica_name_format() -> String {
+    "{vm} (replica {timestamp})".to_string()
+}
+
+fn default_replica_keep() -> u32 {
+    1
+}
+
+fn default_hook_timeout() -> u64 {
+    600
+}
+
+fn default_retry_delay() -> u64 {
+    300
+}
+
+fn default_catch_up_max_lateness() -> u64 {
+    43200
+}
+
+fn default_deferred_retry_delay() -> u64 {
+    60
+}
+
+fn default_coalesce_guard() -> bool {
+    true
+}
+
+fn default_guest_quiesce_timeout() -> u64 {
+    60
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct JobConfig {
     pub enabled: bool,
     pub name: String,
+    /// `vm` backs up whole VMs, `vdi` single VDIs selected by tag or SR, `pool-metadata` the pool
+    /// database, `replication` copies VMs into another pool
+    #[serde(
+        default,
+        rename = "type",
+        deserialize_with = "deserialize_job_type",
+        serialize_with = "serialize_job_type"
+    )]
+    pub job_type: JobType,
     pub schedule: String,
     pub tag_filter: Vec<String>,
     pub tag_filter_exclude: Vec<String>,
+    /// VMs (uuid or name-label) never backed up by the job, regardless of their tags
+    #[serde(default)]
+    pub vm_exclude: Vec<String>,
+    /// SRs (uuid or name-label) whose VDIs are backed up by VDI jobs, in addition to the tagged ones
+    #[serde(default)]
+    pub sr_filter: Vec<String>,
+    /// only back up VMs in one of these power states, all VMs if empty
+    #[serde(default)]
+    pub power_state_filter: Vec<PowerState>,
+    /// disks with one of these tags are left out of VM backups
+    #[serde(default)]
+    pub vdi_tag_filter_exclude: Vec<String>,
+    /// disks (uuid or name-label) left out of VM backups
+    #[serde(default)]
+    pub vdi_exclude: Vec<String>,
     pub concurrency: u32,
     pub storages: Vec<String>,
+    #[serde(default)]
     pub xen_hosts: Vec<String>,
+    /// backs up all xen hosts labelled with one of these pools, in addition to `xen_hosts`
+    #[serde(default)]
+    pub pools: Vec<String>,
     pub use_existing_snapshot: bool,
     pub use_existing_snapshot_age: Option<i64>,
+    /// `memory` checkpoints running VMs including their memory, halted VMs get a basic snapshot
+    #[serde(default)]
+    pub snapshot_type: SnapshotType,
+    #[serde(default)]
+    pub guest_quiesce: Vec<GuestQuiesceConfig>,
+    /// commands run in the guests of tagged VMs around their backup, see `GuestHookConfig`
+    #[serde(default)]
+    pub guest_hooks: Vec<GuestHookConfig>,
+    /// shut down or suspend VMs for their snapshot, see `ColdBackupConfig`
+    #[serde(default)]
+    pub cold_backup: Option<ColdBackupConfig>,
+    #[serde(default)]
+    pub quota: Vec<QuotaConfig>,
+    #[serde(default)]
+    pub verify: bool,
+    /// rotation only logs which backups it would delete, `simulate_prune` in older configs
+    #[serde(default, alias = "simulate_prune")]
+    pub rotate_dry_run: bool,
+    /// only discovers the VMs, checks the storages and simulates the rotation, nothing is
+    /// snapshotted or exported
+    #[serde(default)]
+    pub dry_run: bool,
+    /// seconds to wait before retrying the backups of VMs which were busy with another operation
+    #[serde(default = "default_deferred_retry_delay")]
+    pub deferred_retry_delay: u64,
+    /// number of times a failed backup of a VM is retried at the end of the run before it counts
+    /// as failed
+    #[serde(default)]
+    pub retries: u32,
+    /// seconds to wait before retrying failed backups
+    #[serde(default = "default_retry_delay")]
+    pub retry_delay: u64,
+    /// seconds after a failed run the whole job is run once more, the failure is only reported if
+    /// the re-run fails as well. never re-run if unset
+    #[serde(default)]
+    pub rerun_delay: Option<u64>,
+    /// seconds to wait for the SRs to reclaim the space of deleted snapshots, unchecked if unset
+    #[serde(default)]
+    pub reclaim_timeout: Option<u64>,
+    /// defer VMs whose disks still wait for the coalesce of a deleted snapshot instead of
+    /// snapshotting them again
+    #[serde(default = "default_coalesce_guard")]
+    pub coalesce_guard: bool,
+    /// seconds to wait for the disks of a VM to coalesce after its snapshot was deleted, not
+    /// awaited if unset
+    #[serde(default)]
+    pub coalesce_timeout: Option<u64>,
+    /// number of snapshots per VM kept on the host as quick restore points, the oldest ones are
+    /// deleted once there are more. 0 deletes the snapshot after every backup
+    #[serde(default)]
+    pub keep_snapshots: u32,
+    /// let the host compress VM exports, moving the load onto dom0. storages should not
+    /// compress them again
+    #[serde(default)]
+    pub export_compression: Option<ExportCompression>,
+    /// name of the `[job_templates.<name>]` section this job inherits from
+    #[serde(default)]
+    pub extends: Option<String>,
+    /// export only the blocks changed since the previous backup, see `DifferentialConfig`
+    #[serde(default)]
+    pub differential: Option<DifferentialConfig>,
+    /// destination of `replication` jobs, see `ReplicationConfig`
+    #[serde(default)]
+    pub replication: Option<ReplicationConfig>,
+    /// periodically restore the newest backup into a sandbox, see `TestRestoreConfig`
+    #[serde(default)]
+    pub test_restore: Option<TestRestoreConfig>,
+    /// command and arguments run before the job, e.g. to quiesce an application or mount a target
+    #[serde(default)]
+    pub pre_hook: Vec<String>,
+    /// command and arguments run after the job, with its result and stats
+    #[serde(default)]
+    pub post_hook: Vec<String>,
+    #[serde(default)]
+    pub hook_failure: HookFailureAction,
+    /// seconds after which a hook command is killed and counts as failed
+    #[serde(default = "default_hook_timeout")]
+    pub hook_timeout: u64,
+    /// seconds a run may take, backups still running or waiting then are cancelled and the
+    /// run fails. unlimited if unset
+    #[serde(default)]
+    pub max_runtime_seconds: Option<u64>,
+    /// percentage of the objects of a run which may fail while the run only reports a warning
+    /// instead of a failure. any failed object fails the run if unset
+    #[serde(default)]
+    pub failure_threshold_percent: Option<f64>,
+    /// settings which differ for some of the job's VMs, see `VmOverrideConfig`
+    #[serde(default)]
+    pub vm_overrides: Vec<VmOverrideConfig>,
+    /// applies `xenbakd:<setting>=<value>` tags of the VMs as overrides, so VM owners can tune
+    /// their backups without access to the configuration
+    #[serde(default)]
+    pub tag_overrides: bool,
+    /// back up the VMs a run interrupted by a restart of the daemon didn't get to right after the
+    /// restart, instead of at the next scheduled run. needs the history
+    #[serde(default)]
+    pub resume_interrupted: bool,
+    /// runs the job right after the start of the daemon if its schedule fired while the daemon
+    /// was down, judged by the last run in the history. needs the history
+    #[serde(default)]
+    pub catch_up: bool,
+    /// seconds a missed run may be late to still be caught up
+    #[serde(default = "default_catch_up_max_lateness")]
+    pub catch_up_max_lateness: u64,
+    /// number of runs in a row a VM has to fail to be quarantined: it is skipped by the job until
+    /// it is released with `xenbakd quarantine clear`. never quarantined if unset
+    #[serde(default)]
+    pub quarantine_after: Option<u32>,
+    /// times of day the backups of VMs may start at, any time if empty. backups running when a
+    /// window closes are finished, the VMs not started yet are skipped
+    #[serde(default)]
+    pub backup_windows: Vec<TimeWindow>,
+    /// times of day no backup of a VM may start at, even within a backup window
+    #[serde(default)]
+    pub blackout_periods: Vec<TimeWindow>,
+    /// jobs with a higher priority get the free slots of `max_concurrent_backups` first
+    #[serde(default)]
+    pub priority: i32,
+    /// maximum seconds a scheduled run is delayed by at random, so jobs and daemons sharing a
+    /// schedule don't all hit the pool master and the storages at the same second
+    #[serde(default)]
+    pub schedule_jitter: Option<u64>,
 }
 
 impl JobConfig {
@@ -211,19 +1299,129 @@ impl JobConfig {
             })
             .collect::<Vec<Arc<dyn StorageHandler>>>();
 
+        let chunked_storage = config
+            .chunked
+            .iter()
+            .filter(|x| x.enabled && self.storages.contains(&x.name))
+            .map(|x| {
+                Arc::new(storage::chunked::ChunkedStorage::new(
+                    x.clone(),
+                    self.clone(),
+                )) as Arc<dyn StorageHandler>
+            })
+            .collect::<Vec<Arc<dyn StorageHandler>>>();
+
+        let remote_storage = config
+            .remote
+            .iter()
+            .filter(|x| x.enabled && self.storages.contains(&x.name))
+            .map(|x| {
+                Arc::new(storage::remote::RemoteStorage::new(x.clone(), self.clone()))
+                    as Arc<dyn StorageHandler>
+            })
+            .collect::<Vec<Arc<dyn StorageHandler>>>();
+
+        let plugin_storage = config
+            .plugin
+            .iter()
+            .filter(|x| x.enabled && self.storages.contains(&x.name))
+            .map(|x| {
+                Arc::new(storage::plugin::PluginStorage::new(x.clone(), self.clone()))
+                    as Arc<dyn StorageHandler>
+            })
+            .collect::<Vec<Arc<dyn StorageHandler>>>();
+
         storages.extend(local_storage);
         storages.extend(borg_storage);
+        storages.extend(chunked_storage);
+        storages.extend(remote_storage);
+        storages.extend(plugin_storage);
 
         storages
     }
 
+    /// returns the temporary directories used by the job's storages
+    pub fn get_temp_dirs(&self, config: StorageConfig) -> Vec<String> {
+        config
+            .borg
+            .iter()
+            .filter(|x| x.enabled && self.storages.contains(&x.name))
+            .map(|x| x.temp_dir.clone())
+            .collect()
+    }
+
+    /// returns the guest quiesce configuration for the given VM, if any
+    pub fn get_guest_quiesce(&self, vm_name: &str) -> Option<&GuestQuiesceConfig> {
+        self.guest_quiesce.iter().find(|x| x.vm_name == vm_name)
+    }
+
+    /// whether the VM is left out by `vm_exclude`
+    pub fn is_vm_excluded(&self, vm: &VM) -> bool {
+        self.vm_exclude
+            .iter()
+            .any(|x| x == &vm.name_label || x == &vm.uuid)
+    }
+
+    /// whether the VM is backed up by the job according to its tag filters and `vm_exclude`
+    pub fn selects_vm(&self, vm: &VM) -> bool {
+        self.tag_filter.iter().any(|x| vm.tags.contains(x))
+            && !self.tag_filter_exclude.iter().any(|x| vm.tags.contains(x))
+            && !self.is_vm_excluded(vm)
+    }
+
+    /// merges the `vm_overrides` matching the VM, in the order they are configured, and the
+    /// overrides of its tags if `tag_overrides` is enabled
+    pub fn get_vm_override(&self, vm: &VM) -> VmOverrideConfig {
+        let mut merged = VmOverrideConfig::default();
+        for vm_override in self.vm_overrides.iter().filter(|x| x.matches(vm)) {
+            merged.export_compression =
+                vm_override.export_compression.or(merged.export_compression);
+            merged.snapshot_type = vm_override.snapshot_type.clone().or(merged.snapshot_type);
+            merged.retention = vm_override.retention.or(merged.retention);
+            merged.skip_export |= vm_override.skip_export;
+            merged.schedule = vm_override.schedule.clone().or(merged.schedule);
+        }
+        // the VM's own tags take precedence over the configured overrides
+        if self.tag_overrides {
+            let tags = VmOverrideConfig::from_tags(vm);
+            merged.export_compression = tags.export_compression.or(merged.export_compression);
+            merged.snapshot_type = tags.snapshot_type.or(merged.snapshot_type);
+            merged.retention = tags.retention.or(merged.retention);
+            merged.schedule = tags.schedule.or(merged.schedule);
+        }
+        merged
+    }
+
+    /// returns the quota configured for the given storage, if any
+    pub fn get_quota(&self, storage_name: &str) -> Option<&QuotaConfig> {
+        self.quota.iter().find(|x| x.storage == storage_name)
+    }
+
     pub fn get_xen_configs(&self, xen_config: Vec<XenConfig>) -> Vec<XenConfig> {
         xen_config
             .iter()
-            .filter(|x| self.xen_hosts.contains(&x.name))
+            .filter(|x| {
+                self.xen_hosts.contains(&x.name)
+                    || x.pool
+                        .as_ref()
+                        .is_some_and(|pool| self.pools.contains(pool))
+            })
             .cloned()
             .collect()
     }
+
+    /// narrows the job down to its xen hosts labelled with one of the given pools, returns
+    /// false if none of its hosts is left
+    pub fn restrict_to_pools(&mut self, pools: &[String], xen_config: Vec<XenConfig>) -> bool {
+        self.xen_hosts = self
+            .get_xen_configs(xen_config)
+            .into_iter()
+            .filter(|x| x.pool.as_ref().is_some_and(|pool| pools.contains(pool)))
+            .map(|x| x.name)
+            .collect();
+        self.pools = vec![];
+        !self.xen_hosts.is_empty()
+    }
 }
 
 impl Default for JobConfig {
@@ -231,14 +1429,58 @@ impl Default for JobConfig {
         JobConfig {
             enabled: false,
             name: String::default(),
+            job_type: JobType::VmBackup,
             schedule: "0 0 * * *".into(),
             tag_filter: vec![String::default()],
             tag_filter_exclude: vec![String::default()],
+            sr_filter: vec![],
+            power_state_filter: vec![],
+            vdi_tag_filter_exclude: vec![],
+            vdi_exclude: vec![],
+            vm_exclude: vec![],
             xen_hosts: vec![String::default()],
+            pools: vec![],
             storages: vec![String::default()],
             concurrency: 1,
             use_existing_snapshot: false,
             use_existing_snapshot_age: Some(3600),
+            snapshot_type: SnapshotType::default(),
+            guest_quiesce: vec![],
+            guest_hooks: vec![],
+            cold_backup: None,
+            quota: vec![],
+            verify: false,
+            rotate_dry_run: false,
+            dry_run: false,
+            deferred_retry_delay: default_deferred_retry_delay(),
+            retries: 0,
+            retry_delay: default_retry_delay(),
+            rerun_delay: None,
+            reclaim_timeout: None,
+            coalesce_guard: default_coalesce_guard(),
+            coalesce_timeout: None,
+            keep_snapshots: 0,
+            export_compression: None,
+            extends: None,
+            differential: None,
+            replication: None,
+            test_restore: None,
+            pre_hook: vec![],
+            post_hook: vec![],
+            hook_failure: HookFailureAction::default(),
+            hook_timeout: default_hook_timeout(),
+            max_runtime_seconds: None,
+            failure_threshold_percent: None,
+            vm_overrides: vec![],
+            tag_overrides: false,
+            resume_interrupted: false,
+            catch_up: false,
+            catch_up_max_lateness: default_catch_up_max_lateness(),
+            quarantine_after: None,
+            backup_windows: vec![],
+            blackout_periods: vec![],
+            priority: 0,
+            schedule_jitter: None,
         }
     }
 }
@@ -247,10 +1489,101 @@ impl Default for JobConfig {
 pub struct XenConfig {
     pub enabled: bool,
     pub name: String,
+    /// alias of the pool the host belongs to, selects `[pools.<alias>]` defaults and labels backups
+    #[serde(default)]
+    pub pool: Option<String>,
     pub username: String,
     pub server: String,
+    /// other pool members tried in turn if `server` is unreachable, the pool master may have moved
+    #[serde(default)]
+    pub fallback_servers: Vec<String>,
+    /// password of the XAPI user, takes precedence over `password_file`
+    #[serde(default)]
     pub password: String,
+    /// file containing the password of the XAPI user
+    #[serde(default)]
+    pub password_file: Option<String>,
+    /// how the password is handed to `xe`, `argument` makes it visible in the process list
+    #[serde(default)]
+    pub xe_password: XePasswordMode,
     pub port: u16,
+    /// how xenbakd talks to the host, `xmlrpc` doesn't need a local `xe` for VM queries, snapshots and exports
+    #[serde(default)]
+    pub api: XApiTransport,
+    /// verify the host's TLS certificate with the xmlrpc api, hosts use self-signed ones by default
+    #[serde(default)]
+    pub verify_tls: bool,
+    /// cancels export tasks which made no progress for this many seconds, 0 never cancels them
+    #[serde(default = "default_task_stall_timeout")]
+    pub task_stall_timeout: u64,
+    /// seconds after which `xe` queries and other short commands are killed, 0 never kills them
+    #[serde(default = "default_command_timeout")]
+    pub command_timeout: u64,
+    /// seconds after which creating or deleting a snapshot is killed, 0 never kills it
+    #[serde(default = "default_snapshot_timeout")]
+    pub snapshot_timeout: u64,
+    /// seconds an export may send no data before it is aborted, 0 never aborts it
+    #[serde(default = "default_export_inactivity_timeout")]
+    pub export_inactivity_timeout: u64,
+}
+
+fn default_task_stall_timeout() -> u64 {
+    3600
+}
+
+fn default_command_timeout() -> u64 {
+    300
+}
+
+fn default_snapshot_timeout() -> u64 {
+    1800
+}
+
+fn default_export_inactivity_timeout() -> u64 {
+    900
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Hash, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum XePasswordMode {
+    /// `-pwf` with a temporary file only readable by xenbakd, holding the username and the
+    /// password of `password`/`password_file`. xe can't read `password_file` itself, it expects
+    /// the username in the file as well
+    #[default]
+    File,
+    /// `-pw`, the password shows up in `ps`
+    Argument,
+}
+
+impl XenConfig {
+    /// reads `password_file` anew on every call, logins and `xe` commands pick up a new password
+    pub fn get_password(&self) -> String {
+        if !self.password.is_empty() {
+            return self.password.clone();
+        }
+        let Some(password_file) = &self.password_file else {
+            return String::new();
+        };
+        match std::fs::read_to_string(password_file) {
+            Ok(password) => password.trim_end_matches(['\r', '\n']).to_string(),
+            Err(e) => {
+                // the host rejects the login with a meaningful error
+                warn!(
+                    "Failed to read password file '{}' of xen host '{}': {}",
+                    password_file, self.name, e
+                );
+                String::new()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Hash, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum XApiTransport {
+    #[default]
+    Cli,
+    XmlRpc,
 }
 
 impl Default for XenConfig {
@@ -258,10 +1591,20 @@ impl Default for XenConfig {
         XenConfig {
             enabled: false,
             name: "127.0.0.1".into(),
+            pool: None,
             username: String::default(),
             server: "127.0.0.1".into(),
+            fallback_servers: vec![],
             password: String::default(),
+            password_file: None,
+            xe_password: XePasswordMode::default(),
             port: 443,
+            api: XApiTransport::default(),
+            verify_tls: false,
+            task_stall_timeout: default_task_stall_timeout(),
+            command_timeout: default_command_timeout(),
+            snapshot_timeout: default_snapshot_timeout(),
+            export_inactivity_timeout: default_export_inactivity_timeout(),
         }
     }
 }
@@ -273,6 +1616,53 @@ pub struct AppConfig {
     pub storage: StorageConfig,
     pub monitoring: MonitoringConfig,
     pub jobs: Vec<JobConfig>,
+    #[serde(default)]
+    pub agent: AgentConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub restore: RestoreConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub quarantine: QuarantineConfig,
+    #[serde(default)]
+    pub coverage: CoverageConfig,
+}
+
+impl AppConfig {
+    /// jobs which aren't configured as jobs: the maintenance of borg storages, named
+    /// `borg-maintenance-<storage>`, and the `coverage` check
+    pub fn get_maintenance_jobs(&self) -> Vec<JobConfig> {
+        let mut jobs: Vec<JobConfig> = self
+            .storage
+            .borg
+            .iter()
+            .filter(|x| x.enabled)
+            .filter_map(|x| {
+                Some(JobConfig {
+                    enabled: true,
+                    name: format!("borg-maintenance-{}", x.name),
+                    job_type: JobType::BorgMaintenance,
+                    schedule: x.maintenance.as_ref()?.schedule.clone(),
+                    storages: vec![x.name.clone()],
+                    ..JobConfig::default()
+                })
+            })
+            .collect();
+        if self.coverage.enabled {
+            jobs.push(JobConfig {
+                enabled: true,
+                name: "coverage".into(),
+                job_type: JobType::Coverage,
+                schedule: self.coverage.schedule.clone(),
+                ..JobConfig::default()
+            });
+        }
+        jobs
+    }
 }
 
 impl Default for AppConfig {
@@ -282,14 +1672,183 @@ impl Default for AppConfig {
             storage: StorageConfig::default(),
             monitoring: MonitoringConfig::default(),
             jobs: vec![JobConfig::default()],
+            agent: AgentConfig::default(),
+            metrics: MetricsConfig::default(),
+            logging: LoggingConfig::default(),
+            restore: RestoreConfig::default(),
+            history: HistoryConfig::default(),
+            quarantine: QuarantineConfig::default(),
+            coverage: CoverageConfig::default(),
             xen: vec![XenConfig {
                 enabled: false,
                 name: String::default(),
+                pool: None,
                 username: String::default(),
                 server: String::default(),
+                fallback_servers: vec![],
                 password: String::default(),
+                password_file: None,
+                xe_password: XePasswordMode::default(),
                 port: 443,
+                api: XApiTransport::default(),
+                verify_tls: false,
+                task_stall_timeout: default_task_stall_timeout(),
+                command_timeout: default_command_timeout(),
+                snapshot_timeout: default_snapshot_timeout(),
+                export_inactivity_timeout: default_export_inactivity_timeout(),
             }],
         }
     }
 }
+
+impl AppConfig {
+    /// loads the config (see [`AppConfig::load_unresolved`]), resolves `[job_defaults]` and
+    /// `[job_templates.<name>]` into the jobs and `[pools.<alias>]` into the xen hosts
+    pub fn load(config_paths: &[String], profile: Option<&str>) -> eyre::Result<AppConfig> {
+        let figment = Self::figment(config_paths, profile)?;
+        let jobs = resolve_jobs(&figment)?;
+        let xen = resolve_xen_hosts(&figment)?;
+
+        Ok(figment
+            .merge(Serialized::default("jobs", jobs))
+            .merge(Serialized::default("xen", xen))
+            .extract::<AppConfig>()?)
+    }
+
+    /// returns the merged config as written, without resolving job and pool defaults
+    pub fn load_unresolved(config_paths: &[String], profile: Option<&str>) -> eyre::Result<Value> {
+        Ok(Self::figment(config_paths, profile)?.extract::<Value>()?)
+    }
+
+    /// merges the default config, the given config files in order and the overrides of the
+    /// selected `[profiles.<name>]` section on top
+    fn figment(config_paths: &[String], profile: Option<&str>) -> eyre::Result<Figment> {
+        let mut figment = Figment::from(Serialized::defaults(AppConfig::default()));
+
+        for config_path in config_paths {
+            figment = figment.merge(Toml::file(config_path));
+        }
+
+        if let Some(profile) = profile {
+            let profile_key = format!("profiles.{}", profile);
+            if figment.find_value(&profile_key).is_err() {
+                return Err(eyre::eyre!("Profile '{}' not found in config", profile));
+            }
+            let profile_figment = figment.clone().focus(&profile_key);
+            figment = figment.merge(profile_figment);
+        }
+
+        Ok(figment)
+    }
+}
+
+/// merges every job on top of its template chain and the job defaults
+fn resolve_jobs(figment: &Figment) -> eyre::Result<Vec<Dict>> {
+    let defaults = figment.find_value("job_defaults").ok();
+    let templates = match figment.find_value("job_templates") {
+        Ok(templates) => templates
+            .into_dict()
+            .ok_or_else(|| eyre::eyre!("job_templates has to be a table"))?,
+        Err(_) => Dict::new(),
+    };
+    let jobs = match figment.find_value("jobs") {
+        Ok(jobs) => jobs
+            .into_array()
+            .ok_or_else(|| eyre::eyre!("jobs has to be an array"))?,
+        Err(_) => vec![],
+    };
+
+    let mut resolved_jobs = vec![];
+    for job in jobs {
+        let job = job
+            .into_dict()
+            .ok_or_else(|| eyre::eyre!("Every job has to be a table"))?;
+        let job_name = job
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+
+        // collect the templates the job inherits from, nearest first
+        let mut layers = vec![job];
+        let mut chain = vec![job_name.clone()];
+        while let Some(parent) = layers
+            .last()
+            .unwrap()
+            .get("extends")
+            .and_then(Value::as_str)
+        {
+            let parent = parent.to_string();
+            if chain[1..].contains(&parent) {
+                chain.push(parent);
+                return Err(eyre::eyre!(
+                    "Job '{}' has a template cycle: {}",
+                    job_name,
+                    chain.join(" -> ")
+                ));
+            }
+
+            let template = templates
+                .get(&parent)
+                .and_then(Value::as_dict)
+                .ok_or_else(|| {
+                    eyre::eyre!("Job '{}' extends unknown template '{}'", job_name, parent)
+                })?;
+            layers.push(template.clone());
+            chain.push(parent);
+        }
+
+        let mut resolved = Figment::new();
+        if let Some(defaults) = &defaults {
+            resolved = resolved.merge(Serialized::defaults(defaults));
+        }
+        for layer in layers.into_iter().rev() {
+            resolved = resolved.merge(Serialized::defaults(layer));
+        }
+
+        resolved_jobs.push(resolved.extract::<Dict>()?);
+    }
+
+    Ok(resolved_jobs)
+}
+
+/// merges every xen host on top of the `[pools.<alias>]` defaults of its pool. pools without
+/// such a section are plain labels
+fn resolve_xen_hosts(figment: &Figment) -> eyre::Result<Vec<Dict>> {
+    let pools = match figment.find_value("pools") {
+        Ok(pools) => pools
+            .into_dict()
+            .ok_or_else(|| eyre::eyre!("pools has to be a table"))?,
+        Err(_) => Dict::new(),
+    };
+    let hosts = match figment.find_value("xen") {
+        Ok(hosts) => hosts
+            .into_array()
+            .ok_or_else(|| eyre::eyre!("xen has to be an array"))?,
+        Err(_) => vec![],
+    };
+
+    let mut resolved_hosts = vec![];
+    for host in hosts {
+        let host = host
+            .into_dict()
+            .ok_or_else(|| eyre::eyre!("Every xen host has to be a table"))?;
+
+        let mut resolved = Figment::new();
+        if let Some(defaults) = host
+            .get("pool")
+            .and_then(Value::as_str)
+            .and_then(|pool| pools.get(pool))
+        {
+            let defaults = defaults
+                .as_dict()
+                .ok_or_else(|| eyre::eyre!("Every pool has to be a table"))?;
+            resolved = resolved.merge(Serialized::defaults(defaults));
+        }
+        resolved = resolved.merge(Serialized::defaults(host));
+
+        resolved_hosts.push(resolved.extract::<Dict>()?);
+    }
+
+    Ok(resolved_hosts)
+}
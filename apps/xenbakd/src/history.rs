@@ -0,0 +1,246 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+
+use crate::{
+    cli::HistorySubCommand,
+    config::{AppConfig, HistoryConfig},
+    jobs::XenbakJobStats,
+};
+
+/// a finished run of a job, one line of the history file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub job_name: String,
+    pub job_type: String,
+    pub started: chrono::DateTime<chrono::Utc>,
+    pub finished: chrono::DateTime<chrono::Utc>,
+    pub outcome: String,
+    /// the stats of the run including its per-VM results, kept as JSON so older entries stay
+    /// readable when the stats change
+    pub stats: serde_json::Value,
+}
+
+impl HistoryEntry {
+    pub fn new(
+        job_name: String,
+        job_type: String,
+        started: chrono::DateTime<chrono::Utc>,
+        job_stats: &XenbakJobStats,
+    ) -> eyre::Result<Self> {
+        Ok(HistoryEntry {
+            job_name,
+            job_type,
+            started,
+            finished: chrono::Utc::now(),
+            outcome: job_stats.outcome.to_string(),
+            stats: serde_json::to_value(job_stats)?,
+        })
+    }
+}
+
+/// a run which hasn't finished yet. it is left over if the daemon was restarted during the run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunningJob {
+    pub job_name: String,
+    pub started: chrono::DateTime<chrono::Utc>,
+    /// uuids of the VMs backed up so far
+    pub completed_vms: Vec<String>,
+}
+
+/// append-only JSON lines file of the finished job runs, surviving restarts of the daemon. the
+/// runs in progress are kept next to it, to resume them after a restart
+#[derive(Debug, Clone)]
+pub struct HistoryStore {
+    config: HistoryConfig,
+    /// jobs finishing at the same time must not interleave their lines or prunes
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl HistoryStore {
+    pub fn from_config(config: HistoryConfig) -> Self {
+        HistoryStore {
+            config,
+            lock: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    /// appends the run, dropping the oldest ones beyond `max_entries`
+    pub async fn record(&self, entry: &HistoryEntry) -> eyre::Result<()> {
+        let _lock = self.lock.lock().await;
+        if let Some(parent) = std::path::Path::new(&self.config.path).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+
+        let content = tokio::fs::read_to_string(&self.config.path).await?;
+        let lines: Vec<&str> = content.lines().collect();
+        if lines.len() > self.config.max_entries {
+            debug!(
+                "Pruning history {} to {} entries",
+                self.config.path, self.config.max_entries
+            );
+            let kept = lines[lines.len() - self.config.max_entries..].join("\n") + "\n";
+            let tmp_path = format!("{}.tmp", self.config.path);
+            tokio::fs::write(&tmp_path, kept).await?;
+            tokio::fs::rename(&tmp_path, &self.config.path).await?;
+        }
+
+        Ok(())
+    }
+
+    /// file of the runs in progress, next to the history
+    fn running_path(&self) -> String {
+        format!("{}.running", self.config.path)
+    }
+
+    async fn read_running(&self) -> eyre::Result<BTreeMap<String, RunningJob>> {
+        match tokio::fs::read_to_string(self.running_path()).await {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// writes the runs in progress, replacing the file so a crash never leaves half of it
+    async fn write_running(&self, running: &BTreeMap<String, RunningJob>) -> eyre::Result<()> {
+        let path = self.running_path();
+        if let Some(parent) = std::path::Path::new(&path).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp_path = format!("{}.tmp", path);
+        tokio::fs::write(&tmp_path, serde_json::to_vec(running)?).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+
+    /// marks the job as running, replacing the entry of an earlier run
+    pub async fn begin_run(
+        &self,
+        job_name: &str,
+        started: chrono::DateTime<chrono::Utc>,
+    ) -> eyre::Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut running = self.read_running().await?;
+        running.insert(
+            job_name.to_string(),
+            RunningJob {
+                job_name: job_name.to_string(),
+                started,
+                completed_vms: vec![],
+            },
+        );
+        self.write_running(&running).await
+    }
+
+    /// records that the running job backed up the VM
+    pub async fn mark_completed(&self, job_name: &str, vm: &str) -> eyre::Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut running = self.read_running().await?;
+        if let Some(run) = running.get_mut(job_name) {
+            if !run.completed_vms.iter().any(|x| x == vm) {
+                run.completed_vms.push(vm.to_string());
+            }
+            self.write_running(&running).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish_run(&self, job_name: &str) -> eyre::Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut running = self.read_running().await?;
+        if running.remove(job_name).is_some() {
+            self.write_running(&running).await?;
+        }
+        Ok(())
+    }
+
+    /// returns the runs which were still in progress when the daemon stopped, call it before
+    /// any job is started
+    pub async fn interrupted_runs(&self) -> eyre::Result<Vec<RunningJob>> {
+        let _lock = self.lock.lock().await;
+        Ok(self.read_running().await?.into_values().collect())
+    }
+
+    /// returns the recorded runs, oldest first. lines which can't be parsed are skipped
+    pub async fn read(&self) -> eyre::Result<Vec<HistoryEntry>> {
+        let content = match tokio::fs::read_to_string(&self.config.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(content
+            .lines()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    debug!("Skipping unreadable history entry: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+/// prints the newest runs, as table or JSON lines
+pub async fn print_history(config: &AppConfig, history: &HistorySubCommand) -> eyre::Result<()> {
+    let store = HistoryStore::from_config(config.history.clone());
+    let entries: Vec<HistoryEntry> = store
+        .read()
+        .await?
+        .into_iter()
+        .filter(|x| history.job.as_ref().is_none_or(|job| &x.job_name == job))
+        .collect();
+    let entries = &entries[entries.len().saturating_sub(history.limit)..];
+
+    for entry in entries {
+        if history.json {
+            println!("{}", serde_json::to_string(entry)?);
+            continue;
+        }
+
+        let stats = &entry.stats;
+        println!(
+            "{}  {:<24} {:<15} {:<8} {}/{} ok, {} failed, {} skipped in {:.0}s",
+            entry.started.format("%Y-%m-%d %H:%M:%S"),
+            entry.job_name,
+            entry.job_type,
+            entry.outcome,
+            stats["successful_objects"],
+            stats["total_objects"],
+            stats["failed_objects"],
+            stats["skipped_objects"],
+            stats["duration"].as_f64().unwrap_or_default()
+        );
+        if !history.details {
+            continue;
+        }
+        for result in stats["object_results"].as_array().into_iter().flatten() {
+            println!(
+                "    {} [{}] on {}: {}{}",
+                result["name"].as_str().unwrap_or_default(),
+                result["uuid"].as_str().unwrap_or_default(),
+                result["xen_host"].as_str().unwrap_or_default(),
+                result["status"].as_str().unwrap_or_default(),
+                result["error"]
+                    .as_str()
+                    .map(|x| format!(" ({})", x.replace('\n', ": ")))
+                    .unwrap_or_default()
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use tracing::{error, info};
+
+use crate::{
+    config::JobConfig,
+    jobs::XenbakJobStats,
+    storage::{borg::BorgLocalStorage, StorageHandler},
+    GlobalState,
+};
+
+use super::{JobType, XenbakJob};
+
+/// checks and compacts the repository of a borg storage, see `AppConfig::get_maintenance_jobs`
+#[derive(Clone, Debug)]
+pub struct BorgMaintenanceJob {
+    pub job_type: JobType,
+    pub job_config: JobConfig,
+    pub job_stats: XenbakJobStats,
+    pub global_state: Arc<GlobalState>,
+}
+
+#[async_trait::async_trait]
+impl XenbakJob for BorgMaintenanceJob {
+    fn new(global_state: Arc<GlobalState>, job_config: JobConfig) -> BorgMaintenanceJob {
+        BorgMaintenanceJob {
+            job_type: JobType::BorgMaintenance,
+            global_state,
+            job_config,
+            job_stats: XenbakJobStats::default(),
+        }
+    }
+
+    fn get_name(&self) -> String {
+        self.job_config.name.clone()
+    }
+
+    fn get_job_type(&self) -> JobType {
+        self.job_type.clone()
+    }
+
+    fn get_schedule(&self) -> String {
+        self.job_config.schedule.clone()
+    }
+
+    fn get_job_stats(&self) -> XenbakJobStats {
+        self.job_stats.clone()
+    }
+
+    async fn run(&mut self) -> eyre::Result<()> {
+        let job_timer = tokio::time::Instant::now();
+
+        info!("Running borg maintenance job '{}'", self.job_config.name);
+
+        self.job_stats = XenbakJobStats {
+            config: self.job_config.clone(),
+            ..XenbakJobStats::default()
+        };
+
+        for storage_name in &self.job_config.storages {
+            let Some(storage_config) = self
+                .global_state
+                .config
+                .storage
+                .borg
+                .iter()
+                .find(|x| x.enabled && &x.name == storage_name)
+            else {
+                continue;
+            };
+            let Some(maintenance) = storage_config.maintenance.clone() else {
+                continue;
+            };
+            if let Some(snapshot) = self.global_state.config.storage.snapshot(storage_name) {
+                self.job_stats
+                    .storage_configs
+                    .insert(storage_name.clone(), snapshot);
+            }
+
+            self.job_stats.total_objects += 1;
+            let storage = BorgLocalStorage::new(storage_config.clone(), self.job_config.clone());
+            // breaks stale locks if configured and keeps backup runs of other processes informed
+            let result = match storage.initialize().await {
+                Ok(_) => storage.maintain(&maintenance).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(_) => {
+                    info!(
+                        "Finished maintenance of borg storage '{}'",
+                        storage_config.name
+                    );
+                    self.job_stats.successful_objects += 1;
+                }
+                Err(e) => {
+                    error!(
+                        "Maintenance of borg storage '{}' failed: {}",
+                        storage_config.name, e
+                    );
+                    self.job_stats.failed_objects += 1;
+                    self.job_stats
+                        .errors
+                        .push(format!("{}: {}", storage_config.name, e));
+                }
+            }
+        }
+
+        self.job_stats.duration = job_timer.elapsed().as_secs_f64();
+
+        if self.job_stats.failed_objects > 0 {
+            return Err(eyre::eyre!("Borg maintenance job failed."));
+        }
+
+        info!(
+            "Finished borg maintenance job with name '{}' in {} seconds",
+            self.job_config.name, self.job_stats.duration
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    config::{JobConfig, QuotaAction},
+    storage::{restore_point::is_chain_base, BackupObjectFilter, StorageHandler, StorageType},
+    xapi::{cli::client::XApiCliClient, VM},
+};
+
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// number of most recent backups per VM the size of the next one is projected from
+const PROJECTION_RUNS: usize = 5;
+
+/// estimates the space a job's backups will need under the storage's retention policy, based on
+/// the average size of the last few backups of each VM, and compares it to the storage's free
+/// space. returns a warning if the projected backup set won't fit.
+pub async fn check_backup_budget(
+    storage_handler: &dyn StorageHandler,
+    filter: BackupObjectFilter,
+) -> eyre::Result<Option<String>> {
+    let mut backup_objects: Vec<_> = storage_handler
+        .list(filter)
+        .await?
+        .into_iter()
+        .map(|x| x.backup_object)
+        .collect();
+    backup_objects.sort_by_key(|x| std::cmp::Reverse(x.time_stamp));
+
+    // recorded sizes of each VM's backups, newest first
+    let mut sizes: HashMap<(String, String), Vec<u64>> = HashMap::new();
+    for backup_object in &backup_objects {
+        let Some(size) = backup_object.size else {
+            continue;
+        };
+        sizes
+            .entry((
+                backup_object.xen_host.clone(),
+                backup_object.vm_name.clone(),
+            ))
+            .or_default()
+            .push(size);
+    }
+    // a single unusually small or large backup doesn't skew the projection
+    let projected_sizes: Vec<u64> = sizes
+        .values()
+        .map(|x| &x[..x.len().min(PROJECTION_RUNS)])
+        .map(|x| x.iter().sum::<u64>() / x.len() as u64)
+        .collect();
+
+    // without any history there's nothing to project
+    if projected_sizes.is_empty() {
+        return Ok(None);
+    }
+
+    let status = storage_handler.status().await?;
+    if status.total_space == 0 {
+        return Ok(None);
+    }
+
+    let per_run: u64 = projected_sizes.iter().sum();
+    let current: u64 = backup_objects.iter().filter_map(|x| x.size).sum();
+    let retention = storage_handler.get_retention_count() as u64;
+
+    // rotation happens after the export, so one additional backup set has to fit temporarily
+    let projected = per_run * (retention + 1);
+    let additional = projected.saturating_sub(current);
+
+    if projected > status.total_space {
+        return Ok(Some(format!(
+            "Storage '{}' can't fit the projected backup set: {:.1} GiB per run with a retention of {} needs {:.1} GiB, but the storage only has {:.1} GiB",
+            storage_handler.get_name(),
+            per_run as f64 / GIB,
+            retention,
+            projected as f64 / GIB,
+            status.total_space as f64 / GIB
+        )));
+    }
+
+    if additional > status.free_space {
+        return Ok(Some(format!(
+            "Storage '{}' is running out of space: reaching the retention of {} needs another {:.1} GiB, but only {:.1} GiB are free",
+            storage_handler.get_name(),
+            retention,
+            additional as f64 / GIB,
+            status.free_space as f64 / GIB
+        )));
+    }
+
+    Ok(None)
+}
+
+/// free space of the job's storages, queried once per run instead of before every VM. the
+/// exports of the run are subtracted from it, running ones with their footprint and finished
+/// ones with the size they take up on the storage
+#[derive(Debug, Clone, Default)]
+pub struct RunFreeSpace(Arc<Mutex<HashMap<String, StorageSpace>>>);
+
+#[derive(Debug)]
+struct StorageSpace {
+    free: u64,
+    /// footprint of the running exports
+    in_flight: u64,
+}
+
+impl RunFreeSpace {
+    pub async fn query(storage_handlers: &[Arc<dyn StorageHandler>]) -> RunFreeSpace {
+        let mut storages = HashMap::new();
+        for storage_handler in storage_handlers {
+            let status = match storage_handler.status().await {
+                Ok(status) => status,
+                Err(e) => {
+                    warn!(
+                        "Failed to get status of storage '{}': {}",
+                        storage_handler.get_name(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            // free space can't be determined for every storage
+            if status.total_space == 0 {
+                continue;
+            }
+            storages.insert(
+                storage_handler.get_name(),
+                StorageSpace {
+                    free: status.free_space,
+                    in_flight: 0,
+                },
+            );
+        }
+
+        RunFreeSpace(Arc::new(Mutex::new(storages)))
+    }
+
+    /// fails if a storage can't hold the export of the VM next to the running ones. the disk
+    /// footprint is uncompressed, so it's an upper bound of the export size. the space is
+    /// reserved until the returned reservation is dropped
+    pub fn reserve(
+        &self,
+        vm: &VM,
+        disk_usage: u64,
+        storage_handlers: &[Arc<dyn StorageHandler>],
+    ) -> eyre::Result<SpaceReservation> {
+        let mut storages = self.0.lock().unwrap();
+        for storage_handler in storage_handlers {
+            let Some(space) = storages.get(&storage_handler.get_name()) else {
+                continue;
+            };
+            let available = space.free.saturating_sub(space.in_flight);
+            if disk_usage > available {
+                return Err(eyre::eyre!(
+                    "VM '{}' needs up to {:.1} GiB, but storage '{}' only has {:.1} GiB free next to the running exports",
+                    vm.name_label,
+                    disk_usage as f64 / GIB,
+                    storage_handler.get_name(),
+                    available as f64 / GIB
+                ));
+            }
+        }
+
+        let mut reserved = vec![];
+        for storage_handler in storage_handlers {
+            if let Some(space) = storages.get_mut(&storage_handler.get_name()) {
+                space.in_flight += disk_usage;
+                reserved.push((storage_handler.get_name(), disk_usage));
+            }
+        }
+
+        Ok(SpaceReservation {
+            free_space: self.clone(),
+            reserved,
+        })
+    }
+
+    /// records the space a finished export takes up on a storage
+    pub fn consume(&self, storage_name: &str, bytes: u64) {
+        if let Some(space) = self.0.lock().unwrap().get_mut(storage_name) {
+            space.free = space.free.saturating_sub(bytes);
+        }
+    }
+}
+
+/// space reserved for a running export, released once it's dropped
+#[derive(Debug)]
+pub struct SpaceReservation {
+    free_space: RunFreeSpace,
+    reserved: Vec<(String, u64)>,
+}
+
+impl Drop for SpaceReservation {
+    fn drop(&mut self) {
+        let mut storages = self.free_space.0.lock().unwrap();
+        for (storage_name, bytes) in &self.reserved {
+            if let Some(space) = storages.get_mut(storage_name) {
+                space.in_flight = space.in_flight.saturating_sub(*bytes);
+            }
+        }
+    }
+}
+
+/// compares the summed up disk footprint of all VMs of a run against the free space of the
+/// storage. returns a warning if the run may not fit, single VMs are checked again before their
+/// export
+pub async fn check_run_free_space(
+    storage_handler: &dyn StorageHandler,
+    disk_usage: u64,
+) -> eyre::Result<Option<String>> {
+    let status = storage_handler.status().await?;
+    if status.total_space == 0 || disk_usage <= status.free_space {
+        return Ok(None);
+    }
+
+    Ok(Some(format!(
+        "The VMs of this run use up to {:.1} GiB, but storage '{}' only has {:.1} GiB free",
+        disk_usage as f64 / GIB,
+        storage_handler.get_name(),
+        status.free_space as f64 / GIB
+    )))
+}
+
+/// a new backup would exceed the quota of a storage and no older backups could be rotated
+#[derive(Debug, thiserror::Error)]
+#[error("Backup of VM '{vm_name}' would exceed the {scope} quota of storage '{storage_name}': {used:.1} GiB used, {estimated:.1} GiB estimated for the new backup, limit is {limit:.1} GiB")]
+pub struct QuotaExceededError {
+    pub vm_name: String,
+    pub storage_name: String,
+    /// `VM` or `job`
+    pub scope: &'static str,
+    pub used: f64,
+    pub estimated: f64,
+    pub limit: f64,
+}
+
+/// checks the job's storage quotas before the backup of a VM. depending on the quota's action,
+/// the oldest backups of the VM are deleted until the new backup fits, or the backup fails.
+/// the newest backup and backups within the delete protection window are always kept.
+/// returns the ids of the deleted restore points
+pub async fn enforce_quotas(
+    xapi_client: &XApiCliClient,
+    vm: &VM,
+    disk_usage: Option<u64>,
+    storage_handlers: &[Arc<dyn StorageHandler>],
+    job_config: &JobConfig,
+) -> eyre::Result<Vec<String>> {
+    let mut rotated = vec![];
+
+    for storage_handler in storage_handlers {
+        let Some(quota) = job_config.get_quota(&storage_handler.get_name()) else {
+            continue;
+        };
+
+        // borg doesn't report the size of single archives
+        if matches!(storage_handler.get_storage_type(), StorageType::Borg) {
+            warn!(
+                "Quotas are not supported on borg storage '{}', ignoring its quota",
+                storage_handler.get_name()
+            );
+            continue;
+        }
+
+        let mut job_restore_points = storage_handler.list(BackupObjectFilter::default()).await?;
+        let mut vm_restore_points: Vec<_> = job_restore_points
+            .iter()
+            .filter(|x| {
+                x.backup_object.vm_name == vm.name_label
+                    && x.backup_object.xen_host == xapi_client.get_config().name
+            })
+            .cloned()
+            .collect();
+        // oldest first
+        vm_restore_points.sort_by_key(|x| x.backup_object.time_stamp);
+
+        // the last backup is the best guess for the size of the next one
+        let estimated = match vm_restore_points.last() {
+            Some(restore_point) => restore_point.size(),
+            None => disk_usage.unwrap_or_default(),
+        };
+
+        let protected_since = storage_handler
+            .get_delete_protection_days()
+            .map(|days| chrono::Utc::now() - chrono::Duration::days(days as i64));
+
+        loop {
+            let vm_used: u64 = vm_restore_points.iter().map(|x| x.size()).sum();
+            let job_used: u64 = job_restore_points.iter().map(|x| x.size()).sum();
+
+            let exceeded = if quota
+                .vm_limit
+                .is_some_and(|limit| vm_used + estimated > limit)
+            {
+                Some(("VM", vm_used, quota.vm_limit.unwrap_or_default()))
+            } else if quota
+                .job_limit
+                .is_some_and(|limit| job_used + estimated > limit)
+            {
+                Some(("job", job_used, quota.job_limit.unwrap_or_default()))
+            } else {
+                None
+            };
+            let Some((scope, used, limit)) = exceeded else {
+                break;
+            };
+
+            let quota_error = QuotaExceededError {
+                vm_name: vm.name_label.clone(),
+                storage_name: storage_handler.get_name(),
+                scope,
+                used: used as f64 / GIB,
+                estimated: estimated as f64 / GIB,
+                limit: limit as f64 / GIB,
+            };
+
+            if quota.action == QuotaAction::Fail {
+                return Err(quota_error.into());
+            }
+            // pinned restore points still count towards the quota, but are never deleted.
+            // neither is the base of a differential restore point
+            let oldest = vm_restore_points.iter().find(|x| !x.pinned).filter(|x| {
+                vm_restore_points.len() > 1
+                    && !is_chain_base(x, &vm_restore_points)
+                    && protected_since.is_none_or(|since| x.backup_object.time_stamp <= since)
+            });
+            let Some(oldest) = oldest.cloned() else {
+                return Err(quota_error.into());
+            };
+
+            info!(
+                "Deleting restore point {} early to stay within the {} quota of storage '{}'",
+                oldest.id,
+                scope,
+                storage_handler.get_name()
+            );
+            storage_handler.delete(&oldest).await?;
+            vm_restore_points.retain(|x| x.id != oldest.id);
+            job_restore_points.retain(|x| x.id != oldest.id);
+            rotated.push(oldest.id);
+        }
+    }
+
+    Ok(rotated)
+}
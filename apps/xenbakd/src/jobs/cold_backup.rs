@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::ColdBackupConfig,
+    xapi::{cli::client::XApiCliClient, PowerOperation, PowerState, VM},
+};
+
+/// how often the operations of a VM are polled while waiting for them to finish
+const SETTLE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// how a VM is taken offline for a cold backup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColdBackupMode {
+    /// clean shutdown through the guest, started again afterwards
+    #[default]
+    Shutdown,
+    /// suspended to disk and resumed afterwards, the snapshot includes the suspend image
+    Suspend,
+}
+
+/// coordinates powering a VM down around snapshot creation
+pub struct ColdBackup<'a> {
+    config: &'a ColdBackupConfig,
+    xapi_client: &'a XApiCliClient,
+    vm: &'a VM,
+}
+
+impl<'a> ColdBackup<'a> {
+    pub fn new(config: &'a ColdBackupConfig, xapi_client: &'a XApiCliClient, vm: &'a VM) -> Self {
+        ColdBackup {
+            config,
+            xapi_client,
+            vm,
+        }
+    }
+
+    /// only running VMs carrying one of the configured tags are powered down, all running VMs if
+    /// no tags are configured
+    pub async fn applies(&self) -> eyre::Result<bool> {
+        if self.xapi_client.get_vm_power_state(self.vm).await? != PowerState::Running {
+            return Ok(false);
+        }
+        if self.config.tags.is_empty() {
+            return Ok(true);
+        }
+
+        Ok(self.config.tags.iter().any(|x| self.vm.tags.contains(x)))
+    }
+
+    /// runs `f` while the VM is shut down or suspended. the VM is always powered on again
+    /// afterwards, even if powering it down or `f` failed
+    pub async fn run_powered_off<T, F>(&self, f: F) -> eyre::Result<T>
+    where
+        F: std::future::Future<Output = eyre::Result<T>>,
+    {
+        let operation = match self.config.mode {
+            ColdBackupMode::Shutdown => PowerOperation::Shutdown,
+            ColdBackupMode::Suspend => PowerOperation::Suspend,
+        };
+        info!("Powering down VM '{}' ({})", self.vm.name_label, operation);
+        let result = match self
+            .xapi_client
+            .vm_power_operation(self.vm, operation, self.config.timeout)
+            .await
+        {
+            Ok(()) => f.await,
+            Err(e) => {
+                // the host keeps running the operation if only waiting for it failed
+                if let Err(e) = self.wait_until_settled().await {
+                    warn!(
+                        "Failed to wait for the operations of VM '{}': {}",
+                        self.vm.name_label, e
+                    );
+                }
+                Err(eyre::Error::from(e)
+                    .wrap_err(format!("Failed to power down VM '{}'", self.vm.name_label)))
+            }
+        };
+
+        if let Err(e) = self.power_on().await {
+            error!(
+                "Failed to power on VM '{}' again: {:?}",
+                self.vm.name_label, e
+            );
+            return Err(e);
+        }
+
+        result
+    }
+
+    /// waits up to another `timeout` for the operations still running on the VM, e.g. a clean
+    /// shutdown which timed out on our side. the power state is only final once they're done
+    async fn wait_until_settled(&self) -> eyre::Result<()> {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(self.config.timeout);
+        loop {
+            let operations = self.xapi_client.get_vm_current_operations(self.vm).await?;
+            if operations.is_empty() {
+                return Ok(());
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(eyre::eyre!(
+                    "still running after {}s: {}",
+                    self.config.timeout,
+                    operations.join(", ")
+                ));
+            }
+
+            debug!(
+                "Waiting for operations of VM '{}' to finish: {}",
+                self.vm.name_label,
+                operations.join(", ")
+            );
+            tokio::time::sleep(SETTLE_POLL_INTERVAL).await;
+        }
+    }
+
+    /// starts or resumes the VM depending on the state it ended up in, it may still be running
+    /// if powering it down failed
+    async fn power_on(&self) -> eyre::Result<()> {
+        let operation = match self.xapi_client.get_vm_power_state(self.vm).await? {
+            PowerState::Halted => PowerOperation::Start,
+            PowerState::Suspended => PowerOperation::Resume,
+            power_state => {
+                debug!(
+                    "VM '{}' is {}, not powering it on",
+                    self.vm.name_label, power_state
+                );
+                return Ok(());
+            }
+        };
+
+        info!("Powering on VM '{}' ({})", self.vm.name_label, operation);
+        self.xapi_client
+            .vm_power_operation(self.vm, operation, self.config.timeout)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    collections::BinaryHeap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::oneshot;
+
+/// a job waiting for a slot, the highest priority is served first, equal ones in order of arrival
+#[derive(Debug)]
+struct Waiter {
+    priority: i32,
+    /// decreasing arrival counter, so earlier waiters sort higher
+    order: std::cmp::Reverse<u64>,
+    sender: oneshot::Sender<ConcurrencyPermit>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        (self.priority, self.order) == (other.priority, other.order)
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.priority, self.order).cmp(&(other.priority, other.order))
+    }
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    available: u32,
+    arrivals: u64,
+    waiters: BinaryHeap<Waiter>,
+}
+
+/// daemon-wide limit of concurrent backups shared by all jobs, on top of their own `concurrency`.
+/// a free slot goes to the waiting job with the highest `priority`
+#[derive(Debug, Clone)]
+pub struct ConcurrencyBudget(Option<Arc<Mutex<BudgetState>>>);
+
+/// a slot of the budget, it is handed to the next waiting job when dropped
+#[derive(Debug)]
+pub struct ConcurrencyPermit(Option<Arc<Mutex<BudgetState>>>);
+
+impl ConcurrencyBudget {
+    /// unlimited if `limit` is unset
+    pub fn new(limit: Option<u32>) -> Self {
+        ConcurrencyBudget(limit.map(|available| {
+            Arc::new(Mutex::new(BudgetState {
+                available,
+                ..BudgetState::default()
+            }))
+        }))
+    }
+
+    pub async fn acquire(&self, priority: i32) -> ConcurrencyPermit {
+        let Some(state) = &self.0 else {
+            return ConcurrencyPermit(None);
+        };
+
+        let receiver = {
+            let mut guard = state.lock().unwrap();
+            if guard.available > 0 && guard.waiters.is_empty() {
+                guard.available -= 1;
+                return ConcurrencyPermit(Some(state.clone()));
+            }
+            let (sender, receiver) = oneshot::channel();
+            guard.arrivals += 1;
+            let order = std::cmp::Reverse(guard.arrivals);
+            guard.waiters.push(Waiter {
+                priority,
+                order,
+                sender,
+            });
+            receiver
+        };
+
+        // the sender is only dropped together with the budget
+        receiver.await.unwrap()
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        let Some(state) = self.0.take() else {
+            return;
+        };
+
+        let mut guard = state.lock().unwrap();
+        while let Some(waiter) = guard.waiters.pop() {
+            // a waiter which gave up, e.g. a cancelled backup, is skipped. the permit it didn't
+            // take must not release the slot a second time
+            match waiter.sender.send(ConcurrencyPermit(Some(state.clone()))) {
+                Ok(()) => return,
+                Err(mut permit) => permit.0 = None,
+            }
+        }
+        guard.available += 1;
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use eyre::Context;
+use tracing::{info, warn};
+
+use crate::{
+    cli::CoverageSubCommand,
+    config::{AppConfig, JobConfig},
+    jobs::{ObjectResult, ObjectStatus, XenbakJobStats},
+    xapi::{
+        cli::client::XApiCliClient,
+        preflight::{self, HostStatus},
+        UUID, VM,
+    },
+    GlobalState,
+};
+
+use super::{JobType, XenbakJob};
+
+/// the VMs of a pool by whether an enabled job backs them up
+#[derive(Debug, Clone, Default)]
+pub struct PoolCoverage {
+    pub pool_uuid: UUID,
+    /// name-label of the pool
+    pub pool: String,
+    /// xen host the VMs were listed on
+    pub xen_host: String,
+    pub protected: Vec<VM>,
+    pub unprotected: Vec<VM>,
+    /// VMs which are deliberately not backed up, see `CoverageConfig::ignore`
+    pub ignored: Vec<VM>,
+}
+
+/// lists the VMs of every pool of the enabled xen hosts and checks whether an enabled VM backup
+/// or replication job selects them
+pub async fn check_coverage(
+    config: &AppConfig,
+) -> eyre::Result<(Vec<PoolCoverage>, BTreeMap<String, HostStatus>)> {
+    let clients: Vec<XApiCliClient> = config
+        .xen
+        .iter()
+        .filter(|x| x.enabled)
+        .map(|x| XApiCliClient::new(x.clone()))
+        .collect();
+    let (clients, host_status) = preflight::check_hosts(clients).await;
+
+    // the hosts of a pool list the same VMs
+    let mut pools: BTreeMap<UUID, (String, Vec<XApiCliClient>)> = BTreeMap::new();
+    for client in clients {
+        let (uuid, name_label) = client.get_pool().await.wrap_err(format!(
+            "Failed to get the pool of xen host '{}'",
+            client.get_config().name
+        ))?;
+        pools
+            .entry(uuid)
+            .or_insert_with(|| (name_label, vec![]))
+            .1
+            .push(client);
+    }
+
+    let jobs: Vec<&JobConfig> = config
+        .jobs
+        .iter()
+        .filter(|x| x.enabled && matches!(x.job_type, JobType::VmBackup | JobType::Replication))
+        .collect();
+
+    let mut coverage = vec![];
+    for (pool_uuid, (pool, clients)) in pools {
+        let xen_hosts: Vec<String> = clients
+            .iter()
+            .map(|x| x.get_config().name.clone())
+            .collect();
+        let pool_jobs: Vec<&JobConfig> = jobs
+            .iter()
+            .copied()
+            .filter(|job| {
+                job.get_xen_configs(config.xen.clone())
+                    .iter()
+                    .any(|x| xen_hosts.contains(&x.name))
+            })
+            .collect();
+
+        let mut pool_coverage = PoolCoverage {
+            pool_uuid,
+            pool,
+            xen_host: xen_hosts[0].clone(),
+            ..PoolCoverage::default()
+        };
+        let vms = clients[0].list_vms().await;
+        for client in &clients {
+            client.logout().await;
+        }
+        for vm in vms? {
+            if pool_jobs.iter().any(|job| job.selects_vm(&vm)) {
+                pool_coverage.protected.push(vm);
+            } else if config.coverage.is_ignored(&vm) {
+                pool_coverage.ignored.push(vm);
+            } else {
+                pool_coverage.unprotected.push(vm);
+            }
+        }
+        coverage.push(pool_coverage);
+    }
+
+    Ok((coverage, host_status))
+}
+
+/// uuids of the VMs seen by the last check, by pool uuid
+type KnownVms = BTreeMap<UUID, BTreeSet<UUID>>;
+
+async fn read_known_vms(path: &str) -> eyre::Result<KnownVms> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(content) => Ok(serde_json::from_str(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn write_known_vms(path: &str, known_vms: &KnownVms) -> eyre::Result<()> {
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = format!("{}.tmp", path);
+    tokio::fs::write(&tmp_path, serde_json::to_vec(known_vms)?).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// reports the VMs which no enabled job backs up, see `CoverageConfig`
+#[derive(Clone, Debug)]
+pub struct CoverageJob {
+    pub job_type: JobType,
+    pub job_config: JobConfig,
+    pub job_stats: XenbakJobStats,
+    pub global_state: Arc<GlobalState>,
+}
+
+impl CoverageJob {
+    /// adds a new unprotected VM to the `default_job` by tagging it, returns whether the job
+    /// backs it up now
+    async fn include_in_default_job(&mut self, pool: &PoolCoverage, vm: &VM) -> bool {
+        let config = &self.global_state.config;
+        let Some(job_name) = &config.coverage.default_job else {
+            return false;
+        };
+        let Some(job) = config
+            .jobs
+            .iter()
+            .find(|x| &x.name == job_name && x.enabled)
+        else {
+            warn!("Default job '{}' of new VMs isn't enabled", job_name);
+            return false;
+        };
+        let Some(xen_config) = job
+            .get_xen_configs(config.xen.clone())
+            .into_iter()
+            .find(|x| x.name == pool.xen_host)
+        else {
+            warn!(
+                "Default job '{}' doesn't back up pool '{}', can't add VM '{}' to it",
+                job_name, pool.pool, vm.name_label
+            );
+            return false;
+        };
+        let Some(tag) = job.tag_filter.first() else {
+            warn!("Default job '{}' has no tag to add VMs with", job_name);
+            return false;
+        };
+
+        let mut tagged = vm.clone();
+        tagged.tags.push(tag.clone());
+        if !job.selects_vm(&tagged) {
+            warn!(
+                "Default job '{}' excludes new VM '{}' even with tag '{}'",
+                job_name, vm.name_label, tag
+            );
+            return false;
+        }
+
+        let client = XApiCliClient::new(xen_config);
+        let result = client.add_vm_tag(vm, tag).await;
+        client.logout().await;
+        match result {
+            Ok(()) => {
+                let warning = format!(
+                    "Added new VM '{}' [{}] of pool '{}' to job '{}' by tagging it with '{}'",
+                    vm.name_label, vm.uuid, pool.pool, job_name, tag
+                );
+                info!("{}", warning);
+                self.job_stats.warnings.push(warning);
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to add new VM '{}' to job '{}': {}",
+                    vm.name_label, job_name, e
+                );
+                false
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl XenbakJob for CoverageJob {
+    fn new(global_state: Arc<GlobalState>, job_config: JobConfig) -> CoverageJob {
+        CoverageJob {
+            job_type: JobType::Coverage,
+            global_state,
+            job_config,
+            job_stats: XenbakJobStats::default(),
+        }
+    }
+
+    fn get_name(&self) -> String {
+        self.job_config.name.clone()
+    }
+
+    fn get_job_type(&self) -> JobType {
+        self.job_type.clone()
+    }
+
+    fn get_schedule(&self) -> String {
+        self.job_config.schedule.clone()
+    }
+
+    fn get_job_stats(&self) -> XenbakJobStats {
+        self.job_stats.clone()
+    }
+
+    async fn run(&mut self) -> eyre::Result<()> {
+        let job_timer = tokio::time::Instant::now();
+
+        info!("Running coverage check '{}'", self.job_config.name);
+
+        self.job_stats = XenbakJobStats {
+            config: self.job_config.clone(),
+            ..XenbakJobStats::default()
+        };
+
+        let config = self.global_state.config.coverage.clone();
+        let (coverage, host_status) = check_coverage(&self.global_state.config).await?;
+        self.job_stats.record_host_status(host_status);
+
+        // pools which weren't checked before have no new VMs, all of theirs are known from now on
+        let mut known_vms = read_known_vms(&config.known_vms_path)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to read known VMs: {}", e);
+                KnownVms::new()
+            });
+
+        for pool in coverage {
+            let known = known_vms.insert(
+                pool.pool_uuid.clone(),
+                pool.protected
+                    .iter()
+                    .chain(&pool.unprotected)
+                    .chain(&pool.ignored)
+                    .map(|x| x.uuid.clone())
+                    .collect(),
+            );
+            self.job_stats.total_objects += (pool.protected.len() + pool.unprotected.len()) as u32;
+            self.job_stats.successful_objects += pool.protected.len() as u32;
+            for vm in &pool.unprotected {
+                let is_new = known.as_ref().is_some_and(|x| !x.contains(&vm.uuid));
+                if is_new && self.include_in_default_job(&pool, vm).await {
+                    self.job_stats.successful_objects += 1;
+                    continue;
+                }
+
+                let warning = format!(
+                    "{} '{}' [{}] of pool '{}' isn't backed up by any enabled job",
+                    match is_new {
+                        true => "New VM",
+                        false => "VM",
+                    },
+                    vm.name_label,
+                    vm.uuid,
+                    pool.pool
+                );
+                warn!("{}", warning);
+                self.job_stats.warnings.push(warning);
+                // unprotected VMs only make the run send a warning if they are reported as failed
+                let status = match config.notify || (config.notify_new && is_new) {
+                    true => {
+                        self.job_stats.failed_objects += 1;
+                        ObjectStatus::Failed
+                    }
+                    false => {
+                        self.job_stats.skipped_objects += 1;
+                        ObjectStatus::Skipped
+                    }
+                };
+                self.job_stats.object_results.push(ObjectResult {
+                    name: vm.name_label.clone(),
+                    uuid: vm.uuid.clone(),
+                    xen_host: pool.xen_host.clone(),
+                    status,
+                    duration: 0.0,
+                    exported_bytes: 0,
+                    stored_bytes: 0,
+                    storages: vec![],
+                    error: Some("Not backed up by any enabled job".into()),
+                });
+            }
+        }
+
+        if let Err(e) = write_known_vms(&config.known_vms_path, &known_vms).await {
+            warn!("Failed to record known VMs: {}", e);
+        }
+
+        self.job_stats.duration = job_timer.elapsed().as_secs_f64();
+
+        info!(
+            "Finished coverage check '{}' in {} seconds, {} of {} VMs are backed up",
+            self.job_config.name,
+            self.job_stats.duration,
+            self.job_stats.successful_objects,
+            self.job_stats.total_objects
+        );
+
+        Ok(())
+    }
+}
+
+/// prints the unprotected VMs of every pool
+pub async fn print_coverage(config: &AppConfig, coverage: &CoverageSubCommand) -> eyre::Result<()> {
+    let (pools, host_status) = check_coverage(config).await?;
+    for (host, status) in host_status {
+        if let HostStatus::Unreachable(e) = status {
+            warn!("Skipped unreachable xen host '{}': {}", host, e);
+        }
+    }
+
+    for pool in pools {
+        if coverage.json {
+            let names = |vms: &[VM]| {
+                vms.iter()
+                    .map(|x| serde_json::json!({ "name": x.name_label, "uuid": x.uuid }))
+                    .collect::<Vec<_>>()
+            };
+            println!(
+                "{}",
+                serde_json::json!({
+                    "pool": pool.pool,
+                    "xen_host": pool.xen_host,
+                    "protected": names(&pool.protected),
+                    "unprotected": names(&pool.unprotected),
+                    "ignored": names(&pool.ignored),
+                })
+            );
+            continue;
+        }
+
+        println!(
+            "Pool '{}' ({}): {} protected, {} unprotected, {} ignored VMs",
+            pool.pool,
+            pool.xen_host,
+            pool.protected.len(),
+            pool.unprotected.len(),
+            pool.ignored.len()
+        );
+        for vm in &pool.unprotected {
+            println!("    {} [{}]", vm.name_label, vm.uuid);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::xapi::{cli::client::XApiCliClient, UUID};
+
+/// the job ran longer than its `max_runtime_seconds`, the VM's backup was cancelled
+#[derive(Debug, thiserror::Error)]
+#[error("Job exceeded its maximum runtime of {0} seconds, cancelled the backup")]
+pub struct JobTimeoutError(pub u64);
+
+/// point in time a job has to be finished by
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    at: Instant,
+    max_runtime: u64,
+}
+
+impl Deadline {
+    pub fn new(start: Instant, max_runtime: Option<u64>) -> Option<Self> {
+        max_runtime.map(|max_runtime| Deadline {
+            at: start + std::time::Duration::from_secs(max_runtime),
+            max_runtime,
+        })
+    }
+
+    pub fn error(&self) -> JobTimeoutError {
+        JobTimeoutError(self.max_runtime)
+    }
+}
+
+/// runs `f` until the deadline, `f` is dropped once it's exceeded. child processes are killed on
+/// drop, so this aborts running exports
+pub async fn run_until<T, F>(deadline: Option<Deadline>, f: F) -> eyre::Result<T>
+where
+    F: std::future::Future<Output = eyre::Result<T>>,
+{
+    let Some(deadline) = deadline else {
+        return f.await;
+    };
+
+    match tokio::time::timeout_at(deadline.at, f).await {
+        Ok(result) => result,
+        Err(_) => Err(deadline.error().into()),
+    }
+}
+
+/// snapshots of running VM backups. a backup which is cancelled can't delete its snapshot
+/// anymore, so the job deletes the leftovers
+#[derive(Debug, Clone, Default)]
+pub struct TemporarySnapshots(Arc<Mutex<Vec<(XApiCliClient, UUID)>>>);
+
+impl TemporarySnapshots {
+    pub fn add(&self, xapi_client: &XApiCliClient, snapshot: &UUID) {
+        self.0
+            .lock()
+            .unwrap()
+            .push((xapi_client.clone(), snapshot.clone()));
+    }
+
+    pub fn remove(&self, snapshot: &UUID) {
+        self.0.lock().unwrap().retain(|(_, x)| x != snapshot);
+    }
+
+    /// deletes the snapshots of all cancelled backups, returns a warning for every one which
+    /// is left on its host
+    pub async fn cleanup(&self) -> Vec<String> {
+        let snapshots = std::mem::take(&mut *self.0.lock().unwrap());
+        let mut warnings = vec![];
+        for (xapi_client, snapshot) in snapshots {
+            info!("Deleting snapshot {} of a cancelled backup", snapshot);
+            if let Err(e) = xapi_client.delete_snapshot_by_uuid(&snapshot).await {
+                let warning = format!(
+                    "Failed to delete snapshot {} of a cancelled backup on xen host '{}': {}",
+                    snapshot,
+                    xapi_client.get_config().name,
+                    e
+                );
+                warn!("{}", warning);
+                warnings.push(warning);
+            }
+        }
+        warnings
+    }
+}
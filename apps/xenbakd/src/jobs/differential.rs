@@ -0,0 +1,210 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::DifferentialConfig,
+    storage::{progress::ExportSummary, restore_point::RestorePoint, BackupObject, StorageHandler},
+    xapi::{
+        cli::client::XApiCliClient,
+        diff_archive::{self, DiffDisk},
+        VM,
+    },
+};
+
+/// other-config key marking the snapshot a job keeps as base of its next differential backup,
+/// the value is the job's name
+const BASE_SNAPSHOT_KEY: &str = "xenbakd-diff-base";
+
+async fn list_base_snapshots(
+    xapi_client: &XApiCliClient,
+    vm: &VM,
+    job_name: &str,
+) -> eyre::Result<Vec<VM>> {
+    let mut snapshots = vec![];
+    for uuid in xapi_client
+        .list_uuids(
+            "snapshot",
+            &[
+                &format!("snapshot-of={}", vm.uuid),
+                &format!("other-config:{}={}", BASE_SNAPSHOT_KEY, job_name),
+            ],
+        )
+        .await?
+    {
+        snapshots.push(xapi_client.get_vm_by_uuid(&uuid).await?);
+    }
+    snapshots.sort_by_key(|x| x.snapshot_time);
+
+    Ok(snapshots)
+}
+
+/// returns the snapshot kept by the previous backup of the job, the newest one if a crashed
+/// run left several
+pub async fn find_base_snapshot(
+    xapi_client: &XApiCliClient,
+    vm: &VM,
+    job_name: &str,
+) -> eyre::Result<Option<VM>> {
+    Ok(list_base_snapshots(xapi_client, vm, job_name).await?.pop())
+}
+
+/// keeps the snapshot as base of the next backup and deletes the previous bases of the job
+pub async fn replace_base_snapshot(
+    xapi_client: &XApiCliClient,
+    vm: &VM,
+    job_name: &str,
+    snapshot: &VM,
+) -> eyre::Result<()> {
+    xapi_client
+        .set_param(
+            "snapshot",
+            &snapshot.uuid,
+            &format!("other-config:{}", BASE_SNAPSHOT_KEY),
+            job_name,
+        )
+        .await?;
+
+    for base in list_base_snapshots(xapi_client, vm, job_name).await? {
+        if base.uuid != snapshot.uuid {
+            debug!("Deleting previous base snapshot {}", base.uuid);
+            xapi_client.delete_snapshot_by_uuid(&base.uuid).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// looks up the backup of the base snapshot on the storage. returns `None` if a full backup
+/// is due, because the backup is missing, its chain is broken or has reached `full_interval`
+async fn find_base_restore_point(
+    storage_handler: &dyn StorageHandler,
+    backup_object: &BackupObject,
+    base_snapshot: &VM,
+    differential: &DifferentialConfig,
+) -> eyre::Result<Option<RestorePoint>> {
+    let restore_points = storage_handler.list(backup_object.to_filter()).await?;
+    let Some(base) = restore_points
+        .iter()
+        .find(|x| x.backup_object.time_stamp == base_snapshot.snapshot_time)
+    else {
+        info!(
+            "Storage '{}' holds no backup of the base snapshot, making a full backup",
+            storage_handler.get_name()
+        );
+        return Ok(None);
+    };
+
+    // length of the chain, including its full backup
+    let mut length = 1;
+    let mut current = base;
+    while let Some(id) = &current.backup_object.base {
+        let Some(next) = restore_points.iter().find(|x| &x.id == id) else {
+            warn!(
+                "Base {} of restore point {} is missing on storage '{}', making a full backup",
+                id,
+                current.id,
+                storage_handler.get_name()
+            );
+            return Ok(None);
+        };
+        length += 1;
+        current = next;
+    }
+
+    if length >= differential.full_interval {
+        info!(
+            "Chain of {} has reached {} restore points, making a full backup",
+            base.id, length
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(base.clone()))
+}
+
+/// exports the disks of the snapshot to the storage as differential archive, relative to the
+/// base snapshot if the storage holds its backup. returns the stored backup object and the size
+/// of the export
+pub async fn export_to_storage(
+    xapi_client: &XApiCliClient,
+    snapshot: &VM,
+    base_snapshot: Option<&VM>,
+    storage_handler: Arc<dyn StorageHandler>,
+    mut backup_object: BackupObject,
+    differential: &DifferentialConfig,
+) -> eyre::Result<(BackupObject, ExportSummary)> {
+    let base = match base_snapshot {
+        Some(base_snapshot) => {
+            find_base_restore_point(
+                storage_handler.as_ref(),
+                &backup_object,
+                base_snapshot,
+                differential,
+            )
+            .await?
+        }
+        None => None,
+    };
+
+    // disks added since the base snapshot are exported in full
+    let base_vdis: HashMap<String, String> = match (&base, base_snapshot) {
+        (Some(_), Some(base_snapshot)) => xapi_client
+            .get_vm_disks(base_snapshot)
+            .await?
+            .into_iter()
+            .collect(),
+        _ => HashMap::new(),
+    };
+
+    let mut disks = vec![];
+    for (userdevice, vdi) in xapi_client.get_vm_disks(snapshot).await? {
+        let sr_uuid = xapi_client.get_param("vdi", &vdi, "sr-uuid").await?;
+        disks.push(DiffDisk {
+            base_vdi_uuid: base_vdis.get(&userdevice).cloned(),
+            name_label: xapi_client.get_param("vdi", &vdi, "name-label").await?,
+            virtual_size: xapi_client
+                .get_param("vdi", &vdi, "virtual-size")
+                .await?
+                .parse()?,
+            sr_name: xapi_client.get_param("sr", &sr_uuid, "name-label").await?,
+            sr_uuid,
+            userdevice,
+            vdi_uuid: vdi,
+        });
+    }
+
+    backup_object.base = base.map(|x| x.id);
+    match &backup_object.base {
+        Some(base) => info!(
+            "Exporting {} disks to storage '{}', based on {}",
+            disks.len(),
+            storage_handler.get_name(),
+            base
+        ),
+        None => info!(
+            "Exporting {} disks to storage '{}' in full",
+            disks.len(),
+            storage_handler.get_name()
+        ),
+    }
+
+    // the size of a differential export can't be told beforehand
+    let estimated_size = match backup_object.base {
+        Some(_) => None,
+        None => xapi_client.get_vm_disk_usage(snapshot).await.ok(),
+    };
+
+    let (stdout, stderr) = diff_archive::write_archive(xapi_client.clone(), disks);
+    let summary = xapi_client
+        .handle_export_stream(
+            storage_handler,
+            backup_object.clone(),
+            stdout,
+            stderr,
+            estimated_size,
+        )
+        .await?;
+
+    Ok((backup_object, summary))
+}
@@ -0,0 +1,65 @@
+use tracing::{debug, info};
+
+use crate::{
+    config::JobConfig,
+    xapi::{cli::client::XApiCliClient, VM},
+};
+
+/// whether the VDI is excluded by the job, either by one of its tags or by uuid or name-label
+fn is_excluded(job_config: &JobConfig, vdi: &str, name_label: &str, tags: &str) -> bool {
+    job_config
+        .vdi_exclude
+        .iter()
+        .any(|x| x == vdi || x == name_label)
+        || tags
+            .split(',')
+            .map(|x| x.trim())
+            .any(|x| job_config.vdi_tag_filter_exclude.iter().any(|y| y == x))
+}
+
+/// removes the disks the job excludes from the snapshot of the VM before it is exported, e.g.
+/// scratch or swap disks. the snapshot VDIs are destroyed, the disks of the VM itself stay
+/// untouched
+pub async fn remove_excluded_disks(
+    xapi_client: &XApiCliClient,
+    vm: &VM,
+    snapshot: &VM,
+    job_config: &JobConfig,
+) -> eyre::Result<()> {
+    if job_config.vdi_exclude.is_empty() && job_config.vdi_tag_filter_exclude.is_empty() {
+        return Ok(());
+    }
+
+    for vbd in xapi_client
+        .list_uuids("vbd", &[&format!("vm-uuid={}", snapshot.uuid), "type=Disk"])
+        .await?
+    {
+        let snapshot_vdi = xapi_client.get_param("vbd", &vbd, "vdi-uuid").await?;
+        if snapshot_vdi.is_empty() || snapshot_vdi == "<not in database>" {
+            continue;
+        }
+
+        // tags and name-label are set on the disk of the VM, the snapshot VDI is a copy of it
+        let vdi = xapi_client
+            .get_param("vdi", &snapshot_vdi, "snapshot-of")
+            .await?;
+        let name_label = xapi_client.get_param("vdi", &vdi, "name-label").await?;
+        let tags = xapi_client.get_param("vdi", &vdi, "tags").await?;
+        if !is_excluded(job_config, &vdi, &name_label, &tags) {
+            continue;
+        }
+
+        info!(
+            "Excluding disk '{}' [{}] of VM '{}' from the backup",
+            name_label, vdi, vm.name_label
+        );
+        debug!(
+            "Destroying VBD {} and VDI {} of snapshot {}",
+            vbd, snapshot_vdi, snapshot.uuid
+        );
+        xapi_client.destroy("vbd", &vbd).await?;
+        xapi_client.destroy("vdi", &snapshot_vdi).await?;
+    }
+
+    Ok(())
+}
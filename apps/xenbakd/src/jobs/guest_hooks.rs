@@ -0,0 +1,121 @@
+use eyre::Context;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    config::{GuestHookConfig, JobConfig},
+    xapi::VM,
+};
+
+use super::guest_quiesce::{default_ssh_user, run_ssh, SshHostKeys};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "method")]
+pub enum GuestHookMethod {
+    /// runs the given commands inside the guest via ssh. `{vm}` in the host is replaced by the
+    /// name-label of the VM
+    #[serde(rename = "ssh")]
+    Ssh {
+        #[serde(default = "default_ssh_host")]
+        host: String,
+        #[serde(default = "default_ssh_user")]
+        user: String,
+        ssh_key_path: Option<String>,
+        #[serde(flatten)]
+        host_keys: SshHostKeys,
+        #[serde(default)]
+        pre_snapshot: Option<String>,
+        #[serde(default)]
+        post_export: Option<String>,
+    },
+}
+
+fn default_ssh_host() -> String {
+    "{vm}".into()
+}
+
+/// point of the backup of a VM a guest hook is run at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuestHookStage {
+    /// right before the snapshot is created, e.g. to dump a database
+    PreSnapshot,
+    /// after the VM was exported to all storages, e.g. to remove the dump again
+    PostExport,
+}
+
+impl std::fmt::Display for GuestHookStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GuestHookStage::PreSnapshot => write!(f, "pre-snapshot"),
+            GuestHookStage::PostExport => write!(f, "post-export"),
+        }
+    }
+}
+
+/// runs the guest hooks of the job whose tags match the VM
+pub struct GuestHooks<'a> {
+    hooks: Vec<&'a GuestHookConfig>,
+    vm: &'a VM,
+}
+
+impl<'a> GuestHooks<'a> {
+    pub fn new(job_config: &'a JobConfig, vm: &'a VM) -> Self {
+        GuestHooks {
+            hooks: job_config
+                .guest_hooks
+                .iter()
+                .filter(|x| x.tags.iter().any(|tag| vm.tags.contains(tag)))
+                .collect(),
+            vm,
+        }
+    }
+
+    /// runs the hooks of the stage in the order they are configured, stopping at the first
+    /// failing one
+    pub async fn run(&self, stage: GuestHookStage) -> eyre::Result<()> {
+        for hook in &self.hooks {
+            let timeout = std::time::Duration::from_secs(hook.timeout);
+            tokio::time::timeout(timeout, self.execute(hook, stage))
+                .await
+                .map_err(|_| {
+                    eyre::eyre!(
+                        "Guest {} hook timed out after {} seconds",
+                        stage,
+                        hook.timeout
+                    )
+                })?
+                .wrap_err(format!(
+                    "Guest {} hook of VM '{}' failed",
+                    stage, self.vm.name_label
+                ))?;
+        }
+
+        Ok(())
+    }
+
+    async fn execute(&self, hook: &GuestHookConfig, stage: GuestHookStage) -> eyre::Result<()> {
+        match &hook.method {
+            GuestHookMethod::Ssh {
+                host,
+                user,
+                ssh_key_path,
+                host_keys,
+                pre_snapshot,
+                post_export,
+            } => {
+                let command = match stage {
+                    GuestHookStage::PreSnapshot => pre_snapshot,
+                    GuestHookStage::PostExport => post_export,
+                };
+                let Some(command) = command else {
+                    return Ok(());
+                };
+                info!("Running {} hook in guest '{}'", stage, self.vm.name_label);
+                let host = host.replace("{vm}", &self.vm.name_label);
+                run_ssh(&host, user, ssh_key_path.as_deref(), host_keys, command).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
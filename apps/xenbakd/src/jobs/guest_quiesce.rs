@@ -0,0 +1,180 @@
+use std::process::Stdio;
+
+use eyre::Context;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command as AsyncCommand;
+use tracing::{info, warn};
+
+use crate::{config::GuestQuiesceConfig, xapi::VM};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "method")]
+pub enum GuestQuiesceMethod {
+    /// runs the given commands inside the guest via ssh
+    #[serde(rename = "ssh")]
+    Ssh {
+        host: String,
+        #[serde(default = "default_ssh_user")]
+        user: String,
+        ssh_key_path: Option<String>,
+        #[serde(flatten)]
+        host_keys: SshHostKeys,
+        freeze_command: String,
+        thaw_command: String,
+    },
+}
+
+pub fn default_ssh_user() -> String {
+    "root".into()
+}
+
+/// how the host keys of guests are verified
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SshHostKeys {
+    /// known_hosts file holding the guests' host keys, ssh's default files if unset
+    #[serde(default)]
+    pub known_hosts: Option<String>,
+    /// unknown or changed host keys fail the command, disabling it accepts any host key
+    #[serde(default = "default_host_key_checking")]
+    pub host_key_checking: bool,
+}
+
+fn default_host_key_checking() -> bool {
+    true
+}
+
+impl Default for SshHostKeys {
+    fn default() -> SshHostKeys {
+        SshHostKeys {
+            known_hosts: None,
+            host_key_checking: default_host_key_checking(),
+        }
+    }
+}
+
+/// runs a command inside a guest via ssh, without asking for passwords
+pub async fn run_ssh(
+    host: &str,
+    user: &str,
+    ssh_key_path: Option<&str>,
+    host_keys: &SshHostKeys,
+    command: &str,
+) -> eyre::Result<()> {
+    let mut cmd = AsyncCommand::new("ssh");
+    cmd.arg("-o")
+        .arg("BatchMode=yes")
+        .arg("-o")
+        .arg(match host_keys.host_key_checking {
+            true => "StrictHostKeyChecking=yes",
+            false => "StrictHostKeyChecking=no",
+        });
+    if let Some(known_hosts) = &host_keys.known_hosts {
+        cmd.arg("-o")
+            .arg(format!("UserKnownHostsFile={}", known_hosts));
+    }
+    if let Some(ssh_key_path) = ssh_key_path {
+        cmd.arg("-i").arg(ssh_key_path);
+    }
+    cmd.arg(format!("{}@{}", user, host));
+    cmd.arg(command);
+
+    let output = cmd.stdin(Stdio::null()).kill_on_drop(true).output().await?;
+
+    if !output.status.success() {
+        return Err(eyre::eyre!(
+            "Guest command exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+enum QuiesceAction {
+    Freeze,
+    Thaw,
+}
+
+impl std::fmt::Display for QuiesceAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuiesceAction::Freeze => write!(f, "freeze"),
+            QuiesceAction::Thaw => write!(f, "thaw"),
+        }
+    }
+}
+
+/// coordinates freezing/thawing a guest around snapshot creation
+pub struct GuestQuiesce<'a> {
+    config: &'a GuestQuiesceConfig,
+    vm: &'a VM,
+}
+
+impl<'a> GuestQuiesce<'a> {
+    pub fn new(config: &'a GuestQuiesceConfig, vm: &'a VM) -> Self {
+        GuestQuiesce { config, vm }
+    }
+
+    /// runs `f` while the guest is frozen. the guest is always thawed afterwards, even if
+    /// freezing or `f` failed.
+    pub async fn run_frozen<T, F>(&self, f: F) -> eyre::Result<T>
+    where
+        F: std::future::Future<Output = eyre::Result<T>>,
+    {
+        info!("Freezing guest '{}'", self.vm.name_label);
+        let freeze_result = self.execute(QuiesceAction::Freeze).await;
+
+        let result = match freeze_result {
+            Ok(_) => f.await,
+            Err(e) => Err(e),
+        };
+
+        info!("Thawing guest '{}'", self.vm.name_label);
+        if let Err(e) = self.execute(QuiesceAction::Thaw).await {
+            warn!("Failed to thaw guest '{}': {:?}", self.vm.name_label, e);
+            if result.is_ok() {
+                return Err(e);
+            }
+        }
+
+        result
+    }
+
+    async fn execute(&self, action: QuiesceAction) -> eyre::Result<()> {
+        let timeout = std::time::Duration::from_secs(self.config.timeout);
+
+        tokio::time::timeout(timeout, self.execute_inner(action))
+            .await
+            .map_err(|_| {
+                eyre::eyre!(
+                    "Guest {} timed out after {} seconds",
+                    action,
+                    self.config.timeout
+                )
+            })?
+            .wrap_err(format!("Guest {} failed", action))
+    }
+
+    async fn execute_inner(&self, action: QuiesceAction) -> eyre::Result<()> {
+        match &self.config.method {
+            GuestQuiesceMethod::Ssh {
+                host,
+                user,
+                ssh_key_path,
+                host_keys,
+                freeze_command,
+                thaw_command,
+            } => {
+                let command = match action {
+                    QuiesceAction::Freeze => freeze_command,
+                    QuiesceAction::Thaw => thaw_command,
+                };
+                run_ssh(host, user, ssh_key_path.as_deref(), host_keys, command).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use std::process::Stdio;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    config::JobConfig,
+    jobs::{JobOutcome, XenbakJobStats},
+};
+
+/// what happens when a hook command fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailureAction {
+    /// a failed pre hook skips the job, a failed post hook fails it
+    #[default]
+    Abort,
+    /// the failure is only reported as a warning of the job
+    Warn,
+}
+
+/// point of the job run a hook is executed at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookStage {
+    Pre,
+    Post,
+}
+
+impl std::fmt::Display for HookStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookStage::Pre => write!(f, "pre"),
+            HookStage::Post => write!(f, "post"),
+        }
+    }
+}
+
+/// runs the hook command of the stage, the job is passed in `XENBAKD_*` environment variables.
+/// post hooks get the result and the stats of the run as well
+async fn run_command(
+    job_config: &JobConfig,
+    stage: HookStage,
+    command: &[String],
+    job_stats: Option<&XenbakJobStats>,
+) -> eyre::Result<()> {
+    let Some((program, args)) = command.split_first() else {
+        return Ok(());
+    };
+
+    let mut command = tokio::process::Command::new(program);
+    command
+        .args(args)
+        .env("XENBAKD_HOOK", stage.to_string())
+        .env("XENBAKD_JOB", &job_config.name)
+        .env("XENBAKD_JOB_TYPE", job_config.job_type.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(job_stats) = job_stats {
+        let result = match job_stats.outcome {
+            JobOutcome::Success => "success",
+            JobOutcome::Warning => "warning",
+            JobOutcome::Failure => "failure",
+        };
+        command
+            .env("XENBAKD_RESULT", result)
+            .env("XENBAKD_STATS", serde_json::to_string(job_stats)?);
+    }
+
+    let timeout = std::time::Duration::from_secs(job_config.hook_timeout);
+    let output = match tokio::time::timeout(timeout, command.output()).await {
+        Ok(output) => output?,
+        Err(_) => {
+            return Err(eyre::eyre!(
+                "timed out after {} seconds",
+                job_config.hook_timeout
+            ))
+        }
+    };
+    if !output.status.success() {
+        return Err(eyre::eyre!(
+            "exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+/// runs the pre or post hook of the job, if it has one. returns the warning of a failed hook
+/// if the job carries on regardless, the error if it doesn't
+pub async fn run_hook(
+    job_config: &JobConfig,
+    stage: HookStage,
+    job_stats: Option<&XenbakJobStats>,
+) -> eyre::Result<Option<String>> {
+    let command = match stage {
+        HookStage::Pre => &job_config.pre_hook,
+        HookStage::Post => &job_config.post_hook,
+    };
+    if command.is_empty() {
+        return Ok(None);
+    }
+
+    info!(
+        "Running {} hook of job '{}': {}",
+        stage,
+        job_config.name,
+        command.join(" ")
+    );
+    let Err(e) = run_command(job_config, stage, command, job_stats).await else {
+        return Ok(None);
+    };
+    let message = format!(
+        "The {} hook of job '{}' failed: {}",
+        stage, job_config.name, e
+    );
+    match job_config.hook_failure {
+        HookFailureAction::Abort => Err(eyre::eyre!(message)),
+        HookFailureAction::Warn => {
+            warn!("{}", message);
+            Ok(Some(message))
+        }
+    }
+}
@@ -1,12 +1,37 @@
+use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::config::JobConfig;
+use crate::config::{JobConfig, XenConfig};
+use crate::xapi::{error::XApiErrorKind, preflight::HostStatus, PowerState, VmSizeEstimate};
 use crate::GlobalState;
 
+use self::{resource_usage::ResourceUsage, test_restore::TestRestoreResult};
+
+pub mod borg_maintenance;
+pub mod budget;
+pub mod cold_backup;
+pub mod concurrency;
+pub mod coverage;
+pub mod deadline;
+pub mod differential;
+pub mod excluded_disks;
+pub mod guest_hooks;
+pub mod guest_quiesce;
+pub mod hooks;
+pub mod pause;
+pub mod pool_metadata;
+pub mod reclaim;
+pub mod replication;
+pub mod resource_usage;
+pub mod retained_snapshots;
+pub mod status;
+pub mod test_restore;
+pub mod vdi_backup;
 pub mod vm_backup;
+pub mod window;
 
 #[async_trait::async_trait]
 pub trait XenbakJob {
@@ -18,40 +43,212 @@ pub trait XenbakJob {
     async fn run(&mut self) -> eyre::Result<()>;
 }
 
+/// result of a run as reported to the monitoring services
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobOutcome {
+    #[default]
+    Success,
+    /// some objects failed, but no more than `failure_threshold_percent`
+    Warning,
+    Failure,
+}
+
+impl std::fmt::Display for JobOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobOutcome::Success => write!(f, "success"),
+            JobOutcome::Warning => write!(f, "warning"),
+            JobOutcome::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+/// final state of an object of the run, after all its retries
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectStatus {
+    Success,
+    Failed,
+    /// busy with another operation during the whole run
+    Skipped,
+}
+
+/// result of the backup of a single VM
+#[derive(Debug, Clone, Serialize)]
+pub struct ObjectResult {
+    pub name: String,
+    pub uuid: String,
+    pub xen_host: String,
+    pub status: ObjectStatus,
+    /// seconds of the last attempt
+    pub duration: f64,
+    /// bytes exported to all storages
+    pub exported_bytes: u64,
+    /// bytes the backups take up on the storages after compression, as far as they report it
+    pub stored_bytes: u64,
+    /// storages the VM was exported to
+    pub storages: Vec<String>,
+    pub error: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct XenbakJobStats {
     pub config: JobConfig,
+    pub outcome: JobOutcome,
+    /// configs of the job's storages at the start of the run, secrets redacted
+    pub storage_configs: BTreeMap<String, serde_json::Value>,
     pub total_objects: u32,
     pub successful_objects: u32,
     pub failed_objects: u32,
+    /// objects which were busy with another operation during the whole run
+    pub skipped_objects: u32,
+    /// objects whose backup failed at first and was retried, see `retries`
+    pub retried_objects: u32,
+    /// the run exceeded `max_runtime_seconds`, its remaining backups were cancelled
+    pub timed_out: bool,
+    /// objects whose backup failed because it would have exceeded a storage quota
+    pub quota_exceeded_objects: u32,
+    /// restore points deleted before their retention to stay within a storage quota
+    pub quota_rotated_objects: u32,
+    /// pinned restore points on the job's storages, as `<storage>: <restore point>`
+    pub pinned_restore_points: Vec<String>,
+    /// power state of every VM of the run at its start, as `<name-label> [<uuid>]`
+    pub power_states: BTreeMap<String, PowerState>,
+    /// result of the connectivity check of the job's xen hosts, unreachable ones are skipped
+    pub host_status: BTreeMap<String, HostStatus>,
+    /// pool alias of every xen host of the job which has one, by host name
+    pub pools: BTreeMap<String, String>,
+    /// size of the disks of every VM of the run at its start, as `<name-label> [<uuid>]`
+    pub size_estimates: BTreeMap<String, VmSizeEstimate>,
+    /// bytes allocated by the disks of all VMs of the run, an upper bound of the exports
+    pub estimated_bytes: u64,
     pub duration: f64,
+    /// bytes exported to the storages, by successful objects
+    pub exported_bytes: u64,
+    /// seconds spent on those exports, summed up over concurrent exports
+    pub export_duration: f64,
     pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+    /// number of failed objects per classified xe error
+    pub error_kinds: BTreeMap<XApiErrorKind, u32>,
+    /// likely cause of every classified error of the run
+    pub error_hints: BTreeMap<XApiErrorKind, String>,
+    /// outcome of the test restore, if the run made one
+    pub test_restore: Option<TestRestoreResult>,
+    /// result of every VM of the run
+    pub object_results: Vec<ObjectResult>,
+    /// what a dry run would have done
+    pub dry_run_actions: Vec<String>,
+    /// VMs quarantined by the run, as `name [uuid]`
+    pub quarantined_objects: Vec<String>,
+    pub resource_usage: ResourceUsage,
 }
 
 impl Default for XenbakJobStats {
     fn default() -> XenbakJobStats {
         XenbakJobStats {
             config: JobConfig::default(),
+            outcome: JobOutcome::default(),
+            storage_configs: BTreeMap::new(),
             total_objects: 0,
             successful_objects: 0,
             failed_objects: 0,
+            skipped_objects: 0,
+            retried_objects: 0,
+            timed_out: false,
+            quota_exceeded_objects: 0,
+            quota_rotated_objects: 0,
+            pinned_restore_points: vec![],
+            power_states: BTreeMap::new(),
+            host_status: BTreeMap::new(),
+            pools: BTreeMap::new(),
+            size_estimates: BTreeMap::new(),
+            estimated_bytes: 0,
             duration: 0.0,
+            exported_bytes: 0,
+            export_duration: 0.0,
             errors: vec![],
+            warnings: vec![],
+            error_kinds: BTreeMap::new(),
+            error_hints: BTreeMap::new(),
+            test_restore: None,
+            object_results: vec![],
+            dry_run_actions: vec![],
+            quarantined_objects: vec![],
+            resource_usage: ResourceUsage::default(),
         }
     }
 }
 
-impl XenbakJobStats {}
+impl XenbakJobStats {
+    /// counts a classified snapshot/export failure and records its hint
+    pub fn add_error_kind(&mut self, kind: XApiErrorKind) {
+        *self.error_kinds.entry(kind).or_default() += 1;
+        if let Some(hint) = kind.hint() {
+            self.error_hints.insert(kind, hint.to_string());
+        }
+    }
+
+    /// whether the failed objects of the run stay within the job's `failure_threshold_percent`
+    pub fn within_failure_threshold(&self) -> bool {
+        if self.failed_objects == 0 {
+            return true;
+        }
+        match self.config.failure_threshold_percent {
+            Some(threshold) if self.total_objects > 0 => {
+                self.failed_objects as f64 * 100.0 / self.total_objects as f64 <= threshold
+            }
+            _ => false,
+        }
+    }
+
+    pub fn record_pools(&mut self, xen_configs: &[XenConfig]) {
+        self.pools = xen_configs
+            .iter()
+            .filter_map(|x| Some((x.name.clone(), x.pool.clone()?)))
+            .collect();
+    }
+
+    /// keeps the status of the job's hosts and warns about every unreachable one, returns their number
+    pub fn record_host_status(&mut self, host_status: BTreeMap<String, HostStatus>) -> u32 {
+        let mut unreachable = 0;
+        for (host, status) in &host_status {
+            if let HostStatus::Unreachable(e) = status {
+                unreachable += 1;
+                self.warnings
+                    .push(format!("Skipped unreachable xen host '{}': {}", host, e));
+            }
+        }
+        self.host_status = host_status;
+        unreachable
+    }
+}
 
-#[derive(Debug, Clone, PartialEq, Serialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub enum JobType {
+    #[default]
     VmBackup,
+    /// backs up single VDIs, selected by tag or SR, instead of whole VMs
+    VdiBackup,
+    /// dumps the pool database, to rebuild a pool after a disaster
+    PoolMetadata,
+    /// copies VMs into another pool instead of a storage
+    Replication,
+    BorgMaintenance,
+    /// looks for VMs which no enabled job backs up
+    Coverage,
 }
 
-impl ToString for JobType {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for JobType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            JobType::VmBackup => "vm".to_string(),
+            JobType::VmBackup => write!(f, "vm"),
+            JobType::VdiBackup => write!(f, "vdi"),
+            JobType::PoolMetadata => write!(f, "pool-metadata"),
+            JobType::Replication => write!(f, "replication"),
+            JobType::BorgMaintenance => write!(f, "borg-maintenance"),
+            JobType::Coverage => write!(f, "coverage"),
         }
     }
 }
@@ -62,6 +259,11 @@ impl FromStr for JobType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "vm" => Ok(JobType::VmBackup),
+            "vdi" => Ok(JobType::VdiBackup),
+            "pool-metadata" => Ok(JobType::PoolMetadata),
+            "replication" => Ok(JobType::Replication),
+            "borg-maintenance" => Ok(JobType::BorgMaintenance),
+            "coverage" => Ok(JobType::Coverage),
             _ => Err(eyre::eyre!("Invalid job type")),
         }
     }
@@ -0,0 +1,88 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    config::JobConfig, monitoring::healthchecks::HealthchecksManagementApiTrait, GlobalState,
+};
+
+/// jobs paused at runtime, their scheduled runs are skipped until they are resumed. the state
+/// doesn't survive a restart of the daemon, the config stays the reference
+#[derive(Debug, Clone, Default)]
+pub struct PausedJobs(Arc<Mutex<BTreeSet<String>>>);
+
+impl PausedJobs {
+    pub fn is_paused(&self, job_name: &str) -> bool {
+        self.0.lock().unwrap().contains(job_name)
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+/// the enabled jobs of the daemon, including the maintenance jobs
+pub fn daemon_jobs(global_state: &GlobalState) -> Vec<JobConfig> {
+    global_state
+        .config
+        .jobs
+        .iter()
+        .filter(|x| x.enabled)
+        .cloned()
+        .chain(global_state.config.get_maintenance_jobs())
+        .collect()
+}
+
+/// pauses or resumes the job. the healthchecks.io check of a paused job is paused as well, so
+/// the skipped runs don't raise alerts
+pub async fn set_paused(
+    global_state: &GlobalState,
+    job_name: &str,
+    paused: bool,
+) -> eyre::Result<()> {
+    if !daemon_jobs(global_state).iter().any(|x| x.name == job_name) {
+        return Err(eyre::eyre!("Job '{}' not found", job_name));
+    }
+
+    let changed = {
+        let mut jobs = global_state.paused.0.lock().unwrap();
+        match paused {
+            true => jobs.insert(job_name.to_string()),
+            false => jobs.remove(job_name),
+        }
+    };
+    if !changed {
+        return Ok(());
+    }
+    info!(
+        "{} job '{}'",
+        match paused {
+            true => "Paused",
+            false => "Resumed",
+        },
+        job_name
+    );
+
+    if let Some(healthchecks_service) = &global_state.healthchecks_service {
+        let result = match paused {
+            true => healthchecks_service.pause_check(job_name).await,
+            false => healthchecks_service.resume_check(job_name).await,
+        };
+        if let Err(e) = result {
+            warn!(
+                "Failed to {} healthchecks.io check of job '{}': {}",
+                match paused {
+                    true => "pause",
+                    false => "resume",
+                },
+                job_name,
+                e
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,251 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use eyre::WrapErr;
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::{
+    config::JobConfig,
+    jobs::XenbakJobStats,
+    storage::{self, manifest::ConfigSnapshot, StorageHandler},
+    xapi::{cli::client::XApiCliClient, error::XApiCliError, preflight},
+    GlobalState,
+};
+
+use super::{JobType, XenbakJob};
+
+/// dumps the database of the host's pool to all storages of the job
+async fn backup_pool(
+    xapi_client: XApiCliClient,
+    storage_handlers: Vec<Arc<dyn StorageHandler>>,
+    job_config: JobConfig,
+) -> eyre::Result<()> {
+    let pool_timer = tokio::time::Instant::now();
+    let (pool, name_label) = xapi_client.get_pool().await?;
+    // pools created without a name only have their uuid
+    let name_label = if name_label.is_empty() {
+        pool.clone()
+    } else {
+        name_label
+    };
+    info!(
+        "Starting backup of pool database '{}' [{}]",
+        name_label, pool
+    );
+
+    let backup_result = async {
+        let time_stamp = Utc::now();
+        for storage_handler in storage_handlers {
+            let backup_object = storage::BackupObject {
+                pool: xapi_client.get_config().pool.clone(),
+                ..storage::BackupObject::new(
+                    JobType::PoolMetadata,
+                    Some(pool.clone()),
+                    name_label.clone(),
+                    xapi_client.get_config().name.clone(),
+                    time_stamp,
+                    None,
+                )
+            };
+
+            info!(
+                "Dumping pool database to storage '{}'...",
+                storage_handler.get_name()
+            );
+            xapi_client
+                .pool_dump_database_to_storage(storage_handler.clone(), backup_object.clone())
+                .await?;
+
+            if job_config.verify {
+                info!("Verifying backup...");
+                let restore_point = storage_handler.get_restore_point(&backup_object).await?;
+                storage_handler.verify(&restore_point).await?;
+            }
+
+            debug!("Rotating backups");
+            let dry_run = job_config.rotate_dry_run.into();
+            let deleted = storage_handler
+                .rotate(
+                    storage::BackupObjectFilter::from_backup_object(backup_object),
+                    None,
+                    dry_run,
+                )
+                .await?;
+            storage::log_rotation(&storage_handler.get_name(), &deleted, dry_run);
+        }
+
+        Ok::<(), eyre::Error>(())
+    }
+    .await;
+
+    backup_result.wrap_err(format!(
+        "Backup of pool database '{}' [{}] failed",
+        name_label, pool
+    ))?;
+
+    info!(
+        "Finished backup of pool database '{}' [{}] in {} seconds",
+        name_label,
+        pool,
+        pool_timer.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}
+
+/// backs up the pool database of every xen host of the job, which holds everything but the
+/// disks needed to rebuild a pool with `xe pool-restore-database`
+#[derive(Clone, Debug)]
+pub struct PoolMetadataJob {
+    pub job_type: JobType,
+    pub job_config: JobConfig,
+    pub job_stats: XenbakJobStats,
+    pub global_state: Arc<GlobalState>,
+}
+
+#[async_trait::async_trait]
+impl XenbakJob for PoolMetadataJob {
+    fn new(global_state: Arc<GlobalState>, job_config: JobConfig) -> PoolMetadataJob {
+        PoolMetadataJob {
+            job_type: JobType::PoolMetadata,
+            global_state,
+            job_config,
+            job_stats: XenbakJobStats::default(),
+        }
+    }
+
+    fn get_name(&self) -> String {
+        self.job_config.name.clone()
+    }
+
+    fn get_job_type(&self) -> JobType {
+        self.job_type.clone()
+    }
+
+    fn get_schedule(&self) -> String {
+        self.job_config.schedule.clone()
+    }
+
+    fn get_job_stats(&self) -> XenbakJobStats {
+        self.job_stats.clone()
+    }
+
+    async fn run(&mut self) -> eyre::Result<()> {
+        let job_timer = tokio::time::Instant::now();
+
+        info!("Running pool metadata job '{}'", self.job_config.name);
+
+        self.job_stats = XenbakJobStats {
+            config: self.job_config.clone(),
+            ..XenbakJobStats::default()
+        };
+        for storage_name in &self.job_config.storages {
+            if let Some(snapshot) = self.global_state.config.storage.snapshot(storage_name) {
+                self.job_stats
+                    .storage_configs
+                    .insert(storage_name.clone(), snapshot);
+            }
+        }
+        debug!(
+            "Effective configuration of job '{}': {}",
+            self.job_config.name,
+            serde_json::to_string(&ConfigSnapshot::new(
+                &self.job_config,
+                &self.job_stats.storage_configs
+            ))?
+        );
+
+        let xen_configs = self
+            .job_config
+            .get_xen_configs(self.global_state.config.xen.clone());
+        self.job_stats.record_pools(&xen_configs);
+        let xapi_clients: Vec<XApiCliClient> = xen_configs
+            .iter()
+            .map(|x| XApiCliClient::new(x.clone()))
+            .collect();
+
+        self.job_stats.total_objects = xapi_clients.len() as u32;
+        if xapi_clients.is_empty() {
+            warn!("No xen hosts found for job '{}'", self.job_config.name);
+        }
+
+        // unreachable pools are skipped, the others are still backed up
+        let (xapi_clients, host_status) = preflight::check_hosts(xapi_clients).await;
+        self.job_stats.skipped_objects += self.job_stats.record_host_status(host_status);
+
+        let storage_handlers = self
+            .job_config
+            .get_storages(self.global_state.config.storage.clone());
+        for storage_handler in storage_handlers.clone() {
+            debug!(
+                "Initializing storage handler '{}'",
+                storage_handler.get_job_config().name
+            );
+            storage_handler.initialize().await?;
+        }
+
+        let probe_errors = storage::probe::probe_storages(&storage_handlers).await;
+        if !probe_errors.is_empty() {
+            self.job_stats.failed_objects = self.job_stats.total_objects;
+            self.job_stats.errors.extend(probe_errors);
+            self.job_stats.duration = job_timer.elapsed().as_secs_f64();
+            return Err(eyre::eyre!("Storage health check failed."));
+        }
+
+        storage::orphans::cleanup_storages(&storage_handlers).await;
+
+        // the dumps are small, so the pools are backed up one after another
+        for xapi_client in &xapi_clients {
+            let span = tracing::span!(
+                tracing::Level::INFO,
+                "PoolMetadataJob::run::backup_pool",
+                xen.host = xapi_client.get_config().name.clone()
+            );
+            let result = backup_pool(
+                xapi_client.clone(),
+                storage_handlers.clone(),
+                self.job_config.clone(),
+            )
+            .instrument(span)
+            .await;
+
+            match result {
+                Ok(_) => self.job_stats.successful_objects += 1,
+                Err(e) => {
+                    self.job_stats.failed_objects += 1;
+                    self.job_stats.errors.push(
+                        e.chain()
+                            .map(|e| e.to_string())
+                            .collect::<Vec<String>>()
+                            .join("\n"),
+                    );
+                    if let Some(kind) = e
+                        .chain()
+                        .find_map(|x| x.downcast_ref::<XApiCliError>())
+                        .and_then(|x| x.kind())
+                    {
+                        self.job_stats.add_error_kind(kind);
+                    }
+                    error!("{:?}", e);
+                }
+            }
+        }
+
+        for client in &xapi_clients {
+            client.logout().await;
+        }
+
+        self.job_stats.duration = job_timer.elapsed().as_secs_f64();
+
+        if !self.job_stats.within_failure_threshold() {
+            return Err(eyre::eyre!("Backup job failed."));
+        }
+
+        info!(
+            "Finished pool metadata job with name '{}' in {} seconds",
+            self.job_config.name, self.job_stats.duration
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+
+use tracing::{debug, info, warn};
+
+use crate::xapi::{cli::client::XApiCliClient, UUID, VM};
+
+const RECLAIM_POLL_INTERVAL: u64 = 30;
+
+/// an SR whose space should shrink after a snapshot of a VM was deleted
+#[derive(Debug, Clone)]
+pub struct PendingReclaim {
+    pub xapi_client: XApiCliClient,
+    pub vm_name: String,
+    pub sr: UUID,
+    /// physical utilisation of the SR right before the snapshot was deleted
+    pub baseline: u64,
+}
+
+/// records the physical utilisation of the SRs of the VM, has to be called before deleting its snapshot
+pub async fn record_utilisation(xapi_client: &XApiCliClient, vm: &VM) -> Vec<PendingReclaim> {
+    let srs = match xapi_client.get_vm_disk_srs(vm).await {
+        Ok(srs) => srs,
+        Err(e) => {
+            warn!("Failed to get SRs of VM '{}': {}", vm.name_label, e);
+            return vec![];
+        }
+    };
+
+    let mut pending = vec![];
+    for sr in srs {
+        match xapi_client.get_sr_physical_utilisation(&sr).await {
+            Ok(baseline) => pending.push(PendingReclaim {
+                xapi_client: xapi_client.clone(),
+                vm_name: vm.name_label.clone(),
+                sr,
+                baseline,
+            }),
+            Err(e) => warn!("Failed to get physical utilisation of SR {}: {}", sr, e),
+        }
+    }
+
+    pending
+}
+
+/// polls the SRs until their physical utilisation dropped below the one recorded before the
+/// snapshots were deleted. a coalesce which doesn't finish within the timeout usually doesn't
+/// finish at all, so a warning listing the affected VMs is returned for every such SR
+pub async fn await_reclamation(mut pending: Vec<PendingReclaim>, timeout: u64) -> Vec<String> {
+    if pending.is_empty() {
+        return vec![];
+    }
+
+    info!(
+        "Waiting up to {} seconds for the space of deleted snapshots to be reclaimed",
+        timeout
+    );
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(timeout);
+
+    loop {
+        // the same SR is usually shared by several VMs, only query it once per round
+        let mut utilisation: BTreeMap<(String, UUID), Option<u64>> = BTreeMap::new();
+        for entry in &pending {
+            let key = (
+                entry.xapi_client.get_config().name.clone(),
+                entry.sr.clone(),
+            );
+            if utilisation.contains_key(&key) {
+                continue;
+            }
+            let value = match entry
+                .xapi_client
+                .get_sr_physical_utilisation(&entry.sr)
+                .await
+            {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    warn!(
+                        "Failed to get physical utilisation of SR {}: {}",
+                        entry.sr, e
+                    );
+                    None
+                }
+            };
+            utilisation.insert(key, value);
+        }
+
+        pending.retain(|entry| {
+            let key = (
+                entry.xapi_client.get_config().name.clone(),
+                entry.sr.clone(),
+            );
+            match utilisation.get(&key).copied().flatten() {
+                Some(value) if value < entry.baseline => {
+                    debug!(
+                        "Space of the snapshot of VM '{}' on SR {} was reclaimed",
+                        entry.vm_name, entry.sr
+                    );
+                    false
+                }
+                _ => true,
+            }
+        });
+
+        if pending.is_empty() {
+            return vec![];
+        }
+
+        let now = tokio::time::Instant::now();
+        if now >= deadline {
+            break;
+        }
+        tokio::time::sleep(
+            (deadline - now).min(std::time::Duration::from_secs(RECLAIM_POLL_INTERVAL)),
+        )
+        .await;
+    }
+
+    let mut unreclaimed: BTreeMap<(String, UUID), Vec<String>> = BTreeMap::new();
+    for entry in pending {
+        unreclaimed
+            .entry((entry.xapi_client.get_config().name.clone(), entry.sr))
+            .or_default()
+            .push(entry.vm_name);
+    }
+
+    unreclaimed
+        .into_iter()
+        .map(|((host, sr), vm_names)| {
+            format!(
+                "Space of deleted snapshots on SR {} of host '{}' was not reclaimed within {} seconds, coalesce may be stuck (VMs: {})",
+                sr,
+                host,
+                timeout,
+                vm_names.join(", ")
+            )
+        })
+        .collect()
+}
+
+/// polls the disks of the VM until none of them waits for a coalesce anymore. returns a warning
+/// if they didn't coalesce within the timeout
+pub async fn await_coalesce(xapi_client: &XApiCliClient, vm: &VM, timeout: u64) -> Option<String> {
+    info!(
+        "Waiting up to {} seconds for the disks of VM '{}' to coalesce",
+        timeout, vm.name_label
+    );
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(timeout);
+
+    loop {
+        match xapi_client.get_vm_coalescing_vdis(vm).await {
+            Ok(vdis) if vdis.is_empty() => {
+                debug!("Disks of VM '{}' are coalesced", vm.name_label);
+                return None;
+            }
+            Ok(vdis) => debug!(
+                "Disks of VM '{}' still wait for a coalesce: {}",
+                vm.name_label,
+                vdis.join(", ")
+            ),
+            Err(e) => {
+                warn!(
+                    "Failed to check the coalesce of VM '{}': {}",
+                    vm.name_label, e
+                );
+                return None;
+            }
+        }
+
+        let now = tokio::time::Instant::now();
+        if now >= deadline {
+            break;
+        }
+        tokio::time::sleep(
+            (deadline - now).min(std::time::Duration::from_secs(RECLAIM_POLL_INTERVAL)),
+        )
+        .await;
+    }
+
+    Some(format!(
+        "Disks of VM '{}' [{}] did not coalesce within {} seconds, coalesce may be stuck",
+        vm.name_label, vm.uuid, timeout
+    ))
+}
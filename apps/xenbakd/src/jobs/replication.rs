@@ -0,0 +1,414 @@
+use std::sync::Arc;
+
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::{
+    config::{JobConfig, ReplicationConfig},
+    jobs::XenbakJobStats,
+    storage::manifest::ConfigSnapshot,
+    xapi::{cli::client::XApiCliClient, error::XApiCliError, preflight, UUID, VM},
+    GlobalState,
+};
+
+use super::{
+    guest_hooks::{GuestHookStage, GuestHooks},
+    vm_backup, JobType, XenbakJob,
+};
+
+/// other-config key marking the replicas of a job on the destination pool, the value is the
+/// job's name
+const REPLICA_JOB_KEY: &str = "xenbakd-replica-job";
+/// other-config key holding the uuid of the replicated VM
+const REPLICA_OF_KEY: &str = "xenbakd-replica-of";
+/// other-config key holding the time of the snapshot the replica was made from
+const REPLICA_TIME_KEY: &str = "xenbakd-replica-time";
+
+/// returns the name-label of a replica made from a snapshot of the VM
+fn replica_name(replication: &ReplicationConfig, vm: &VM, snapshot: &VM) -> String {
+    replication
+        .name_format
+        .replace("{vm}", &vm.name_label)
+        .replace(
+            "{timestamp}",
+            &snapshot.snapshot_time.format("%Y-%m-%d %H:%M").to_string(),
+        )
+}
+
+/// lists the replicas of the VM made by the job, oldest first
+async fn list_replicas(
+    target: &XApiCliClient,
+    vm: &VM,
+    job_name: &str,
+) -> eyre::Result<Vec<(String, UUID)>> {
+    let mut replicas = vec![];
+    for uuid in target
+        .list_uuids(
+            "vm",
+            &[
+                &format!("other-config:{}={}", REPLICA_OF_KEY, vm.uuid),
+                &format!("other-config:{}={}", REPLICA_JOB_KEY, job_name),
+            ],
+        )
+        .await?
+    {
+        // e.g. `xenbakd-replica-time: 2024-01-01T02:00:00+00:00; ...`
+        let other_config = target.get_param("vm", &uuid, "other-config").await?;
+        let time = other_config
+            .split(';')
+            .filter_map(|x| x.split_once(':'))
+            .find(|(key, _)| key.trim() == REPLICA_TIME_KEY)
+            .map(|(_, value)| value.trim().to_string())
+            .unwrap_or_default();
+        replicas.push((time, uuid));
+    }
+    replicas.sort();
+
+    Ok(replicas)
+}
+
+/// pipes the export of the snapshot into an import on the destination pool, returns the UUID
+/// of the replica
+async fn copy_snapshot(
+    xapi_client: &XApiCliClient,
+    target: &XApiCliClient,
+    snapshot: &VM,
+    sr: Option<&UUID>,
+    job_config: &JobConfig,
+) -> eyre::Result<UUID> {
+    let mut export = xapi_client.open_vm_export(snapshot, job_config.export_compression)?;
+    let stdout = Box::new(export.stdout.take().unwrap());
+    let import = target.vm_import(stdout, sr, false).await;
+
+    let output = export.wait_with_output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // a failed import stops reading the export, which is the more telling error then
+        import?;
+        return Err(XApiCliError::CommandFailed(stderr.into()).into());
+    }
+
+    import
+}
+
+/// replicates a single VM into the destination pool and deletes its oldest replicas beyond
+/// `keep`
+async fn replicate_vm(
+    xapi_client: XApiCliClient,
+    target: XApiCliClient,
+    vm: VM,
+    sr: Option<UUID>,
+    job_config: JobConfig,
+    clock_skew_threshold: i64,
+) -> eyre::Result<()> {
+    let vm_timer = tokio::time::Instant::now();
+    // replicas have their own retention and are always copied, only the snapshot settings apply
+    let vm_override = job_config.get_vm_override(&vm);
+    let job_config = JobConfig {
+        export_compression: vm_override
+            .export_compression
+            .or(job_config.export_compression),
+        snapshot_type: vm_override
+            .snapshot_type
+            .unwrap_or(job_config.snapshot_type),
+        ..job_config
+    };
+    let replication = job_config
+        .replication
+        .as_ref()
+        .ok_or_else(|| eyre::eyre!("Replication job '{}' has no target", job_config.name))?;
+    info!(
+        "Starting replication of VM '{}' [{}] to xen host '{}'",
+        vm.name_label,
+        vm.uuid,
+        target.get_config().name
+    );
+
+    debug!("Creating snapshot");
+    let snapshot =
+        vm_backup::create_snapshot(&xapi_client, &vm, &job_config, clock_skew_threshold).await?;
+
+    let replica = async {
+        // the snapshot is imported as a template otherwise
+        let snapshot = xapi_client
+            .set_snapshot_param_not_template(&snapshot)
+            .await?;
+        info!("Copying VM to xen host '{}'...", target.get_config().name);
+        copy_snapshot(&xapi_client, &target, &snapshot, sr.as_ref(), &job_config).await
+    }
+    .await;
+
+    debug!("Deleting snapshot...");
+    xapi_client.delete_snapshot_by_uuid(&snapshot.uuid).await?;
+    if let Err(e) = GuestHooks::new(&job_config, &vm)
+        .run(GuestHookStage::PostExport)
+        .await
+    {
+        warn!("{:#}", e);
+    }
+
+    let replica = replica.map_err(|e| {
+        e.wrap_err(format!(
+            "Replication of VM '{}' [{}] failed",
+            vm.name_label, vm.uuid
+        ))
+    })?;
+
+    let name = replica_name(replication, &vm, &snapshot);
+    debug!("Naming replica [{}] '{}'", replica, name);
+    target
+        .set_param("vm", &replica, "name-label", &name)
+        .await?;
+    for (key, value) in [
+        (REPLICA_JOB_KEY, job_config.name.clone()),
+        (REPLICA_OF_KEY, vm.uuid.clone()),
+        (REPLICA_TIME_KEY, snapshot.snapshot_time.to_rfc3339()),
+    ] {
+        target
+            .set_param("vm", &replica, &format!("other-config:{}", key), &value)
+            .await?;
+    }
+
+    let replicas = list_replicas(&target, &vm, &job_config.name).await?;
+    let excess = replicas
+        .len()
+        .saturating_sub(replication.keep.max(1) as usize);
+    for (_, old) in replicas.iter().take(excess) {
+        debug!("Deleting old replica [{}]", old);
+        target.vm_uninstall(old).await?;
+    }
+
+    info!(
+        "Finished replication of VM '{}' [{}] in {} seconds",
+        vm.name_label,
+        vm.uuid,
+        vm_timer.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}
+
+/// keeps replicas of VMs on another pool, e.g. at a DR site. the export of every VM is piped
+/// straight into the import on the destination pool, nothing is written to a storage
+#[derive(Clone, Debug)]
+pub struct ReplicationJob {
+    pub job_type: JobType,
+    pub job_config: JobConfig,
+    pub job_stats: XenbakJobStats,
+    pub global_state: Arc<GlobalState>,
+}
+
+#[async_trait::async_trait]
+impl XenbakJob for ReplicationJob {
+    fn new(global_state: Arc<GlobalState>, job_config: JobConfig) -> ReplicationJob {
+        ReplicationJob {
+            job_type: JobType::Replication,
+            global_state,
+            job_config,
+            job_stats: XenbakJobStats::default(),
+        }
+    }
+
+    fn get_name(&self) -> String {
+        self.job_config.name.clone()
+    }
+
+    fn get_job_type(&self) -> JobType {
+        self.job_type.clone()
+    }
+
+    fn get_schedule(&self) -> String {
+        self.job_config.schedule.clone()
+    }
+
+    fn get_job_stats(&self) -> XenbakJobStats {
+        self.job_stats.clone()
+    }
+
+    async fn run(&mut self) -> eyre::Result<()> {
+        let job_timer = tokio::time::Instant::now();
+
+        info!("Running replication job '{}'", self.job_config.name);
+
+        self.job_stats = XenbakJobStats {
+            config: self.job_config.clone(),
+            ..XenbakJobStats::default()
+        };
+        debug!(
+            "Effective configuration of job '{}': {}",
+            self.job_config.name,
+            serde_json::to_string(&ConfigSnapshot::new(
+                &self.job_config,
+                &self.job_stats.storage_configs
+            ))?
+        );
+
+        let Some(replication) = self.job_config.replication.clone() else {
+            return Err(eyre::eyre!(
+                "Replication job '{}' has no [jobs.replication] section",
+                self.job_config.name
+            ));
+        };
+        if !self.job_config.storages.is_empty() {
+            warn!(
+                "Replication job '{}' doesn't write to storages, its storages are ignored",
+                self.job_config.name
+            );
+        }
+
+        let target_config = self
+            .global_state
+            .config
+            .xen
+            .iter()
+            .find(|x| x.name == replication.target)
+            .cloned()
+            .ok_or_else(|| {
+                eyre::eyre!(
+                    "Replication target '{}' of job '{}' is no configured xen host",
+                    replication.target,
+                    self.job_config.name
+                )
+            })?;
+        let target = XApiCliClient::new(target_config);
+
+        let xen_configs = self
+            .job_config
+            .get_xen_configs(self.global_state.config.xen.clone());
+        self.job_stats.record_pools(&xen_configs);
+        let mut xapi_clients: Vec<XApiCliClient> = xen_configs
+            .iter()
+            .map(|x| XApiCliClient::new(x.clone()))
+            .collect();
+        if !xen_configs.iter().any(|x| x.name == replication.target) {
+            xapi_clients.push(target.clone());
+        }
+
+        // without the destination pool there is nothing to replicate to
+        let (xapi_clients, host_status) = preflight::check_hosts(xapi_clients).await;
+        self.job_stats.record_host_status(host_status);
+        let Some(target) = xapi_clients
+            .iter()
+            .find(|x| x.get_config().name == target.get_config().name)
+            .cloned()
+        else {
+            self.job_stats.duration = job_timer.elapsed().as_secs_f64();
+            return Err(eyre::eyre!(
+                "Replication target '{}' is unreachable",
+                replication.target
+            ));
+        };
+        let sr = match &replication.sr {
+            Some(sr) => Some(target.resolve_uuid("sr", sr).await?),
+            None => None,
+        };
+
+        let mut queue: Vec<(XApiCliClient, VM)> = vec![];
+        for client in xapi_clients
+            .iter()
+            .filter(|x| x.get_config().name != replication.target)
+        {
+            client.get_version().await;
+            let vms = client
+                .filter_vms_by_tag(
+                    self.job_config.tag_filter.clone(),
+                    self.job_config.tag_filter_exclude.clone(),
+                )
+                .await?
+                .into_iter()
+                .filter(|x| !self.job_config.is_vm_excluded(x));
+            queue.extend(vms.map(|x| (client.clone(), x)));
+        }
+
+        self.job_stats.total_objects = queue.len() as u32;
+        debug!(
+            "{} objects affected by replication job",
+            self.job_stats.total_objects
+        );
+        if queue.is_empty() {
+            warn!(
+                "No VMs found for replication job '{}'",
+                self.job_config.name
+            );
+        }
+
+        let permits = Arc::new(tokio::sync::Semaphore::new(
+            self.job_config.concurrency as usize,
+        ));
+        let mut handles = vec![];
+        for (xapi_client, vm) in queue {
+            let span = tracing::span!(
+                tracing::Level::INFO,
+                "ReplicationJob::run::replicate_vm",
+                vm.name_label = vm.name_label.clone(),
+                xen.host = xapi_client.get_config().name.clone()
+            );
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            let global_permit = self
+                .global_state
+                .concurrency
+                .acquire(self.job_config.priority)
+                .await;
+            let target = target.clone();
+            let sr = sr.clone();
+            let job_config = self.job_config.clone();
+            let clock_skew_threshold = self.global_state.config.general.clock_skew_threshold;
+
+            handles.push(tokio::spawn(
+                async move {
+                    let _permit = permit;
+                    let _global_permit = global_permit;
+                    replicate_vm(
+                        xapi_client,
+                        target,
+                        vm,
+                        sr,
+                        job_config,
+                        clock_skew_threshold,
+                    )
+                    .await
+                }
+                .instrument(span),
+            ));
+        }
+
+        for handle in handles {
+            match handle.await? {
+                Ok(()) => self.job_stats.successful_objects += 1,
+                Err(e) => {
+                    self.job_stats.failed_objects += 1;
+                    self.job_stats.errors.push(
+                        e.chain()
+                            .map(|e| e.to_string())
+                            .collect::<Vec<String>>()
+                            .join("\n"),
+                    );
+                    if let Some(kind) = e
+                        .chain()
+                        .find_map(|x| x.downcast_ref::<XApiCliError>())
+                        .and_then(|x| x.kind())
+                    {
+                        self.job_stats.add_error_kind(kind);
+                    }
+                    error!("{:?}", e);
+                }
+            }
+        }
+
+        for client in &xapi_clients {
+            client.logout().await;
+        }
+
+        self.job_stats.duration = job_timer.elapsed().as_secs_f64();
+
+        if !self.job_stats.within_failure_threshold() {
+            return Err(eyre::eyre!("Replication job failed."));
+        }
+
+        info!(
+            "Finished replication job with name '{}' in {} seconds",
+            self.job_config.name, self.job_stats.duration
+        );
+
+        Ok(())
+    }
+}
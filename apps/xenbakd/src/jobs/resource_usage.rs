@@ -0,0 +1,124 @@
+use serde::Serialize;
+use tokio::sync::oneshot;
+use tracing::debug;
+
+/// procfs reports cpu times in USER_HZ, which is 100 on all platforms xenbakd runs on
+const USER_HZ: f64 = 100.0;
+
+/// interval in which memory and temp-disk usage is sampled
+const SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// resources consumed by the xenbakd process during a job run.
+/// values are process-wide, so concurrently running jobs will influence each other.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResourceUsage {
+    /// user + system cpu time in seconds
+    pub cpu_time: f64,
+    /// peak resident set size in bytes
+    pub peak_rss: u64,
+    /// peak usage of the job's temporary directories in bytes
+    pub peak_temp_disk: u64,
+}
+
+/// samples the process' resource usage in the background until stopped
+pub struct ResourceSampler {
+    cpu_time_start: f64,
+    stop_tx: oneshot::Sender<()>,
+    handle: tokio::task::JoinHandle<(u64, u64)>,
+}
+
+impl ResourceSampler {
+    pub fn start(temp_dirs: Vec<String>) -> ResourceSampler {
+        let (stop_tx, mut stop_rx) = oneshot::channel::<()>();
+
+        let handle = tokio::spawn(async move {
+            let mut peak_rss = 0;
+            let mut peak_temp_disk = 0;
+
+            loop {
+                peak_rss = peak_rss.max(read_rss().unwrap_or_default());
+
+                let mut temp_disk = 0;
+                for temp_dir in &temp_dirs {
+                    temp_disk += dir_size(temp_dir).await.unwrap_or_default();
+                }
+                peak_temp_disk = peak_temp_disk.max(temp_disk);
+
+                tokio::select! {
+                    _ = tokio::time::sleep(SAMPLE_INTERVAL) => {}
+                    _ = &mut stop_rx => break,
+                }
+            }
+
+            (peak_rss, peak_temp_disk)
+        });
+
+        ResourceSampler {
+            cpu_time_start: read_cpu_time().unwrap_or_default(),
+            stop_tx,
+            handle,
+        }
+    }
+
+    pub async fn stop(self) -> ResourceUsage {
+        let _ = self.stop_tx.send(());
+        let (peak_rss, peak_temp_disk) = self.handle.await.unwrap_or_default();
+        let cpu_time = read_cpu_time().unwrap_or_default() - self.cpu_time_start;
+
+        let usage = ResourceUsage {
+            cpu_time,
+            peak_rss,
+            peak_temp_disk,
+        };
+        debug!("Resource usage: {:?}", usage);
+        usage
+    }
+}
+
+/// reads user + system cpu time of the current process from /proc/self/stat
+pub fn read_cpu_time() -> eyre::Result<f64> {
+    let stat = std::fs::read_to_string("/proc/self/stat")?;
+
+    // the command name may contain spaces, so skip past its closing paren
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .ok_or_else(|| eyre::eyre!("Failed to parse /proc/self/stat"))?
+        .1
+        .split_whitespace()
+        .collect();
+
+    // utime and stime are fields 14 and 15, counted from the pid
+    let utime: u64 = fields.get(11).unwrap_or(&"0").parse()?;
+    let stime: u64 = fields.get(12).unwrap_or(&"0").parse()?;
+
+    Ok((utime + stime) as f64 / USER_HZ)
+}
+
+/// reads the current resident set size of the process from /proc/self/status
+pub fn read_rss() -> eyre::Result<u64> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+
+    let kilobytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|value| value.split_whitespace().next())
+        .ok_or_else(|| eyre::eyre!("Failed to parse /proc/self/status"))?
+        .parse::<u64>()?;
+
+    Ok(kilobytes * 1024)
+}
+
+/// sums the size of all files directly inside a directory
+async fn dir_size(path: &str) -> eyre::Result<u64> {
+    let mut entries = tokio::fs::read_dir(path).await?;
+    let mut size = 0;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
@@ -0,0 +1,62 @@
+use tracing::debug;
+
+use crate::xapi::{cli::client::XApiCliClient, VM};
+
+/// other-config key marking the snapshots a job keeps on the host as restore points, the value
+/// is the job's name
+const RETAINED_SNAPSHOT_KEY: &str = "xenbakd-retained";
+
+/// lists the snapshots of the VM kept by the job, oldest first
+async fn list_retained_snapshots(
+    xapi_client: &XApiCliClient,
+    vm: &VM,
+    job_name: &str,
+) -> eyre::Result<Vec<VM>> {
+    let mut snapshots = vec![];
+    for uuid in xapi_client
+        .list_uuids(
+            "snapshot",
+            &[
+                &format!("snapshot-of={}", vm.uuid),
+                &format!("other-config:{}={}", RETAINED_SNAPSHOT_KEY, job_name),
+            ],
+        )
+        .await?
+    {
+        snapshots.push(xapi_client.get_vm_by_uuid(&uuid).await?);
+    }
+    snapshots.sort_by_key(|x| x.snapshot_time);
+
+    Ok(snapshots)
+}
+
+/// keeps the snapshot on the host and deletes the oldest snapshots of the job beyond `keep`.
+/// returns the number of deleted snapshots
+pub async fn retain_snapshot(
+    xapi_client: &XApiCliClient,
+    vm: &VM,
+    job_name: &str,
+    snapshot: &VM,
+    keep: u32,
+) -> eyre::Result<usize> {
+    xapi_client
+        .set_param(
+            "snapshot",
+            &snapshot.uuid,
+            &format!("other-config:{}", RETAINED_SNAPSHOT_KEY),
+            job_name,
+        )
+        .await?;
+
+    let retained = list_retained_snapshots(xapi_client, vm, job_name).await?;
+    let excess = retained.len().saturating_sub(keep as usize);
+    for old in retained.iter().take(excess) {
+        debug!(
+            "Deleting retained snapshot '{}' [{}]",
+            old.name_label, old.uuid
+        );
+        xapi_client.delete_snapshot_by_uuid(&old.uuid).await?;
+    }
+
+    Ok(excess)
+}
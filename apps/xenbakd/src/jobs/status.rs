@@ -0,0 +1,177 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio_cron_scheduler::JobScheduler;
+use tracing::{info, warn};
+
+use crate::{history::HistoryStore, GlobalState};
+
+use super::{pause, JobOutcome};
+
+/// the last finished run of a job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastRun {
+    pub started: chrono::DateTime<chrono::Utc>,
+    pub finished: chrono::DateTime<chrono::Utc>,
+    /// success, warning or failure
+    pub outcome: String,
+}
+
+/// what the daemon knows and plans about a job, see `xenbakd jobs status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub job_type: String,
+    pub schedule: String,
+    pub paused: bool,
+    pub running: bool,
+    /// next time the scheduler fires the job, before its `schedule_jitter`
+    pub next_run: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_run: Option<LastRun>,
+}
+
+/// runtime state of the scheduled jobs, shared by the scheduler and the metrics endpoint
+#[derive(Clone, Default)]
+pub struct JobTracker {
+    /// set once the daemon starts the scheduler, which is asked for the next runs
+    scheduler: Arc<Mutex<Option<JobScheduler>>>,
+    /// ids of the jobs in the scheduler
+    job_ids: Arc<Mutex<BTreeMap<String, uuid::Uuid>>>,
+    last_runs: Arc<Mutex<BTreeMap<String, LastRun>>>,
+}
+
+impl std::fmt::Debug for JobTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JobTracker")
+            .field("job_ids", &self.job_ids)
+            .field("last_runs", &self.last_runs)
+            .finish()
+    }
+}
+
+impl JobTracker {
+    pub fn set_scheduler(&self, scheduler: JobScheduler) {
+        *self.scheduler.lock().unwrap() = Some(scheduler);
+    }
+
+    pub fn add_job(&self, job_name: &str, id: uuid::Uuid) {
+        self.job_ids
+            .lock()
+            .unwrap()
+            .insert(job_name.to_string(), id);
+    }
+
+    pub fn record_run(
+        &self,
+        job_name: &str,
+        started: chrono::DateTime<chrono::Utc>,
+        outcome: JobOutcome,
+    ) {
+        self.last_runs.lock().unwrap().insert(
+            job_name.to_string(),
+            LastRun {
+                started,
+                finished: chrono::Utc::now(),
+                outcome: outcome.to_string(),
+            },
+        );
+    }
+
+    /// takes the last runs from before the start of the daemon from the history
+    pub async fn load_history(&self, history: &HistoryStore) {
+        let entries = match history.read().await {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(
+                    "Failed to read the last runs of the jobs from the history: {}",
+                    e
+                );
+                return;
+            }
+        };
+        let mut last_runs = self.last_runs.lock().unwrap();
+        for entry in entries {
+            last_runs.insert(
+                entry.job_name,
+                LastRun {
+                    started: entry.started,
+                    finished: entry.finished,
+                    outcome: entry.outcome,
+                },
+            );
+        }
+    }
+
+    async fn next_run(&self, job_name: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+        let id = *self.job_ids.lock().unwrap().get(job_name)?;
+        let mut scheduler = self.scheduler.lock().unwrap().clone()?;
+        match scheduler.next_tick_for_job(id).await {
+            Ok(next_run) => next_run,
+            Err(e) => {
+                warn!("Failed to get the next run of job '{}': {}", job_name, e);
+                None
+            }
+        }
+    }
+}
+
+/// the status of every job of the daemon
+pub async fn job_statuses(global_state: &GlobalState) -> Vec<JobStatus> {
+    let running = global_state.shutdown.running_jobs();
+    let mut statuses = vec![];
+    for job in pause::daemon_jobs(global_state) {
+        statuses.push(JobStatus {
+            job_type: job.job_type.to_string(),
+            schedule: job.schedule.clone(),
+            paused: global_state.paused.is_paused(&job.name),
+            running: running.contains(&job.name),
+            next_run: global_state.job_tracker.next_run(&job.name).await,
+            last_run: global_state
+                .job_tracker
+                .last_runs
+                .lock()
+                .unwrap()
+                .get(&job.name)
+                .cloned(),
+            name: job.name,
+        });
+    }
+    statuses
+}
+
+/// logs the status of every job, on SIGUSR1
+pub async fn log_job_statuses(global_state: &GlobalState) {
+    for status in job_statuses(global_state).await {
+        info!("{}", status);
+    }
+}
+
+impl std::fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format_time = |x: Option<chrono::DateTime<chrono::Utc>>| {
+            x.map(|x| x.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "-".into())
+        };
+        let state = match (self.running, self.paused) {
+            (true, _) => "running",
+            (false, true) => "paused",
+            (false, false) => "idle",
+        };
+        write!(
+            f,
+            "{:<24} {:<16} {:<8} next {:<19}  last {:<19} {}",
+            self.name,
+            self.job_type,
+            state,
+            format_time(self.next_run),
+            format_time(self.last_run.as_ref().map(|x| x.started)),
+            self.last_run
+                .as_ref()
+                .map(|x| x.outcome.as_str())
+                .unwrap_or_default()
+        )
+    }
+}
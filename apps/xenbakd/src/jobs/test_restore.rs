@@ -0,0 +1,201 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{TestRestoreConfig, XenConfig},
+    storage::{restore_point::RestorePoint, BackupObjectFilter, StorageHandler},
+    xapi::{cli::client::XApiCliClient, diff_archive, PowerOperation, PowerState, UUID},
+};
+
+use super::JobType;
+
+/// seconds between the checks of a booting test VM
+const BOOT_POLL_INTERVAL: u64 = 5;
+/// seconds to wait for the test VM to power off before it is destroyed
+const SHUTDOWN_TIMEOUT: u64 = 120;
+
+/// outcome of the test restore of a run
+#[derive(Debug, Clone, Serialize)]
+pub struct TestRestoreResult {
+    pub restore_point: String,
+    pub storage: String,
+    pub passed: bool,
+    pub error: Option<String>,
+    pub duration: f64,
+}
+
+/// returns the newest full VM restore point of the job over all its storages
+async fn find_newest(
+    storage_handlers: &[Arc<dyn StorageHandler>],
+) -> eyre::Result<Option<(Arc<dyn StorageHandler>, RestorePoint)>> {
+    let filter = BackupObjectFilter {
+        job_type: Some(vec![JobType::VmBackup]),
+        ..BackupObjectFilter::default()
+    };
+    let mut newest: Option<(Arc<dyn StorageHandler>, RestorePoint)> = None;
+    for storage_handler in storage_handlers {
+        for restore_point in storage_handler.list(filter.clone()).await? {
+            if newest.as_ref().is_some_and(|(_, x)| {
+                x.backup_object.time_stamp >= restore_point.backup_object.time_stamp
+            }) {
+                continue;
+            }
+            newest = Some((storage_handler.clone(), restore_point));
+        }
+    }
+
+    Ok(newest)
+}
+
+/// starts the test VM and waits for it to come up, it fails if it crashes or, with
+/// `guest_agent`, the agent doesn't report in
+async fn boot_check(
+    xapi_client: &XApiCliClient,
+    vm: &UUID,
+    config: &TestRestoreConfig,
+) -> eyre::Result<()> {
+    let vm = xapi_client.get_vm_by_uuid(vm).await?;
+    info!("Booting test VM '{}'", vm.name_label);
+    xapi_client
+        .vm_power_operation(&vm, PowerOperation::Start, config.boot_time)
+        .await?;
+
+    let boot_timer = tokio::time::Instant::now();
+    loop {
+        let power_state = xapi_client.get_vm_power_state(&vm).await?;
+        if power_state != PowerState::Running {
+            return Err(eyre::eyre!("Test VM is {} after its start", power_state));
+        }
+        if config.guest_agent
+            && xapi_client
+                .get_param("vm", &vm.uuid, "PV-drivers-detected")
+                .await?
+                == "true"
+        {
+            debug!("Guest agent of test VM '{}' reported in", vm.name_label);
+            return Ok(());
+        }
+        if boot_timer.elapsed().as_secs() >= config.boot_time {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(BOOT_POLL_INTERVAL)).await;
+    }
+
+    if config.guest_agent {
+        return Err(eyre::eyre!(
+            "Guest agent of the test VM didn't report in within {} seconds",
+            config.boot_time
+        ));
+    }
+
+    Ok(())
+}
+
+/// powers the test VM off and deletes it with its disks
+async fn destroy_test_vm(xapi_client: &XApiCliClient, vm: &UUID) -> eyre::Result<()> {
+    let vm = xapi_client.get_vm_by_uuid(vm).await?;
+    if xapi_client.get_vm_power_state(&vm).await? != PowerState::Halted {
+        xapi_client
+            .vm_power_operation(&vm, PowerOperation::HardShutdown, SHUTDOWN_TIMEOUT)
+            .await?;
+    }
+    xapi_client.vm_uninstall(&vm.uuid).await?;
+
+    Ok(())
+}
+
+/// imports the restore point as a test VM without network, boot checks it and destroys it again
+async fn run_test_restore(
+    xapi_client: &XApiCliClient,
+    storage_handler: &dyn StorageHandler,
+    restore_point: &RestorePoint,
+    config: &TestRestoreConfig,
+) -> eyre::Result<()> {
+    let sr = xapi_client.resolve_uuid("sr", &config.sr).await?;
+    let stream = storage_handler.open_restore_stream(restore_point).await?;
+    let (is_diff_archive, stream) = diff_archive::is_diff_archive(stream).await?;
+    if is_diff_archive {
+        return Err(eyre::eyre!(
+            "{} is a differential restore point, only whole VMs can be test restored",
+            restore_point.id
+        ));
+    }
+
+    let vm = xapi_client.vm_import(stream, Some(&sr), false).await?;
+    info!("Imported {} as test VM {}", restore_point.id, vm);
+
+    let result = async {
+        xapi_client
+            .set_param(
+                "vm",
+                &vm,
+                "name-label",
+                &format!("xenbakd-test-restore-{}", restore_point.id),
+            )
+            .await?;
+        // the test VM must not show up on the network next to the original one
+        for vif in xapi_client
+            .list_uuids("vif", &[&format!("vm-uuid={}", vm)])
+            .await?
+        {
+            xapi_client.destroy("vif", &vif).await?;
+        }
+        boot_check(xapi_client, &vm, config).await
+    }
+    .await;
+
+    debug!("Destroying test VM {}", vm);
+    if let Err(e) = destroy_test_vm(xapi_client, &vm).await {
+        warn!("Failed to destroy test VM {}: {}", vm, e);
+        result?;
+        return Err(e.wrap_err(format!("Test VM {} is left on the host", vm)));
+    }
+
+    result
+}
+
+/// test restores the newest backup of the job, returns `None` if the job has no backup to test
+pub async fn test_restore(
+    xen: &[XenConfig],
+    storage_handlers: &[Arc<dyn StorageHandler>],
+    config: &TestRestoreConfig,
+) -> eyre::Result<Option<TestRestoreResult>> {
+    let Some((storage_handler, restore_point)) = find_newest(storage_handlers).await? else {
+        return Ok(None);
+    };
+    let xen_host = config
+        .xen_host
+        .as_ref()
+        .unwrap_or(&restore_point.backup_object.xen_host);
+    let xen_config = xen
+        .iter()
+        .find(|x| &x.name == xen_host)
+        .ok_or_else(|| eyre::eyre!("Xen host '{}' not found in config", xen_host))?;
+    let xapi_client = XApiCliClient::new(xen_config.clone());
+
+    info!(
+        "Test restoring {} from storage '{}' on xen host '{}'",
+        restore_point.id,
+        storage_handler.get_name(),
+        xen_host
+    );
+    let timer = tokio::time::Instant::now();
+    let result = run_test_restore(
+        &xapi_client,
+        storage_handler.as_ref(),
+        &restore_point,
+        config,
+    )
+    .await;
+    xapi_client.logout().await;
+
+    Ok(Some(TestRestoreResult {
+        restore_point: restore_point.id,
+        storage: storage_handler.get_name(),
+        passed: result.is_ok(),
+        error: result.err().map(|e| format!("{:#}", e)),
+        duration: timer.elapsed().as_secs_f64(),
+    }))
+}
@@ -0,0 +1,341 @@
+use std::sync::Arc;
+
+use eyre::WrapErr;
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::{
+    config::JobConfig,
+    jobs::XenbakJobStats,
+    storage::{self, manifest::ConfigSnapshot, progress::ExportSummary, StorageHandler},
+    xapi::{
+        cli::client::XApiCliClient,
+        diff_archive::{self, DiffDisk},
+        error::XApiCliError,
+        parse_timestamp, preflight, UUID,
+    },
+    GlobalState,
+};
+
+use super::{JobType, XenbakJob};
+
+/// backs up a single VDI to all storages of the job, from a temporary snapshot of the VDI.
+/// returns the exports to all storages
+async fn backup_vdi(
+    xapi_client: XApiCliClient,
+    vdi: UUID,
+    storage_handlers: Vec<Arc<dyn StorageHandler>>,
+    job_config: JobConfig,
+) -> eyre::Result<ExportSummary> {
+    let vdi_timer = tokio::time::Instant::now();
+    let name_label = xapi_client.get_param("vdi", &vdi, "name-label").await?;
+    info!("Starting backup of VDI '{}' [{}]", name_label, vdi);
+
+    let sr_uuid = xapi_client.get_param("vdi", &vdi, "sr-uuid").await?;
+    let sr_name = xapi_client.get_param("sr", &sr_uuid, "name-label").await?;
+    let virtual_size: u64 = xapi_client
+        .get_param("vdi", &vdi, "virtual-size")
+        .await?
+        .parse()?;
+    // the export holds the used blocks of the VDI
+    let estimated_size = xapi_client
+        .get_param("vdi", &vdi, "physical-utilisation")
+        .await
+        .ok()
+        .and_then(|x| x.parse().ok());
+
+    debug!("Creating snapshot");
+    let snapshot = xapi_client.vdi_snapshot(&vdi).await?;
+
+    let backup_result = async {
+        let mut export = ExportSummary::default();
+        let time_stamp = parse_timestamp(
+            &xapi_client
+                .get_param("vdi", &snapshot, "snapshot-time")
+                .await?,
+        )?;
+
+        for storage_handler in storage_handlers {
+            // VDI backups are identified by the VDI's uuid and name-label
+            let backup_object = storage::BackupObject {
+                pool: xapi_client.get_config().pool.clone(),
+                ..storage::BackupObject::new(
+                    JobType::VdiBackup,
+                    Some(vdi.clone()),
+                    name_label.clone(),
+                    xapi_client.get_config().name.clone(),
+                    time_stamp,
+                    None,
+                )
+            };
+
+            // the VDI is stored as an archive with a single disk, restored like differential backups
+            info!(
+                "Exporting VDI to storage '{}'...",
+                storage_handler.get_name()
+            );
+            let disk = DiffDisk {
+                userdevice: "0".to_string(),
+                vdi_uuid: snapshot.clone(),
+                base_vdi_uuid: None,
+                name_label: name_label.clone(),
+                virtual_size,
+                sr_uuid: sr_uuid.clone(),
+                sr_name: sr_name.clone(),
+            };
+            let (stdout, stderr) = diff_archive::write_archive(xapi_client.clone(), vec![disk]);
+            let summary = xapi_client
+                .handle_export_stream(
+                    storage_handler.clone(),
+                    backup_object.clone(),
+                    stdout,
+                    stderr,
+                    estimated_size,
+                )
+                .await?;
+            export.add(summary);
+
+            if job_config.verify {
+                info!("Verifying backup...");
+                let restore_point = storage_handler.get_restore_point(&backup_object).await?;
+                storage_handler.verify(&restore_point).await?;
+            }
+
+            debug!("Rotating backups");
+            let dry_run = job_config.rotate_dry_run.into();
+            let deleted = storage_handler
+                .rotate(
+                    storage::BackupObjectFilter::from_backup_object(backup_object),
+                    None,
+                    dry_run,
+                )
+                .await?;
+            storage::log_rotation(&storage_handler.get_name(), &deleted, dry_run);
+        }
+
+        Ok::<ExportSummary, eyre::Error>(export)
+    }
+    .await;
+
+    debug!("Deleting snapshot...");
+    xapi_client.destroy("vdi", &snapshot).await?;
+
+    let export =
+        backup_result.wrap_err(format!("Backup of VDI '{}' [{}] failed", name_label, vdi))?;
+
+    info!(
+        "Finished backup of VDI '{}' [{}] in {} seconds",
+        name_label,
+        vdi,
+        vdi_timer.elapsed().as_secs_f64()
+    );
+
+    Ok(export)
+}
+
+/// backs up single VDIs instead of whole VMs, e.g. data disks which need another schedule or
+/// retention than the rest of the VM
+#[derive(Clone, Debug)]
+pub struct VdiBackupJob {
+    pub job_type: JobType,
+    pub job_config: JobConfig,
+    pub job_stats: XenbakJobStats,
+    pub global_state: Arc<GlobalState>,
+}
+
+#[async_trait::async_trait]
+impl XenbakJob for VdiBackupJob {
+    fn new(global_state: Arc<GlobalState>, job_config: JobConfig) -> VdiBackupJob {
+        VdiBackupJob {
+            job_type: JobType::VdiBackup,
+            global_state,
+            job_config,
+            job_stats: XenbakJobStats::default(),
+        }
+    }
+
+    fn get_name(&self) -> String {
+        self.job_config.name.clone()
+    }
+
+    fn get_job_type(&self) -> JobType {
+        self.job_type.clone()
+    }
+
+    fn get_schedule(&self) -> String {
+        self.job_config.schedule.clone()
+    }
+
+    fn get_job_stats(&self) -> XenbakJobStats {
+        self.job_stats.clone()
+    }
+
+    async fn run(&mut self) -> eyre::Result<()> {
+        let job_timer = tokio::time::Instant::now();
+
+        info!("Running VDI backup job '{}'", self.job_config.name);
+
+        self.job_stats = XenbakJobStats {
+            config: self.job_config.clone(),
+            ..XenbakJobStats::default()
+        };
+        for storage_name in &self.job_config.storages {
+            if let Some(snapshot) = self.global_state.config.storage.snapshot(storage_name) {
+                self.job_stats
+                    .storage_configs
+                    .insert(storage_name.clone(), snapshot);
+            }
+        }
+        debug!(
+            "Effective configuration of job '{}': {}",
+            self.job_config.name,
+            serde_json::to_string(&ConfigSnapshot::new(
+                &self.job_config,
+                &self.job_stats.storage_configs
+            ))?
+        );
+
+        if self.job_config.differential.is_some() {
+            warn!(
+                "Differential backups are only supported by VM jobs, job '{}' exports its VDIs in full",
+                self.job_config.name
+            );
+        }
+
+        let xen_configs = self
+            .job_config
+            .get_xen_configs(self.global_state.config.xen.clone());
+        self.job_stats.record_pools(&xen_configs);
+        let xapi_clients: Vec<XApiCliClient> = xen_configs
+            .iter()
+            .map(|x| XApiCliClient::new(x.clone()))
+            .collect();
+
+        // skip unreachable hosts instead of failing halfway through the run
+        let (xapi_clients, host_status) = preflight::check_hosts(xapi_clients).await;
+        self.job_stats.record_host_status(host_status);
+
+        // select the VDIs by tag and SR
+        let mut queue: Vec<(XApiCliClient, UUID)> = vec![];
+        for client in &xapi_clients {
+            // logs the host's version once
+            client.get_version().await;
+            let mut srs = vec![];
+            for sr in &self.job_config.sr_filter {
+                srs.push(client.resolve_uuid("sr", sr).await?);
+            }
+            let vdis = client
+                .filter_vdis(
+                    &self.job_config.tag_filter,
+                    &self.job_config.tag_filter_exclude,
+                    &srs,
+                )
+                .await?;
+            queue.extend(vdis.into_iter().map(|x| (client.clone(), x)));
+        }
+
+        self.job_stats.total_objects = queue.len() as u32;
+        debug!(
+            "{} objects affected by backup job",
+            self.job_stats.total_objects
+        );
+        if queue.is_empty() {
+            warn!("No VDIs found for backup job '{}'", self.job_config.name);
+        }
+
+        let storage_handlers = self
+            .job_config
+            .get_storages(self.global_state.config.storage.clone());
+        for storage_handler in storage_handlers.clone() {
+            debug!(
+                "Initializing storage handler '{}'",
+                storage_handler.get_job_config().name
+            );
+            storage_handler.initialize().await?;
+        }
+
+        // fail before any VDI is snapshotted if a storage is unreachable or read-only
+        let probe_errors = storage::probe::probe_storages(&storage_handlers).await;
+        if !probe_errors.is_empty() {
+            self.job_stats.failed_objects = self.job_stats.total_objects;
+            self.job_stats.errors.extend(probe_errors);
+            self.job_stats.duration = job_timer.elapsed().as_secs_f64();
+            return Err(eyre::eyre!("Storage health check failed."));
+        }
+
+        storage::orphans::cleanup_storages(&storage_handlers).await;
+
+        let permits = Arc::new(tokio::sync::Semaphore::new(
+            self.job_config.concurrency as usize,
+        ));
+        let mut handles = vec![];
+        for (xapi_client, vdi) in queue {
+            let span = tracing::span!(
+                tracing::Level::INFO,
+                "VdiBackupJob::run::backup_vdi",
+                vdi.uuid = vdi.clone(),
+                xen.host = xapi_client.get_config().name.clone()
+            );
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            let global_permit = self
+                .global_state
+                .concurrency
+                .acquire(self.job_config.priority)
+                .await;
+            let storage_handlers = storage_handlers.clone();
+            let job_config = self.job_config.clone();
+
+            handles.push(tokio::spawn(
+                async move {
+                    let _permit = permit;
+                    let _global_permit = global_permit;
+                    backup_vdi(xapi_client, vdi, storage_handlers, job_config).await
+                }
+                .instrument(span),
+            ));
+        }
+
+        for handle in handles {
+            match handle.await? {
+                Ok(export) => {
+                    self.job_stats.successful_objects += 1;
+                    self.job_stats.exported_bytes += export.bytes;
+                    self.job_stats.export_duration += export.duration;
+                }
+                Err(e) => {
+                    self.job_stats.failed_objects += 1;
+                    self.job_stats.errors.push(
+                        e.chain()
+                            .map(|e| e.to_string())
+                            .collect::<Vec<String>>()
+                            .join("\n"),
+                    );
+                    if let Some(kind) = e
+                        .chain()
+                        .find_map(|x| x.downcast_ref::<XApiCliError>())
+                        .and_then(|x| x.kind())
+                    {
+                        self.job_stats.add_error_kind(kind);
+                    }
+                    error!("{:?}", e);
+                }
+            }
+        }
+
+        for client in &xapi_clients {
+            client.logout().await;
+        }
+
+        self.job_stats.duration = job_timer.elapsed().as_secs_f64();
+
+        if !self.job_stats.within_failure_threshold() {
+            return Err(eyre::eyre!("Backup job failed."));
+        }
+
+        info!(
+            "Finished VDI backup job with name '{}' in {} seconds",
+            self.job_config.name, self.job_stats.duration
+        );
+
+        Ok(())
+    }
+}
@@ -1,20 +1,510 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
 
 use tracing::{debug, error, info, warn, Instrument};
 
 use crate::{
     config::JobConfig,
-    jobs::XenbakJobStats,
-    storage,
+    jobs::{ObjectResult, ObjectStatus, XenbakJobStats},
+    shutdown::ShutdownError,
+    storage::{self, manifest::ConfigSnapshot, progress::ExportSummary, DryRun, StorageHandler},
     xapi::{
         cli::client::XApiCliClient,
         error::{XApiCliError, XApiParseError},
-        SnapshotType, VM,
+        preflight,
+        version::HostFeature,
+        PowerState, SnapshotType, VmSizeEstimate, UUID, VM,
     },
     GlobalState,
 };
 
-use super::{JobType, XenbakJob};
+use super::{
+    budget::{self, RunFreeSpace},
+    cold_backup::ColdBackup,
+    deadline::{self, Deadline, JobTimeoutError, TemporarySnapshots},
+    differential, excluded_disks,
+    guest_hooks::{GuestHookStage, GuestHooks},
+    guest_quiesce::GuestQuiesce,
+    reclaim::{self, PendingReclaim},
+    retained_snapshots, test_restore, window, JobType, XenbakJob,
+};
+
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// creates a new snapshot of the VM, powering it down or freezing the guest beforehand if
+/// configured
+pub(super) async fn create_snapshot(
+    xapi_client: &XApiCliClient,
+    vm: &VM,
+    job_config: &JobConfig,
+    clock_skew_threshold: i64,
+) -> eyre::Result<VM> {
+    // the guest has to be up for its hooks, so they run before it is powered down
+    GuestHooks::new(job_config, vm)
+        .run(GuestHookStage::PreSnapshot)
+        .await?;
+
+    let cold_backup = match &job_config.cold_backup {
+        Some(cold_backup_config) => {
+            let cold_backup = ColdBackup::new(cold_backup_config, xapi_client, vm);
+            cold_backup.applies().await?.then_some(cold_backup)
+        }
+        None => None,
+    };
+
+    // only running VMs have memory to checkpoint
+    let snapshot_type = match job_config.snapshot_type {
+        SnapshotType::Memory if cold_backup.is_some() => {
+            debug!(
+                "VM '{}' is powered down for its snapshot, creating a basic snapshot instead of a memory one",
+                vm.name_label
+            );
+            SnapshotType::Normal
+        }
+        SnapshotType::Memory
+            if xapi_client.get_vm_power_state(vm).await? != PowerState::Running =>
+        {
+            debug!(
+                "VM '{}' isn't running, creating a basic snapshot instead of a memory one",
+                vm.name_label
+            );
+            SnapshotType::Normal
+        }
+        ref snapshot_type => snapshot_type.clone(),
+    };
+    let snapshot = async {
+        xapi_client
+            .snapshot(vm, snapshot_type)
+            .await
+            .map_err(eyre::Error::from)
+    };
+
+    // a powered down guest has nothing to freeze
+    let started = chrono::Utc::now();
+    let snapshot = match (cold_backup, job_config.get_guest_quiesce(&vm.name_label)) {
+        (Some(cold_backup), _) => cold_backup.run_powered_off(snapshot).await,
+        (None, Some(quiesce_config)) => {
+            GuestQuiesce::new(quiesce_config, vm)
+                .run_frozen(snapshot)
+                .await
+        }
+        (None, None) => snapshot.await,
+    }?;
+    let finished = chrono::Utc::now();
+
+    // the snapshot time is set by the host, so it has to lie within the local time range
+    // the snapshot was created in
+    let skew = if snapshot.snapshot_time < started {
+        -(started - snapshot.snapshot_time).num_seconds()
+    } else if snapshot.snapshot_time > finished {
+        (snapshot.snapshot_time - finished).num_seconds()
+    } else {
+        0
+    };
+    warn_clock_skew(xapi_client, skew, clock_skew_threshold);
+
+    Ok(snapshot)
+}
+
+/// warns if the clock of the host differs from the local one by more than the threshold
+fn warn_clock_skew(xapi_client: &XApiCliClient, skew: i64, clock_skew_threshold: i64) {
+    if skew.abs() <= clock_skew_threshold {
+        return;
+    }
+
+    warn!(
+        "Clock of host '{}' is about {}s {} the local clock, snapshot ages and backup timestamps will be off",
+        xapi_client.get_config().name,
+        skew.abs(),
+        if skew < 0 { "behind" } else { "ahead of" }
+    );
+}
+
+/// outcome of the backup of a single VM
+enum VmBackupOutcome {
+    Done {
+        /// restore points deleted early to stay within a storage quota
+        quota_rotated: Vec<String>,
+        /// SRs which should reclaim the space of the deleted snapshot
+        pending_reclaim: Vec<PendingReclaim>,
+        /// set if the disks didn't coalesce within `coalesce_timeout` after deleting the snapshot
+        coalesce_warning: Option<String>,
+        /// set if a post-export guest hook failed, the backup itself is fine
+        guest_hook_warning: Option<String>,
+        /// exports to all storages
+        export: ExportSummary,
+        /// storages the VM was exported to
+        storages: Vec<String>,
+        /// bytes of the new restore points on the storages
+        stored_bytes: u64,
+    },
+    /// the VM was busy, its backup is retried at the end of the run
+    Deferred(String),
+    /// the backup window of the job closed before the VM's backup could start
+    WindowClosed,
+    /// the daemon is shutting down, the VM's backup wasn't started
+    ShuttingDown,
+}
+
+/// backs up a single VM to all storages of the job
+#[allow(clippy::too_many_arguments)]
+async fn backup_vm(
+    xapi_client: XApiCliClient,
+    vm: VM,
+    storage_handlers: Vec<Arc<dyn StorageHandler>>,
+    job_type: JobType,
+    job_config: JobConfig,
+    clock_skew_threshold: i64,
+    size_estimate: Option<VmSizeEstimate>,
+    temporary_snapshots: TemporarySnapshots,
+    free_space: RunFreeSpace,
+) -> eyre::Result<VmBackupOutcome> {
+    let vm_timer = tokio::time::Instant::now();
+    info!("Starting backup of VM '{}' [{}]", vm.name_label, vm.uuid);
+    let vm_override = job_config.get_vm_override(&vm);
+    let job_config = vm_override.apply(&job_config);
+    let storage_handlers = match vm_override.skip_export {
+        true => {
+            debug!(
+                "Export of VM '{}' is skipped, only keeping its snapshot",
+                vm.name_label
+            );
+            vec![]
+        }
+        false => storage_handlers,
+    };
+    let disk_usage = size_estimate.map(|x| x.physical_utilisation);
+
+    // leave VMs alone which are busy with another operation, e.g. a migration or a
+    // snapshot by another tool
+    let current_operations = xapi_client.get_vm_current_operations(&vm).await?;
+    if !current_operations.is_empty() {
+        let reason = format!(
+            "VM '{}' [{}] is busy ({})",
+            vm.name_label,
+            vm.uuid,
+            current_operations.join(", ")
+        );
+        info!("{}, deferring its backup", reason);
+        return Ok(VmBackupOutcome::Deferred(reason));
+    }
+
+    // another snapshot on top of a pending coalesce grows the VHD chain and needs the space of
+    // the whole disk again, on a busy SR this can fill it up
+    if job_config.coalesce_guard {
+        match xapi_client.get_vm_coalescing_vdis(&vm).await {
+            Ok(vdis) if !vdis.is_empty() => {
+                let reason = format!(
+                    "VM '{}' [{}] waits for the coalesce of its disks ({})",
+                    vm.name_label,
+                    vm.uuid,
+                    vdis.join(", ")
+                );
+                info!("{}, deferring its backup", reason);
+                return Ok(VmBackupOutcome::Deferred(reason));
+            }
+            Ok(_) => {}
+            Err(e) => warn!(
+                "Failed to check the coalesce of VM '{}': {}",
+                vm.name_label, e
+            ),
+        }
+    }
+
+    // make room within the storage quotas, this may free space for the check below
+    let quota_rotated = budget::enforce_quotas(
+        &xapi_client,
+        &vm,
+        disk_usage,
+        &storage_handlers,
+        &job_config,
+    )
+    .await?;
+
+    // fail fast if a storage can't hold the export next to the other exports of the run
+    let _space_reservation = match disk_usage {
+        Some(disk_usage) => Some(free_space.reserve(&vm, disk_usage, &storage_handlers)?),
+        None => None,
+    };
+
+    // differential backups are based on the snapshot kept by the previous run
+    let base_snapshot = match job_config.differential {
+        Some(_) => {
+            xapi_client
+                .require(HostFeature::ChangedBlockTracking)
+                .await?;
+            differential::find_base_snapshot(&xapi_client, &vm, &job_config.name).await?
+        }
+        None => None,
+    };
+
+    // check if xenbakd should try to create a backup from an already-existing
+    // snapshot - otherwise create a temporary new one. differential backups need their own
+    let mut is_xenbakd_snapshot = true;
+    let snapshot: VM = match job_config.use_existing_snapshot && job_config.differential.is_none() {
+        true => {
+            // get all existing snapshots for the given VM
+            let existing_snapshots = xapi_client.get_snapshots(&vm).await;
+
+            // no snapshots? damn. create a new one.
+            if existing_snapshots.as_ref().is_err_and(|e| {
+                matches!(e, XApiCliError::XApiParseError(XApiParseError::EmptyOutput))
+            }) {
+                debug!("No recent snapshot found, creating new one");
+                create_snapshot(&xapi_client, &vm, &job_config, clock_skew_threshold).await?
+            } else {
+                let mut existing_snapshots = existing_snapshots?;
+                // sort existing snapshots by snapshot time and get the most recent
+                existing_snapshots.sort_by(|a, b| {
+                    a.snapshot_time
+                        .timestamp()
+                        .partial_cmp(&b.snapshot_time.timestamp())
+                        .unwrap()
+                });
+                let newest_snapshot = existing_snapshots.last().unwrap();
+
+                // calculate snapshot age
+                let now = chrono::Utc::now();
+                let age_limit = job_config.use_existing_snapshot_age.unwrap_or(3600);
+                let snapshot_age = now - newest_snapshot.snapshot_time;
+
+                // a snapshot from the future means the host's clock is ahead
+                if snapshot_age.num_seconds() < 0 {
+                    warn_clock_skew(
+                        &xapi_client,
+                        -snapshot_age.num_seconds(),
+                        clock_skew_threshold,
+                    );
+                }
+
+                // check if the snapshot is within age limit
+                if snapshot_age.num_seconds() < age_limit {
+                    is_xenbakd_snapshot = false;
+                    newest_snapshot.clone()
+                } else {
+                    debug!(
+                        "Newest existing snapshot is older than {} seconds",
+                        age_limit
+                    );
+                    debug!("Creating new snapshot");
+                    create_snapshot(&xapi_client, &vm, &job_config, clock_skew_threshold).await?
+                }
+            }
+        }
+        false => {
+            debug!("Creating new snapshot");
+            create_snapshot(&xapi_client, &vm, &job_config, clock_skew_threshold).await?
+        }
+    };
+
+    if is_xenbakd_snapshot {
+        temporary_snapshots.add(&xapi_client, &snapshot.uuid);
+    }
+
+    let backup_result = async {
+        let mut export = ExportSummary::default();
+        let mut storages = vec![];
+        let mut stored_bytes = 0;
+        // set is-a-template to false
+        debug!("Setting is-a-template to false...");
+        let mut snapshot = xapi_client
+            .set_snapshot_param_not_template(&snapshot)
+            .await?;
+
+        // set snapshot name to a more readable format
+        if is_xenbakd_snapshot {
+            snapshot = xapi_client
+                .set_snapshot_name(
+                    &snapshot,
+                    format!("{}__{}", vm.name_label, snapshot.snapshot_time).as_str(),
+                )
+                .await?;
+        }
+
+        // existing snapshots aren't ours to modify, they are exported with all their disks
+        if is_xenbakd_snapshot {
+            excluded_disks::remove_excluded_disks(&xapi_client, &vm, &snapshot, &job_config)
+                .await?;
+        } else if !job_config.vdi_exclude.is_empty()
+            || !job_config.vdi_tag_filter_exclude.is_empty()
+        {
+            warn!(
+                "Exporting existing snapshot of VM '{}' including its excluded disks",
+                vm.name_label
+            );
+        }
+
+        // iterate through enabled local storages, export snapshost for each storage and rotate/cleanup backups
+        for storage_handler in storage_handlers {
+            // create the backup object
+            let backup_object = storage::BackupObject {
+                pool: xapi_client.get_config().pool.clone(),
+                ..storage::BackupObject::new(
+                    job_type.clone(),
+                    Some(vm.uuid.clone()),
+                    vm.name_label.clone(),
+                    xapi_client.get_config().name.clone(),
+                    snapshot.snapshot_time,
+                    None,
+                )
+            };
+
+            // export the snaphhot using the current storage handler
+            let backup_object = match &job_config.differential {
+                Some(differential) if storage_handler.supports_chains() => {
+                    let (backup_object, summary) = differential::export_to_storage(
+                        &xapi_client,
+                        &snapshot,
+                        base_snapshot.as_ref(),
+                        storage_handler.clone(),
+                        backup_object,
+                        differential,
+                    )
+                    .await?;
+                    export.add(summary);
+                    backup_object
+                }
+                differential => {
+                    if differential.is_some() {
+                        info!(
+                            "Storage '{}' can't keep differential chains, exporting the whole VM",
+                            storage_handler.get_name()
+                        );
+                    }
+                    info!("Exporting VM to storage handler...",);
+                    let summary = xapi_client
+                        .vm_export_to_storage(
+                            &snapshot,
+                            storage_handler.clone(),
+                            backup_object.clone(),
+                            disk_usage,
+                            job_config.export_compression,
+                        )
+                        .await?;
+                    export.add(summary);
+                    backup_object
+                }
+            };
+
+            // re-read the backup and validate its checksum
+            if job_config.verify {
+                info!("Verifying backup...");
+                let restore_point = storage_handler.get_restore_point(&backup_object).await?;
+                storage_handler.verify(&restore_point).await?;
+            }
+
+            // size of the backup after compression, the export stream is the uncompressed XVA
+            match storage_handler.get_restore_point(&backup_object).await {
+                Ok(restore_point) => {
+                    stored_bytes += restore_point.size();
+                    free_space.consume(&storage_handler.get_name(), restore_point.size());
+                }
+                Err(e) => debug!(
+                    "Failed to get the size of the backup on storage '{}': {}",
+                    storage_handler.get_name(),
+                    e
+                ),
+            }
+            storages.push(storage_handler.get_name());
+
+            // rotate backups
+            debug!("Rotating backups");
+            let backup_object_filter =
+                storage::BackupObjectFilter::from_backup_object(backup_object.clone());
+            let dry_run = job_config.rotate_dry_run.into();
+            let deleted = storage_handler
+                .rotate(backup_object_filter, vm_override.retention, dry_run)
+                .await?;
+            storage::log_rotation(&storage_handler.get_name(), &deleted, dry_run);
+        }
+
+        Ok::<_, eyre::Error>((export, storages, stored_bytes))
+    }
+    .await;
+
+    // the pre-snapshot hooks only ran for our own snapshots
+    let mut guest_hook_warning = None;
+    if is_xenbakd_snapshot {
+        if let Err(e) = GuestHooks::new(&job_config, &vm)
+            .run(GuestHookStage::PostExport)
+            .await
+        {
+            guest_hook_warning = Some(format!("{:#}", e));
+        }
+    }
+
+    let mut pending_reclaim = vec![];
+    let mut coalesce_warning = None;
+    if is_xenbakd_snapshot {
+        if job_config.reclaim_timeout.is_some() {
+            pending_reclaim = reclaim::record_utilisation(&xapi_client, &vm).await;
+        }
+        if job_config.differential.is_some() && backup_result.is_ok() {
+            debug!("Keeping snapshot as base of the next differential backup");
+            differential::replace_base_snapshot(&xapi_client, &vm, &job_config.name, &snapshot)
+                .await?;
+            temporary_snapshots.remove(&snapshot.uuid);
+        } else if job_config.keep_snapshots > 0 && backup_result.is_ok() {
+            debug!("Keeping snapshot on the host as restore point");
+            let deleted = retained_snapshots::retain_snapshot(
+                &xapi_client,
+                &vm,
+                &job_config.name,
+                &snapshot,
+                job_config.keep_snapshots,
+            )
+            .await?;
+            temporary_snapshots.remove(&snapshot.uuid);
+            // the space of the new snapshot isn't freed, so there is only something to reclaim if
+            // an old one was deleted
+            if deleted == 0 {
+                pending_reclaim.clear();
+            }
+            if let Some(coalesce_timeout) = job_config.coalesce_timeout.filter(|_| deleted > 0) {
+                coalesce_warning =
+                    reclaim::await_coalesce(&xapi_client, &vm, coalesce_timeout).await;
+            }
+        } else {
+            debug!("Deleting snapshot...");
+            xapi_client.delete_snapshot_by_uuid(&snapshot.uuid).await?;
+            temporary_snapshots.remove(&snapshot.uuid);
+            if let Some(coalesce_timeout) = job_config.coalesce_timeout {
+                coalesce_warning =
+                    reclaim::await_coalesce(&xapi_client, &vm, coalesce_timeout).await;
+            }
+        }
+    }
+
+    // propagate any errors that occurred during backup
+    let (export, storages, stored_bytes) = backup_result.map_err(|e| {
+        e.wrap_err(format!(
+            "Backup of VM '{}' [{}] failed",
+            vm.name_label, vm.uuid
+        ))
+    })?;
+
+    // get the elapsed time and log it
+    let elapsed = vm_timer.elapsed().as_secs_f64();
+    info!(
+        "Finished backup of VM '{}' [{}] in {} seconds",
+        vm.name_label, vm.uuid, elapsed
+    );
+
+    Ok(VmBackupOutcome::Done {
+        quota_rotated,
+        pending_reclaim,
+        coalesce_warning,
+        guest_hook_warning,
+        export,
+        storages,
+        stored_bytes,
+    })
+}
 
 #[derive(Clone, Debug)]
 pub struct VmBackupJob {
@@ -22,6 +512,271 @@ pub struct VmBackupJob {
     pub job_config: JobConfig,
     pub job_stats: XenbakJobStats,
     pub global_state: Arc<GlobalState>,
+    /// runs since the start of xenbakd, shared by the clones the scheduler runs
+    runs: Arc<AtomicU32>,
+    /// uuids of the VMs backed up by the interrupted run this one resumes, they are left out
+    pub completed_vms: HashSet<UUID>,
+}
+
+impl VmBackupJob {
+    /// records the power state of the VMs in the stats and keeps those matching the
+    /// `power_state_filter` of the job
+    async fn filter_vms_by_power_state(
+        &mut self,
+        xapi_client: &XApiCliClient,
+        vms: Vec<VM>,
+    ) -> eyre::Result<Vec<VM>> {
+        let mut filtered_vms = vec![];
+        for vm in vms {
+            let power_state = match vm.power_state {
+                Some(power_state) => power_state,
+                None => xapi_client.get_vm_power_state(&vm).await?,
+            };
+            if !self.job_config.power_state_filter.is_empty()
+                && !self.job_config.power_state_filter.contains(&power_state)
+            {
+                debug!(
+                    "Skipping VM '{}' [{}], it is {}",
+                    vm.name_label, vm.uuid, power_state
+                );
+                continue;
+            }
+            self.job_stats
+                .power_states
+                .insert(format!("{} [{}]", vm.name_label, vm.uuid), power_state);
+            filtered_vms.push(vm);
+        }
+
+        Ok(filtered_vms)
+    }
+
+    /// records what the run would do with every VM, without touching it, and simulates the
+    /// rotation of its backups
+    #[allow(clippy::mutable_key_type)]
+    async fn dry_run(
+        &mut self,
+        vms: &HashMap<XApiCliClient, Vec<VM>>,
+        storage_handlers: &[Arc<dyn StorageHandler>],
+    ) {
+        for (xapi_client, vms) in vms {
+            let xen_host = xapi_client.get_config().name.clone();
+            for vm in vms {
+                let vm_override = self.job_config.get_vm_override(vm);
+                let job_config = vm_override.apply(&self.job_config);
+                let action = match vm_override.skip_export {
+                    true => format!(
+                        "Would create a {} snapshot of VM '{}' [{}] on xen host '{}' and keep it without exporting it",
+                        job_config.snapshot_type, vm.name_label, vm.uuid, xen_host
+                    ),
+                    false => format!(
+                        "Would back up VM '{}' [{}] on xen host '{}' with a {} snapshot{} to storages {}",
+                        vm.name_label,
+                        vm.uuid,
+                        xen_host,
+                        job_config.snapshot_type,
+                        match job_config.differential {
+                            Some(_) => " as differential export",
+                            None => "",
+                        },
+                        storage_handlers
+                            .iter()
+                            .map(|x| x.get_name())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                };
+                info!("{}", action);
+                self.job_stats.dry_run_actions.push(action);
+                if vm_override.skip_export {
+                    continue;
+                }
+
+                // the rotation reports the restore points it would delete
+                let filter = storage::BackupObjectFilter {
+                    job_type: Some(vec![self.job_type.clone()]),
+                    xen_host: Some(vec![xen_host.clone()]),
+                    vm_name: Some(vec![vm.name_label.clone()]),
+                    vm_uuid: Some(vec![vm.uuid.clone()]),
+                    pool: None,
+                    time_stamp: None,
+                };
+                for storage_handler in storage_handlers {
+                    match storage_handler
+                        .rotate(filter.clone(), vm_override.retention, DryRun::Enabled)
+                        .await
+                    {
+                        Ok(deleted) if deleted.is_empty() => {}
+                        Ok(deleted) => {
+                            let action = format!(
+                                "Would delete restore points {} of VM '{}' on storage '{}'",
+                                deleted.join(", "),
+                                vm.name_label,
+                                storage_handler.get_name()
+                            );
+                            info!("{}", action);
+                            self.job_stats.dry_run_actions.push(action);
+                        }
+                        Err(e) => warn!(
+                            "Failed to simulate rotation of VM '{}' on storage '{}': {}",
+                            vm.name_label,
+                            storage_handler.get_name(),
+                            e
+                        ),
+                    }
+                }
+            }
+        }
+    }
+
+    /// skips the VMs with a `schedule` override which didn't fire since their newest backup on
+    /// the job's storages
+    #[allow(clippy::mutable_key_type)]
+    async fn filter_vms_by_schedule(
+        &mut self,
+        vms: &mut HashMap<XApiCliClient, Vec<VM>>,
+        storage_handlers: &[Arc<dyn StorageHandler>],
+    ) {
+        let now = chrono::Utc::now();
+        for (xapi_client, vms) in vms.iter_mut() {
+            let mut due = vec![];
+            for vm in std::mem::take(vms) {
+                let Some(schedule) = self.job_config.get_vm_override(&vm).schedule else {
+                    due.push(vm);
+                    continue;
+                };
+
+                let filter = storage::BackupObjectFilter {
+                    job_type: Some(vec![self.job_type.clone()]),
+                    vm_name: Some(vec![vm.name_label.clone()]),
+                    vm_uuid: Some(vec![vm.uuid.clone()]),
+                    ..storage::BackupObjectFilter::default()
+                };
+                let mut last_backup = None;
+                for storage_handler in storage_handlers {
+                    match storage_handler.list(filter.clone()).await {
+                        Ok(restore_points) => {
+                            last_backup = restore_points
+                                .iter()
+                                .map(|x| x.backup_object.time_stamp)
+                                .chain(last_backup)
+                                .max()
+                        }
+                        Err(e) => warn!(
+                            "Failed to list backups of VM '{}' on storage '{}': {}",
+                            vm.name_label,
+                            storage_handler.get_name(),
+                            e
+                        ),
+                    }
+                }
+                let Some(last_backup) = last_backup else {
+                    due.push(vm);
+                    continue;
+                };
+
+                match window::next_scheduled(&schedule, last_backup) {
+                    Ok(Some(next)) if next > now => {
+                        info!(
+                            "Skipping VM '{}' [{}], it isn't due until {} by its schedule '{}'",
+                            vm.name_label, vm.uuid, next, schedule
+                        );
+                        self.job_stats.object_results.push(ObjectResult {
+                            name: vm.name_label.clone(),
+                            uuid: vm.uuid.clone(),
+                            xen_host: xapi_client.get_config().name.clone(),
+                            status: ObjectStatus::Skipped,
+                            duration: 0.0,
+                            exported_bytes: 0,
+                            stored_bytes: 0,
+                            storages: vec![],
+                            error: Some(format!("Not due until {}", next)),
+                        });
+                        self.job_stats.skipped_objects += 1;
+                    }
+                    Ok(_) => due.push(vm),
+                    Err(e) => {
+                        warn!(
+                            "Backing up VM '{}' regardless of its schedule: {}",
+                            vm.name_label, e
+                        );
+                        due.push(vm);
+                    }
+                }
+            }
+            *vms = due;
+        }
+    }
+
+    /// counts the failures in a row of the VM, for jobs with a `quarantine_after`
+    async fn record_quarantine(&mut self, vm: &VM, error: Option<&str>) {
+        let Some(quarantine_after) = self.job_config.quarantine_after else {
+            return;
+        };
+        let quarantine = &self.global_state.quarantine;
+        let result = match error {
+            None => quarantine.record_success(&self.job_config.name, vm).await,
+            Some(error) => match quarantine
+                .record_failure(&self.job_config.name, vm, error, quarantine_after)
+                .await
+            {
+                Ok(true) => {
+                    let message = format!(
+                        "Quarantined VM '{}' [{}] after {} failed runs in a row, it is skipped until released with `xenbakd quarantine clear`",
+                        vm.name_label, vm.uuid, quarantine_after
+                    );
+                    error!("{}", message);
+                    self.job_stats.warnings.push(message);
+                    self.job_stats
+                        .quarantined_objects
+                        .push(format!("{} [{}]", vm.name_label, vm.uuid));
+                    Ok(())
+                }
+                Ok(false) => Ok(()),
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            warn!(
+                "Failed to record the result of VM '{}' for the quarantine: {}",
+                vm.name_label, e
+            );
+        }
+    }
+
+    /// estimates the size of the VMs once per run and records it in the stats, for the space
+    /// checks and the export progress
+    async fn estimate_vm_sizes(
+        &mut self,
+        xapi_client: &XApiCliClient,
+        vms: &[VM],
+    ) -> HashMap<UUID, VmSizeEstimate> {
+        let mut estimates = HashMap::new();
+        for vm in vms {
+            let estimate = match xapi_client.estimate_vm_size(vm).await {
+                Ok(estimate) => estimate,
+                Err(e) => {
+                    warn!(
+                        "Failed to estimate the size of VM '{}': {}",
+                        vm.name_label, e
+                    );
+                    continue;
+                }
+            };
+            debug!(
+                "VM '{}' has {:.1} GiB of disks, {:.1} GiB of them allocated",
+                vm.name_label,
+                estimate.virtual_size as f64 / GIB,
+                estimate.physical_utilisation as f64 / GIB
+            );
+            self.job_stats.estimated_bytes += estimate.physical_utilisation;
+            self.job_stats
+                .size_estimates
+                .insert(format!("{} [{}]", vm.name_label, vm.uuid), estimate);
+            estimates.insert(vm.uuid.clone(), estimate);
+        }
+
+        estimates
+    }
 }
 
 #[async_trait::async_trait]
@@ -32,6 +787,8 @@ impl XenbakJob for VmBackupJob {
             global_state,
             job_config,
             job_stats: XenbakJobStats::default(),
+            runs: Arc::new(AtomicU32::new(0)),
+            completed_vms: HashSet::new(),
         }
     }
 
@@ -57,31 +814,157 @@ impl XenbakJob for VmBackupJob {
 
         info!("Running VM backup job '{}'", self.job_config.name);
 
+        // keep the configuration in force with the run, old runs may have been made with different settings
         self.job_stats.config = self.job_config.clone();
+        for storage_name in &self.job_config.storages {
+            if let Some(snapshot) = self.global_state.config.storage.snapshot(storage_name) {
+                self.job_stats
+                    .storage_configs
+                    .insert(storage_name.clone(), snapshot);
+            }
+        }
+        debug!(
+            "Effective configuration of job '{}': {}",
+            self.job_config.name,
+            serde_json::to_string(&ConfigSnapshot::new(
+                &self.job_config,
+                &self.job_stats.storage_configs
+            ))?
+        );
+
+        if self.job_config.snapshot_type == SnapshotType::Memory
+            && self.job_config.differential.is_some()
+        {
+            warn!(
+                "Differential exports only hold the disks, memory snapshots of job '{}' are restored without their memory",
+                self.job_config.name
+            );
+        }
+
+        if self.job_config.snapshot_type == SnapshotType::Memory
+            && (!self.job_config.vdi_exclude.is_empty()
+                || !self.job_config.vdi_tag_filter_exclude.is_empty())
+        {
+            warn!(
+                "Memory snapshots of job '{}' without their excluded disks can't be resumed, restored VMs have to be started fresh",
+                self.job_config.name
+            );
+        }
+
+        if self.job_config.keep_snapshots > 0 && self.job_config.differential.is_some() {
+            warn!(
+                "Differential backups keep only their base snapshot, keep_snapshots of job '{}' is ignored",
+                self.job_config.name
+            );
+        }
 
         // iterate through the job's configured xen hosts and create a XAPI client for each
-        let xapi_clients: Vec<XApiCliClient> = self
+        let xen_configs = self
             .job_config
-            .get_xen_configs(self.global_state.config.xen.clone())
+            .get_xen_configs(self.global_state.config.xen.clone());
+        self.job_stats.record_pools(&xen_configs);
+        let xapi_clients: Vec<XApiCliClient> = xen_configs
             .iter()
             .map(|x| XApiCliClient::new(x.clone()))
             .collect();
 
-        // filter VMs by tag and map them to their respective XAPI clients (-> xen hosts)
+        // skip unreachable hosts instead of failing halfway through the run
+        let (xapi_clients, host_status) = preflight::check_hosts(xapi_clients).await;
+        self.job_stats.record_host_status(host_status);
+
+        // filter VMs by tag and map them to their respective XAPI clients (-> xen hosts).
+        // clients are hashed by their config only, the shared xmlrpc session doesn't change it
+        #[allow(clippy::mutable_key_type)]
         let mut vms: HashMap<XApiCliClient, Vec<VM>> = HashMap::new();
+        let mut size_estimates: HashMap<UUID, VmSizeEstimate> = HashMap::new();
+
+        // VMs which failed too many runs in a row are left alone until they are released
+        let quarantined = match self.job_config.quarantine_after {
+            Some(_) => self
+                .global_state
+                .quarantine
+                .quarantined(&self.job_config.name)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!(
+                        "Failed to read quarantined VMs of job '{}': {}",
+                        self.job_config.name, e
+                    );
+                    Default::default()
+                }),
+            None => Default::default(),
+        };
 
-        for client in xapi_clients {
+        for client in xapi_clients.clone() {
+            // logs the version once, features are gated on it later
+            client.get_version().await;
             let filtered_vms = client
                 .filter_vms_by_tag(
                     self.job_config.tag_filter.clone(),
                     self.job_config.tag_filter_exclude.clone(),
                 )
-                .await?;
+                .await?
+                .into_iter()
+                .filter(|x| !self.job_config.is_vm_excluded(x))
+                .collect();
+            let filtered_vms: Vec<VM> = self
+                .filter_vms_by_power_state(&client, filtered_vms)
+                .await?
+                .into_iter()
+                .filter(|x| !self.completed_vms.contains(&x.uuid))
+                .collect();
+            let (quarantined_vms, filtered_vms): (Vec<VM>, Vec<VM>) = filtered_vms
+                .into_iter()
+                .partition(|x| quarantined.contains_key(&x.uuid));
+            for vm in quarantined_vms {
+                let reason = format!(
+                    "VM '{}' [{}] is quarantined after {} failed runs in a row, release it with `xenbakd quarantine clear`",
+                    vm.name_label, vm.uuid, quarantined[&vm.uuid].consecutive_failures
+                );
+                warn!("Skipped backup: {}", reason);
+                self.job_stats
+                    .warnings
+                    .push(format!("Skipped backup: {}", reason));
+                self.job_stats.object_results.push(ObjectResult {
+                    name: vm.name_label.clone(),
+                    uuid: vm.uuid.clone(),
+                    xen_host: client.get_config().name.clone(),
+                    status: ObjectStatus::Skipped,
+                    duration: 0.0,
+                    exported_bytes: 0,
+                    stored_bytes: 0,
+                    storages: vec![],
+                    error: Some(reason),
+                });
+                self.job_stats.total_objects += 1;
+                self.job_stats.skipped_objects += 1;
+            }
+            size_estimates.extend(self.estimate_vm_sizes(&client, &filtered_vms).await);
             vms.insert(client, filtered_vms);
         }
 
+        if !self.completed_vms.is_empty() {
+            let message = format!(
+                "Resumed an interrupted run, {} VMs were backed up by it already",
+                self.completed_vms.len()
+            );
+            info!("{}", message);
+            self.job_stats.warnings.push(message);
+            // keep them for the case this run is interrupted as well
+            if let Some(history) = &self.global_state.history {
+                for vm in &self.completed_vms {
+                    if let Err(e) = history.mark_completed(&self.job_config.name, vm).await {
+                        warn!(
+                            "Failed to record progress of job '{}': {}",
+                            self.job_config.name, e
+                        );
+                    }
+                }
+            }
+        }
+
         // here's the total number of objects affected by the backup job
-        self.job_stats.total_objects = vms.values().flatten().count() as u32;
+        self.job_stats.total_objects += vms.values().flatten().count() as u32;
         debug!(
             "{} objects affected by backup job",
             self.job_stats.total_objects
@@ -106,17 +989,106 @@ impl XenbakJob for VmBackupJob {
             storage_handler.initialize().await?;
         }
 
+        // fail before any VM is snapshotted if a storage is unreachable or read-only
+        let probe_errors = storage::probe::probe_storages(&storage_handlers).await;
+        if !probe_errors.is_empty() {
+            self.job_stats.failed_objects = self.job_stats.total_objects;
+            self.job_stats.errors.extend(probe_errors);
+            self.job_stats.duration = job_timer.elapsed().as_secs_f64();
+            return Err(eyre::eyre!("Storage health check failed."));
+        }
+
+        // VMs with their own schedule are only backed up once it fired since their newest backup
+        self.filter_vms_by_schedule(&mut vms, &storage_handlers)
+            .await;
+
+        // remove leftovers of crashed runs before they eat up the space of this one
+        if !self.job_config.dry_run {
+            storage::orphans::cleanup_storages(&storage_handlers).await;
+        }
+
+        // warn ahead of time if the retention policy can't be satisfied with the available space
+        let budget_filter = storage::BackupObjectFilter {
+            job_type: Some(vec![self.job_type.clone()]),
+            xen_host: Some(vms.keys().map(|x| x.get_config().name.clone()).collect()),
+            vm_name: Some(
+                vms.values()
+                    .flatten()
+                    .map(|x| x.name_label.clone())
+                    .collect(),
+            ),
+            vm_uuid: Some(vms.values().flatten().map(|x| x.uuid.clone()).collect()),
+            pool: None,
+            time_stamp: None,
+        };
+        for storage_handler in storage_handlers.clone() {
+            match budget::check_backup_budget(storage_handler.as_ref(), budget_filter.clone()).await
+            {
+                Ok(Some(warning)) => {
+                    warn!("{}", warning);
+                    self.job_stats.warnings.push(warning);
+                }
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "Failed to check backup budget of storage '{}': {}",
+                    storage_handler.get_name(),
+                    e
+                ),
+            }
+            match budget::check_run_free_space(
+                storage_handler.as_ref(),
+                self.job_stats.estimated_bytes,
+            )
+            .await
+            {
+                Ok(Some(warning)) => {
+                    warn!("{}", warning);
+                    self.job_stats.warnings.push(warning);
+                }
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "Failed to check free space of storage '{}': {}",
+                    storage_handler.get_name(),
+                    e
+                ),
+            }
+        }
+
+        if self.job_config.dry_run {
+            self.dry_run(&vms, &storage_handlers).await;
+            for client in &xapi_clients {
+                client.logout().await;
+            }
+            self.job_stats.duration = job_timer.elapsed().as_secs_f64();
+            return Ok(());
+        }
+
         // sempahore to limit concurrent tasks, use arc to share across threads.
         let permits = Arc::new(tokio::sync::Semaphore::new(
             self.job_config.concurrency as usize,
         ));
 
-        // this will store all thread/task handles
-        let mut handles = vec![];
+        let mut queue: Vec<(XApiCliClient, VM)> = vms
+            .into_iter()
+            .flat_map(|(xapi_client, vms)| vms.into_iter().map(move |vm| (xapi_client.clone(), vm)))
+            .collect();
 
-        // iterate over  VMs and perform backup for each
-        for (xapi_client, vms) in vms {
-            for vm in vms {
+        let mut pending_reclaim = vec![];
+        let deadline = Deadline::new(job_timer, self.job_config.max_runtime_seconds);
+        let temporary_snapshots = TemporarySnapshots::default();
+        let free_space = RunFreeSpace::query(&storage_handlers).await;
+
+        // busy VMs are deferred to the end of the run and retried once, failed ones are retried
+        // up to `retries` times
+        let mut deferred: HashSet<UUID> = HashSet::new();
+        let mut failures: HashMap<UUID, u32> = HashMap::new();
+        let mut cancelled = false;
+        loop {
+            // this will store all thread/task handles
+            let mut handles = vec![];
+
+            // iterate over  VMs and perform backup for each
+            for (xapi_client, vm) in queue {
                 let span = tracing::span!(
                     tracing::Level::INFO,
                     "VmBackupJob::run::backup_vm",
@@ -124,192 +1096,394 @@ impl XenbakJob for VmBackupJob {
                     xen.host = xapi_client.get_config().name.clone()
                 );
 
-                // get a permit from the semaphore
-                let permit = permits.clone().acquire_owned().await.unwrap();
-
                 // we have to clone this data, as it will be moved into a potential separate thread
+                let permits = permits.clone();
                 let storage_handlers = storage_handlers.clone();
                 let job_type = self.job_type.clone();
-                let xapi_client = xapi_client.clone();
                 let job_config = self.job_config.clone();
+                let job_name = self.job_config.name.clone();
+                let clock_skew_threshold = self.global_state.config.general.clock_skew_threshold;
+                let size_estimate = size_estimates.get(&vm.uuid).copied();
+                let temporary_snapshots = temporary_snapshots.clone();
+                let free_space = free_space.clone();
+                let history = self.global_state.history.clone();
+                let concurrency = self.global_state.concurrency.clone();
+                let priority = self.job_config.priority;
+                let shutdown = self.global_state.shutdown.clone();
 
-                // the backup task itself - will be spawned into a separate thread/task
-                let handle = tokio::spawn(async move {
-                    let _permit = permit;
-                    let vm_timer = tokio::time::Instant::now();
-                    info!("Starting backup of VM '{}' [{}]", vm.name_label, vm.uuid);
-
-                    // check if xenbakd should try to create a backup from an already-existing
-                    // snapshot - otherwise create a temporary new one
-                    let mut is_xenbakd_snapshot = true;
-                    let snapshot: VM = match job_config.use_existing_snapshot {
-                        true => {
-                            // get all existing snapshots for the given VM
-                            let existing_snapshots = xapi_client.get_snapshots(&vm).await;
-
-                            // no snapshots? damn. create a new one.
-                            if existing_snapshots.as_ref().is_err_and(|e| {
-                                matches!(
-                                    e,
-                                    XApiCliError::XApiParseError(XApiParseError::EmptyOutput)
-                                )
-                            }) {
-                                debug!("No recent snapshot found, creating new one");
-                                xapi_client.snapshot(&vm, SnapshotType::Normal).await?
-                            } else {
-                                let mut existing_snapshots = existing_snapshots?;
-                                // sort existing snapshots by snapshot time and get the most recent
-                                existing_snapshots.sort_by(|a, b| {
-                                    a.snapshot_time
-                                        .timestamp()
-                                        .partial_cmp(&b.snapshot_time.timestamp())
-                                        .unwrap()
-                                });
-                                let newest_snapshot = existing_snapshots.last().unwrap();
-
-                                // calculate snapshot age
-                                let now = chrono::Utc::now();
-                                let age_limit =
-                                    job_config.use_existing_snapshot_age.unwrap_or(3600);
-                                let snapshot_age = now - newest_snapshot.snapshot_time;
-
-                                // check if the snapshot is within age limit
-                                if snapshot_age.num_seconds() < age_limit {
-                                    is_xenbakd_snapshot = false;
-                                    newest_snapshot.clone()
-                                } else {
-                                    debug!(
-                                        "Newest existing snapshot is older than {} seconds",
-                                        age_limit
-                                    );
-                                    debug!("Creating new snapshot");
-                                    xapi_client.snapshot(&vm, SnapshotType::Normal).await?
-                                }
+                // the backup task itself - will be spawned into a separate thread/task. VMs still
+                // waiting for a permit at the deadline or the end of the shutdown grace period are
+                // cancelled as well
+                let handle = tokio::spawn(
+                    async move {
+                        // the time spent waiting for a permit doesn't count towards the VM
+                        let mut started = None;
+                        let backup = deadline::run_until(deadline, async {
+                            let _permit = permits.acquire_owned().await?;
+                            let _global_permit = concurrency.acquire(priority).await;
+                            // only new backups are held back, running ones are finished
+                            if shutdown.is_requested() {
+                                return Ok(VmBackupOutcome::ShuttingDown);
+                            }
+                            if !window::may_start(&job_config, chrono::Utc::now()) {
+                                return Ok(VmBackupOutcome::WindowClosed);
                             }
+                            started = Some(tokio::time::Instant::now());
+                            backup_vm(
+                                xapi_client.clone(),
+                                vm.clone(),
+                                storage_handlers,
+                                job_type,
+                                job_config,
+                                clock_skew_threshold,
+                                size_estimate,
+                                temporary_snapshots,
+                                free_space,
+                            )
+                            .await
+                        });
+                        let outcome = tokio::select! {
+                            biased;
+                            outcome = backup => outcome,
+                            _ = shutdown.cancelled() => Err(shutdown.error().into()),
+                        };
+                        let duration = started
+                            .map(|x| x.elapsed().as_secs_f64())
+                            .unwrap_or_default();
+                        // a run resumed after a restart leaves out the VMs which are done
+                        if let (Some(history), Ok(VmBackupOutcome::Done { .. })) =
+                            (&history, &outcome)
+                        {
+                            if let Err(e) = history.mark_completed(&job_name, &vm.uuid).await {
+                                warn!("Failed to record progress of job '{}': {}", job_name, e);
+                            }
+                        }
+                        (xapi_client, vm, outcome, duration)
+                    }
+                    .instrument(span),
+                );
+                // push the task handle into the handles vector to await it later
+                handles.push(handle);
+            }
+
+            // wait for all async/threaded tasks to finish and save the results into a vector
+            let mut results = vec![];
+            for handle in handles {
+                results.push(handle.await?);
+            }
+
+            // check if there are any errors in the results, fill stats object appropiately
+            queue = vec![];
+            let mut retry_delay = 0;
+            for (xapi_client, vm, result, duration) in results {
+                let mut object_result = ObjectResult {
+                    name: vm.name_label.clone(),
+                    uuid: vm.uuid.clone(),
+                    xen_host: xapi_client.get_config().name.clone(),
+                    status: ObjectStatus::Success,
+                    duration,
+                    exported_bytes: 0,
+                    stored_bytes: 0,
+                    storages: vec![],
+                    error: None,
+                };
+                match result {
+                    Ok(VmBackupOutcome::Done {
+                        quota_rotated,
+                        pending_reclaim: vm_pending_reclaim,
+                        coalesce_warning,
+                        guest_hook_warning,
+                        export,
+                        storages,
+                        stored_bytes,
+                    }) => {
+                        object_result.exported_bytes = export.bytes;
+                        object_result.stored_bytes = stored_bytes;
+                        object_result.storages = storages;
+                        self.job_stats.object_results.push(object_result);
+                        self.job_stats.successful_objects += 1;
+                        self.record_quarantine(&vm, None).await;
+                        self.job_stats.exported_bytes += export.bytes;
+                        self.job_stats.export_duration += export.duration;
+                        pending_reclaim.extend(vm_pending_reclaim);
+                        if let Some(warning) = guest_hook_warning {
+                            warn!("{}", warning);
+                            self.job_stats.warnings.push(warning);
                         }
-                        false => {
-                            debug!("Creating new snapshot");
-                            xapi_client.snapshot(&vm, SnapshotType::Normal).await?
+                        if let Some(warning) = coalesce_warning {
+                            warn!("{}", warning);
+                            self.job_stats.warnings.push(warning);
                         }
-                    };
-
-                    let backup_result = async {
-                        // set is-a-template to false
-                        debug!("Setting is-a-template to false...");
-                        let mut snapshot = xapi_client
-                            .set_snapshot_param_not_template(&snapshot)
-                            .await?;
-
-                        // set snapshot name to a more readable format
-                        if is_xenbakd_snapshot {
-                            snapshot = xapi_client
-                                .set_snapshot_name(
-                                    &snapshot,
-                                    format!("{}__{}", vm.name_label, snapshot.snapshot_time)
-                                        .as_str(),
-                                )
-                                .await?;
+                        for id in quota_rotated {
+                            self.job_stats.quota_rotated_objects += 1;
+                            self.job_stats.warnings.push(format!(
+                                "Deleted restore point {} early due to a storage quota",
+                                id
+                            ));
                         }
+                    }
+                    Ok(VmBackupOutcome::Deferred(_)) if deferred.insert(vm.uuid.clone()) => {
+                        retry_delay = retry_delay.max(self.job_config.deferred_retry_delay);
+                        queue.push((xapi_client, vm));
+                    }
+                    Ok(VmBackupOutcome::Deferred(reason)) => {
+                        let warning = format!("Skipped backup: {}", reason);
+                        warn!("{}", warning);
+                        object_result.status = ObjectStatus::Skipped;
+                        object_result.error = Some(reason);
+                        self.job_stats.object_results.push(object_result);
+                        self.job_stats.skipped_objects += 1;
+                        self.job_stats.warnings.push(warning);
+                    }
+                    Ok(VmBackupOutcome::WindowClosed) => {
+                        let reason = format!(
+                            "VM '{}' [{}] is outside of the backup window",
+                            vm.name_label, vm.uuid
+                        );
+                        warn!("Skipped backup: {}", reason);
+                        self.job_stats
+                            .warnings
+                            .push(format!("Skipped backup: {}", reason));
+                        object_result.status = ObjectStatus::Skipped;
+                        object_result.error = Some(reason);
+                        self.job_stats.object_results.push(object_result);
+                        self.job_stats.skipped_objects += 1;
+                    }
+                    Ok(VmBackupOutcome::ShuttingDown) => {
+                        let reason = format!(
+                            "VM '{}' [{}] wasn't backed up, the daemon is shutting down",
+                            vm.name_label, vm.uuid
+                        );
+                        warn!("Skipped backup: {}", reason);
+                        self.job_stats
+                            .warnings
+                            .push(format!("Skipped backup: {}", reason));
+                        object_result.status = ObjectStatus::Skipped;
+                        object_result.error = Some(reason);
+                        self.job_stats.object_results.push(object_result);
+                        self.job_stats.skipped_objects += 1;
+                    }
+                    // quotas, the deadline and the shutdown won't be any different on the next
+                    // attempt
+                    Err(e)
+                        if failures.get(&vm.uuid).copied().unwrap_or_default()
+                            < self.job_config.retries
+                            && !e.chain().any(|x| {
+                                x.downcast_ref::<budget::QuotaExceededError>().is_some()
+                                    || x.downcast_ref::<JobTimeoutError>().is_some()
+                                    || x.downcast_ref::<ShutdownError>().is_some()
+                            }) =>
+                    {
+                        let failed = failures.entry(vm.uuid.clone()).or_default();
+                        *failed += 1;
+                        warn!(
+                            "Backup of VM '{}' failed, retrying it ({}/{}): {:#}",
+                            vm.name_label, failed, self.job_config.retries, e
+                        );
+                        if *failed == 1 {
+                            self.job_stats.retried_objects += 1;
+                        }
+                        retry_delay = retry_delay.max(self.job_config.retry_delay);
+                        queue.push((xapi_client, vm));
+                    }
+                    Err(e) => {
+                        let full_err = e
+                            .chain()
+                            .map(|e| e.to_string())
+                            .collect::<Vec<String>>()
+                            .join("\n");
 
-                        // iterate through enabled local storages, export snapshost for each storage and rotate/cleanup backups
-                        for storage_handler in storage_handlers {
-                            // create the backup object
-                            let backup_object = storage::BackupObject::new(
-                                job_type.clone(),
-                                vm.name_label.clone(),
-                                xapi_client.get_config().name.clone(),
-                                snapshot.snapshot_time,
-                                None,
-                            );
-
-                            // export the snaphhot using the current storage handler
-                            info!("Exporting VM to storage handler...",);
-                            xapi_client
-                                .vm_export_to_storage(
-                                    &snapshot,
-                                    storage_handler.clone(),
-                                    backup_object.clone(),
-                                )
-                                .await?;
-
-                            // rotate backups
-                            debug!("Rotating backups");
-                            let backup_object_filter =
-                                storage::BackupObjectFilter::from_backup_object(
-                                    backup_object.clone(),
-                                );
-                            storage_handler.rotate(backup_object_filter).await?;
+                        // the deadline and the shutdown aren't the VM's fault
+                        if !e.chain().any(|x| {
+                            x.downcast_ref::<JobTimeoutError>().is_some()
+                                || x.downcast_ref::<ShutdownError>().is_some()
+                        }) {
+                            self.record_quarantine(&vm, Some(&full_err)).await;
                         }
 
-                        Ok::<(), eyre::Error>(())
-                    }
-                    .await;
+                        object_result.status = ObjectStatus::Failed;
+                        object_result.error = Some(full_err.clone());
+                        self.job_stats.object_results.push(object_result);
+                        self.job_stats.failed_objects += 1;
+                        self.job_stats.errors.push(full_err.clone());
 
-                    if is_xenbakd_snapshot {
-                        debug!("Deleting snapshot...");
-                        xapi_client.delete_snapshot_by_uuid(&snapshot.uuid).await?;
-                    }
+                        if e.chain()
+                            .any(|x| x.downcast_ref::<budget::QuotaExceededError>().is_some())
+                        {
+                            self.job_stats.quota_exceeded_objects += 1;
+                        }
+                        if e.chain()
+                            .any(|x| x.downcast_ref::<JobTimeoutError>().is_some())
+                        {
+                            self.job_stats.timed_out = true;
+                        }
+                        if e.chain()
+                            .any(|x| x.downcast_ref::<ShutdownError>().is_some())
+                        {
+                            cancelled = true;
+                        }
 
-                    // propagate any errors that occurred during backup
-                    if let Err(e) = backup_result {
-                        return Err(e.wrap_err(format!(
-                            "Backup of VM '{}' [{}] failed",
-                            vm.name_label, vm.uuid
-                        )));
+                        // count classified snapshot/export failures
+                        if let Some(kind) = e
+                            .chain()
+                            .find_map(|x| x.downcast_ref::<XApiCliError>())
+                            .and_then(|x| x.kind())
+                        {
+                            self.job_stats.add_error_kind(kind);
+                        }
+                        error!("{:?}", e);
                     }
+                }
+            }
 
-                    // get the elapsed time and log it
-                    let elapsed = vm_timer.elapsed().as_secs_f64();
-                    info!(
-                        "Finished backup of VM '{}' [{}] in {} seconds",
-                        vm.name_label, vm.uuid, elapsed
-                    );
+            if queue.is_empty() || self.job_stats.timed_out {
+                break;
+            }
+            // the remaining VMs are skipped right away
+            if self.global_state.shutdown.is_requested() {
+                continue;
+            }
+            info!(
+                "Retrying {} deferred or failed VMs in {} seconds",
+                queue.len(),
+                retry_delay
+            );
+            tokio::time::sleep(std::time::Duration::from_secs(retry_delay)).await;
+        }
 
-                    // drop the permit to allow another task to run
-                    drop(_permit);
+        // cancelled backups leave their snapshots behind, busy VMs which weren't retried anymore
+        // are cancelled as well
+        if self.job_stats.timed_out {
+            for (xapi_client, vm) in queue {
+                let error = format!(
+                    "Backup of VM '{}' [{}] failed\n{}",
+                    vm.name_label,
+                    vm.uuid,
+                    deadline.map(|x| x.error().to_string()).unwrap_or_default()
+                );
+                self.job_stats.object_results.push(ObjectResult {
+                    name: vm.name_label,
+                    uuid: vm.uuid,
+                    xen_host: xapi_client.get_config().name.clone(),
+                    status: ObjectStatus::Failed,
+                    duration: 0.0,
+                    exported_bytes: 0,
+                    stored_bytes: 0,
+                    storages: vec![],
+                    error: Some(error.clone()),
+                });
+                self.job_stats.failed_objects += 1;
+                self.job_stats.errors.push(error);
+            }
+        }
+        if self.job_stats.timed_out || cancelled {
+            let warnings = temporary_snapshots.cleanup().await;
+            self.job_stats.warnings.extend(warnings);
+        }
 
-                    eyre::Result::<()>::Ok(())
-                })
-                .instrument(span);
-                // push the task handle into the handles vector to await it later
-                handles.push(handle);
+        // a stuck coalesce silently fills up the SR, so check whether the snapshots' space is freed
+        if let Some(reclaim_timeout) = self
+            .job_config
+            .reclaim_timeout
+            .filter(|_| !self.job_stats.timed_out && !self.global_state.shutdown.is_requested())
+        {
+            for warning in reclaim::await_reclamation(pending_reclaim, reclaim_timeout).await {
+                warn!("{}", warning);
+                self.job_stats.warnings.push(warning);
             }
         }
 
-        // wait for all async/threaded tasks to finish and save the results into a vector
-        let mut results = vec![];
-        for handle in handles {
-            results.push(handle.await?);
+        // pinned restore points are exempt from the rotation, so list them to not forget about them
+        for storage_handler in &storage_handlers {
+            match storage_handler
+                .list(storage::BackupObjectFilter::default())
+                .await
+            {
+                Ok(restore_points) => self.job_stats.pinned_restore_points.extend(
+                    restore_points
+                        .into_iter()
+                        .filter(|x| x.pinned)
+                        .map(|x| format!("{}: {}", storage_handler.get_name(), x.id)),
+                ),
+                Err(e) => warn!(
+                    "Failed to list pinned restore points of storage '{}': {}",
+                    storage_handler.get_name(),
+                    e
+                ),
+            }
         }
 
-        // check if there are any errors in the results, fill stats object appropiately
-        for result in results.iter() {
-            match result {
-                Ok(_) => {
-                    self.job_stats.successful_objects += 1;
-                }
-                Err(e) => {
-                    let full_err = e
-                        .chain()
-                        .map(|e| e.to_string())
-                        .collect::<Vec<String>>()
-                        .join("\n");
-
-                    self.job_stats.failed_objects += 1;
-                    self.job_stats.errors.push(full_err.clone());
-                    error!("{:?}", e);
+        // prove every few runs that the backups can actually be restored
+        let run = self.runs.fetch_add(1, Ordering::Relaxed);
+        let mut test_restore_failed = false;
+        if let Some(test_restore_config) = self
+            .job_config
+            .test_restore
+            .as_ref()
+            .filter(|_| !self.job_stats.timed_out && !self.global_state.shutdown.is_requested())
+        {
+            if run.is_multiple_of(test_restore_config.interval.max(1)) {
+                match test_restore::test_restore(
+                    &self.global_state.config.xen,
+                    &storage_handlers,
+                    test_restore_config,
+                )
+                .await
+                {
+                    Ok(Some(result)) => {
+                        if let Some(e) = &result.error {
+                            test_restore_failed = true;
+                            error!("Test restore of {} failed: {}", result.restore_point, e);
+                            self.job_stats.errors.push(format!(
+                                "Test restore of {} failed: {}",
+                                result.restore_point, e
+                            ));
+                        }
+                        self.job_stats.test_restore = Some(result);
+                    }
+                    Ok(None) => warn!(
+                        "Job '{}' has no backup to test restore",
+                        self.job_config.name
+                    ),
+                    Err(e) => {
+                        test_restore_failed = true;
+                        error!("Test restore failed: {:?}", e);
+                        self.job_stats
+                            .errors
+                            .push(format!("Test restore failed: {:#}", e));
+                    }
                 }
             }
         }
 
+        for client in &xapi_clients {
+            client.logout().await;
+        }
+
         // get the elapsed time
         let elapsed = job_timer.elapsed();
         self.job_stats.duration = elapsed.as_secs_f64();
 
-        // if there were any errors, return an error
-        if self.job_stats.failed_objects > 0 {
+        if self.job_stats.timed_out {
+            return Err(eyre::eyre!(
+                "Backup job exceeded its maximum runtime of {} seconds.",
+                self.job_config.max_runtime_seconds.unwrap_or_default()
+            ));
+        }
+
+        if cancelled {
+            return Err(eyre::eyre!(
+                "Backup job was cancelled by the shutdown of the daemon."
+            ));
+        }
+
+        // fail the run if more objects failed than the threshold allows
+        if !self.job_stats.within_failure_threshold() {
             return Err(eyre::eyre!("Backup job failed.",));
         }
+        if test_restore_failed {
+            return Err(eyre::eyre!("Test restore failed."));
+        }
 
         info!(
             "Finished VM backup job with name '{}' in {} seconds",
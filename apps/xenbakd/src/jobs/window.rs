@@ -0,0 +1,96 @@
+use std::str::FromStr;
+
+use chrono::{NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+
+use crate::config::JobConfig;
+
+/// daily time range in UTC like the schedules, e.g. `22:00-06:00`. a range ending before it
+/// starts spans midnight
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| eyre::eyre!("Time window '{}' has to look like 22:00-06:00", s))?;
+        let parse = |x: &str| {
+            NaiveTime::parse_from_str(x.trim(), "%H:%M")
+                .map_err(|e| eyre::eyre!("Invalid time '{}' in time window '{}': {}", x, s, e))
+        };
+        Ok(TimeWindow {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl std::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start.hour(),
+            self.start.minute(),
+            self.end.hour(),
+            self.end.minute()
+        )
+    }
+}
+
+impl Serialize for TimeWindow {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeWindow {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        TimeWindow::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// whether the job may start the backup of another VM at the given time: within one of its
+/// backup windows, if it has any, and outside of its blackout periods
+pub fn may_start(job_config: &JobConfig, now: chrono::DateTime<chrono::Utc>) -> bool {
+    let time = now.time();
+    (job_config.backup_windows.is_empty()
+        || job_config.backup_windows.iter().any(|x| x.contains(time)))
+        && !job_config.blackout_periods.iter().any(|x| x.contains(time))
+}
+
+/// parses a cron schedule, in the 6 field format of the job schedules or the classic 5 field
+/// format without seconds
+pub fn parse_schedule(schedule: &str) -> eyre::Result<cron::Schedule> {
+    let schedule = match schedule.split_whitespace().count() {
+        5 => format!("0 {}", schedule),
+        _ => schedule.to_string(),
+    };
+    cron::Schedule::from_str(&schedule)
+        .map_err(|e| eyre::eyre!("Invalid schedule '{}': {}", schedule, e))
+}
+
+/// first time the schedule fires after the given one
+pub fn next_scheduled(
+    schedule: &str,
+    after: chrono::DateTime<chrono::Utc>,
+) -> eyre::Result<Option<chrono::DateTime<chrono::Utc>>> {
+    Ok(parse_schedule(schedule)?.after(&after).next())
+}
@@ -5,60 +5,173 @@ __  _____ _ __ | |__   __ _| | ____| |
 /_/\_\___|_| |_|_.__/ \__,_|_|\_\__,_|
   "#;
 
+mod agent;
 mod cli;
 mod config;
+mod history;
 mod jobs;
+mod metrics;
 mod monitoring;
+mod quarantine;
+mod restore;
 mod scheduler;
+mod shutdown;
 mod storage;
+mod syslog;
+mod systemd;
 mod xapi;
 
 use crate::{
     config::AppConfig,
-    jobs::{vm_backup::VmBackupJob, XenbakJob},
+    jobs::{
+        borg_maintenance::BorgMaintenanceJob, coverage::CoverageJob,
+        pool_metadata::PoolMetadataJob, replication::ReplicationJob, vdi_backup::VdiBackupJob,
+        vm_backup::VmBackupJob, JobType, XenbakJob,
+    },
     monitoring::healthchecks::HealthchecksManagementApiTrait,
     scheduler::XenbakScheduler,
 };
 use clap::Parser;
 use colored::Colorize;
-use figment::{
-    providers::{Format, Serialized, Toml},
-    Figment,
-};
 use std::sync::Arc;
-use tracing::{info, Level};
+use tracing::{info, warn, Level};
+use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, Layer};
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     // initialize colored eyre for better-looking panics
     color_eyre::install().unwrap();
 
-    // print banner
-    println!("{}", BANNER.cyan());
-
     // parse cli args
     let cli = cli::XenbakdCli::parse();
-    let config_path = cli.config;
-    // load default config, then override/merge using config.toml
-    let mut config = Figment::from(Serialized::defaults(AppConfig::default()))
-        .merge(Toml::file(config_path))
-        .extract::<AppConfig>()
-        .expect("Failed to load configuration");
+
+    // print the config without banner and logging, so it can be piped
+    if let cli::SubCommand::Config(cli::ConfigSubCommand {
+        subcmd: cli::ConfigCommand::Show(show),
+    }) = &cli.subcmd
+    {
+        let output = match show.resolved {
+            true => toml::to_string_pretty(&AppConfig::load(&cli.config, cli.profile.as_deref())?)?,
+            false => toml::to_string_pretty(&AppConfig::load_unresolved(
+                &cli.config,
+                cli.profile.as_deref(),
+            )?)?,
+        };
+        print!("{}", output);
+        return Ok(());
+    }
+
+    if let cli::SubCommand::History(history) = &cli.subcmd {
+        let config = AppConfig::load(&cli.config, cli.profile.as_deref())?;
+        return history::print_history(&config, history).await;
+    }
+
+    if let cli::SubCommand::Quarantine(quarantine) = &cli.subcmd {
+        let config = AppConfig::load(&cli.config, cli.profile.as_deref())?;
+        return quarantine::quarantine(&config, &quarantine.subcmd).await;
+    }
+
+    if let cli::SubCommand::Debug(cli::DebugSubCommand {
+        subcmd: cli::DebugCommand::DumpTasks,
+    }) = &cli.subcmd
+    {
+        let config = AppConfig::load(&cli.config, cli.profile.as_deref())?;
+        print!("{}", metrics::fetch_tasks(&config.metrics.listen).await?);
+        return Ok(());
+    }
+
+    if let cli::SubCommand::Jobs(jobs) = &cli.subcmd {
+        let config = AppConfig::load(&cli.config, cli.profile.as_deref())?;
+        let output = match &jobs.subcmd {
+            cli::JobsCommand::Status(status) => {
+                let mut output = String::new();
+                for job in metrics::fetch_jobs(&config.metrics.listen).await? {
+                    output += &match status.json {
+                        true => serde_json::to_string(&job)?,
+                        false => job.to_string(),
+                    };
+                    output.push('\n');
+                }
+                output
+            }
+            cli::JobsCommand::Pause(control) => {
+                metrics::set_job_paused(&config.metrics.listen, &control.job, true).await?
+            }
+            cli::JobsCommand::Resume(control) => {
+                metrics::set_job_paused(&config.metrics.listen, &control.job, false).await?
+            }
+        };
+        print!("{}", output);
+        return Ok(());
+    }
+
+    // print banner
+    println!("{}", BANNER.cyan());
+    // load default config, then override/merge using the given config files and profile
+    let mut config =
+        AppConfig::load(&cli.config, cli.profile.as_deref()).expect("Failed to load configuration");
+    if cli.dry_run {
+        for job in &mut config.jobs {
+            job.rotate_dry_run = true;
+            job.dry_run |= matches!(cli.subcmd, cli::SubCommand::Run(_));
+        }
+    }
 
     // initialize tracing/logging
-    let log_level = match config.general.log_level.as_str() {
-        "trace" => Level::TRACE,
-        "debug" => Level::DEBUG,
-        "info" => Level::INFO,
-        "warn" => Level::WARN,
-        "error" => Level::ERROR,
-        _ => Level::INFO,
+    let log_level = parse_log_level(&config.general.log_level);
+    let syslog_config = &config.logging.syslog;
+    let syslog_layer = match syslog_config.enabled {
+        true => Some(
+            syslog::SyslogLayer::new(syslog_config)?.with_filter(LevelFilter::from_level(
+                syslog_config
+                    .log_level
+                    .as_deref()
+                    .map_or(log_level, parse_log_level),
+            )),
+        ),
+        false => None,
+    };
+    let journald_config = &config.logging.journald;
+    let journald_layer = match journald_config.enabled {
+        true => match tracing_journald::layer() {
+            Ok(layer) => Some(
+                layer
+                    .with_field_prefix(Some(journald_config.field_prefix.clone()))
+                    .with_syslog_identifier(journald_config.syslog_identifier.clone())
+                    .with_filter(LevelFilter::from_level(
+                        journald_config
+                            .log_level
+                            .as_deref()
+                            .map_or(log_level, parse_log_level),
+                    )),
+            ),
+            // the log isn't set up yet
+            Err(e) => {
+                eprintln!(
+                    "Failed to connect to journald, logging to stdout only: {}",
+                    e
+                );
+                None
+            }
+        },
+        false => None,
+    };
+    // under systemd stdout ends up in the journal as well, its lines would show up twice
+    let stdout_layer = match journald_layer.is_some() && systemd::stdout_is_journal() {
+        true => None,
+        false => Some(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_filter(LevelFilter::from_level(log_level)),
+        ),
     };
-    let subscriber = tracing_subscriber::fmt::Subscriber::builder()
-        .with_ansi(false)
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .with_max_level(log_level)
-        .finish();
+    // the span tracker sees all spans regardless of the log level, for `debug dump-tasks`
+    let span_tracker = metrics::spans::SpanTracker::default();
+    let subscriber = tracing_subscriber::registry()
+        .with(stdout_layer)
+        .with(syslog_layer)
+        .with(journald_layer)
+        .with(span_tracker.clone());
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
     info!("Starting Xenbakd!");
@@ -72,7 +185,10 @@ async fn main() -> eyre::Result<()> {
                     config.monitoring.healthchecks.clone(),
                 );
 
-                match service.initialize(config.jobs.clone()).await {
+                match service
+                    .initialize([config.jobs.clone(), config.get_maintenance_jobs()].concat())
+                    .await
+                {
                     Ok(_) => {
                         tracing::info!("Healthchecks service initialized successfully");
                         Some(service)
@@ -117,41 +233,341 @@ async fn main() -> eyre::Result<()> {
         }
     };
 
+    // initialize telegram_service
+    let telegram_service: Option<monitoring::telegram::TelegramService> =
+        match config.monitoring.telegram.enabled {
+            true => {
+                info!("Initializing telegram service...");
+                let service = monitoring::telegram::TelegramService::from_config(
+                    config.monitoring.telegram.clone(),
+                )
+                .await;
+
+                match service {
+                    Ok(service) => {
+                        tracing::info!("Telegram service initialized successfully");
+                        Some(service)
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to initialize telegram service: {}", e);
+                        tracing::warn!("Disabling telegram service...");
+                        config.monitoring.telegram.enabled = false;
+                        None
+                    }
+                }
+            }
+            false => None,
+        };
+
+    // opsgenie is only called once a run finishes
+    let opsgenie_service: Option<monitoring::opsgenie::OpsgenieService> =
+        match config.monitoring.opsgenie.enabled {
+            true => match monitoring::opsgenie::OpsgenieService::from_config(
+                config.monitoring.opsgenie.clone(),
+            ) {
+                Ok(service) => Some(service),
+                Err(e) => {
+                    tracing::warn!("Failed to initialize opsgenie service: {}", e);
+                    tracing::warn!("Disabling opsgenie service...");
+                    config.monitoring.opsgenie.enabled = false;
+                    None
+                }
+            },
+            false => None,
+        };
+
+    // the bucket is only written to once a run finishes
+    let influxdb_service: Option<monitoring::influxdb::InfluxDbService> =
+        match config.monitoring.influxdb.enabled {
+            true => match monitoring::influxdb::InfluxDbService::from_config(
+                config.monitoring.influxdb.clone(),
+            ) {
+                Ok(service) => Some(service),
+                Err(e) => {
+                    tracing::warn!("Failed to initialize influxdb service: {}", e);
+                    tracing::warn!("Disabling influxdb service...");
+                    config.monitoring.influxdb.enabled = false;
+                    None
+                }
+            },
+            false => None,
+        };
+
+    // statsd and graphite are connected to for every run
+    let statsd_service =
+        config.monitoring.statsd.enabled.then(|| {
+            monitoring::statsd::StatsdService::from_config(config.monitoring.statsd.clone())
+        });
+
+    // external commands don't need any initialization
+    let exec_notifiers: Vec<monitoring::exec::ExecNotifier> = config
+        .monitoring
+        .exec
+        .iter()
+        .filter(|x| x.enabled)
+        .map(|x| monitoring::exec::ExecNotifier::from_config(x.clone()))
+        .collect();
+
+    let history = config
+        .history
+        .enabled
+        .then(|| history::HistoryStore::from_config(config.history.clone()));
+
     // create global state
     let global_state = Arc::new(GlobalState {
         config: config.clone(),
         mail_service,
         healthchecks_service,
+        telegram_service,
+        opsgenie_service,
+        influxdb_service,
+        statsd_service,
+        exec_notifiers,
+        history,
+        quarantine: quarantine::QuarantineStore::from_config(config.quarantine.clone()),
+        concurrency: jobs::concurrency::ConcurrencyBudget::new(
+            config.general.max_concurrent_backups,
+        ),
+        shutdown: shutdown::Shutdown::new(config.general.shutdown_grace_period),
+        paused: jobs::pause::PausedJobs::default(),
+        job_tracker: jobs::status::JobTracker::default(),
     });
 
+    if config.metrics.enabled {
+        let listen = config.metrics.listen.clone();
+        let global_state = global_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listen, span_tracker, global_state).await {
+                tracing::error!("Metrics endpoint failed: {}", e);
+            }
+        });
+    }
+
+    // clean up after crashed runs, the storages of each job are checked again when it starts
+    if matches!(cli.subcmd, cli::SubCommand::Daemon(_)) {
+        for job in config.jobs.iter().filter(|x| x.enabled) {
+            storage::orphans::cleanup_storages(&job.get_storages(config.storage.clone())).await;
+        }
+
+        // jobs check their hosts again and skip unreachable ones, this only reports them early
+        let xapi_clients: Vec<xapi::cli::client::XApiCliClient> = config
+            .xen
+            .iter()
+            .filter(|x| x.enabled)
+            .map(|x| xapi::cli::client::XApiCliClient::new(x.clone()))
+            .collect();
+        let (reachable, host_status) = xapi::preflight::check_hosts(xapi_clients).await;
+        for (host, status) in host_status {
+            info!("Xen host '{}' is {}", host, status);
+        }
+        for client in reachable {
+            client.logout().await;
+        }
+    }
+
     // match clap cli
     match cli.subcmd {
         cli::SubCommand::Daemon(_) => {
             let mut scheduler = XenbakScheduler::new().await;
+            if let Some(history) = &global_state.history {
+                global_state.job_tracker.load_history(history).await;
+            }
+            // resume the runs a restart interrupted instead of waiting for their next schedule.
+            // before adding the jobs, so their catch-up runs aren't taken for interrupted ones
+            if let Some(history) = &global_state.history {
+                for interrupted in history.interrupted_runs().await? {
+                    let job = config.jobs.iter().find(|x| {
+                        x.name == interrupted.job_name
+                            && x.enabled
+                            && x.resume_interrupted
+                            && x.job_type == JobType::VmBackup
+                    });
+                    let Some(job) = job else {
+                        warn!(
+                            "Run of job '{}' started at {} was interrupted",
+                            interrupted.job_name, interrupted.started
+                        );
+                        history.finish_run(&interrupted.job_name).await?;
+                        continue;
+                    };
+                    info!(
+                        "Resuming run of job '{}' started at {}, {} VMs were backed up already",
+                        job.name,
+                        interrupted.started,
+                        interrupted.completed_vms.len()
+                    );
+                    let mut backup_job = VmBackupJob::new(global_state.clone(), job.clone());
+                    backup_job.completed_vms = interrupted.completed_vms.into_iter().collect();
+                    scheduler.spawn_once(backup_job, global_state.clone());
+                }
+            }
             for job in config.jobs.clone() {
                 if !job.enabled {
                     continue;
                 }
-                let backup_job = VmBackupJob::new(global_state.clone(), job.clone());
-                scheduler.add_job(backup_job, global_state.clone()).await?;
+                match job.job_type {
+                    JobType::VdiBackup => {
+                        let backup_job = VdiBackupJob::new(global_state.clone(), job.clone());
+                        scheduler.add_job(backup_job, global_state.clone()).await?;
+                    }
+                    JobType::PoolMetadata => {
+                        let backup_job = PoolMetadataJob::new(global_state.clone(), job.clone());
+                        scheduler.add_job(backup_job, global_state.clone()).await?;
+                    }
+                    JobType::Replication => {
+                        let replication_job =
+                            ReplicationJob::new(global_state.clone(), job.clone());
+                        scheduler
+                            .add_job(replication_job, global_state.clone())
+                            .await?;
+                    }
+                    _ => {
+                        let backup_job = VmBackupJob::new(global_state.clone(), job.clone());
+                        scheduler.add_job(backup_job, global_state.clone()).await?;
+                    }
+                }
+            }
+            for job in config.get_maintenance_jobs() {
+                match job.job_type {
+                    JobType::Coverage => {
+                        let coverage_job = CoverageJob::new(global_state.clone(), job);
+                        scheduler
+                            .add_job(coverage_job, global_state.clone())
+                            .await?;
+                    }
+                    _ => {
+                        let maintenance_job = BorgMaintenanceJob::new(global_state.clone(), job);
+                        scheduler
+                            .add_job(maintenance_job, global_state.clone())
+                            .await?;
+                    }
+                }
             }
             // start scheduler
-            scheduler.start().await;
-            tokio::signal::ctrl_c().await.unwrap();
+            let mut signals = shutdown::Signals::new()?;
+            scheduler.start(&global_state).await;
+            systemd::ready();
+            XenbakScheduler::report_status(&global_state);
+            if let Some(interval) = systemd::watchdog_interval() {
+                scheduler.spawn_watchdog(interval);
+            }
+            // SIGUSR1 logs what the daemon is doing and plans to do next
+            let mut status_signal =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+            loop {
+                tokio::select! {
+                    _ = signals.recv() => break,
+                    _ = status_signal.recv() => jobs::status::log_job_statuses(&global_state).await,
+                }
+            }
+
+            // finish the running jobs, so they delete their snapshots and send their notifications
+            info!("Shutting down, no new jobs or VM backups are started");
+            systemd::stopping();
+            scheduler.shutdown().await;
+            global_state.shutdown.request();
+            XenbakScheduler::report_status(&global_state);
+            global_state.shutdown.drain(&mut signals).await;
+            info!("Stopped xenbakd");
+            return Ok(());
         }
         cli::SubCommand::Run(run) => {
             let mut scheduler = XenbakScheduler::new().await;
 
-            for job in run.jobs {
-                let job = config
+            let maintenance_jobs = config.get_maintenance_jobs();
+            // a pool filter without jobs runs every enabled job covering the pools
+            let job_names = match run.jobs.is_empty() && !run.pools.is_empty() {
+                true => config
+                    .jobs
+                    .iter()
+                    .filter(|x| x.enabled)
+                    .map(|x| x.name.clone())
+                    .collect(),
+                false => run.jobs,
+            };
+            for job in job_names {
+                if let Some(job) = maintenance_jobs.iter().find(|j| j.name == job) {
+                    match job.job_type {
+                        JobType::Coverage => {
+                            let coverage_job = CoverageJob::new(global_state.clone(), job.clone());
+                            scheduler
+                                .run_once(coverage_job, global_state.clone())
+                                .await?;
+                        }
+                        _ => {
+                            let maintenance_job =
+                                BorgMaintenanceJob::new(global_state.clone(), job.clone());
+                            scheduler
+                                .run_once(maintenance_job, global_state.clone())
+                                .await?;
+                        }
+                    }
+                    continue;
+                }
+
+                let mut job = config
                     .jobs
                     .iter()
                     .find(|j| j.name == job)
-                    .expect("Given Job not found in config");
+                    .expect("Given Job not found in config")
+                    .clone();
+                if !run.pools.is_empty() && !job.restrict_to_pools(&run.pools, config.xen.clone()) {
+                    info!(
+                        "Job '{}' has no xen hosts in pools {}, skipping",
+                        job.name,
+                        run.pools.join(", ")
+                    );
+                    continue;
+                }
 
-                let backup_job = VmBackupJob::new(global_state.clone(), job.clone());
-                scheduler.run_once(backup_job, global_state.clone()).await?;
+                match job.job_type {
+                    JobType::VdiBackup => {
+                        let backup_job = VdiBackupJob::new(global_state.clone(), job.clone());
+                        scheduler.run_once(backup_job, global_state.clone()).await?;
+                    }
+                    JobType::PoolMetadata => {
+                        let backup_job = PoolMetadataJob::new(global_state.clone(), job.clone());
+                        scheduler.run_once(backup_job, global_state.clone()).await?;
+                    }
+                    JobType::Replication => {
+                        let replication_job =
+                            ReplicationJob::new(global_state.clone(), job.clone());
+                        scheduler
+                            .run_once(replication_job, global_state.clone())
+                            .await?;
+                    }
+                    _ => {
+                        let backup_job = VmBackupJob::new(global_state.clone(), job.clone());
+                        scheduler.run_once(backup_job, global_state.clone()).await?;
+                    }
+                }
+            }
+        }
+        cli::SubCommand::Agent(_) => {
+            tokio::select! {
+                result = agent::run_agent(config.clone()) => result?,
+                _ = tokio::signal::ctrl_c() => info!("Stopping agent..."),
             }
+            return Ok(());
+        }
+        cli::SubCommand::Pin(pin) => return storage::pin::set_pinned(&config, &pin, true).await,
+        cli::SubCommand::Unpin(pin) => return storage::pin::set_pinned(&config, &pin, false).await,
+        cli::SubCommand::Restore(restore) => return restore::restore(&config, &restore).await,
+        cli::SubCommand::Coverage(coverage) => {
+            return jobs::coverage::print_coverage(&config, &coverage).await
+        }
+        cli::SubCommand::ExtractDisk(extract) => {
+            return restore::extract_disk(&config, &extract).await
+        }
+        cli::SubCommand::Convert(convert) => {
+            return restore::convert::convert(&config, &convert).await
+        }
+        cli::SubCommand::Config(_)
+        | cli::SubCommand::Debug(_)
+        | cli::SubCommand::History(_)
+        | cli::SubCommand::Jobs(_)
+        | cli::SubCommand::Quarantine(_) => {
+            unreachable!()
         }
     }
 
@@ -160,9 +576,35 @@ async fn main() -> eyre::Result<()> {
     Ok(())
 }
 
+fn parse_log_level(level: &str) -> Level {
+    match level {
+        "trace" => Level::TRACE,
+        "debug" => Level::DEBUG,
+        "info" => Level::INFO,
+        "warn" => Level::WARN,
+        "error" => Level::ERROR,
+        _ => Level::INFO,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct GlobalState {
     pub config: AppConfig,
     pub mail_service: Option<monitoring::mail::MailService>,
     pub healthchecks_service: Option<monitoring::healthchecks::HealthchecksService>,
+    pub telegram_service: Option<monitoring::telegram::TelegramService>,
+    pub opsgenie_service: Option<monitoring::opsgenie::OpsgenieService>,
+    pub influxdb_service: Option<monitoring::influxdb::InfluxDbService>,
+    pub statsd_service: Option<monitoring::statsd::StatsdService>,
+    pub exec_notifiers: Vec<monitoring::exec::ExecNotifier>,
+    pub history: Option<history::HistoryStore>,
+    /// failures in a row of the VMs, for the jobs with a `quarantine_after`
+    pub quarantine: quarantine::QuarantineStore,
+    /// slots of `max_concurrent_backups`, shared by all jobs
+    pub concurrency: jobs::concurrency::ConcurrencyBudget,
+    pub shutdown: shutdown::Shutdown,
+    /// jobs paused at runtime via the control endpoint
+    pub paused: jobs::pause::PausedJobs,
+    /// ids, next and last runs of the scheduled jobs
+    pub job_tracker: jobs::status::JobTracker,
 }
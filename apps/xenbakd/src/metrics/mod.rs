@@ -0,0 +1,345 @@
+use std::{fmt::Write, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    jobs::{
+        pause,
+        resource_usage::{read_cpu_time, read_rss},
+        status::{self, JobStatus},
+    },
+    GlobalState,
+};
+
+use self::spans::SpanTracker;
+
+pub mod spans;
+
+/// serves the prometheus metrics on `/metrics`, the open spans on `/debug/tasks` and the status
+/// of the jobs on `/jobs`, which are paused and resumed on `/jobs/<name>/pause` and `/jobs/<name>/resume`
+pub async fn serve(
+    listen: String,
+    span_tracker: SpanTracker,
+    global_state: Arc<GlobalState>,
+) -> eyre::Result<()> {
+    let listener = TcpListener::bind(&listen).await?;
+    info!("Metrics endpoint listening on {}", listen);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let span_tracker = span_tracker.clone();
+        let global_state = global_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, peer, span_tracker, global_state).await {
+                debug!("Failed to handle metrics request from {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    span_tracker: SpanTracker,
+    global_state: Arc<GlobalState>,
+) -> eyre::Result<()> {
+    // only the request line is of interest, headers and body are ignored
+    let mut request = vec![0u8; 8192];
+    let mut read = 0;
+    while !request[..read].windows(4).any(|x| x == b"\r\n\r\n") {
+        if read == request.len() {
+            return Err(eyre::eyre!("Request header too large"));
+        }
+        match stream.read(&mut request[read..]).await? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    let request = String::from_utf8_lossy(&request[..read]);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render_metrics(&span_tracker, &global_state)),
+        (Some("GET"), Some("/debug/tasks")) => ("200 OK", span_tracker.dump()),
+        (Some("GET"), Some("/jobs")) => ("200 OK", render_jobs(&global_state).await),
+        (Some("POST"), Some(path)) if path.starts_with("/jobs/") => {
+            control_job(&global_state, peer, path).await
+        }
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// pauses or resumes a job, only for clients on the local host
+async fn control_job(
+    global_state: &GlobalState,
+    peer: SocketAddr,
+    path: &str,
+) -> (&'static str, String) {
+    if !peer.ip().is_loopback() {
+        return (
+            "403 Forbidden",
+            "Jobs can only be paused or resumed from the local host\n".into(),
+        );
+    }
+    let paused = match path.trim_start_matches("/jobs/").rsplit_once('/') {
+        Some((job_name, "pause")) => Some((job_name, true)),
+        Some((job_name, "resume")) => Some((job_name, false)),
+        _ => None,
+    };
+    let Some((job_name, paused)) = paused else {
+        return ("404 Not Found", "Not Found\n".to_string());
+    };
+
+    let job_name = percent_decode(job_name);
+    match pause::set_paused(global_state, &job_name, paused).await {
+        Ok(()) => (
+            "200 OK",
+            format!(
+                "Job '{}' is {}\n",
+                job_name,
+                match paused {
+                    true => "paused",
+                    false => "active",
+                }
+            ),
+        ),
+        Err(e) => ("404 Not Found", format!("{}\n", e)),
+    }
+}
+
+/// renders the status of every job of the daemon as JSON lines
+async fn render_jobs(global_state: &GlobalState) -> String {
+    let mut output = String::new();
+    for status in status::job_statuses(global_state).await {
+        match serde_json::to_string(&status) {
+            Ok(line) => {
+                let _ = writeln!(output, "{}", line);
+            }
+            Err(e) => warn!("Failed to serialize status of job '{}': {}", status.name, e),
+        }
+    }
+    output
+}
+
+/// decodes the `%XX` escapes of a path segment
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => s
+                .get(i + 1..i + 3)
+                .and_then(|x| u8::from_str_radix(x, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// renders the runtime and process metrics in the prometheus text format
+fn render_metrics(span_tracker: &SpanTracker, global_state: &GlobalState) -> String {
+    let runtime = tokio::runtime::Handle::current().metrics();
+    let process = ProcessStats::read().unwrap_or_else(|e| {
+        warn!("Failed to read process stats: {}", e);
+        ProcessStats::default()
+    });
+
+    let metrics: Vec<(&str, &str, &str, f64)> = vec![
+        (
+            "xenbakd_tokio_workers",
+            "gauge",
+            "Number of worker threads of the tokio runtime",
+            runtime.num_workers() as f64,
+        ),
+        (
+            "xenbakd_tokio_alive_tasks",
+            "gauge",
+            "Number of tasks which are alive in the tokio runtime",
+            runtime.num_alive_tasks() as f64,
+        ),
+        (
+            "xenbakd_open_spans",
+            "gauge",
+            "Number of open tracing spans, see /debug/tasks",
+            span_tracker.count() as f64,
+        ),
+        (
+            "xenbakd_paused_jobs",
+            "gauge",
+            "Number of jobs paused at runtime, see /jobs",
+            global_state.paused.count() as f64,
+        ),
+        (
+            "xenbakd_process_threads",
+            "gauge",
+            "Number of threads of the process",
+            process.threads as f64,
+        ),
+        (
+            "xenbakd_process_blocked_threads",
+            "gauge",
+            "Number of threads in uninterruptible sleep, usually waiting for I/O (e.g. a hanging NFS mount)",
+            process.blocked_threads as f64,
+        ),
+        (
+            "xenbakd_process_open_fds",
+            "gauge",
+            "Number of open file descriptors",
+            process.open_fds as f64,
+        ),
+        (
+            "xenbakd_process_child_processes",
+            "gauge",
+            "Number of child processes (xe, borg, encryption commands, ...)",
+            process.child_processes as f64,
+        ),
+        (
+            "xenbakd_process_resident_memory_bytes",
+            "gauge",
+            "Resident memory size in bytes",
+            read_rss().unwrap_or_default() as f64,
+        ),
+        (
+            "xenbakd_process_cpu_seconds_total",
+            "counter",
+            "Total user and system cpu time in seconds",
+            read_cpu_time().unwrap_or_default(),
+        ),
+    ];
+
+    let mut output = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} {}", name, kind);
+        let _ = writeln!(output, "{} {}", name, value);
+    }
+    output
+}
+
+#[derive(Debug, Default)]
+struct ProcessStats {
+    threads: u64,
+    blocked_threads: u64,
+    open_fds: u64,
+    child_processes: u64,
+}
+
+impl ProcessStats {
+    fn read() -> eyre::Result<ProcessStats> {
+        let mut stats = ProcessStats::default();
+
+        for task in std::fs::read_dir("/proc/self/task")? {
+            stats.threads += 1;
+            let stat = std::fs::read_to_string(task?.path().join("stat")).unwrap_or_default();
+            if stat_fields(&stat).first() == Some(&"D") {
+                stats.blocked_threads += 1;
+            }
+        }
+
+        stats.open_fds = std::fs::read_dir("/proc/self/fd")?.count() as u64;
+
+        // the parent pid is the second field after the command name
+        let pid = std::process::id().to_string();
+        for entry in std::fs::read_dir("/proc")? {
+            let path = entry?.path();
+            if !path
+                .file_name()
+                .is_some_and(|x| x.to_string_lossy().chars().all(|c| c.is_ascii_digit()))
+            {
+                continue;
+            }
+            let stat = std::fs::read_to_string(path.join("stat")).unwrap_or_default();
+            if stat_fields(&stat).get(1) == Some(&pid.as_str()) {
+                stats.child_processes += 1;
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+/// splits a procfs stat line into the fields following the command name, starting with the state
+fn stat_fields(stat: &str) -> Vec<&str> {
+    // the command name may contain spaces, so skip past its closing paren
+    stat.rsplit_once(')')
+        .map(|x| x.1.split_whitespace().collect())
+        .unwrap_or_default()
+}
+
+/// sends a request to the metrics endpoint of a running daemon, returns the body of the response
+async fn request(listen: &str, method: reqwest::Method, path: &[&str]) -> eyre::Result<String> {
+    // a daemon listening on all interfaces is reachable via loopback
+    let address = match listen.rsplit_once(':') {
+        Some(("0.0.0.0", port)) => format!("127.0.0.1:{}", port),
+        Some(("[::]", port)) => format!("[::1]:{}", port),
+        _ => listen.to_string(),
+    };
+
+    let mut url = reqwest::Url::parse(&format!("http://{}/", address))?;
+    url.path_segments_mut()
+        .map_err(|_| eyre::eyre!("Invalid metrics endpoint '{}'", listen))?
+        .clear()
+        .extend(path);
+    let response = reqwest::Client::new().request(method, url).send().await?;
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        return Err(eyre::eyre!(
+            "Metrics endpoint returned {}: {}",
+            status,
+            body.trim()
+        ));
+    }
+
+    Ok(body)
+}
+
+/// fetches the open spans from the metrics endpoint of a running daemon
+pub async fn fetch_tasks(listen: &str) -> eyre::Result<String> {
+    request(listen, reqwest::Method::GET, &["debug", "tasks"]).await
+}
+
+/// fetches the status of the jobs from the metrics endpoint of a running daemon
+pub async fn fetch_jobs(listen: &str) -> eyre::Result<Vec<JobStatus>> {
+    let body = request(listen, reqwest::Method::GET, &["jobs"]).await?;
+    body.lines().map(|x| Ok(serde_json::from_str(x)?)).collect()
+}
+
+/// pauses or resumes a job of a running daemon
+pub async fn set_job_paused(listen: &str, job_name: &str, paused: bool) -> eyre::Result<String> {
+    let action = match paused {
+        true => "pause",
+        false => "resume",
+    };
+    request(listen, reqwest::Method::POST, &["jobs", job_name, action]).await
+}
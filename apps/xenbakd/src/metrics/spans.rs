@@ -0,0 +1,118 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// a span which hasn't been closed yet
+#[derive(Debug, Clone)]
+struct OpenSpan {
+    name: &'static str,
+    target: String,
+    fields: String,
+    parent: Option<u64>,
+    opened: chrono::DateTime<chrono::Utc>,
+}
+
+/// tracing layer keeping track of all open spans, so hanging jobs can be inspected at runtime.
+/// every backup of a VM runs in its own span, which makes the spans a good stand-in for tasks
+#[derive(Debug, Clone, Default)]
+pub struct SpanTracker {
+    spans: Arc<Mutex<BTreeMap<u64, OpenSpan>>>,
+}
+
+struct FieldVisitor<'a>(&'a mut String);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        let _ = write!(self.0, "{}={:?}", field.name(), value);
+    }
+}
+
+impl SpanTracker {
+    pub fn count(&self) -> usize {
+        self.spans.lock().unwrap().len()
+    }
+
+    /// renders the open spans as a tree, children indented below their parents
+    pub fn dump(&self) -> String {
+        let spans = self.spans.lock().unwrap().clone();
+        let now = chrono::Utc::now();
+
+        let mut children: BTreeMap<Option<u64>, Vec<u64>> = BTreeMap::new();
+        for (id, span) in &spans {
+            // parents may already be closed while their children live on, e.g. spawned tasks
+            let parent = span.parent.filter(|x| spans.contains_key(x));
+            children.entry(parent).or_default().push(*id);
+        }
+
+        let mut output = format!("{} open spans\n", spans.len());
+        let mut stack: Vec<(u64, usize)> = children
+            .get(&None)
+            .map(|x| x.iter().rev().map(|id| (*id, 0)).collect())
+            .unwrap_or_default();
+        while let Some((id, depth)) = stack.pop() {
+            let span = &spans[&id];
+            let _ = writeln!(
+                output,
+                "{}{} [{}] {} (open for {}s)",
+                "  ".repeat(depth),
+                span.name,
+                span.target,
+                span.fields,
+                (now - span.opened).num_seconds()
+            );
+            if let Some(ids) = children.get(&Some(id)) {
+                stack.extend(ids.iter().rev().map(|id| (*id, depth + 1)));
+            }
+        }
+
+        output
+    }
+}
+
+impl<S> Layer<S> for SpanTracker
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = String::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+
+        let parent = if attrs.is_contextual() {
+            ctx.current_span().id().map(|x| x.into_u64())
+        } else {
+            attrs.parent().map(|x| x.into_u64())
+        };
+
+        self.spans.lock().unwrap().insert(
+            id.into_u64(),
+            OpenSpan {
+                name: attrs.metadata().name(),
+                target: attrs.metadata().target().to_string(),
+                fields,
+                parent,
+                opened: chrono::Utc::now(),
+            },
+        );
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, _ctx: Context<'_, S>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&id.into_u64()) {
+            values.record(&mut FieldVisitor(&mut span.fields));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, _ctx: Context<'_, S>) {
+        self.spans.lock().unwrap().remove(&id.into_u64());
+    }
+}
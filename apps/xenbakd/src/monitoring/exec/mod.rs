@@ -0,0 +1,125 @@
+use std::process::Stdio;
+
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+
+use crate::{config::ExecNotifierConfig, jobs::XenbakJobStats};
+
+use super::MonitoringTrait;
+
+/// payload written to the command's stdin
+#[derive(Debug, Serialize)]
+struct ExecNotifierPayload<'a> {
+    event: &'a str,
+    job_name: &'a str,
+    job_stats: Option<&'a XenbakJobStats>,
+}
+
+/// runs a user-specified command for every event, passing the event as JSON on stdin
+#[derive(Debug, Clone)]
+pub struct ExecNotifier {
+    config: ExecNotifierConfig,
+}
+
+impl ExecNotifier {
+    pub fn from_config(config: ExecNotifierConfig) -> Self {
+        ExecNotifier { config }
+    }
+
+    async fn notify(
+        &self,
+        event: &str,
+        job_name: &str,
+        job_stats: Option<&XenbakJobStats>,
+    ) -> eyre::Result<()> {
+        let payload = serde_json::to_vec(&ExecNotifierPayload {
+            event,
+            job_name,
+            job_stats,
+        })?;
+
+        let mut command = tokio::process::Command::new(&self.config.command);
+        command
+            .args(&self.config.args)
+            .env("XENBAKD_EVENT", event)
+            .env("XENBAKD_JOB", job_name)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let run = async {
+            let mut child = command.spawn()?;
+            // a command which doesn't read its stdin closes the pipe early, that's fine
+            let mut stdin = child.stdin.take().unwrap();
+            let _ = stdin.write_all(&payload).await;
+            drop(stdin);
+
+            let output = child.wait_with_output().await?;
+            if !output.status.success() {
+                return Err(eyre::eyre!(
+                    "exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Ok(())
+        };
+
+        let timeout = std::time::Duration::from_secs(self.config.timeout);
+        match tokio::time::timeout(timeout, run).await {
+            Ok(result) => result,
+            Err(_) => Err(eyre::eyre!(
+                "timed out after {} seconds",
+                self.config.timeout
+            )),
+        }
+    }
+
+    /// a failing notifier must not affect the job or the other notifiers, so errors are only logged
+    async fn notify_logged(&self, event: &str, job_name: &str, job_stats: Option<&XenbakJobStats>) {
+        debug!(
+            "Running exec notifier '{}' for event '{}'",
+            self.config.name, event
+        );
+        if let Err(e) = self.notify(event, job_name, job_stats).await {
+            warn!(
+                "Exec notifier '{}' failed for event '{}' of job '{}': {}",
+                self.config.name, event, job_name, e
+            );
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl MonitoringTrait for ExecNotifier {
+    async fn start(&self, job_name: String) -> eyre::Result<()> {
+        self.notify_logged("start", &job_name, None).await;
+        Ok(())
+    }
+
+    async fn success(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.notify_logged("success", &job_name, Some(&job_stats))
+            .await;
+        Ok(())
+    }
+
+    async fn warning(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.notify_logged("warning", &job_name, Some(&job_stats))
+            .await;
+        Ok(())
+    }
+
+    async fn failure(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.notify_logged("failure", &job_name, Some(&job_stats))
+            .await;
+        Ok(())
+    }
+
+    async fn quarantine(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.notify_logged("quarantine", &job_name, Some(&job_stats))
+            .await;
+        Ok(())
+    }
+}
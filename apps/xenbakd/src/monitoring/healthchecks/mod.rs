@@ -1,11 +1,13 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use eyre::ContextCompat;
+use reqwest::StatusCode;
 use reqwest::{header::HeaderMap, Url};
 use reqwest_middleware::ClientWithMiddleware;
 use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
 
-use tracing::debug;
+use tokio::sync::RwLock;
+use tracing::{debug, info};
 
 mod types;
 
@@ -25,7 +27,8 @@ pub struct HealthchecksService {
     config: HealthchecksConfig,
     server: Url,
     client: ClientWithMiddleware,
-    checks: HashMap<String, HealthchecksCheckInfo>,
+    checks: Arc<RwLock<HashMap<String, HealthchecksCheckInfo>>>,
+    jobs: Arc<RwLock<HashMap<String, JobConfig>>>,
 }
 
 impl HealthchecksService {
@@ -46,7 +49,8 @@ impl HealthchecksService {
             config: config.clone(),
             client,
             server: Url::parse(&config.server).expect("Failed to parse healthchecks.io server url"),
-            checks: HashMap::new(),
+            checks: Arc::new(RwLock::new(HashMap::new())),
+            jobs: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
@@ -66,72 +70,160 @@ impl HealthchecksService {
     }
 
     async fn generate_slug(&self, job_name: String) -> String {
-        format!("{}", job_name)
+        job_name
     }
-}
 
-#[async_trait::async_trait]
-impl MonitoringTrait for HealthchecksService {
-    async fn success(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
-        debug!("Sending success notification for job '{}'", job_name);
+    /// creates the check for a job, or updates it if it already exists
+    async fn create_or_update_check(&self, job: &JobConfig) -> eyre::Result<HealthchecksCheckInfo> {
+        let tags = [""].join(" ");
+        let name = self.generate_slug(job.name.clone()).await;
+        let slug = name.clone();
+        let grace = self.config.grace;
+        let schedule = job
+            .schedule
+            .split_whitespace()
+            .skip(1)
+            .collect::<Vec<&str>>()
+            .join(" ");
 
-        let check = self
-            .checks
-            .get(&self.generate_slug(job_name).await)
-            .context("Check not found")?;
+        let mut url = self.server.clone();
+        url.set_path("/api/v2/checks/");
 
-        let uuid = check.ping_url.split('/').last().unwrap();
+        let request = HealthchecksCreateCheckRequest {
+            name: name.clone(),
+            tags,
+            schedule,
+            grace,
+            timeout: 86400,
+            slug,
+            unique: vec!["name".into()],
+        };
+
+        let response: HealthchecksCheckInfo = self
+            .client
+            .post(url)
+            .headers(self.generate_auth_header().await?)
+            .json(&request)
+            .send()
+            .await?
+            .json()
+            .await?;
 
-        let mut url = self.server.clone();
-        url.set_path(&format!("/ping/{}", uuid));
-        self.client.post(url).json(&job_stats).send().await?;
+        self.checks.write().await.insert(name, response.clone());
 
-        Ok(())
+        Ok(response)
     }
 
-    async fn start(&self, job_name: String) -> eyre::Result<()> {
-        debug!("Sending start notification for job '{}' ", job_name);
+    /// recreates the check of a job which is missing locally or was deleted on the server
+    async fn recreate_check(&self, job_name: &str) -> eyre::Result<HealthchecksCheckInfo> {
+        let job = self
+            .jobs
+            .read()
+            .await
+            .get(job_name)
+            .cloned()
+            .context("Job not found")?;
+
+        info!(
+            "Recreating missing healthchecks.io check for job '{}'",
+            job_name
+        );
+        self.create_or_update_check(&job).await
+    }
 
-        let check = self
-            .checks
-            .get(&self.generate_slug(job_name).await)
-            .context("Check not found")?;
+    /// the check of a job, recreating it if it is missing locally
+    async fn get_check(&self, job_name: &str) -> eyre::Result<HealthchecksCheckInfo> {
+        let slug = self.generate_slug(job_name.to_string()).await;
+        let check = self.checks.read().await.get(&slug).cloned();
+        match check {
+            Some(check) => Ok(check),
+            None => self.recreate_check(&slug).await,
+        }
+    }
 
-        let uuid = check.ping_url.split('/').last().unwrap();
+    /// pings the check of a job, recreating the check if it doesn't exist (anymore)
+    async fn ping(
+        &self,
+        job_name: String,
+        suffix: &str,
+        job_stats: Option<&XenbakJobStats>,
+    ) -> eyre::Result<()> {
+        let slug = self.generate_slug(job_name).await;
 
-        let mut url = self.server.clone();
-        url.set_path(&format!("/ping/{}/start", uuid));
-        self.client.post(url).send().await?;
+        let check = self.checks.read().await.get(&slug).cloned();
+        let mut check = match check {
+            Some(check) => check,
+            None => self.recreate_check(&slug).await?,
+        };
+
+        for attempt in 0..2 {
+            let uuid = check.ping_url.split('/').next_back().unwrap();
+
+            let mut url = self.server.clone();
+            url.set_path(&format!("/ping/{}{}", uuid, suffix));
+
+            let mut request = self.client.post(url);
+            if let Some(job_stats) = job_stats {
+                request = request.json(job_stats);
+            }
+            let response = request.send().await?;
+
+            // the check was deleted on the server, recreate it and try again
+            if response.status() == StatusCode::NOT_FOUND && attempt == 0 {
+                check = self.recreate_check(&slug).await?;
+                continue;
+            }
+
+            response.error_for_status()?;
+            break;
+        }
 
         Ok(())
     }
+}
 
-    async fn failure(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
-        debug!("Sending failure notification for job '{}'", job_name);
+#[async_trait::async_trait]
+impl MonitoringTrait for HealthchecksService {
+    async fn success(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        debug!("Sending success notification for job '{}'", job_name);
+        self.ping(job_name, "", Some(&job_stats)).await
+    }
 
-        let check = self
-            .checks
-            .get(&self.generate_slug(job_name).await)
-            .context("Check not found")?;
+    async fn warning(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        // healthchecks.io has no warning state, the check stays up and the stats carry the outcome
+        debug!("Sending warning notification for job '{}'", job_name);
+        self.ping(job_name, "", Some(&job_stats)).await
+    }
 
-        let uuid = check.ping_url.split('/').last().unwrap();
+    async fn start(&self, job_name: String) -> eyre::Result<()> {
+        debug!("Sending start notification for job '{}' ", job_name);
+        self.ping(job_name, "/start", None).await
+    }
 
-        let mut url = self.server.clone();
-        url.set_path(&format!("/ping/{}/fail", uuid));
-        self.client.post(url).json(&job_stats).send().await?;
+    async fn failure(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        debug!("Sending failure notification for job '{}'", job_name);
+        self.ping(job_name, "/fail", Some(&job_stats)).await
+    }
 
-        Ok(())
+    async fn quarantine(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        // logged to the check's events without changing its state, the run has pinged already
+        debug!("Sending quarantine notification for job '{}'", job_name);
+        self.ping(job_name, "/log", Some(&job_stats)).await
     }
 }
 
 #[async_trait::async_trait]
 pub trait HealthchecksManagementApiTrait {
+    #[allow(dead_code)]
     async fn list_checks(
         &self,
         tag_filter: Option<Vec<String>>,
         slug_filter: Option<String>,
     ) -> eyre::Result<HealthchecksListChecksResponse>;
     async fn initialize(&mut self, jobs: Vec<JobConfig>) -> eyre::Result<()>;
+    /// pauses the check of a paused job, so its skipped runs don't raise alerts
+    async fn pause_check(&self, job_name: &str) -> eyre::Result<()>;
+    async fn resume_check(&self, job_name: &str) -> eyre::Result<()>;
 }
 
 #[async_trait::async_trait]
@@ -179,43 +271,35 @@ impl HealthchecksManagementApiTrait for HealthchecksService {
     async fn initialize(&mut self, jobs: Vec<JobConfig>) -> eyre::Result<()> {
         // iterate over configured jobs, update or create checks
         for job in jobs {
-            let tags = vec![""].join(" ");
-            let name = self.generate_slug(job.name.clone()).await;
-            let slug = name.clone();
-            let grace = self.config.grace;
-            let schedule = job
-                .schedule
-                .split_whitespace()
-                .skip(1)
-                .collect::<Vec<&str>>()
-                .join(" ");
-
-            let mut url = self.server.clone();
-            url.set_path(&format!("/api/v2/checks/"));
-
-            let request = HealthchecksCreateCheckRequest {
-                name: name.clone(),
-                tags,
-                schedule,
-                grace,
-                timeout: 86400,
-                slug,
-                unique: vec!["name".into()],
-            };
-
-            let response: HealthchecksCheckInfo = self
-                .client
-                .post(url)
-                .headers(self.generate_auth_header().await?)
-                .json(&request)
-                .send()
-                .await?
-                .json()
-                .await?;
-
-            self.checks.insert(name.clone(), response);
+            self.jobs
+                .write()
+                .await
+                .insert(self.generate_slug(job.name.clone()).await, job.clone());
+            self.create_or_update_check(&job).await?;
         }
 
         Ok(())
     }
+
+    async fn pause_check(&self, job_name: &str) -> eyre::Result<()> {
+        let check = self.get_check(job_name).await?;
+        self.client
+            .post(&check.pause_url)
+            .headers(self.generate_auth_header().await?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn resume_check(&self, job_name: &str) -> eyre::Result<()> {
+        let check = self.get_check(job_name).await?;
+        self.client
+            .post(&check.resume_url)
+            .headers(self.generate_auth_header().await?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
 }
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Clone)]
 pub struct HealthchecksCheckInfo {
     pub name: String,
@@ -29,6 +30,7 @@ pub struct HealthchecksCheckInfo {
     pub timeout: Option<u64>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct HealthchecksListChecksResponse {
     pub checks: Vec<HealthchecksCheckInfo>,
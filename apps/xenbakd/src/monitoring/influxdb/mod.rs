@@ -0,0 +1,148 @@
+use std::fmt::Write;
+
+use reqwest::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE};
+use tracing::debug;
+
+use crate::{
+    config::InfluxDbConfig,
+    jobs::{ObjectStatus, XenbakJobStats},
+};
+
+use super::{outcome_code, MonitoringTrait};
+
+/// writes a measurement of every run and of every VM of the run to an influxdb v2 bucket
+#[derive(Debug, Clone)]
+pub struct InfluxDbService {
+    config: InfluxDbConfig,
+    client: reqwest::Client,
+}
+
+impl InfluxDbService {
+    pub fn from_config(config: InfluxDbConfig) -> eyre::Result<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Token {}", config.token).parse()?);
+        headers.insert(CONTENT_TYPE, "text/plain; charset=utf-8".parse()?);
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(format!("xenbakd/{}", env!("CARGO_PKG_VERSION")))
+            .default_headers(headers)
+            .timeout(std::time::Duration::from_secs(config.timeout))
+            .build()?;
+
+        Ok(InfluxDbService { config, client })
+    }
+
+    async fn write(&self, lines: String) -> eyre::Result<()> {
+        let mut url = reqwest::Url::parse(&self.config.url)?;
+        url.set_path("/api/v2/write");
+        url.query_pairs_mut()
+            .append_pair("org", &self.config.org)
+            .append_pair("bucket", &self.config.bucket)
+            .append_pair("precision", "s");
+
+        debug!("Writing {} lines to influxdb", lines.lines().count());
+        let response = self.client.post(url).body(lines).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(eyre::eyre!(
+                "InfluxDB returned {}: {}",
+                status,
+                response.text().await.unwrap_or_default().trim()
+            ));
+        }
+        Ok(())
+    }
+
+    /// one `<prefix>_job` line for the run and one `<prefix>_vm` line per VM of the run
+    fn format_lines(&self, job_name: &str, job_stats: &XenbakJobStats) -> String {
+        let timestamp = chrono::Utc::now().timestamp();
+        let mut lines = String::new();
+
+        let _ = writeln!(
+            lines,
+            "{}_job,job={},job_type={},outcome={} duration={},total_objects={}i,successful_objects={}i,\
+             failed_objects={}i,skipped_objects={}i,retried_objects={}i,exported_bytes={}i,\
+             estimated_bytes={}i,result={}i {}",
+            escape_key(&self.config.measurement_prefix),
+            escape_key(job_name),
+            escape_key(&job_stats.config.job_type.to_string()),
+            job_stats.outcome,
+            job_stats.duration,
+            job_stats.total_objects,
+            job_stats.successful_objects,
+            job_stats.failed_objects,
+            job_stats.skipped_objects,
+            job_stats.retried_objects,
+            job_stats.exported_bytes,
+            job_stats.estimated_bytes,
+            outcome_code(job_stats.outcome),
+            timestamp
+        );
+
+        for object in &job_stats.object_results {
+            let mut tags = format!(
+                "job={},vm={},xen_host={},status={}",
+                escape_key(job_name),
+                escape_key(&object.name),
+                escape_key(&object.xen_host),
+                match object.status {
+                    ObjectStatus::Success => "success",
+                    ObjectStatus::Failed => "failed",
+                    ObjectStatus::Skipped => "skipped",
+                }
+            );
+            // tags can't be empty
+            if !object.uuid.is_empty() {
+                let _ = write!(tags, ",uuid={}", escape_key(&object.uuid));
+            }
+            if let Some(pool) = job_stats.pools.get(&object.xen_host) {
+                let _ = write!(tags, ",pool={}", escape_key(pool));
+            }
+            let _ = writeln!(
+                lines,
+                "{}_vm,{} duration={},exported_bytes={}i,stored_bytes={}i,success={}i {}",
+                escape_key(&self.config.measurement_prefix),
+                tags,
+                object.duration,
+                object.exported_bytes,
+                object.stored_bytes,
+                (object.status == ObjectStatus::Success) as u8,
+                timestamp
+            );
+        }
+
+        lines
+    }
+}
+
+/// escapes a measurement, tag key or tag value of the line protocol
+fn escape_key(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+        .replace('\n', "\\n")
+}
+
+#[async_trait::async_trait]
+impl MonitoringTrait for InfluxDbService {
+    async fn start(&self, _job_name: String) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    async fn success(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.write(self.format_lines(&job_name, &job_stats)).await
+    }
+
+    async fn warning(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.write(self.format_lines(&job_name, &job_stats)).await
+    }
+
+    async fn failure(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.write(self.format_lines(&job_name, &job_stats)).await
+    }
+
+    async fn quarantine(&self, _job_name: String, _job_stats: XenbakJobStats) -> eyre::Result<()> {
+        // the failed VMs are already written with the run
+        Ok(())
+    }
+}
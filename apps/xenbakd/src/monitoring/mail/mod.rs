@@ -1,4 +1,7 @@
-use crate::{config::MailConfig, jobs::XenbakJobStats};
+use crate::{
+    config::MailConfig,
+    jobs::{ObjectStatus, XenbakJobStats},
+};
 
 use lettre::{AsyncSmtpTransport, AsyncTransport};
 
@@ -54,6 +57,41 @@ impl MailService {
     }
 }
 
+/// one line per VM of the run, so the outcome can be read without digging through the stats
+fn format_object_results(job_stats: &XenbakJobStats) -> String {
+    let mut lines = vec![];
+    for result in &job_stats.object_results {
+        let mut line = format!(
+            "{} [{}] on {}: {}",
+            result.name,
+            result.uuid,
+            result.xen_host,
+            match result.status {
+                ObjectStatus::Success => "success",
+                ObjectStatus::Failed => "failed",
+                ObjectStatus::Skipped => "skipped",
+            }
+        );
+        if result.status == ObjectStatus::Success {
+            line.push_str(&format!(
+                " in {:.1}s, {:.1} MiB exported, {:.1} MiB stored on {}",
+                result.duration,
+                result.exported_bytes as f64 / 1024.0 / 1024.0,
+                result.stored_bytes as f64 / 1024.0 / 1024.0,
+                result.storages.join(", ")
+            ));
+        }
+        if let Some(error) = &result.error {
+            line.push_str(&format!(" ({})", error.replace('\n', ": ")));
+        }
+        lines.push(line);
+    }
+    match lines.is_empty() {
+        true => String::new(),
+        false => format!("{}\n\n", lines.join("\n")),
+    }
+}
+
 #[async_trait::async_trait]
 impl MonitoringTrait for MailService {
     async fn start(&self, _job_name: String) -> eyre::Result<()> {
@@ -63,11 +101,12 @@ impl MonitoringTrait for MailService {
     // Method to send an email
     async fn success(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
         // pretty print the job_stats object
+        let object_results = format_object_results(&job_stats);
         let job_stats = serde_json::to_string_pretty(&job_stats)?;
 
         let body = format!(
-            "Backup Job '{}' succeeded.\n\nStats: {}",
-            job_name, job_stats
+            "Backup Job '{}' succeeded.\n\n{}Stats: {}",
+            job_name, object_results, job_stats
         );
 
         let email = lettre::Message::builder()
@@ -82,9 +121,35 @@ impl MonitoringTrait for MailService {
         }
     }
 
+    async fn warning(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        let body = format!(
+            "Backup Job '{}' succeeded, but {} of {} objects failed.\n\n{}Stats: {}",
+            job_name,
+            job_stats.failed_objects,
+            job_stats.total_objects,
+            format_object_results(&job_stats),
+            serde_json::to_string_pretty(&job_stats)?
+        );
+
+        let email = lettre::Message::builder()
+            .from(self.from.parse()?)
+            .to(self.to.parse()?)
+            .subject(format!("xenbakd | Warning: Backup Job '{}'", job_name).as_str())
+            .body(body)?;
+
+        match self.mailer.send(email).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(eyre::eyre!("Failed to send email: {}", e)),
+        }
+    }
+
     async fn failure(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        let object_results = format_object_results(&job_stats);
         let job_stats = serde_json::to_string_pretty(&job_stats)?;
-        let body = format!("Backup Job '{}' failed\n\nStats: {}", job_name, job_stats);
+        let body = format!(
+            "Backup Job '{}' failed\n\n{}Stats: {}",
+            job_name, object_results, job_stats
+        );
 
         let email = lettre::Message::builder()
             .from(self.from.parse()?)
@@ -97,4 +162,26 @@ impl MonitoringTrait for MailService {
             Err(e) => Err(eyre::eyre!("Failed to send email: {}", e)),
         }
     }
+
+    async fn quarantine(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        let body = format!(
+            "Backup Job '{}' quarantined VMs which failed {} runs in a row, they are skipped until \
+             they are released with `xenbakd quarantine clear`:\n\n{}\n\nStats: {}",
+            job_name,
+            job_stats.config.quarantine_after.unwrap_or_default(),
+            job_stats.quarantined_objects.join("\n"),
+            serde_json::to_string_pretty(&job_stats)?
+        );
+
+        let email = lettre::Message::builder()
+            .from(self.from.parse()?)
+            .to(self.to.parse()?)
+            .subject(format!("xenbakd | Quarantine: Backup Job '{}'", job_name).as_str())
+            .body(body)?;
+
+        match self.mailer.send(email).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(eyre::eyre!("Failed to send email: {}", e)),
+        }
+    }
 }
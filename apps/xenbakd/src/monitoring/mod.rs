@@ -1,11 +1,39 @@
-use crate::jobs::XenbakJobStats;
+use crate::jobs::{JobOutcome, XenbakJobStats};
 
+pub mod exec;
 pub mod healthchecks;
+pub mod influxdb;
 pub mod mail;
+pub mod opsgenie;
+pub mod statsd;
+pub mod telegram;
 
 #[async_trait::async_trait]
 pub trait MonitoringTrait: Send + Sync {
     async fn success(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()>;
+    /// the run succeeded, but some of its objects failed within the job's failure threshold
+    async fn warning(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()>;
     async fn failure(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()>;
     async fn start(&self, job_name: String) -> eyre::Result<()>;
+    /// VMs of the run were quarantined, they failed too many runs in a row
+    async fn quarantine(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()>;
+}
+
+/// cuts the text to at most `max` characters, marking the cut with an ellipsis
+pub fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// the outcome as a number, which dashboards can aggregate: 0 success, 1 warning, 2 failure
+pub fn outcome_code(outcome: JobOutcome) -> u8 {
+    match outcome {
+        JobOutcome::Success => 0,
+        JobOutcome::Warning => 1,
+        JobOutcome::Failure => 2,
+    }
 }
@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+
+use reqwest::header::{HeaderMap, AUTHORIZATION};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::{config::OpsgenieConfig, jobs::XenbakJobStats};
+
+use super::{truncate, MonitoringTrait};
+
+/// opsgenie cuts longer messages
+const MAX_MESSAGE_LENGTH: usize = 130;
+/// opsgenie rejects longer descriptions
+const MAX_DESCRIPTION_LENGTH: usize = 15000;
+
+#[derive(Debug, Serialize)]
+struct OpsgenieCreateAlertRequest {
+    message: String,
+    /// alerts with the alias of an open alert are added to it instead of raising a new one
+    alias: String,
+    description: String,
+    priority: String,
+    entity: String,
+    source: String,
+    tags: Vec<String>,
+    details: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OpsgenieCloseAlertRequest {
+    source: String,
+    note: String,
+}
+
+/// raises an opsgenie alert for every failed run, deduplicated by job, and closes it once the
+/// job succeeds again
+#[derive(Debug, Clone)]
+pub struct OpsgenieService {
+    config: OpsgenieConfig,
+    client: reqwest::Client,
+}
+
+impl OpsgenieService {
+    pub fn from_config(config: OpsgenieConfig) -> eyre::Result<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            format!("GenieKey {}", config.api_key).parse()?,
+        );
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(format!("xenbakd/{}", env!("CARGO_PKG_VERSION")))
+            .default_headers(headers)
+            .timeout(std::time::Duration::from_secs(30))
+            .build()?;
+
+        Ok(OpsgenieService { config, client })
+    }
+
+    /// the alias of the alerts of a job's runs, a failing job keeps a single open alert
+    fn alias(job_name: &str) -> String {
+        format!("xenbakd-{}", job_name)
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.api_url.trim_end_matches('/'), path)
+    }
+
+    /// opsgenie processes requests asynchronously, it only answers whether it accepted them
+    async fn post<T: Serialize>(&self, url: String, body: &T) -> eyre::Result<()> {
+        let response = self.client.post(url).json(body).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(eyre::eyre!(
+                "Opsgenie returned {}: {}",
+                status,
+                response.text().await.unwrap_or_default().trim()
+            ));
+        }
+        Ok(())
+    }
+
+    async fn create_alert(
+        &self,
+        alias: String,
+        message: String,
+        description: String,
+        priority: String,
+        job_name: &str,
+        job_stats: &XenbakJobStats,
+    ) -> eyre::Result<()> {
+        debug!("Raising opsgenie alert '{}'", alias);
+        let details = BTreeMap::from([
+            ("job".to_string(), job_name.to_string()),
+            ("outcome".to_string(), job_stats.outcome.to_string()),
+            (
+                "failed_objects".to_string(),
+                job_stats.failed_objects.to_string(),
+            ),
+            (
+                "total_objects".to_string(),
+                job_stats.total_objects.to_string(),
+            ),
+        ]);
+
+        self.post(
+            self.url("/v2/alerts"),
+            &OpsgenieCreateAlertRequest {
+                message: truncate(&message, MAX_MESSAGE_LENGTH),
+                alias,
+                description: truncate(&description, MAX_DESCRIPTION_LENGTH),
+                priority,
+                entity: job_name.to_string(),
+                source: "xenbakd".into(),
+                tags: self.config.tags.clone(),
+                details,
+            },
+        )
+        .await
+    }
+
+    /// closing an alert which isn't open is a no-op on opsgenie's side
+    async fn close_alert(&self, alias: &str, note: String) -> eyre::Result<()> {
+        debug!("Closing opsgenie alert '{}'", alias);
+        let mut url = reqwest::Url::parse(&self.url("/v2/alerts/"))?;
+        url.path_segments_mut()
+            .map_err(|_| eyre::eyre!("Invalid opsgenie api url '{}'", self.config.api_url))?
+            .pop_if_empty()
+            .extend([alias, "close"]);
+        url.query_pairs_mut().append_pair("identifierType", "alias");
+
+        self.post(
+            url.to_string(),
+            &OpsgenieCloseAlertRequest {
+                source: "xenbakd".into(),
+                note,
+            },
+        )
+        .await
+    }
+}
+
+/// the summary of the run and its errors, one per line
+fn format_description(job_stats: &XenbakJobStats) -> String {
+    let mut description = format_summary(job_stats);
+    for error in &job_stats.errors {
+        description.push_str(&format!("\n- {}", error.trim().replace('\n', ": ")));
+    }
+    description
+}
+
+/// one line with the objects and the duration of the run
+fn format_summary(job_stats: &XenbakJobStats) -> String {
+    format!(
+        "{} of {} objects succeeded, {} failed, {} skipped in {:.0} seconds",
+        job_stats.successful_objects,
+        job_stats.total_objects,
+        job_stats.failed_objects,
+        job_stats.skipped_objects,
+        job_stats.duration
+    )
+}
+
+#[async_trait::async_trait]
+impl MonitoringTrait for OpsgenieService {
+    async fn start(&self, _job_name: String) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    async fn success(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.close_alert(
+            &Self::alias(&job_name),
+            format!("Job succeeded: {}", format_summary(&job_stats)),
+        )
+        .await
+    }
+
+    async fn warning(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        if !self.config.alert_on_warning {
+            // the job ran, an alert of a previous failure is resolved
+            return self.success(job_name, job_stats).await;
+        }
+        self.create_alert(
+            Self::alias(&job_name),
+            format!(
+                "xenbakd | Warning: Backup Job '{}', {} of {} objects failed",
+                job_name, job_stats.failed_objects, job_stats.total_objects
+            ),
+            format_description(&job_stats),
+            self.config.warning_priority.clone(),
+            &job_name,
+            &job_stats,
+        )
+        .await
+    }
+
+    async fn failure(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.create_alert(
+            Self::alias(&job_name),
+            format!("xenbakd | Failure: Backup Job '{}'", job_name),
+            format_description(&job_stats),
+            self.config.priority.clone(),
+            &job_name,
+            &job_stats,
+        )
+        .await
+    }
+
+    /// quarantined VMs stay skipped after successful runs, their alert is closed by hand
+    async fn quarantine(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.create_alert(
+            format!("{}-quarantine", Self::alias(&job_name)),
+            format!(
+                "xenbakd | Quarantine: Backup Job '{}' quarantined {} VMs",
+                job_name,
+                job_stats.quarantined_objects.len()
+            ),
+            format!(
+                "VMs which failed {} runs in a row are skipped until they are released with \
+                 `xenbakd quarantine clear`:\n{}",
+                job_stats.config.quarantine_after.unwrap_or_default(),
+                job_stats.quarantined_objects.join("\n")
+            ),
+            self.config.priority.clone(),
+            &job_name,
+            &job_stats,
+        )
+        .await
+    }
+}
@@ -0,0 +1,234 @@
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+};
+use tracing::debug;
+
+use crate::{
+    config::{StatsdConfig, StatsdProtocol},
+    jobs::{ObjectStatus, XenbakJobStats},
+};
+
+use super::{outcome_code, MonitoringTrait};
+
+/// statsd packets are kept below the usual MTU, so they aren't fragmented
+const MAX_PACKET_SIZE: usize = 1432;
+/// seconds a graphite connection may take
+const GRAPHITE_TIMEOUT: u64 = 30;
+
+#[derive(Debug, Clone, Copy)]
+enum MetricKind {
+    Gauge,
+    /// milliseconds in statsd, seconds in graphite
+    Timing,
+    Counter,
+}
+
+#[derive(Debug)]
+struct Metric {
+    name: String,
+    value: f64,
+    kind: MetricKind,
+}
+
+/// sends the metrics of every run and its VMs to statsd or graphite
+#[derive(Debug, Clone)]
+pub struct StatsdService {
+    config: StatsdConfig,
+}
+
+impl StatsdService {
+    pub fn from_config(config: StatsdConfig) -> Self {
+        StatsdService { config }
+    }
+
+    fn metrics(&self, job_name: &str, job_stats: &XenbakJobStats) -> Vec<Metric> {
+        let job = format!("{}.jobs.{}", self.config.prefix, sanitize(job_name));
+        let metric = |name: &str, value: f64, kind: MetricKind| Metric {
+            name: format!("{}.{}", job, name),
+            value,
+            kind,
+        };
+        let mut metrics = vec![
+            metric("duration", job_stats.duration, MetricKind::Timing),
+            metric(
+                "total_objects",
+                job_stats.total_objects as f64,
+                MetricKind::Gauge,
+            ),
+            metric(
+                "successful_objects",
+                job_stats.successful_objects as f64,
+                MetricKind::Gauge,
+            ),
+            metric(
+                "failed_objects",
+                job_stats.failed_objects as f64,
+                MetricKind::Gauge,
+            ),
+            metric(
+                "skipped_objects",
+                job_stats.skipped_objects as f64,
+                MetricKind::Gauge,
+            ),
+            metric(
+                "exported_bytes",
+                job_stats.exported_bytes as f64,
+                MetricKind::Gauge,
+            ),
+            metric(
+                "result",
+                outcome_code(job_stats.outcome) as f64,
+                MetricKind::Gauge,
+            ),
+            metric(
+                &format!("runs.{}", job_stats.outcome),
+                1.0,
+                MetricKind::Counter,
+            ),
+        ];
+
+        for object in &job_stats.object_results {
+            let vm = format!(
+                "{}.vms.{}.{}",
+                self.config.prefix,
+                sanitize(job_name),
+                sanitize(&object.name)
+            );
+            let metric = |name: &str, value: f64, kind: MetricKind| Metric {
+                name: format!("{}.{}", vm, name),
+                value,
+                kind,
+            };
+            metrics.extend([
+                metric("duration", object.duration, MetricKind::Timing),
+                metric(
+                    "exported_bytes",
+                    object.exported_bytes as f64,
+                    MetricKind::Gauge,
+                ),
+                metric(
+                    "stored_bytes",
+                    object.stored_bytes as f64,
+                    MetricKind::Gauge,
+                ),
+                metric(
+                    "success",
+                    (object.status == ObjectStatus::Success) as u8 as f64,
+                    MetricKind::Gauge,
+                ),
+            ]);
+        }
+
+        metrics
+    }
+
+    async fn send_statsd(&self, metrics: &[Metric]) -> eyre::Result<()> {
+        let address = tokio::net::lookup_host(&self.config.address)
+            .await?
+            .next()
+            .ok_or_else(|| {
+                eyre::eyre!("Failed to resolve statsd address '{}'", self.config.address)
+            })?;
+        let socket = match address.is_ipv6() {
+            true => UdpSocket::bind("[::]:0").await?,
+            false => UdpSocket::bind("0.0.0.0:0").await?,
+        };
+        socket.connect(address).await?;
+
+        let mut packet = String::new();
+        for metric in metrics {
+            let line = match metric.kind {
+                MetricKind::Gauge => format!("{}:{}|g", metric.name, metric.value),
+                MetricKind::Timing => format!("{}:{:.0}|ms", metric.name, metric.value * 1000.0),
+                MetricKind::Counter => format!("{}:{}|c", metric.name, metric.value),
+            };
+            if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_PACKET_SIZE {
+                socket.send(packet.as_bytes()).await?;
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+        if !packet.is_empty() {
+            socket.send(packet.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// graphite has no counters, a run is recorded as a 1 at the time of the run
+    async fn send_graphite(&self, metrics: &[Metric]) -> eyre::Result<()> {
+        let timestamp = chrono::Utc::now().timestamp();
+        let mut lines = String::new();
+        for metric in metrics {
+            lines.push_str(&format!("{} {} {}\n", metric.name, metric.value, timestamp));
+        }
+
+        let send = async {
+            let mut stream = TcpStream::connect(&self.config.address).await?;
+            stream.write_all(lines.as_bytes()).await?;
+            stream.shutdown().await?;
+            Ok::<(), std::io::Error>(())
+        };
+        match tokio::time::timeout(std::time::Duration::from_secs(GRAPHITE_TIMEOUT), send).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(eyre::eyre!(
+                "Sending metrics to graphite at {} timed out after {} seconds",
+                self.config.address,
+                GRAPHITE_TIMEOUT
+            )),
+        }
+    }
+
+    async fn send(&self, job_name: &str, job_stats: &XenbakJobStats) -> eyre::Result<()> {
+        let metrics = self.metrics(job_name, job_stats);
+        debug!(
+            "Sending {} metrics of job '{}' to {}",
+            metrics.len(),
+            job_name,
+            self.config.address
+        );
+        match self.config.protocol {
+            StatsdProtocol::Statsd => self.send_statsd(&metrics).await,
+            StatsdProtocol::Graphite => self.send_graphite(&metrics).await,
+        }
+    }
+}
+
+/// the dots of a metric path separate its nodes, names of jobs and VMs are reduced to a single node
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(
+            |x| match x.is_ascii_alphanumeric() || x == '-' || x == '_' {
+                true => x,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl MonitoringTrait for StatsdService {
+    async fn start(&self, _job_name: String) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    async fn success(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.send(&job_name, &job_stats).await
+    }
+
+    async fn warning(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.send(&job_name, &job_stats).await
+    }
+
+    async fn failure(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        self.send(&job_name, &job_stats).await
+    }
+
+    async fn quarantine(&self, _job_name: String, _job_stats: XenbakJobStats) -> eyre::Result<()> {
+        // the failed VMs are already sent with the run
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{config::TelegramConfig, jobs::XenbakJobStats};
+
+use super::{truncate, MonitoringTrait};
+
+/// telegram rejects longer messages
+const MAX_MESSAGE_LENGTH: usize = 4096;
+/// characters of a single error listed in a message
+const MAX_ERROR_LENGTH: usize = 300;
+
+#[derive(Debug, Serialize)]
+struct TelegramSendMessageRequest<'a> {
+    chat_id: &'a str,
+    text: &'a str,
+    disable_web_page_preview: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct TelegramResponse {
+    ok: bool,
+    description: Option<String>,
+}
+
+/// sends the outcome of every run as a message of a telegram bot
+#[derive(Debug, Clone)]
+pub struct TelegramService {
+    config: TelegramConfig,
+    client: reqwest::Client,
+}
+
+impl TelegramService {
+    pub async fn from_config(config: TelegramConfig) -> eyre::Result<Self> {
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(format!("xenbakd/{}", env!("CARGO_PKG_VERSION")))
+            .timeout(std::time::Duration::from_secs(30))
+            .build()?;
+        let telegram_service = TelegramService { config, client };
+
+        // test the bot token
+        telegram_service
+            .call("getMe", &serde_json::json!({}))
+            .await?;
+
+        Ok(telegram_service)
+    }
+
+    /// calls a method of the bot api. the url contains the bot token, so it is left out of errors
+    async fn call<T: Serialize + ?Sized>(&self, method: &str, body: &T) -> eyre::Result<()> {
+        let url = format!(
+            "{}/bot{}/{}",
+            self.config.api_url.trim_end_matches('/'),
+            self.config.bot_token,
+            method
+        );
+        let response: TelegramResponse = self
+            .client
+            .post(url)
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?
+            .json()
+            .await
+            .map_err(|e| e.without_url())?;
+        match response.ok {
+            true => Ok(()),
+            false => Err(eyre::eyre!(
+                "Telegram bot api call '{}' failed: {}",
+                method,
+                response.description.unwrap_or_default()
+            )),
+        }
+    }
+
+    async fn send_message(&self, text: &str) -> eyre::Result<()> {
+        self.call(
+            "sendMessage",
+            &TelegramSendMessageRequest {
+                chat_id: &self.config.chat_id,
+                text,
+                disable_web_page_preview: true,
+            },
+        )
+        .await
+    }
+
+    /// the first `max_errors` errors, each cut to one line of at most `MAX_ERROR_LENGTH`
+    /// characters, the rest is only counted
+    fn format_errors(&self, errors: &[String]) -> String {
+        let mut lines: Vec<String> = errors
+            .iter()
+            .take(self.config.max_errors)
+            .map(|x| {
+                format!(
+                    "- {}",
+                    truncate(&x.trim().replace('\n', ": "), MAX_ERROR_LENGTH)
+                )
+            })
+            .collect();
+        if errors.len() > self.config.max_errors {
+            lines.push(format!(
+                "... and {} more errors",
+                errors.len() - self.config.max_errors
+            ));
+        }
+        match lines.is_empty() {
+            true => String::new(),
+            false => format!("\n\nErrors:\n{}", lines.join("\n")),
+        }
+    }
+}
+
+/// one line with the objects and the duration of the run
+fn format_summary(job_stats: &XenbakJobStats) -> String {
+    format!(
+        "{} of {} objects succeeded, {} failed, {} skipped in {:.0} seconds, {:.1} MiB exported",
+        job_stats.successful_objects,
+        job_stats.total_objects,
+        job_stats.failed_objects,
+        job_stats.skipped_objects,
+        job_stats.duration,
+        job_stats.exported_bytes as f64 / 1024.0 / 1024.0
+    )
+}
+
+#[async_trait::async_trait]
+impl MonitoringTrait for TelegramService {
+    async fn start(&self, _job_name: String) -> eyre::Result<()> {
+        // only the outcome is sent, a message per start would be noise
+        Ok(())
+    }
+
+    async fn success(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        let text = format!(
+            "xenbakd | Success: Backup Job '{}'\n{}",
+            job_name,
+            format_summary(&job_stats)
+        );
+        self.send_message(&truncate(&text, MAX_MESSAGE_LENGTH))
+            .await
+    }
+
+    async fn warning(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        let text = format!(
+            "xenbakd | Warning: Backup Job '{}'\n{}{}",
+            job_name,
+            format_summary(&job_stats),
+            self.format_errors(&job_stats.errors)
+        );
+        self.send_message(&truncate(&text, MAX_MESSAGE_LENGTH))
+            .await
+    }
+
+    async fn failure(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        let text = format!(
+            "xenbakd | Failure: Backup Job '{}'\n{}{}",
+            job_name,
+            format_summary(&job_stats),
+            self.format_errors(&job_stats.errors)
+        );
+        self.send_message(&truncate(&text, MAX_MESSAGE_LENGTH))
+            .await
+    }
+
+    async fn quarantine(&self, job_name: String, job_stats: XenbakJobStats) -> eyre::Result<()> {
+        let text = format!(
+            "xenbakd | Quarantine: Backup Job '{}'\nVMs which failed {} runs in a row are skipped \
+             until they are released with `xenbakd quarantine clear`:\n{}",
+            job_name,
+            job_stats.config.quarantine_after.unwrap_or_default(),
+            job_stats.quarantined_objects.join("\n")
+        );
+        self.send_message(&truncate(&text, MAX_MESSAGE_LENGTH))
+            .await
+    }
+}
@@ -0,0 +1,211 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::{QuarantineClearSubCommand, QuarantineCommand, QuarantineListSubCommand},
+    config::{AppConfig, QuarantineConfig},
+    xapi::VM,
+};
+
+/// failures in a row of a VM in a job, it is quarantined once they reach the job's
+/// `quarantine_after`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmFailures {
+    pub vm_name: String,
+    pub vm_uuid: String,
+    pub consecutive_failures: u32,
+    pub last_error: String,
+    pub last_failure: chrono::DateTime<chrono::Utc>,
+    /// set while the VM is quarantined
+    pub quarantined: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl VmFailures {
+    pub fn matches(&self, vm: &str) -> bool {
+        self.vm_name == vm || self.vm_uuid == vm
+    }
+}
+
+/// failing VMs by job and VM uuid
+type Failures = BTreeMap<String, BTreeMap<String, VmFailures>>;
+
+/// failures in a row of the VMs, surviving restarts of the daemon. only jobs with a
+/// `quarantine_after` use it
+#[derive(Debug, Clone)]
+pub struct QuarantineStore {
+    config: QuarantineConfig,
+    /// VMs of concurrent backups must not overwrite each others failures
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl QuarantineStore {
+    pub fn from_config(config: QuarantineConfig) -> Self {
+        QuarantineStore {
+            config,
+            lock: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    async fn read_failures(&self) -> eyre::Result<Failures> {
+        match tokio::fs::read_to_string(&self.config.path).await {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// replaces the file, so a crash never leaves half of it
+    async fn write_failures(&self, failures: &Failures) -> eyre::Result<()> {
+        if let Some(parent) = std::path::Path::new(&self.config.path).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp_path = format!("{}.tmp", self.config.path);
+        tokio::fs::write(&tmp_path, serde_json::to_vec_pretty(failures)?).await?;
+        tokio::fs::rename(&tmp_path, &self.config.path).await?;
+        Ok(())
+    }
+
+    /// returns the quarantined VMs of the job by uuid
+    pub async fn quarantined(&self, job_name: &str) -> eyre::Result<BTreeMap<String, VmFailures>> {
+        let _lock = self.lock.lock().await;
+        Ok(self
+            .read_failures()
+            .await?
+            .remove(job_name)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_, x)| x.quarantined.is_some())
+            .collect())
+    }
+
+    /// a successful backup resets the failures of the VM
+    pub async fn record_success(&self, job_name: &str, vm: &VM) -> eyre::Result<()> {
+        let _lock = self.lock.lock().await;
+        let mut failures = self.read_failures().await?;
+        let Some(job) = failures.get_mut(job_name) else {
+            return Ok(());
+        };
+        if job.remove(&vm.uuid).is_some() {
+            if job.is_empty() {
+                failures.remove(job_name);
+            }
+            self.write_failures(&failures).await?;
+        }
+        Ok(())
+    }
+
+    /// counts the failed backup of the VM, returns whether it is quarantined by it
+    pub async fn record_failure(
+        &self,
+        job_name: &str,
+        vm: &VM,
+        error: &str,
+        quarantine_after: u32,
+    ) -> eyre::Result<bool> {
+        let _lock = self.lock.lock().await;
+        let mut failures = self.read_failures().await?;
+        let now = chrono::Utc::now();
+        let entry = failures
+            .entry(job_name.to_string())
+            .or_default()
+            .entry(vm.uuid.clone())
+            .or_insert_with(|| VmFailures {
+                vm_name: vm.name_label.clone(),
+                vm_uuid: vm.uuid.clone(),
+                consecutive_failures: 0,
+                last_error: String::new(),
+                last_failure: now,
+                quarantined: None,
+            });
+        entry.vm_name = vm.name_label.clone();
+        entry.consecutive_failures += 1;
+        entry.last_error = error.to_string();
+        entry.last_failure = now;
+        let quarantined =
+            entry.quarantined.is_none() && entry.consecutive_failures >= quarantine_after;
+        if quarantined {
+            entry.quarantined = Some(now);
+        }
+        self.write_failures(&failures).await?;
+        Ok(quarantined)
+    }
+
+    /// releases the quarantined VMs matching the job and the VM's name-label or uuid, their
+    /// failures start over
+    pub async fn clear(
+        &self,
+        job_name: Option<&str>,
+        vm: Option<&str>,
+    ) -> eyre::Result<Vec<(String, VmFailures)>> {
+        let _lock = self.lock.lock().await;
+        let mut failures = self.read_failures().await?;
+        let mut cleared = vec![];
+        for (job, vms) in failures.iter_mut() {
+            if job_name.is_some_and(|x| x != job) {
+                continue;
+            }
+            vms.retain(|_, x| {
+                let matches = x.quarantined.is_some() && vm.is_none_or(|vm| x.matches(vm));
+                if matches {
+                    cleared.push((job.clone(), x.clone()));
+                }
+                !matches
+            });
+        }
+        failures.retain(|_, x| !x.is_empty());
+        if !cleared.is_empty() {
+            self.write_failures(&failures).await?;
+        }
+        Ok(cleared)
+    }
+
+    pub async fn list(&self) -> eyre::Result<Failures> {
+        let _lock = self.lock.lock().await;
+        self.read_failures().await
+    }
+}
+
+/// lists or releases quarantined VMs
+pub async fn quarantine(config: &AppConfig, command: &QuarantineCommand) -> eyre::Result<()> {
+    let store = QuarantineStore::from_config(config.quarantine.clone());
+    match command {
+        QuarantineCommand::List(QuarantineListSubCommand { job }) => {
+            for (job_name, vms) in store.list().await? {
+                if job.as_ref().is_some_and(|x| x != &job_name) {
+                    continue;
+                }
+                for failures in vms.values() {
+                    let state = match failures.quarantined {
+                        Some(since) => format!("quarantined since {}", since.format("%Y-%m-%d")),
+                        None => "failing".into(),
+                    };
+                    println!(
+                        "{:<24} {} [{}]: {}, {} failures in a row, last at {}: {}",
+                        job_name,
+                        failures.vm_name,
+                        failures.vm_uuid,
+                        state,
+                        failures.consecutive_failures,
+                        failures.last_failure.format("%Y-%m-%d %H:%M:%S"),
+                        failures.last_error.lines().next().unwrap_or_default()
+                    );
+                }
+            }
+        }
+        QuarantineCommand::Clear(QuarantineClearSubCommand { vm, job }) => {
+            let cleared = store.clear(job.as_deref(), vm.as_deref()).await?;
+            if cleared.is_empty() {
+                return Err(eyre::eyre!("No quarantined VM found"));
+            }
+            for (job_name, failures) in cleared {
+                println!(
+                    "Released VM '{}' [{}] of job '{}' from quarantine",
+                    failures.vm_name, failures.vm_uuid, job_name
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
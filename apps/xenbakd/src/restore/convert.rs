@@ -0,0 +1,76 @@
+use tracing::info;
+
+use crate::{
+    cli::{ConvertSubCommand, ImageFormat},
+    config::AppConfig,
+    storage::encode_name_component,
+    xapi::xva,
+};
+
+use super::open_xva;
+
+/// converts a raw image to qcow2 with `qemu-img`, the raw image is deleted afterwards
+async fn convert_to_qcow2(raw: &str, qcow2: &str) -> eyre::Result<()> {
+    let output = tokio::process::Command::new("qemu-img")
+        .args(["convert", "-f", "raw", "-O", "qcow2", raw, qcow2])
+        .output()
+        .await
+        .map_err(|e| eyre::eyre!("Failed to run qemu-img: {}", e))?;
+    if !output.status.success() {
+        return Err(eyre::eyre!(
+            "qemu-img failed to convert '{}': {}",
+            raw,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    tokio::fs::remove_file(raw).await?;
+    Ok(())
+}
+
+/// writes the disks of a VM restore point as raw or qcow2 images, e.g. to move the VM to KVM or
+/// Proxmox. prints `<userdevice> <image>` for every disk
+pub async fn convert(config: &AppConfig, convert: &ConvertSubCommand) -> eyre::Result<()> {
+    let (storage_name, restore_point, stream) = open_xva(
+        config,
+        &convert.restore_point,
+        convert.job.as_deref(),
+        convert.storage.as_deref(),
+    )
+    .await?;
+    tokio::fs::create_dir_all(&convert.output_dir).await?;
+
+    info!(
+        "Extracting the disks of {} from storage '{}' to '{}'",
+        restore_point.id, storage_name, convert.output_dir
+    );
+    let vm_name = encode_name_component(&restore_point.backup_object.vm_name);
+    let disks = xva::extract_disks(stream, |x| {
+        (convert.disks.is_empty() || convert.disks.contains(&x.userdevice))
+            .then(|| format!("{}/{}-{}.raw", convert.output_dir, vm_name, x.userdevice))
+    })
+    .await?;
+    if disks.is_empty() {
+        return Err(eyre::eyre!("{} has no disks to convert", restore_point.id));
+    }
+
+    for (disk, raw, _) in disks {
+        let image = match convert.format {
+            ImageFormat::Raw => raw,
+            ImageFormat::Qcow2 => {
+                let qcow2 = format!("{}.qcow2", raw.trim_end_matches(".raw"));
+                info!("Converting disk {} to '{}'", disk.userdevice, qcow2);
+                convert_to_qcow2(&raw, &qcow2).await?;
+                qcow2
+            }
+        };
+
+        info!(
+            "Converted disk {} '{}' of {} to '{}'",
+            disk.userdevice, disk.name_label, restore_point.id, image
+        );
+        println!("{} {}", disk.userdevice, image);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    storage::{restore_point::RestorePoint, StorageHandler},
+    xapi::{
+        cli::client::XApiCliClient,
+        diff_archive::{DiffArchiveReader, DiffDisk},
+        error::XApiCliError,
+        UUID,
+    },
+};
+
+use super::RestoreMapping;
+
+/// collects the chain of a differential restore point, from its full backup up to the restore point
+async fn resolve_chain(
+    storage_handler: &dyn StorageHandler,
+    restore_point: &RestorePoint,
+) -> eyre::Result<Vec<RestorePoint>> {
+    let restore_points: HashMap<String, RestorePoint> = storage_handler
+        .list(restore_point.backup_object.to_filter())
+        .await?
+        .into_iter()
+        .map(|x| (x.id.clone(), x))
+        .collect();
+
+    let mut chain = vec![restore_point.clone()];
+    while let Some(base) = chain.last().and_then(|x| x.backup_object.base.clone()) {
+        let base_restore_point = restore_points.get(&base).ok_or_else(|| {
+            eyre::eyre!(
+                "Base {} of restore point {} not found on storage '{}'",
+                base,
+                chain.last().map(|x| x.id.as_str()).unwrap_or_default(),
+                storage_handler.get_name()
+            )
+        })?;
+        chain.push(base_restore_point.clone());
+    }
+    chain.reverse();
+
+    Ok(chain)
+}
+
+/// creates an empty VDI for a disk on its mapped SR, or the pool's default SR
+async fn create_vdi(
+    xapi_client: &XApiCliClient,
+    disk: &DiffDisk,
+    mapping: &RestoreMapping,
+) -> eyre::Result<UUID> {
+    let sr = match mapping.sr_target(&disk.sr_uuid, &disk.sr_name) {
+        Some(target) => xapi_client.resolve_uuid("sr", target).await?,
+        None => {
+            let pool = xapi_client
+                .list_uuids("pool", &[])
+                .await?
+                .into_iter()
+                .next()
+                .ok_or_else(|| eyre::eyre!("No pool found"))?;
+            xapi_client.get_param("pool", &pool, "default-SR").await?
+        }
+    };
+
+    Ok(xapi_client
+        .create(
+            "vdi",
+            &[
+                format!("sr-uuid={}", sr),
+                format!("name-label={}", disk.name_label),
+                format!("virtual-size={}", disk.virtual_size),
+                "type=user".to_string(),
+            ],
+        )
+        .await?)
+}
+
+/// applies the disks of one restore point of the chain, creating VDIs for disks exported in full
+async fn apply_restore_point(
+    xapi_client: &XApiCliClient,
+    storage_handler: &dyn StorageHandler,
+    restore_point: &RestorePoint,
+    mapping: &RestoreMapping,
+    vdis: &mut BTreeMap<String, UUID>,
+) -> eyre::Result<Vec<String>> {
+    let mut reader =
+        DiffArchiveReader::new(storage_handler.open_restore_stream(restore_point).await?).await?;
+    let mut userdevices = vec![];
+
+    while let Some(disk) = reader.next_disk().await? {
+        let vdi = match (vdis.get(&disk.userdevice).cloned(), &disk.base_vdi_uuid) {
+            (Some(vdi), Some(_)) => vdi,
+            (None, Some(_)) => {
+                return Err(eyre::eyre!(
+                    "Disk {} of restore point {} is differential, but missing in its base",
+                    disk.userdevice,
+                    restore_point.id
+                ))
+            }
+            (previous, None) => {
+                // a disk exported in full replaces the one of the base
+                if let Some(previous) = previous {
+                    xapi_client.destroy("vdi", &previous).await?;
+                }
+                let vdi = create_vdi(xapi_client, &disk, mapping).await?;
+                vdis.insert(disk.userdevice.clone(), vdi.clone());
+                vdi
+            }
+        };
+
+        debug!(
+            "Importing disk {} of {} into VDI {}",
+            disk.userdevice, restore_point.id, vdi
+        );
+        let mut child = xapi_client.open_vdi_import(&vdi)?;
+        let mut stdin = child.stdin.take().unwrap();
+        let copied = reader.copy_disk(&mut stdin).await;
+        drop(stdin);
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(XApiCliError::CommandFailed(stderr.into()).into());
+        }
+        copied?;
+
+        userdevices.push(disk.userdevice);
+    }
+
+    Ok(userdevices)
+}
+
+/// restores the disks of a differential restore point as new VDIs, by applying its chain from the
+/// full backup on. returns the VDIs by userdevice
+pub async fn restore_disks(
+    xapi_client: &XApiCliClient,
+    storage_handler: &dyn StorageHandler,
+    restore_point: &RestorePoint,
+    mapping: &RestoreMapping,
+) -> eyre::Result<BTreeMap<String, UUID>> {
+    let chain = resolve_chain(storage_handler, restore_point).await?;
+    info!(
+        "Restoring the disks of {} from a chain of {} restore points",
+        restore_point.id,
+        chain.len()
+    );
+
+    let mut vdis = BTreeMap::new();
+    let result = async {
+        let mut userdevices = vec![];
+        for link in &chain {
+            info!("Applying restore point {}", link.id);
+            userdevices =
+                apply_restore_point(xapi_client, storage_handler, link, mapping, &mut vdis).await?;
+        }
+        Ok::<_, eyre::Error>(userdevices)
+    }
+    .await;
+
+    // nothing is left behind of a failed restore, neither disks removed since the full backup
+    let userdevices = result.as_deref().unwrap_or_default();
+    for (userdevice, vdi) in &vdis {
+        if !userdevices.contains(userdevice) {
+            if let Err(e) = xapi_client.destroy("vdi", vdi).await {
+                warn!("Failed to destroy VDI {}: {}", vdi, e);
+            }
+        }
+    }
+    let userdevices = result?;
+    vdis.retain(|userdevice, _| userdevices.contains(userdevice));
+
+    Ok(vdis)
+}
@@ -0,0 +1,335 @@
+use std::collections::BTreeMap;
+
+use tracing::{debug, info, warn};
+
+pub mod convert;
+mod differential;
+
+use crate::{
+    cli::{ExtractDiskSubCommand, RestoreSubCommand},
+    config::AppConfig,
+    jobs::JobType,
+    storage::{
+        restore_point::{find_restore_points, RestorePoint},
+        StdioStream,
+    },
+    xapi::{
+        cli::client::XApiCliClient,
+        diff_archive,
+        xva::{self, XvaMetadata},
+        UUID,
+    },
+};
+
+/// SR and network mappings of a restore, from the `[restore]` config overridden by the cli
+#[derive(Debug, Clone, Default)]
+pub struct RestoreMapping {
+    /// SR all disks are restored to, takes precedence over `sr_map`
+    pub sr: Option<String>,
+    pub sr_map: BTreeMap<String, String>,
+    pub network_map: BTreeMap<String, String>,
+    pub regenerate_macs: bool,
+}
+
+impl RestoreMapping {
+    pub fn new(config: &AppConfig, restore: &RestoreSubCommand) -> Self {
+        let mut mapping = RestoreMapping {
+            sr: restore.sr.clone(),
+            sr_map: config.restore.sr_map.clone(),
+            network_map: config.restore.network_map.clone(),
+            regenerate_macs: config.restore.regenerate_macs || restore.regenerate_macs,
+        };
+        mapping.sr_map.extend(restore.sr_map.iter().cloned());
+        mapping
+            .network_map
+            .extend(restore.network_map.iter().cloned());
+        mapping
+    }
+
+    /// sources are matched by uuid first, then by name-label
+    fn lookup<'a>(map: &'a BTreeMap<String, String>, uuid: &str, name: &str) -> Option<&'a String> {
+        map.get(uuid).or_else(|| map.get(name))
+    }
+
+    /// target SR of a disk stored on the given source SR, if it isn't restored to the default SR
+    fn sr_target(&self, uuid: &str, name: &str) -> Option<&String> {
+        self.sr
+            .as_ref()
+            .or_else(|| Self::lookup(&self.sr_map, uuid, name))
+    }
+}
+
+/// name-label of the restored VM, renamed by default so it doesn't collide with the original VM
+fn restored_name(restore: &RestoreSubCommand, restore_point: &RestorePoint) -> Option<String> {
+    if restore.keep_name {
+        return None;
+    }
+
+    Some(restore.name.clone().unwrap_or_else(|| {
+        format!(
+            "restored-{}-{}",
+            restore_point.backup_object.vm_name,
+            restore_point
+                .backup_object
+                .time_stamp
+                .format("%Y-%m-%d_%H-%M")
+        )
+    }))
+}
+
+/// imports the restore point on the given xen host, then moves the disks and VIFs according to the mapping
+pub async fn restore(config: &AppConfig, restore: &RestoreSubCommand) -> eyre::Result<()> {
+    let xen_config = config
+        .xen
+        .iter()
+        .find(|x| x.name == restore.xen_host)
+        .ok_or_else(|| eyre::eyre!("Xen host '{}' not found in config", restore.xen_host))?;
+    let xapi_client = XApiCliClient::new(xen_config.clone());
+    let mapping = RestoreMapping::new(config, restore);
+
+    let (storage_handler, restore_point) = find_restore_points(
+        config,
+        &restore.restore_point,
+        restore.job.as_deref(),
+        restore.storage.as_deref(),
+    )
+    .await?
+    .remove(0);
+
+    if restore_point.backup_object.job_type == JobType::PoolMetadata {
+        return Err(eyre::eyre!(
+            "{} is a pool database dump, copy it from the storage and restore it with `xe pool-restore-database`",
+            restore_point.id
+        ));
+    }
+
+    info!(
+        "Restoring {} from storage '{}' to xen host '{}'",
+        restore_point.id,
+        storage_handler.get_name(),
+        xen_config.name
+    );
+    let stream = storage_handler.open_restore_stream(&restore_point).await?;
+
+    // differential backups only hold the disks, they are restored as VDIs
+    let (is_diff_archive, stream) = diff_archive::is_diff_archive(stream).await?;
+    if is_diff_archive {
+        drop(stream);
+        let vdis = differential::restore_disks(
+            &xapi_client,
+            storage_handler.as_ref(),
+            &restore_point,
+            &mapping,
+        )
+        .await?;
+
+        xapi_client.logout().await;
+        for (userdevice, vdi) in vdis {
+            info!(
+                "Restored disk {} of {} as VDI {}",
+                userdevice, restore_point.id, vdi
+            );
+            println!("{} {}", userdevice, vdi);
+        }
+        return Ok(());
+    }
+
+    let (metadata, stream) = XvaMetadata::read_from_stream(stream).await?;
+    debug!("Metadata of {}: {:?}", restore_point.id, metadata);
+
+    // resolve the target SR of every disk, unmapped disks go to the default SR of the pool
+    let mut disk_targets: BTreeMap<String, UUID> = BTreeMap::new();
+    for disk in &metadata.disks {
+        if let Some(target) = mapping.sr_target(&disk.sr_uuid, &disk.sr_name) {
+            disk_targets.insert(
+                disk.userdevice.clone(),
+                xapi_client.resolve_uuid("sr", target).await?,
+            );
+        }
+    }
+
+    // xe imports all disks into a single SR, so only disks going elsewhere have to be moved
+    let mut targets: Vec<&UUID> = disk_targets.values().collect();
+    targets.sort();
+    targets.dedup();
+    let import_sr = match (
+        targets.as_slice(),
+        disk_targets.len() == metadata.disks.len(),
+    ) {
+        ([target], true) => Some((*target).clone()),
+        _ => None,
+    };
+
+    let vm = xapi_client
+        .vm_import(stream, import_sr.as_ref(), !mapping.regenerate_macs)
+        .await?;
+    info!("Imported {} as VM {}", restore_point.id, vm);
+    if let Some(name) = restored_name(restore, &restore_point) {
+        info!("Renaming VM {} to '{}'", vm, name);
+        xapi_client
+            .set_param("vm", &vm, "name-label", &name)
+            .await?;
+    }
+
+    if import_sr.is_none() {
+        move_disks(&xapi_client, &vm, &disk_targets).await?;
+    }
+    remap_vifs(&xapi_client, &vm, &metadata, &mapping).await?;
+
+    xapi_client.logout().await;
+    info!("Restored {} as VM {}", restore_point.id, vm);
+    println!("{}", vm);
+
+    Ok(())
+}
+
+/// opens the XVA of a full VM restore point, returns the name of its storage, the restore point
+/// and the stream
+async fn open_xva(
+    config: &AppConfig,
+    id: &str,
+    job: Option<&str>,
+    storage: Option<&str>,
+) -> eyre::Result<(String, RestorePoint, StdioStream)> {
+    let (storage_handler, restore_point) = find_restore_points(config, id, job, storage)
+        .await?
+        .remove(0);
+
+    let stream = storage_handler.open_restore_stream(&restore_point).await?;
+    let (is_diff_archive, stream) = diff_archive::is_diff_archive(stream).await?;
+    if is_diff_archive || restore_point.backup_object.job_type == JobType::PoolMetadata {
+        return Err(eyre::eyre!(
+            "{} holds no XVA, disks can only be extracted from full VM backups",
+            restore_point.id
+        ));
+    }
+
+    Ok((storage_handler.get_name(), restore_point, stream))
+}
+
+/// writes a single disk of a VM restore point to a raw image
+pub async fn extract_disk(config: &AppConfig, extract: &ExtractDiskSubCommand) -> eyre::Result<()> {
+    let (storage_name, restore_point, stream) = open_xva(
+        config,
+        &extract.restore_point,
+        extract.job.as_deref(),
+        extract.storage.as_deref(),
+    )
+    .await?;
+
+    info!(
+        "Extracting disk {} of {} from storage '{}' to '{}'",
+        extract.disk, restore_point.id, storage_name, extract.output
+    );
+    let (disk, _, blocks) = xva::extract_disks(stream, |x| {
+        (x.userdevice == extract.disk).then(|| extract.output.clone())
+    })
+    .await?
+    .pop()
+    .ok_or_else(|| eyre::eyre!("{} has no disk {}", restore_point.id, extract.disk))?;
+    info!(
+        "Extracted disk {} '{}' ({:.1} GiB, {} blocks with data) to '{}'",
+        disk.userdevice,
+        disk.name_label,
+        disk.virtual_size as f64 / 1024.0 / 1024.0 / 1024.0,
+        blocks,
+        extract.output
+    );
+
+    Ok(())
+}
+
+/// copies the disks to their target SRs and replaces the VBDs, the VM is halted after an import
+async fn move_disks(
+    xapi_client: &XApiCliClient,
+    vm: &UUID,
+    disk_targets: &BTreeMap<String, UUID>,
+) -> eyre::Result<()> {
+    for vbd in xapi_client
+        .list_uuids("vbd", &[&format!("vm-uuid={}", vm), "type=Disk"])
+        .await?
+    {
+        let userdevice = xapi_client.get_param("vbd", &vbd, "userdevice").await?;
+        let Some(target) = disk_targets.get(&userdevice) else {
+            continue;
+        };
+        let vdi = xapi_client.get_param("vbd", &vbd, "vdi-uuid").await?;
+        if &xapi_client.get_param("vdi", &vdi, "sr-uuid").await? == target {
+            continue;
+        }
+
+        info!("Moving disk {} of VM {} to SR {}", userdevice, vm, target);
+        let bootable = xapi_client.get_param("vbd", &vbd, "bootable").await?;
+        let mode = xapi_client.get_param("vbd", &vbd, "mode").await?;
+        let copy = xapi_client.vdi_copy(&vdi, target).await?;
+
+        xapi_client.destroy("vbd", &vbd).await?;
+        xapi_client
+            .create(
+                "vbd",
+                &[
+                    format!("vm-uuid={}", vm),
+                    format!("vdi-uuid={}", copy),
+                    format!("device={}", userdevice),
+                    format!("bootable={}", bootable),
+                    format!("mode={}", mode),
+                    "type=Disk".to_string(),
+                ],
+            )
+            .await?;
+        xapi_client.destroy("vdi", &vdi).await?;
+    }
+
+    Ok(())
+}
+
+/// recreates the VIFs of mapped networks on their target networks, keeping the device and MAC
+async fn remap_vifs(
+    xapi_client: &XApiCliClient,
+    vm: &UUID,
+    metadata: &XvaMetadata,
+    mapping: &RestoreMapping,
+) -> eyre::Result<()> {
+    for vif in xapi_client
+        .list_uuids("vif", &[&format!("vm-uuid={}", vm)])
+        .await?
+    {
+        let device = xapi_client.get_param("vif", &vif, "device").await?;
+        let Some(source) = metadata.vifs.iter().find(|x| x.device == device) else {
+            warn!(
+                "VIF {} of VM {} is not part of the backup, skipping",
+                device, vm
+            );
+            continue;
+        };
+        let Some(target) = RestoreMapping::lookup(
+            &mapping.network_map,
+            &source.network_uuid,
+            &source.network_name,
+        ) else {
+            continue;
+        };
+        let network = xapi_client.resolve_uuid("network", target).await?;
+
+        info!(
+            "Moving VIF {} of VM {} from network '{}' to {}",
+            device, vm, source.network_name, network
+        );
+        // the import already assigned new MAC addresses if they are to be regenerated
+        let mac = xapi_client.get_param("vif", &vif, "MAC").await?;
+        xapi_client.destroy("vif", &vif).await?;
+        xapi_client
+            .create(
+                "vif",
+                &[
+                    format!("vm-uuid={}", vm),
+                    format!("network-uuid={}", network),
+                    format!("device={}", device),
+                    format!("mac={}", mac),
+                ],
+            )
+            .await?;
+    }
+
+    Ok(())
+}
@@ -1,9 +1,20 @@
 use std::sync::Arc;
 
+use rand::Rng;
 use tokio_cron_scheduler::{Job, JobScheduler};
-use tracing::{error, info};
+use tracing::{error, info, warn, Instrument};
 
-use crate::{jobs::XenbakJob, monitoring::MonitoringTrait, GlobalState};
+use crate::{
+    config::JobConfig,
+    history::HistoryEntry,
+    jobs::{
+        hooks::{self, HookStage},
+        resource_usage::ResourceSampler,
+        window, JobOutcome, JobType, XenbakJob, XenbakJobStats,
+    },
+    monitoring::MonitoringTrait,
+    systemd, GlobalState,
+};
 
 pub struct XenbakScheduler {
     scheduler: JobScheduler,
@@ -16,10 +27,49 @@ impl XenbakScheduler {
         }
     }
 
+    /// runs the job in a span naming it, the log lines of the run and its VMs carry the job
     async fn execute_job_with_monitoring<X: XenbakJob + Send + Clone + Sync + 'static>(
         job: &mut X,
         global_state: Arc<GlobalState>,
     ) {
+        let span = tracing::span!(
+            tracing::Level::INFO,
+            "XenbakScheduler::execute_job",
+            job = job.get_name()
+        );
+        Self::execute_job(job, global_state).instrument(span).await;
+    }
+
+    async fn execute_job<X: XenbakJob + Send + Clone + Sync + 'static>(
+        job: &mut X,
+        global_state: Arc<GlobalState>,
+    ) {
+        if global_state.shutdown.is_requested() {
+            info!(
+                "Not starting job '{}', the daemon is shutting down",
+                job.get_name()
+            );
+            return;
+        }
+        if global_state.paused.is_paused(&job.get_name()) {
+            info!("Job '{}' is paused, skipping its run", job.get_name());
+            return;
+        }
+
+        // dry runs change nothing, so there is nothing to hook, notify or record
+        if global_state
+            .config
+            .jobs
+            .iter()
+            .any(|j| j.name == job.get_name() && j.dry_run)
+        {
+            Self::execute_dry_run(job).await;
+            return;
+        }
+
+        let run = global_state.shutdown.track_run(&job.get_name());
+        Self::report_status(&global_state);
+
         let mut monitoring_services: Vec<Arc<dyn MonitoringTrait>> = vec![];
 
         if let Some(healthchecks_service) = global_state.healthchecks_service.clone() {
@@ -31,33 +81,285 @@ impl XenbakScheduler {
             monitoring_services.push(Arc::new(mail_service) as Arc<dyn MonitoringTrait>);
         }
 
+        if let Some(telegram_service) = global_state.telegram_service.clone() {
+            monitoring_services.push(Arc::new(telegram_service) as Arc<dyn MonitoringTrait>);
+        }
+
+        if let Some(opsgenie_service) = global_state.opsgenie_service.clone() {
+            monitoring_services.push(Arc::new(opsgenie_service) as Arc<dyn MonitoringTrait>);
+        }
+
+        if let Some(influxdb_service) = global_state.influxdb_service.clone() {
+            monitoring_services.push(Arc::new(influxdb_service) as Arc<dyn MonitoringTrait>);
+        }
+
+        if let Some(statsd_service) = global_state.statsd_service.clone() {
+            monitoring_services.push(Arc::new(statsd_service) as Arc<dyn MonitoringTrait>);
+        }
+
+        for exec_notifier in global_state.exec_notifiers.clone() {
+            monitoring_services.push(Arc::new(exec_notifier) as Arc<dyn MonitoringTrait>);
+        }
+
         for service in &monitoring_services {
-            service.start(job.get_name()).await.unwrap();
+            if let Err(e) = service.start(job.get_name()).await {
+                warn!(
+                    "Failed to send start notification of job '{}': {}",
+                    job.get_name(),
+                    e
+                );
+            }
         }
 
-        // run the job
-        let job_result = job.run().await;
+        // maintenance jobs aren't configured as jobs, they have no temp dirs and hooks
+        let job_config = global_state
+            .config
+            .jobs
+            .iter()
+            .find(|j| j.name == job.get_name())
+            .cloned();
 
-        // get job stats after job execution is done
-        let job_stats = job.get_job_stats();
+        // a failed run is re-run once, its failure is only reported if the re-run fails as well
+        let fresh_job = job.clone();
+        let rerun_delay = job_config.as_ref().and_then(|j| j.rerun_delay);
+        let first_run = Self::execute_run(job, &global_state, &job_config).await;
+        let (job_result, job_stats) = match (first_run, rerun_delay) {
+            ((Err(e), failed_stats), Some(delay)) if !global_state.shutdown.is_requested() => {
+                warn!(
+                    "Job '{}' failed, running it once more in {} seconds: {:#}",
+                    job.get_name(),
+                    delay,
+                    e
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(std::time::Duration::from_secs(delay)) => {
+                        *job = fresh_job;
+                        let (job_result, mut job_stats) =
+                            Self::execute_run(job, &global_state, &job_config).await;
+                        job_stats.warnings.push(format!(
+                            "Re-ran the job {} seconds after a failed run: {:#}",
+                            delay, e
+                        ));
+                        (job_result, job_stats)
+                    }
+                    // the failed run is reported right away
+                    _ = global_state.shutdown.requested() => (Err(e), failed_stats),
+                }
+            }
+            (first_run, _) => first_run,
+        };
 
-        // send success/failure notification
+        // send success/warning/failure notification
         if let Err(e) = job_result {
             error!("{:?}", e);
             for service in &monitoring_services {
-                service
+                if let Err(e) = service
                     .failure(job_stats.config.name.clone(), job_stats.clone())
                     .await
-                    .unwrap();
+                {
+                    warn!(
+                        "Failed to send failure notification of job '{}': {}",
+                        job.get_name(),
+                        e
+                    );
+                }
+            }
+        } else if job_stats.outcome == JobOutcome::Warning {
+            warn!(
+                "Job '{}' succeeded, but {} of {} objects failed",
+                job.get_name(),
+                job_stats.failed_objects,
+                job_stats.total_objects
+            );
+            for service in &monitoring_services {
+                if let Err(e) = service
+                    .warning(job_stats.config.name.clone(), job_stats.clone())
+                    .await
+                {
+                    warn!(
+                        "Failed to send warning notification of job '{}': {}",
+                        job.get_name(),
+                        e
+                    );
+                }
             }
         } else {
             for service in &monitoring_services {
-                service
+                if let Err(e) = service
                     .success(job_stats.config.name.clone(), job_stats.clone())
                     .await
-                    .unwrap();
+                {
+                    warn!(
+                        "Failed to send success notification of job '{}': {}",
+                        job.get_name(),
+                        e
+                    );
+                }
+            }
+        }
+
+        if !job_stats.quarantined_objects.is_empty() {
+            for service in &monitoring_services {
+                if let Err(e) = service
+                    .quarantine(job_stats.config.name.clone(), job_stats.clone())
+                    .await
+                {
+                    warn!(
+                        "Failed to send quarantine notification of job '{}': {}",
+                        job.get_name(),
+                        e
+                    );
+                }
+            }
+        }
+
+        drop(run);
+        Self::report_status(&global_state);
+    }
+
+    /// runs the job once with its hooks and records the run in the history
+    async fn execute_run<X: XenbakJob + Send + Clone + Sync + 'static>(
+        job: &mut X,
+        global_state: &GlobalState,
+        job_config: &Option<JobConfig>,
+    ) -> (eyre::Result<()>, XenbakJobStats) {
+        let started = chrono::Utc::now();
+        if let Some(history) = &global_state.history {
+            if let Err(e) = history.begin_run(&job.get_name(), started).await {
+                warn!("Failed to record start of job '{}': {}", job.get_name(), e);
+            }
+        }
+
+        // sample xenbakd's own resource usage while the job is running
+        let temp_dirs = job_config
+            .as_ref()
+            .map(|j| j.get_temp_dirs(global_state.config.storage.clone()))
+            .unwrap_or_default();
+        let sampler = ResourceSampler::start(temp_dirs);
+
+        // run the job, unless its pre hook failed
+        let mut hook_warnings = vec![];
+        let pre_hook = match job_config {
+            Some(job_config) => hooks::run_hook(job_config, HookStage::Pre, None).await,
+            None => Ok(None),
+        };
+        let mut skipped = None;
+        let mut job_result = match pre_hook {
+            Ok(warning) => {
+                hook_warnings.extend(warning);
+                job.run().await
+            }
+            Err(e) => {
+                skipped = Some(e.to_string());
+                Err(e)
+            }
+        };
+
+        // get job stats after job execution is done
+        let mut job_stats = job.get_job_stats();
+        job_stats.resource_usage = sampler.stop().await;
+        if let (Some(job_config), Some(e)) = (job_config, skipped) {
+            // the job didn't run, its stats are still empty
+            job_stats.config = job_config.clone();
+            job_stats.errors.push(e);
+        }
+        // a run which succeeded despite failed objects stayed within its failure threshold
+        job_stats.outcome = match &job_result {
+            Err(_) => JobOutcome::Failure,
+            Ok(_) if job_stats.failed_objects > 0 => JobOutcome::Warning,
+            Ok(_) => JobOutcome::Success,
+        };
+        if let Some(job_config) = job_config {
+            match hooks::run_hook(job_config, HookStage::Post, Some(&job_stats)).await {
+                Ok(warning) => hook_warnings.extend(warning),
+                Err(e) => {
+                    job_stats.errors.push(e.to_string());
+                    job_stats.outcome = JobOutcome::Failure;
+                    if job_result.is_ok() {
+                        job_result = Err(e);
+                    }
+                }
+            }
+        }
+        job_stats.warnings.extend(hook_warnings);
+        info!(
+            "Job '{}' used {:.1}s cpu time, {} MiB peak rss, {} MiB peak temp-disk",
+            job.get_name(),
+            job_stats.resource_usage.cpu_time,
+            job_stats.resource_usage.peak_rss / 1024 / 1024,
+            job_stats.resource_usage.peak_temp_disk / 1024 / 1024
+        );
+
+        global_state
+            .job_tracker
+            .record_run(&job.get_name(), started, job_stats.outcome);
+        if let Some(history) = &global_state.history {
+            let recorded = HistoryEntry::new(
+                job.get_name(),
+                job.get_job_type().to_string(),
+                started,
+                &job_stats,
+            );
+            if let Err(e) = async { history.record(&recorded?).await }.await {
+                warn!(
+                    "Failed to record run of job '{}' in the history: {}",
+                    job.get_name(),
+                    e
+                );
+            }
+            // a run cut short by the shutdown is resumed like one interrupted by a crash
+            let cut_short = global_state.shutdown.is_requested()
+                && job_stats.successful_objects < job_stats.total_objects;
+            if cut_short {
+                info!(
+                    "Keeping run of job '{}' as interrupted by the shutdown",
+                    job.get_name()
+                );
+            } else if let Err(e) = history.finish_run(&job.get_name()).await {
+                warn!("Failed to record end of job '{}': {}", job.get_name(), e);
             }
         }
+
+        (job_result, job_stats)
+    }
+
+    /// reports the running jobs to systemd
+    pub fn report_status(global_state: &GlobalState) {
+        let running = global_state.shutdown.running_jobs();
+        let status = match (global_state.shutdown.is_requested(), running.is_empty()) {
+            (false, true) => "idle, waiting for scheduled jobs".to_string(),
+            (false, false) => format!("running job {}", running.join(", ")),
+            (true, true) => "shutting down".to_string(),
+            (true, false) => format!("shutting down, waiting for job {}", running.join(", ")),
+        };
+        systemd::status(&status);
+    }
+
+    /// runs the job without snapshotting or exporting anything and prints what it would do
+    async fn execute_dry_run<X: XenbakJob + Send + Clone + Sync + 'static>(job: &mut X) {
+        if job.get_job_type() != JobType::VmBackup {
+            warn!(
+                "Dry runs aren't supported by {} jobs, skipping job '{}'",
+                job.get_job_type(),
+                job.get_name()
+            );
+            return;
+        }
+
+        info!("Dry run of job '{}'", job.get_name());
+        let job_result = job.run().await;
+        let job_stats = job.get_job_stats();
+        println!("Dry run of job '{}':", job.get_name());
+        for action in &job_stats.dry_run_actions {
+            println!("  {}", action);
+        }
+        for warning in &job_stats.warnings {
+            println!("  Warning: {}", warning);
+        }
+        if let Err(e) = job_result {
+            error!("{:?}", e);
+            println!("  Failed: {:#}", e);
+        }
     }
 
     pub async fn add_job<X: XenbakJob + Send + Clone + Sync + 'static>(
@@ -72,22 +374,120 @@ impl XenbakScheduler {
             job.get_name(),
             job.get_schedule()
         );
-        self.scheduler
+        if let Some(missed) = Self::missed_run(&job, &global_state).await {
+            info!(
+                "Catching up run of job '{}' missed at {}",
+                job.get_name(),
+                missed
+            );
+            self.spawn_once(job.clone(), global_state.clone());
+        }
+        let job_name = job.get_name();
+        let job_tracker = global_state.job_tracker.clone();
+        let id = self
+            .scheduler
             .add(Job::new_async(
                 job.get_schedule().as_ref(),
                 move |mut _uuid, mut _l| {
                     let mut job = job.clone();
                     let global_state = global_state.clone();
                     Box::pin(async move {
+                        let jitter = global_state
+                            .config
+                            .jobs
+                            .iter()
+                            .find(|j| j.name == job.get_name())
+                            .and_then(|j| j.schedule_jitter);
+                        if let Some(jitter) = jitter {
+                            let delay = rand::thread_rng().gen_range(0..=jitter);
+                            info!(
+                                "Delaying job '{}' by {} seconds of schedule jitter",
+                                job.get_name(),
+                                delay
+                            );
+                            tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+                        }
                         Self::execute_job_with_monitoring(&mut job, global_state).await;
                     })
                 },
             )?)
             .await
             .unwrap();
+        job_tracker.add_job(&job_name, id);
         Ok(())
     }
 
+    /// the latest run the job's schedule missed while the daemon was down, for jobs with
+    /// `catch_up` whose missed run is at most `catch_up_max_lateness` late
+    async fn missed_run<X: XenbakJob>(
+        job: &X,
+        global_state: &GlobalState,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        let job_config = global_state
+            .config
+            .jobs
+            .iter()
+            .find(|j| j.name == job.get_name() && j.catch_up)?;
+        let Some(history) = &global_state.history else {
+            warn!(
+                "Job '{}' can't catch up missed runs without the history",
+                job.get_name()
+            );
+            return None;
+        };
+
+        let last_finished = match history.read().await {
+            Ok(entries) => entries
+                .into_iter()
+                .filter(|x| x.job_name == job.get_name())
+                .map(|x| x.started)
+                .max(),
+            Err(e) => {
+                warn!(
+                    "Failed to read the history of job '{}': {}",
+                    job.get_name(),
+                    e
+                );
+                return None;
+            }
+        };
+        let interrupted = history
+            .interrupted_runs()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .find(|x| x.job_name == job.get_name());
+        // a resumed run backs up the VMs the missed run would have
+        if interrupted.is_some()
+            && job_config.resume_interrupted
+            && job_config.job_type == JobType::VmBackup
+        {
+            return None;
+        }
+        // a job which never ran has nothing to catch up
+        let last_run = last_finished
+            .into_iter()
+            .chain(interrupted.map(|x| x.started))
+            .max()?;
+
+        let now = chrono::Utc::now();
+        let missed = window::parse_schedule(&job.get_schedule())
+            .ok()?
+            .after(&last_run)
+            .take_while(|x| *x <= now)
+            .last()?;
+        if (now - missed).num_seconds() > job_config.catch_up_max_lateness as i64 {
+            info!(
+                "Not catching up run of job '{}' missed at {}, it is more than {} seconds late",
+                job.get_name(),
+                missed,
+                job_config.catch_up_max_lateness
+            );
+            return None;
+        }
+        Some(missed)
+    }
+
     pub async fn run_once<X: XenbakJob + Send + Clone + Sync + 'static>(
         &mut self,
         job: X,
@@ -100,7 +500,46 @@ impl XenbakScheduler {
         Ok(())
     }
 
-    pub async fn start(&mut self) {
+    /// runs the job once in the background, next to the scheduled jobs
+    pub fn spawn_once<X: XenbakJob + Send + Clone + Sync + 'static>(
+        &self,
+        mut job: X,
+        global_state: Arc<GlobalState>,
+    ) {
+        info!("Running job '{}' once in the background", job.get_name());
+        tokio::spawn(async move {
+            Self::execute_job_with_monitoring(&mut job, global_state).await;
+        });
+    }
+
+    pub async fn start(&mut self, global_state: &GlobalState) {
         self.scheduler.start().await.unwrap();
+        global_state
+            .job_tracker
+            .set_scheduler(self.scheduler.clone());
+    }
+
+    /// pings the systemd watchdog as long as the scheduler answers, systemd restarts the daemon
+    /// once the pings stop
+    pub fn spawn_watchdog(&self, interval: std::time::Duration) {
+        info!("Pinging the systemd watchdog every {:?}", interval / 2);
+        let mut scheduler = self.scheduler.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval / 2).await;
+                match tokio::time::timeout(interval / 2, scheduler.time_till_next_job()).await {
+                    Ok(Ok(_)) => systemd::notify("WATCHDOG=1"),
+                    Ok(Err(e)) => warn!("Scheduler failed, skipping watchdog ping: {}", e),
+                    Err(_) => warn!("Scheduler doesn't respond, skipping watchdog ping"),
+                }
+            }
+        });
+    }
+
+    /// stops firing scheduled jobs, running ones carry on
+    pub async fn shutdown(&mut self) {
+        if let Err(e) = self.scheduler.shutdown().await {
+            warn!("Failed to stop the scheduler: {}", e);
+        }
     }
 }
@@ -0,0 +1,150 @@
+use std::sync::Arc;
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::{info, warn};
+
+/// the daemon shut down and its grace period ran out, the VM's backup was cancelled
+#[derive(Debug, thiserror::Error)]
+#[error("Daemon shut down, cancelled the backup after the grace period of {0} seconds")]
+pub struct ShutdownError(pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShutdownState {
+    Running,
+    /// no new VM backups are started, running ones are finished
+    Draining,
+    /// the grace period is over, running VM backups are cancelled
+    Cancelled,
+}
+
+/// shutdown of the daemon, shared by all jobs
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    state: Arc<watch::Sender<ShutdownState>>,
+    /// names of the jobs with a run in progress, the daemon exits once they are done
+    running: Arc<watch::Sender<Vec<String>>>,
+    grace_period: u64,
+}
+
+/// a run in progress, see `Shutdown::track_run`
+#[derive(Debug)]
+pub struct RunGuard {
+    running: Arc<watch::Sender<Vec<String>>>,
+    job_name: String,
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        self.running.send_modify(|x| {
+            if let Some(i) = x.iter().position(|x| x == &self.job_name) {
+                x.remove(i);
+            }
+        });
+    }
+}
+
+impl Shutdown {
+    pub fn new(grace_period: u64) -> Self {
+        Shutdown {
+            state: Arc::new(watch::Sender::new(ShutdownState::Running)),
+            running: Arc::new(watch::Sender::new(vec![])),
+            grace_period,
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.state.borrow() != ShutdownState::Running
+    }
+
+    /// resolves once the shutdown is requested
+    pub async fn requested(&self) {
+        let mut receiver = self.state.subscribe();
+        // the sender lives as long as self
+        let _ = receiver.wait_for(|x| *x != ShutdownState::Running).await;
+    }
+
+    /// resolves once running VM backups have to be cancelled
+    pub async fn cancelled(&self) {
+        let mut receiver = self.state.subscribe();
+        // the sender lives as long as self
+        let _ = receiver.wait_for(|x| *x == ShutdownState::Cancelled).await;
+    }
+
+    pub fn error(&self) -> ShutdownError {
+        ShutdownError(self.grace_period)
+    }
+
+    /// counts the run of the job until the guard is dropped
+    pub fn track_run(&self, job_name: &str) -> RunGuard {
+        self.running.send_modify(|x| x.push(job_name.to_string()));
+        RunGuard {
+            running: self.running.clone(),
+            job_name: job_name.to_string(),
+        }
+    }
+
+    /// names of the jobs with a run in progress
+    pub fn running_jobs(&self) -> Vec<String> {
+        self.running.borrow().clone()
+    }
+
+    /// stops starting new jobs and VM backups
+    pub fn request(&self) {
+        self.state.send_replace(ShutdownState::Draining);
+    }
+
+    /// waits for the running jobs after the shutdown was requested, cancelling their VM backups
+    /// once the grace period is over. another signal cancels them right away
+    pub async fn drain(&self, signals: &mut Signals) {
+        let mut running = self.running.subscribe();
+        if running.borrow().is_empty() {
+            return;
+        }
+
+        info!(
+            "Waiting up to {} seconds for {} running jobs to finish",
+            self.grace_period,
+            running.borrow().len()
+        );
+        tokio::select! {
+            _ = running.wait_for(|x| x.is_empty()) => return,
+            _ = tokio::time::sleep(std::time::Duration::from_secs(self.grace_period)) => {
+                warn!("Grace period is over, cancelling running backups");
+            }
+            _ = signals.recv() => {
+                warn!("Received another signal, cancelling running backups");
+            }
+        }
+        self.state.send_replace(ShutdownState::Cancelled);
+        // cancelled backups still delete their snapshots and the jobs send their notifications
+        tokio::select! {
+            _ = running.wait_for(|x| x.is_empty()) => {}
+            _ = signals.recv() => warn!("Received another signal, exiting right away"),
+        }
+    }
+}
+
+/// SIGTERM and SIGINT
+pub struct Signals {
+    sigterm: tokio::signal::unix::Signal,
+    sigint: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    pub fn new() -> eyre::Result<Self> {
+        Ok(Signals {
+            sigterm: signal(SignalKind::terminate())?,
+            sigint: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    pub async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.sigterm.recv() => {}
+            _ = self.sigint.recv() => {}
+        }
+    }
+}
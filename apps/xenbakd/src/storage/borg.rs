@@ -1,19 +1,96 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    path::PathBuf,
+    process::Stdio,
+    sync::{Arc, OnceLock},
+};
 
 use async_tempfile::TempFile;
 use eyre::Context;
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncReadExt;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 
 use tokio::process::Command as AsyncCommand;
 
 use crate::{
-    config::{BorgStorageConfig, JobConfig},
+    config::{BorgMaintenanceConfig, BorgStorageConfig, JobConfig},
     jobs::JobType,
 };
 
-use super::{BackupObjectFilter, CompressionType, StorageHandler, StorageStatus, StorageType};
+use super::{
+    borg_lock::{ledger_prefix, live_owners, stale_lock_owners, RepoLease, LOCK_FAILED_MESSAGE},
+    checksum::{sha256_of_reader, HashingReader, CHECKSUM_EXTENSION},
+    encode_name_component,
+    orphans::remove_orphaned_files,
+    probe::{is_probe_file, probe_dir},
+    restore_point::{RestorePoint, RestorePointArtifactKind},
+    BackupObject, BackupObjectFilter, CompressionType, DryRun, StdioStream, StorageHandler,
+    StorageStatus, StorageType,
+};
+
+/// prefix of the temporary files created by async-tempfile
+const TEMP_FILE_PREFIX: &str = "atmp_";
+
+/// metadata kept as JSON in the comment of every archive created by xenbakd
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BorgArchiveComment {
+    pub job_name: String,
+    pub job_type: JobType,
+    pub vm_uuid: Option<String>,
+    pub vm_name: String,
+    pub xen_host: String,
+    pub snapshot_time: chrono::DateTime<chrono::Utc>,
+    pub checksum: Option<String>,
+    pub xenbakd_version: String,
+    /// pool alias of the xen host
+    #[serde(default)]
+    pub pool: Option<String>,
+}
+
+impl BorgArchiveComment {
+    pub fn new(job_name: &str, backup_object: &BackupObject, checksum: Option<String>) -> Self {
+        BorgArchiveComment {
+            job_name: job_name.to_string(),
+            job_type: backup_object.job_type.clone(),
+            vm_uuid: backup_object.vm_uuid.clone(),
+            vm_name: backup_object.vm_name.clone(),
+            xen_host: backup_object.xen_host.clone(),
+            snapshot_time: backup_object.time_stamp,
+            checksum,
+            xenbakd_version: env!("CARGO_PKG_VERSION").to_string(),
+            pool: backup_object.pool.clone(),
+        }
+    }
+
+    /// parses the comment of an archive. archives of older versions only carry the checksum
+    /// as `sha256:<hex>`, they are returned without metadata
+    pub fn parse(comment: &str) -> (Option<BorgArchiveComment>, Option<String>) {
+        if let Ok(parsed) = serde_json::from_str::<BorgArchiveComment>(comment) {
+            let checksum = parsed.checksum.clone();
+            return (Some(parsed), checksum);
+        }
+
+        let checksum = comment
+            .split_whitespace()
+            .find_map(|x| x.strip_prefix(&format!("{}:", CHECKSUM_EXTENSION)))
+            .map(String::from);
+        (None, checksum)
+    }
+
+    pub fn to_backup_object(&self) -> BackupObject {
+        BackupObject {
+            pool: self.pool.clone(),
+            ..BackupObject::new(
+                self.job_type.clone(),
+                self.vm_uuid.clone(),
+                self.vm_name.clone(),
+                self.xen_host.clone(),
+                self.snapshot_time,
+                None,
+            )
+        }
+    }
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum BorgCompressionType {
@@ -63,11 +140,11 @@ pub enum BorgEncryptionType {
     RepokeyBlake2,
 }
 
-impl ToString for BorgEncryptionType {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for BorgEncryptionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            BorgEncryptionType::Repokey => "repokey".to_string(),
-            BorgEncryptionType::RepokeyBlake2 => "repokey-blake2".to_string(),
+            BorgEncryptionType::Repokey => write!(f, "repokey"),
+            BorgEncryptionType::RepokeyBlake2 => write!(f, "repokey-blake2"),
         }
     }
 }
@@ -77,6 +154,8 @@ pub struct BorgLocalStorage {
     pub storage_type: StorageType,
     pub storage_config: BorgStorageConfig,
     pub job_config: JobConfig,
+    /// entry in the repo-lock ledger, held from `initialize` until the job run ends
+    lease: Arc<OnceLock<RepoLease>>,
 }
 
 impl BorgLocalStorage {
@@ -85,56 +164,187 @@ impl BorgLocalStorage {
             storage_type: StorageType::Borg,
             job_config,
             storage_config,
+            lease: Arc::new(OnceLock::new()),
+        }
+    }
+
+    fn get_ledger_prefix(&self) -> String {
+        ledger_prefix(
+            &self.storage_config.temp_dir,
+            &self.storage_config.repository,
+        )
+    }
+
+    /// a crashed run leaves the repository locked, blocking all following runs. the lock is
+    /// only considered stale if no running xenbakd has the repository in its ledger and every
+    /// holder named in the lock is a process of this host which is gone
+    async fn recover_stale_lock(&self) -> eyre::Result<()> {
+        let ledger_prefix = self.get_ledger_prefix();
+        let owners = live_owners(&ledger_prefix).await?;
+        if !owners.is_empty() {
+            debug!(
+                "Borg repository of storage '{}' is in use by {} xenbakd run(s), skipping lock check",
+                self.storage_config.name,
+                owners.len()
+            );
+            return Ok(());
+        }
+
+        let mut probe_cmd = self.borg_base_cmd();
+        probe_cmd.arg("with-lock").arg("::").arg("true");
+        let probe_output = probe_cmd.output().await?;
+        let stderr = String::from_utf8_lossy(&probe_output.stderr);
+        // other errors surface in the following borg commands
+        if probe_output.status.success() || !stderr.contains(LOCK_FAILED_MESSAGE) {
+            return Ok(());
+        }
+
+        // a run may have started while waiting for the lock
+        if !live_owners(&ledger_prefix).await?.is_empty() {
+            return Ok(());
+        }
+
+        let lock_owners = match stale_lock_owners(&self.storage_config.repository).await {
+            Ok(lock_owners) => lock_owners
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            Err(e) => {
+                warn!(
+                    "Borg repository '{}' of storage '{}' is locked and no xenbakd run owns it, \
+                     but the lock isn't known to be stale ({}), leaving it in place: {}",
+                    self.storage_config.repository,
+                    self.storage_config.name,
+                    e,
+                    stderr.trim()
+                );
+                return Ok(());
+            }
+        };
+
+        if !self.storage_config.break_stale_locks {
+            warn!(
+                "Borg repository '{}' of storage '{}' is locked, but no xenbakd run owns it. \
+                 Remove the lock of the crashed run with `borg break-lock` or enable `break_stale_locks`: {}",
+                self.storage_config.repository,
+                self.storage_config.name,
+                stderr.trim()
+            );
+            return Ok(());
         }
+
+        warn!(
+            "Breaking stale lock of borg repository '{}' (storage '{}', job '{}'), no xenbakd run owns it \
+             and its holder {} is gone: {}",
+            self.storage_config.repository,
+            self.storage_config.name,
+            self.job_config.name,
+            lock_owners,
+            stderr.trim()
+        );
+        let mut break_cmd = self.borg_base_cmd();
+        break_cmd.arg("break-lock").arg("::");
+        let break_output = break_cmd.output().await?;
+        if !break_output.status.success() {
+            return Err(eyre::eyre!(
+                "Failed to break stale lock of borg repository: {}",
+                String::from_utf8_lossy(&break_output.stderr)
+            ));
+        }
+        warn!(
+            "Broke stale lock of borg repository '{}'",
+            self.storage_config.repository
+        );
+
+        Ok(())
     }
 
     pub fn backup_object_to_archive_name(
         &self,
         backup_object: crate::storage::BackupObject,
     ) -> String {
-        format!(
-            "{}__{}__{}__{}",
-            backup_object.xen_host,
-            backup_object.job_type.to_string(),
-            backup_object.vm_name,
-            backup_object.time_stamp.to_rfc3339()
-        )
+        backup_object.to_base_name()
     }
 
-    pub fn _archive_name_to_backup_object(
+    pub fn archive_name_to_backup_object(
         &self,
         archive_name: String,
-    ) -> crate::storage::BackupObject {
-        let parts: Vec<&str> = archive_name.split("__").collect();
-        if parts.len() != 4 {
-            panic!("Invalid backup object name");
-        }
+    ) -> eyre::Result<crate::storage::BackupObject> {
+        crate::storage::BackupObject::from_name_with_extension(&archive_name, None)
+    }
 
-        let xen_host = parts[0];
-        let job_type = JobType::from_str(parts[1]).unwrap();
-        let vm_name = parts[2];
-        let time_stamp = chrono::DateTime::parse_from_rfc3339(parts[3].split(".").next().unwrap())
-            .unwrap()
-            .to_utc();
+    pub fn get_rsh_env(&self) -> Option<String> {
+        self.storage_config
+            .ssh_key_path
+            .as_ref()
+            .map(|ssh_key_path| format!("ssh -o StrictHostKeyChecking=no -i {}", ssh_key_path))
+    }
 
-        crate::storage::BackupObject {
-            job_type,
-            xen_host: xen_host.to_string(),
-            vm_name: vm_name.to_string(),
-            time_stamp,
-            size: None,
+    /// the passphrase file is read for every command, so it can be rotated without a restart
+    pub fn get_passphrase(&self) -> Option<String> {
+        if let Some(passphrase) = &self.storage_config.passphrase {
+            return Some(passphrase.clone());
+        }
+        let passphrase_file = self.storage_config.passphrase_file.as_ref()?;
+        match std::fs::read_to_string(passphrase_file) {
+            Ok(passphrase) => Some(passphrase.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) => {
+                // borg fails with a meaningful error without the passphrase
+                warn!(
+                    "Failed to read borg passphrase file '{}': {}",
+                    passphrase_file, e
+                );
+                None
+            }
         }
     }
 
-    pub fn get_rsh_env(&self) -> Option<String> {
-        if let Some(ssh_key_path) = &self.storage_config.ssh_key_path {
-            Some(format!(
-                "ssh -o StrictHostKeyChecking=no -i {}",
-                ssh_key_path
-            ))
-        } else {
-            None
+    /// checks the consistency of the repository and frees the space of deleted archives
+    pub async fn maintain(&self, maintenance: &BorgMaintenanceConfig) -> eyre::Result<()> {
+        if maintenance.check {
+            info!(
+                "Checking borg repository of storage '{}'{}...",
+                self.storage_config.name,
+                if maintenance.verify_data {
+                    " including all data"
+                } else {
+                    ""
+                }
+            );
+            let mut check_cmd = self.borg_base_cmd();
+            check_cmd.arg("check");
+            if maintenance.verify_data {
+                check_cmd.arg("--verify-data");
+            }
+            let check_output = check_cmd.output().await?;
+
+            if !check_output.status.success() {
+                return Err(eyre::eyre!(
+                    "Borg repository check failed, run `borg check --repair` manually after investigating: {}",
+                    String::from_utf8_lossy(&check_output.stderr)
+                ));
+            }
+        }
+
+        if maintenance.compact && !self.storage_config.append_only {
+            info!(
+                "Compacting borg repository of storage '{}'...",
+                self.storage_config.name
+            );
+            let mut compact_cmd = self.borg_base_cmd();
+            compact_cmd.arg("compact");
+            let compact_output = compact_cmd.output().await?;
+
+            if !compact_output.status.success() {
+                return Err(eyre::eyre!(
+                    "Failed to compact borg repository: {}",
+                    String::from_utf8_lossy(&compact_output.stderr)
+                ));
+            }
         }
+
+        Ok(())
     }
 
     pub fn borg_base_cmd(&self) -> AsyncCommand {
@@ -144,6 +354,15 @@ impl BorgLocalStorage {
         if let Some(rsh) = self.get_rsh_env() {
             cmd.env("BORG_RSH", rsh);
         }
+        if let Some(passphrase) = self.get_passphrase() {
+            cmd.env("BORG_PASSPHRASE", passphrase);
+        }
+        if let Some(key_file) = &self.storage_config.key_file {
+            cmd.env("BORG_KEY_FILE", key_file);
+        }
+        if let Some(remote_path) = &self.storage_config.remote_path {
+            cmd.arg("--remote-path").arg(remote_path);
+        }
         cmd.arg("--lock-wait").arg("300");
         cmd
     }
@@ -152,13 +371,58 @@ impl BorgLocalStorage {
 #[async_trait::async_trait]
 impl StorageHandler for BorgLocalStorage {
     async fn status(&self) -> eyre::Result<StorageStatus> {
-        todo!()
+        let mut info_cmd = self.borg_base_cmd();
+        info_cmd.arg("info").arg("--json");
+        let info_output = info_cmd.output().await?;
+
+        if !info_output.status.success() {
+            return Err(eyre::eyre!(
+                "Failed to get borg repository info: {}",
+                String::from_utf8_lossy(&info_output.stderr)
+            ));
+        }
+
+        let info: serde_json::Value = serde_json::from_slice(&info_output.stdout)?;
+        let used_space = info["cache"]["stats"]["unique_csize"]
+            .as_u64()
+            .unwrap_or_default();
+
+        // free space can only be determined for repositories on a local path
+        let repository = std::path::Path::new(&self.storage_config.repository);
+        let (free_space, total_space) = if repository.is_dir() {
+            (
+                fs2::available_space(repository)?,
+                fs2::total_space(repository)?,
+            )
+        } else {
+            (0, 0)
+        };
+
+        Ok(StorageStatus {
+            free_space,
+            total_space,
+            used_space,
+            backup_count: self.list(BackupObjectFilter::default()).await?.len() as u32,
+        })
+    }
+
+    fn get_retention_count(&self) -> u32 {
+        let retention = &self.storage_config.retention;
+        retention.daily + retention.weekly + retention.monthly + retention.yearly
+    }
+
+    fn get_delete_protection_days(&self) -> Option<u32> {
+        self.storage_config.delete_protection_days
     }
 
     fn get_job_config(&self) -> JobConfig {
         self.job_config.clone()
     }
 
+    fn get_name(&self) -> String {
+        self.storage_config.name.clone()
+    }
+
     fn get_storage_type(&self) -> StorageType {
         self.storage_type.clone()
     }
@@ -176,9 +440,7 @@ impl StorageHandler for BorgLocalStorage {
         }
         .await;
 
-        if let Err(e) = temp_dir_result {
-            return Err(e);
-        }
+        temp_dir_result?;
 
         let borg_init_result: eyre::Result<()> = async {
             let mut init_cmd = self.borg_base_cmd();
@@ -190,6 +452,9 @@ impl StorageHandler for BorgLocalStorage {
                     Some(encryption) => encryption.to_string(),
                     None => "none".to_string(),
                 });
+            if self.storage_config.append_only {
+                init_cmd.arg("--append-only");
+            }
 
             let init_output = init_cmd.output().await?;
 
@@ -211,69 +476,192 @@ impl StorageHandler for BorgLocalStorage {
         }
         .await;
 
-        if let Err(e) = borg_init_result {
-            return Err(e);
+        borg_init_result?;
+
+        self.recover_stale_lock().await?;
+        if self.lease.get().is_none() {
+            let lease = RepoLease::acquire(
+                &self.get_ledger_prefix(),
+                &self.storage_config.name,
+                &self.job_config.name,
+            )
+            .await?;
+            let _ = self.lease.set(lease);
         }
 
-        borg_init_result
+        Ok(())
     }
 
-    async fn list(
-        &self,
-        _filter: BackupObjectFilter,
-    ) -> eyre::Result<Vec<crate::storage::BackupObject>> {
-        todo!()
+    // write access to the repository itself is checked by borg's lock on every command
+    async fn probe(&self) -> eyre::Result<()> {
+        probe_dir(&self.storage_config.temp_dir).await?;
+
+        let mut info_cmd = self.borg_base_cmd();
+        info_cmd.arg("info").arg("--json");
+        let info_output = info_cmd.output().await?;
+        if !info_output.status.success() {
+            return Err(eyre::eyre!(
+                "Borg repository is not accessible: {}",
+                String::from_utf8_lossy(&info_output.stderr)
+            ));
+        }
+
+        Ok(())
+    }
+
+    // exports which were never handed over to borg
+    async fn cleanup_orphans(&self) -> eyre::Result<u64> {
+        remove_orphaned_files(&self.storage_config.temp_dir, 0, |x| {
+            x.starts_with(TEMP_FILE_PREFIX) || is_probe_file(x)
+        })
+        .await
+    }
+
+    async fn list(&self, filter: BackupObjectFilter) -> eyre::Result<Vec<RestorePoint>> {
+        let mut list_cmd = self.borg_base_cmd();
+        // keys used in the format are added to the json output
+        list_cmd
+            .arg("list")
+            .arg("--json")
+            .arg("--format")
+            .arg("{comment}");
+        let list_output = list_cmd.output().await?;
+
+        if !list_output.status.success() {
+            return Err(eyre::eyre!(
+                "Failed to list borg archives: {}",
+                String::from_utf8_lossy(&list_output.stderr)
+            ));
+        }
+
+        let list: serde_json::Value = serde_json::from_slice(&list_output.stdout)?;
+        let mut restore_points = vec![];
+
+        for archive in list["archives"].as_array().cloned().unwrap_or_default() {
+            let Some(name) = archive["name"].as_str() else {
+                continue;
+            };
+
+            // the metadata in the comment is preferred over the archive name, older archives
+            // only have the name. archives with neither were not created by xenbakd
+            let (comment, checksum) =
+                BorgArchiveComment::parse(archive["comment"].as_str().unwrap_or_default());
+            let backup_object = match comment {
+                Some(comment) => comment.to_backup_object(),
+                None => match self.archive_name_to_backup_object(name.to_string()) {
+                    Ok(backup_object) => backup_object,
+                    Err(_) => continue,
+                },
+            };
+
+            if !filter.matches(&backup_object) {
+                continue;
+            }
+
+            let mut restore_point = RestorePoint::new(self.get_name(), backup_object);
+            restore_point.add_artifact(
+                RestorePointArtifactKind::Data,
+                format!("{}::{}", self.storage_config.repository, name),
+                None,
+            );
+
+            // the checksum of the export stream is kept in the archive comment
+            restore_point.checksum = checksum;
+
+            restore_points.push(restore_point);
+        }
+
+        Ok(restore_points)
     }
 
-    async fn rotate(&self, filter: BackupObjectFilter) -> eyre::Result<()> {
-        if self.storage_config.retention.daily == 0
+    async fn rotate(
+        &self,
+        filter: BackupObjectFilter,
+        retention: Option<u32>,
+        dry_run: DryRun,
+    ) -> eyre::Result<Vec<String>> {
+        if retention.is_none()
+            && self.storage_config.retention.daily == 0
             && self.storage_config.retention.weekly == 0
             && self.storage_config.retention.monthly == 0
             && self.storage_config.retention.yearly == 0
         {
             info!("Retention is set to 0, skipping rotation...");
-            return Ok(());
+            return Ok(vec![]);
         }
 
-        let mut prune_cmd = self.borg_base_cmd();
-        prune_cmd.arg("prune");
-
-        prune_cmd
-            .arg("--keep-daily")
-            .arg(self.storage_config.retention.daily.to_string().as_str());
-
-        prune_cmd
-            .arg("--keep-weekly")
-            .arg(self.storage_config.retention.weekly.to_string().as_str());
+        // deletions in an append-only repository free no space, pruning is left to a trusted client
+        if self.storage_config.append_only {
+            info!(
+                "Borg repository of storage '{}' is append-only, skipping rotation",
+                self.storage_config.name
+            );
+            return Ok(vec![]);
+        }
 
-        prune_cmd
-            .arg("--keep-monthly")
-            .arg(self.storage_config.retention.monthly.to_string().as_str());
+        // the pruned archives are read from the list
+        let mut prune_cmd = self.borg_base_cmd();
+        prune_cmd.arg("prune").arg("--list");
+
+        match retention {
+            // a plain number of archives replaces the daily/weekly/... policy
+            Some(retention) => {
+                prune_cmd
+                    .arg("--keep-last")
+                    .arg(retention.to_string().as_str());
+            }
+            None => {
+                prune_cmd
+                    .arg("--keep-daily")
+                    .arg(self.storage_config.retention.daily.to_string().as_str());
+
+                prune_cmd
+                    .arg("--keep-weekly")
+                    .arg(self.storage_config.retention.weekly.to_string().as_str());
+
+                prune_cmd
+                    .arg("--keep-monthly")
+                    .arg(self.storage_config.retention.monthly.to_string().as_str());
+
+                prune_cmd
+                    .arg("--keep-yearly")
+                    .arg(self.storage_config.retention.yearly.to_string().as_str());
+            }
+        }
 
-        prune_cmd
-            .arg("--keep-yearly")
-            .arg(self.storage_config.retention.yearly.to_string().as_str());
+        // archives within the window are kept regardless of the retention policy
+        if let Some(days) = self.storage_config.delete_protection_days {
+            prune_cmd.arg("--keep-within").arg(format!("{}d", days));
+        }
 
+        // encoded names contain no glob characters
         prune_cmd.arg("--glob-archives").arg(format!(
-            "{}__{}__{}*",
-            filter
-                .xen_host
-                .unwrap_or_default()
-                .first()
-                .unwrap_or(&"".to_string()),
+            "{}__{}__{}__*",
+            encode_name_component(
+                filter
+                    .xen_host
+                    .unwrap_or_default()
+                    .first()
+                    .unwrap_or(&"".to_string())
+            ),
             filter
                 .job_type
                 .unwrap_or_default()
                 .first()
-                .unwrap_or(&JobType::VmBackup)
-                .to_string(),
-            filter
-                .vm_name
-                .unwrap_or_default()
-                .first()
-                .unwrap_or(&"".to_string())
+                .unwrap_or(&JobType::VmBackup),
+            encode_name_component(
+                filter
+                    .vm_name
+                    .unwrap_or_default()
+                    .first()
+                    .unwrap_or(&"".to_string())
+            )
         ));
 
+        if dry_run.is_enabled() {
+            prune_cmd.arg("--dry-run");
+        }
+
         info!("Pruning borg repository...");
         let prune_output = prune_cmd.output().await?;
 
@@ -284,6 +672,12 @@ impl StorageHandler for BorgLocalStorage {
             ));
         }
 
+        // borg prints the archive list to stderr, a dry run deleted nothing, so there's nothing to compact
+        let pruned = pruned_archives(&String::from_utf8_lossy(&prune_output.stderr));
+        if dry_run.is_enabled() {
+            return Ok(pruned);
+        }
+
         info!("Compacting borg repository...");
         let mut compact_cmd = self.borg_base_cmd();
         compact_cmd.arg("compact");
@@ -297,14 +691,119 @@ impl StorageHandler for BorgLocalStorage {
             ));
         }
 
+        Ok(pruned)
+    }
+
+    // the space is only freed by the next compaction during rotation
+    async fn delete(&self, restore_point: &RestorePoint) -> eyre::Result<()> {
+        if self.storage_config.append_only {
+            return Err(eyre::eyre!(
+                "Borg repository of storage '{}' is append-only, archives can't be deleted",
+                self.storage_config.name
+            ));
+        }
+
+        let archive = format!(
+            "::{}",
+            self.backup_object_to_archive_name(restore_point.backup_object.clone())
+        );
+
+        debug!("Deleting borg archive {}", archive);
+        let mut delete_cmd = self.borg_base_cmd();
+        delete_cmd.arg("delete").arg(&archive);
+        let delete_output = delete_cmd.output().await?;
+
+        if !delete_output.status.success() {
+            return Err(eyre::eyre!(
+                "Failed to delete borg archive: {}",
+                String::from_utf8_lossy(&delete_output.stderr)
+            ));
+        }
+
+        Ok(())
+    }
+
+    // borg prune selects archives by name only, so single archives can't be excluded
+    async fn set_pinned(&self, _restore_point: &RestorePoint, _pinned: bool) -> eyre::Result<()> {
+        Err(eyre::eyre!("Pinning is not supported on borg storages"))
+    }
+
+    async fn open_restore_stream(&self, restore_point: &RestorePoint) -> eyre::Result<StdioStream> {
+        let archive = format!(
+            "::{}",
+            self.backup_object_to_archive_name(restore_point.backup_object.clone())
+        );
+
+        let mut extract_cmd = self.borg_base_cmd();
+        extract_cmd.arg("extract").arg("--stdout").arg(&archive);
+        let mut child = extract_cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+
+        // a failed extraction truncates the stream, the reason is only in borg's error output
+        tokio::spawn(async move {
+            match child.wait_with_output().await {
+                Ok(output) if !output.status.success() => error!(
+                    "Failed to extract borg archive {}: {}",
+                    archive,
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+                Err(e) => error!("Failed to extract borg archive {}: {}", archive, e),
+                Ok(_) => {}
+            }
+        });
+
+        Ok(Box::new(stdout))
+    }
+
+    async fn verify(&self, restore_point: &RestorePoint) -> eyre::Result<()> {
+        let archive = format!(
+            "::{}",
+            self.backup_object_to_archive_name(restore_point.backup_object.clone())
+        );
+
+        let expected = restore_point
+            .checksum
+            .as_ref()
+            .ok_or_else(|| eyre::eyre!("No checksum recorded for archive {}", archive))?;
+
+        // extract the archive and hash its content
+        let mut extract_cmd = self.borg_base_cmd();
+        extract_cmd.arg("extract").arg("--stdout").arg(&archive);
+        let mut child = extract_cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let actual = sha256_of_reader(child.stdout.take().unwrap()).await?;
+        let extract_output = child.wait_with_output().await?;
+
+        if !extract_output.status.success() {
+            return Err(eyre::eyre!(
+                "Failed to extract borg archive: {}",
+                String::from_utf8_lossy(&extract_output.stderr)
+            ));
+        }
+
+        if &actual != expected {
+            return Err(eyre::eyre!(
+                "Checksum mismatch: expected {}, got {}",
+                expected,
+                actual
+            ));
+        }
+
         Ok(())
     }
 
     async fn handle_stdio_stream(
         &self,
         backup_object: crate::storage::BackupObject,
-        mut stdout_stream: tokio::process::ChildStdout,
-        mut stderr_stream: tokio::process::ChildStderr,
+        mut stdout_stream: StdioStream,
+        mut stderr_stream: StdioStream,
     ) -> eyre::Result<()> {
         let mut temp_file = TempFile::new_in(PathBuf::from(&self.storage_config.temp_dir))
             .await
@@ -317,7 +816,7 @@ impl StorageHandler for BorgLocalStorage {
             );
 
             const BUFFER_SIZE: usize = 1024 * 1024 * 10;
-            let mut stdout_buffered = tokio::io::BufReader::with_capacity(BUFFER_SIZE, &mut stdout_stream);
+            let mut stdout_buffered = HashingReader::new(tokio::io::BufReader::with_capacity(BUFFER_SIZE, &mut stdout_stream));
             let mut stderr_buffered = tokio::io::BufReader::new(&mut stderr_stream);
             let tempfile_copy = tokio::io::copy(&mut stdout_buffered, &mut temp_file).await?;
 
@@ -333,14 +832,14 @@ impl StorageHandler for BorgLocalStorage {
                 ));
             }
 
-            Ok(temp_file)
+            Ok((temp_file, stdout_buffered.finalize()))
         }
         .await.wrap_err(
             "Failed to write export stream to temporary file, or encountered error in stderr output",
             );
 
         let borg_results = async {
-            let temp_file = tempfile_results?;
+            let (temp_file, checksum) = tempfile_results?;
 
             info!(
                 "Running borg backup to repo {} with archive: {}",
@@ -355,6 +854,13 @@ impl StorageHandler for BorgLocalStorage {
                 borg_cmd.arg("--compression").arg(compression.to_cli_arg());
             }
 
+            // the backup object and the checksum of the export stream are kept in the archive's metadata
+            let comment =
+                BorgArchiveComment::new(&self.job_config.name, &backup_object, Some(checksum));
+            borg_cmd
+                .arg("--comment")
+                .arg(serde_json::to_string(&comment)?);
+
             borg_cmd.arg(
                 format!(
                     "::{}",
@@ -388,10 +894,18 @@ impl StorageHandler for BorgLocalStorage {
         .await
         .wrap_err("Failed to run borg backup");
 
-        if let Err(e) = borg_results {
-            return Err(e);
-        }
-
-        Ok(())
+        borg_results
     }
 }
+
+/// names of the archives in the output of `borg prune --list`, e.g. `Would prune: <name> <date> [<id>]`
+/// or `Pruning archive (1/3): <name> <date> [<id>]`
+fn pruned_archives(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|x| x.starts_with("Would prune") || x.starts_with("Pruning archive"))
+        .filter_map(|x| x.split_once(':'))
+        .filter_map(|(_, x)| x.split_whitespace().next())
+        .map(|x| x.to_string())
+        .collect()
+}
@@ -0,0 +1,207 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+/// stderr of borg when the repository lock couldn't be acquired within `--lock-wait`
+pub const LOCK_FAILED_MESSAGE: &str = "Failed to create/acquire the lock";
+
+/// job runs of this process currently using a repository, by ledger prefix
+static ACTIVE_RUNS: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+
+/// holder of borg's repository lock, as recorded in the lock roster of the repository
+#[derive(Debug, Clone)]
+pub struct BorgLockOwner {
+    /// `<hostname>@<node id>` of the holder
+    pub host: String,
+    pub pid: u32,
+}
+
+impl std::fmt::Display for BorgLockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (pid {})", self.host, self.pid)
+    }
+}
+
+/// `lock.roster` of a repository, the lock entries are `[host, pid, thread]`
+#[derive(Debug, Deserialize)]
+struct LockRoster {
+    #[serde(default)]
+    exclusive: Vec<(String, u32, u64)>,
+    #[serde(default)]
+    shared: Vec<(String, u32, u64)>,
+}
+
+/// entry of the repo-lock ledger, written by every xenbakd process while it uses a repository.
+/// borg's own lock can't tell a crashed run from a long-running one on a remote repository
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub pid: u32,
+    pub storage_name: String,
+    pub job_name: String,
+    pub started: chrono::DateTime<chrono::Utc>,
+}
+
+/// registers a job run in the ledger, the entry is removed once the last run of the process ends
+#[derive(Debug)]
+pub struct RepoLease {
+    ledger_prefix: String,
+    ledger_path: String,
+}
+
+/// ledger files are named `<temp_dir>/xenbakd-borg-<repository hash>.<pid>.ledger`
+pub fn ledger_prefix(temp_dir: &str, repository: &str) -> String {
+    let hash = hex::encode(Sha256::digest(repository.as_bytes()));
+    format!("{}/xenbakd-borg-{}", temp_dir, &hash[..16])
+}
+
+fn active_runs() -> &'static Mutex<HashMap<String, usize>> {
+    ACTIVE_RUNS.get_or_init(Default::default)
+}
+
+/// checks whether the process is alive and a xenbakd, pids may have been reused since the entry was written
+fn is_xenbakd_alive(pid: u32) -> bool {
+    let own_comm = std::fs::read_to_string("/proc/self/comm").unwrap_or_default();
+    std::fs::read_to_string(format!("/proc/{}/comm", pid)).is_ok_and(|x| x == own_comm)
+}
+
+impl RepoLease {
+    pub async fn acquire(
+        ledger_prefix: &str,
+        storage_name: &str,
+        job_name: &str,
+    ) -> eyre::Result<RepoLease> {
+        let ledger_path = format!("{}.{}.ledger", ledger_prefix, std::process::id());
+        *active_runs()
+            .lock()
+            .unwrap()
+            .entry(ledger_prefix.to_string())
+            .or_default() += 1;
+
+        let lease = RepoLease {
+            ledger_prefix: ledger_prefix.to_string(),
+            ledger_path,
+        };
+        let entry = LedgerEntry {
+            pid: std::process::id(),
+            storage_name: storage_name.to_string(),
+            job_name: job_name.to_string(),
+            started: chrono::Utc::now(),
+        };
+        tokio::fs::write(&lease.ledger_path, serde_json::to_vec_pretty(&entry)?).await?;
+
+        Ok(lease)
+    }
+}
+
+impl Drop for RepoLease {
+    fn drop(&mut self) {
+        let mut active_runs = active_runs().lock().unwrap();
+        let count = active_runs.entry(self.ledger_prefix.clone()).or_default();
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            active_runs.remove(&self.ledger_prefix);
+            let _ = std::fs::remove_file(&self.ledger_path);
+        }
+    }
+}
+
+/// returns the ledger entries of other xenbakd processes which are still running. entries of
+/// crashed processes are removed. runs of this process are returned as well
+pub async fn live_owners(ledger_prefix: &str) -> eyre::Result<Vec<LedgerEntry>> {
+    let mut owners = vec![];
+    let own_pid = std::process::id();
+
+    let (dir, file_prefix) = ledger_prefix
+        .rsplit_once('/')
+        .ok_or_else(|| eyre::eyre!("Invalid ledger path {}", ledger_prefix))?;
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if !file_name.starts_with(file_prefix) || !file_name.ends_with(".ledger") {
+            continue;
+        }
+
+        let ledger_entry: LedgerEntry = match tokio::fs::read(entry.path())
+            .await
+            .ok()
+            .and_then(|x| serde_json::from_slice(&x).ok())
+        {
+            Some(ledger_entry) => ledger_entry,
+            None => continue,
+        };
+
+        if ledger_entry.pid == own_pid {
+            // the own entry is only valid while a job run holds a lease
+            if active_runs().lock().unwrap().contains_key(ledger_prefix) {
+                owners.push(ledger_entry);
+            }
+        } else if is_xenbakd_alive(ledger_entry.pid) {
+            owners.push(ledger_entry);
+        } else {
+            debug!(
+                "Removing ledger entry of crashed xenbakd run (pid {}, job '{}')",
+                ledger_entry.pid, ledger_entry.job_name
+            );
+            if let Err(e) = tokio::fs::remove_file(entry.path()).await {
+                warn!("Failed to remove stale ledger entry {}: {}", file_name, e);
+            }
+        }
+    }
+
+    Ok(owners)
+}
+
+/// path of a repository on this host, remote repositories have none
+fn local_repository_path(repository: &str) -> Option<&str> {
+    match repository.strip_prefix("file://") {
+        Some(path) => Some(path),
+        None if !repository.contains(':') => Some(repository),
+        None => None,
+    }
+}
+
+/// returns the holders of the repository's lock if all of them are processes of this host which
+/// are gone. the lock of a remote repository, of another host or of a running process is never
+/// stale, a lock of another host may be held by a run that is still going
+pub async fn stale_lock_owners(repository: &str) -> eyre::Result<Vec<BorgLockOwner>> {
+    let path = local_repository_path(repository)
+        .ok_or_else(|| eyre::eyre!("the lock owner of a remote repository can't be checked"))?;
+    let roster = tokio::fs::read(format!("{}/lock.roster", path))
+        .await
+        .map_err(|e| eyre::eyre!("failed to read the lock roster: {}", e))?;
+    let roster: LockRoster = serde_json::from_slice(&roster)
+        .map_err(|e| eyre::eyre!("failed to parse the lock roster: {}", e))?;
+    let owners = roster
+        .exclusive
+        .into_iter()
+        .chain(roster.shared)
+        .map(|(host, pid, _)| BorgLockOwner { host, pid })
+        .collect::<Vec<_>>();
+    if owners.is_empty() {
+        return Err(eyre::eyre!("the lock roster names no owner"));
+    }
+
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|x| x.trim().to_string())
+        .unwrap_or_default();
+    for owner in &owners {
+        // borg names hosts `<hostname>@<node id>`
+        let owner_hostname = owner.host.split('@').next().unwrap_or_default();
+        if owner_hostname != hostname {
+            return Err(eyre::eyre!("the lock is held by {} on another host", owner));
+        }
+        if std::path::Path::new(&format!("/proc/{}", owner.pid)).exists() {
+            return Err(eyre::eyre!(
+                "the lock is held by {}, which is still running",
+                owner
+            ));
+        }
+    }
+
+    Ok(owners)
+}
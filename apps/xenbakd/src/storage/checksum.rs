@@ -0,0 +1,58 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+/// file extension of checksum sidecar files
+pub const CHECKSUM_EXTENSION: &str = "sha256";
+
+/// wraps a reader and computes the SHA-256 of everything read through it
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: AsyncRead + Unpin> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// returns the hex-encoded digest of all data read so far
+    pub fn finalize(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = result {
+            let new_data = &buf.filled()[filled_before..];
+            self.hasher.update(new_data);
+        }
+
+        result
+    }
+}
+
+/// reads the given stream to its end and returns its hex-encoded SHA-256
+pub async fn sha256_of_reader<R: AsyncRead + Unpin>(reader: R) -> eyre::Result<String> {
+    let mut hashing_reader = HashingReader::new(reader);
+    let mut buffer = vec![0u8; 1024 * 1024];
+
+    while hashing_reader.read(&mut buffer).await? != 0 {}
+
+    Ok(hashing_reader.finalize())
+}
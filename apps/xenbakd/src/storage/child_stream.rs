@@ -0,0 +1,83 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    process::ExitStatus,
+    task::{ready, Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, ReadBuf},
+    process::{Child, ChildStdout},
+    task::JoinHandle,
+};
+
+use super::StdioStream;
+
+/// exit status and error output of the process
+type Exit = std::io::Result<(ExitStatus, Vec<u8>)>;
+
+/// stdout of a filter process, e.g. a decryption command. once the output ends, the stream
+/// fails with the error output of the process if it exited unsuccessfully, a failed filter
+/// would look like a truncated stream otherwise
+pub struct ChildStream {
+    stdout: ChildStdout,
+    name: String,
+    exit: Option<JoinHandle<Exit>>,
+}
+
+impl ChildStream {
+    /// wraps the stdout of the child, its stderr is captured if piped
+    pub fn wrap(mut child: Child, name: &str) -> eyre::Result<StdioStream> {
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| eyre::eyre!("stdout of {} is not piped", name))?;
+        let mut stderr = child.stderr.take();
+        // stderr is read while the output is streamed, a full pipe would block the process
+        let exit = tokio::spawn(async move {
+            let mut output = vec![];
+            if let Some(stderr) = &mut stderr {
+                stderr.read_to_end(&mut output).await?;
+            }
+            Ok((child.wait().await?, output))
+        });
+
+        Ok(Box::new(ChildStream {
+            stdout,
+            name: name.to_string(),
+            exit: Some(exit),
+        }))
+    }
+}
+
+impl AsyncRead for ChildStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.stdout).poll_read(cx, buf) {
+            // nothing read into a non-empty buffer is the end of the output
+            Poll::Ready(Ok(())) if buf.filled().len() == filled && buf.remaining() > 0 => {}
+            result => return result,
+        }
+
+        let Some(exit) = self.exit.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let result = ready!(Pin::new(exit).poll(cx));
+        self.exit = None;
+        Poll::Ready(match result {
+            Ok(Ok((status, _))) if status.success() => Ok(()),
+            Ok(Ok((status, stderr))) => Err(std::io::Error::other(format!(
+                "{} failed ({}): {}",
+                self.name,
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            ))),
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err(std::io::Error::other(e)),
+        })
+    }
+}
@@ -0,0 +1,599 @@
+use std::collections::HashSet;
+
+use fastcdc::v2020::AsyncStreamCDC;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use tracing::{debug, error, info};
+
+use crate::config::{ChunkedStorageConfig, JobConfig};
+
+use super::{
+    checksum::HashingReader,
+    local::PARTIAL_EXTENSION,
+    manifest::ConfigSnapshot,
+    orphans::remove_orphaned_files,
+    probe::{is_probe_file, probe_dir},
+    restore_point::{expired_restore_points, RestorePoint, RestorePointArtifactKind},
+    BackupObject, BackupObjectFilter, DryRun, StdioStream, StorageHandler, StorageStatus,
+    StorageType,
+};
+
+/// file extension of the per-backup chunk indexes
+pub const INDEX_EXTENSION: &str = "index.json";
+/// directory within the storage path holding the chunks of all jobs
+const CHUNK_DIR: &str = "chunks";
+/// file extension of zstd compressed chunks
+const COMPRESSED_CHUNK_EXTENSION: &str = "zst";
+/// held shared while backups write chunks and exclusively while unreferenced chunks are removed
+const LOCK_FILE: &str = "chunks.lock";
+
+/// a chunk of an export stream, identified by the SHA-256 of its uncompressed data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub id: String,
+    pub size: u64,
+}
+
+/// lists the chunks an export stream consists of, in order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkIndex {
+    pub backup_object: BackupObject,
+    /// sha256 checksum of the export stream
+    pub checksum: String,
+    /// size of the export stream
+    pub size: u64,
+    pub chunks: Vec<ChunkRef>,
+    /// pinned backups are never deleted by the rotation
+    #[serde(default)]
+    pub pinned: bool,
+    /// configuration the backup was made with, missing for backups of older versions
+    #[serde(default)]
+    pub config: Option<ConfigSnapshot>,
+}
+
+/// a deduplicating storage, splitting exports into content-defined chunks which are shared
+/// between all backups and jobs of the storage
+#[derive(Debug, Clone)]
+pub struct ChunkedStorage {
+    pub path: String,
+    pub storage_type: StorageType,
+    pub storage_config: ChunkedStorageConfig,
+    pub job_config: JobConfig,
+}
+
+impl ChunkedStorage {
+    pub fn new(storage_config: ChunkedStorageConfig, job_config: JobConfig) -> Self {
+        ChunkedStorage {
+            path: format!("{}/{}", storage_config.path, job_config.name),
+            storage_type: StorageType::Chunked,
+            job_config,
+            storage_config,
+        }
+    }
+
+    fn index_path(&self, backup_object: &BackupObject) -> String {
+        format!(
+            "{}/{}.{}",
+            self.path,
+            backup_object.to_base_name(),
+            INDEX_EXTENSION
+        )
+    }
+
+    fn chunk_path(&self, id: &str, compressed: bool) -> String {
+        let path = format!(
+            "{}/{}/{}/{}",
+            self.storage_config.path,
+            CHUNK_DIR,
+            &id[..2],
+            id
+        );
+
+        match compressed {
+            true => format!("{}.{}", path, COMPRESSED_CHUNK_EXTENSION),
+            false => path,
+        }
+    }
+
+    /// returns the path of a stored chunk, compressed or not
+    async fn find_chunk(&self, id: &str) -> Option<String> {
+        for compressed in [true, false] {
+            let path = self.chunk_path(id, compressed);
+            if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+                return Some(path);
+            }
+        }
+
+        None
+    }
+
+    /// the job directories share the storage path with the chunk store, whose indexes the garbage
+    /// collection wouldn't see
+    fn check_job_name(&self) -> eyre::Result<()> {
+        if self.job_config.name == CHUNK_DIR {
+            return Err(eyre::eyre!(
+                "Job name '{}' is reserved for the chunks of storage '{}'",
+                CHUNK_DIR,
+                self.storage_config.name
+            ));
+        }
+        Ok(())
+    }
+
+    /// stores a chunk unless it already exists, returns the number of bytes written
+    async fn write_chunk(&self, id: &str, data: &[u8]) -> eyre::Result<u64> {
+        if self.find_chunk(id).await.is_some() {
+            return Ok(0);
+        }
+
+        let data = match self.storage_config.compress {
+            true => {
+                let mut encoder = async_compression::tokio::write::ZstdEncoder::new(Vec::new());
+                encoder.write_all(data).await?;
+                encoder.shutdown().await?;
+                encoder.into_inner()
+            }
+            false => data.to_vec(),
+        };
+
+        // chunks are written to a partial file first, so a chunk is either complete or missing.
+        // concurrent backups often share chunks, every writer gets a partial file of its own
+        let path = self.chunk_path(id, self.storage_config.compress);
+        let partial_path = format!("{}.{}.{}", path, uuid::Uuid::new_v4(), PARTIAL_EXTENSION);
+        tokio::fs::create_dir_all(&path[..path.rfind('/').unwrap()]).await?;
+        let result = match tokio::fs::write(&partial_path, &data).await {
+            Ok(()) => tokio::fs::rename(&partial_path, &path).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&partial_path).await;
+            // another backup stored the same chunk in the meantime
+            if self.find_chunk(id).await.is_some() {
+                return Ok(0);
+            }
+            return Err(e.into());
+        }
+
+        Ok(data.len() as u64)
+    }
+
+    /// reads and decompresses a chunk, validating its content against its id
+    async fn read_chunk(&self, chunk: &ChunkRef) -> eyre::Result<Vec<u8>> {
+        let path = self
+            .find_chunk(&chunk.id)
+            .await
+            .ok_or_else(|| eyre::eyre!("Chunk {} is missing", chunk.id))?;
+        let stored = tokio::fs::read(&path).await?;
+
+        let data = match path.ends_with(&format!(".{}", COMPRESSED_CHUNK_EXTENSION)) {
+            true => {
+                let mut data = Vec::with_capacity(chunk.size as usize);
+                async_compression::tokio::bufread::ZstdDecoder::new(&stored[..])
+                    .read_to_end(&mut data)
+                    .await?;
+                data
+            }
+            false => stored,
+        };
+
+        if hex::encode(Sha256::digest(&data)) != chunk.id {
+            return Err(eyre::eyre!("Chunk {} is corrupted", chunk.id));
+        }
+
+        Ok(data)
+    }
+
+    async fn read_index(path: &str) -> eyre::Result<ChunkIndex> {
+        let index = tokio::fs::read(path).await?;
+        Ok(serde_json::from_slice(&index)?)
+    }
+
+    /// writes the index to a partial file first, so an index is either complete or missing
+    async fn write_index(path: &str, index: &ChunkIndex) -> eyre::Result<()> {
+        let partial_path = format!("{}.{}", path, PARTIAL_EXTENSION);
+        tokio::fs::write(&partial_path, serde_json::to_vec_pretty(index)?).await?;
+        tokio::fs::rename(&partial_path, path).await?;
+        Ok(())
+    }
+
+    /// locks the chunk store, `exclusive` locks are only tried and return `None` if the store is busy
+    async fn lock(&self, exclusive: bool) -> eyre::Result<Option<std::fs::File>> {
+        let path = format!("{}/{}", self.storage_config.path, LOCK_FILE);
+
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+
+            match exclusive {
+                true => match file.try_lock_exclusive() {
+                    Ok(()) => Ok(Some(file)),
+                    Err(_) => Ok(None),
+                },
+                false => {
+                    file.lock_shared()?;
+                    Ok(Some(file))
+                }
+            }
+        })
+        .await?
+    }
+
+    /// removes chunks which aren't referenced by any index of any job on this storage
+    async fn collect_garbage(&self) -> eyre::Result<()> {
+        let Some(_lock) = self.lock(true).await? else {
+            debug!("Chunk store is in use, skipping removal of unreferenced chunks");
+            return Ok(());
+        };
+
+        let mut referenced = HashSet::new();
+        let mut job_dirs = tokio::fs::read_dir(&self.storage_config.path).await?;
+        while let Some(job_dir) = job_dirs.next_entry().await? {
+            if !job_dir.metadata().await?.is_dir() || job_dir.file_name() == CHUNK_DIR {
+                continue;
+            }
+
+            let mut entries = tokio::fs::read_dir(job_dir.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path().to_string_lossy().to_string();
+                if !path.ends_with(&format!(".{}", INDEX_EXTENSION)) {
+                    continue;
+                }
+                let index = Self::read_index(&path).await?;
+                referenced.extend(index.chunks.into_iter().map(|x| x.id));
+            }
+        }
+
+        let mut removed = 0;
+        let mut removed_size = 0;
+        let mut prefix_dirs =
+            tokio::fs::read_dir(format!("{}/{}", self.storage_config.path, CHUNK_DIR)).await?;
+        while let Some(prefix_dir) = prefix_dirs.next_entry().await? {
+            // probe files of running or killed runs sit next to the prefix directories
+            if !prefix_dir.metadata().await?.is_dir() {
+                continue;
+            }
+            let mut chunks = tokio::fs::read_dir(prefix_dir.path()).await?;
+            while let Some(chunk) = chunks.next_entry().await? {
+                let file_name = chunk.file_name().to_string_lossy().to_string();
+                let id = file_name.split('.').next().unwrap_or_default();
+
+                // partial chunks are left over from interrupted backups, as no backup is running
+                if referenced.contains(id) && !file_name.ends_with(PARTIAL_EXTENSION) {
+                    continue;
+                }
+
+                removed += 1;
+                removed_size += chunk.metadata().await?.len();
+                tokio::fs::remove_file(chunk.path()).await?;
+            }
+        }
+
+        info!(
+            "Removed {} unreferenced chunks ({:.1} MiB)",
+            removed,
+            removed_size as f64 / 1024.0 / 1024.0
+        );
+
+        Ok(())
+    }
+
+    /// total size of all stored chunks
+    async fn chunk_store_size(&self) -> eyre::Result<u64> {
+        let mut size = 0;
+        let mut prefix_dirs = match tokio::fs::read_dir(format!(
+            "{}/{}",
+            self.storage_config.path, CHUNK_DIR
+        ))
+        .await
+        {
+            Ok(prefix_dirs) => prefix_dirs,
+            Err(_) => return Ok(0),
+        };
+
+        while let Some(prefix_dir) = prefix_dirs.next_entry().await? {
+            if !prefix_dir.metadata().await?.is_dir() {
+                continue;
+            }
+            let mut chunks = tokio::fs::read_dir(prefix_dir.path()).await?;
+            while let Some(chunk) = chunks.next_entry().await? {
+                size += chunk.metadata().await?.len();
+            }
+        }
+
+        Ok(size)
+    }
+}
+
+#[async_trait::async_trait]
+impl StorageHandler for ChunkedStorage {
+    async fn status(&self) -> eyre::Result<StorageStatus> {
+        let restore_points = self.list(BackupObjectFilter::default()).await?;
+
+        Ok(StorageStatus {
+            free_space: fs2::available_space(&self.storage_config.path)?,
+            total_space: fs2::total_space(&self.storage_config.path)?,
+            used_space: self.chunk_store_size().await?,
+            backup_count: restore_points.len() as u32,
+        })
+    }
+
+    fn get_retention_count(&self) -> u32 {
+        self.storage_config.retention
+    }
+
+    fn get_delete_protection_days(&self) -> Option<u32> {
+        self.storage_config.delete_protection_days
+    }
+
+    fn get_job_config(&self) -> JobConfig {
+        self.job_config.clone()
+    }
+
+    fn get_name(&self) -> String {
+        self.storage_config.name.clone()
+    }
+
+    fn get_storage_type(&self) -> StorageType {
+        self.storage_type.clone()
+    }
+
+    fn supports_chains(&self) -> bool {
+        true
+    }
+
+    async fn initialize(&self) -> eyre::Result<()> {
+        self.check_job_name()?;
+        tokio::fs::create_dir_all(&self.path).await?;
+        tokio::fs::create_dir_all(format!("{}/{}", self.storage_config.path, CHUNK_DIR)).await?;
+        Ok(())
+    }
+
+    async fn probe(&self) -> eyre::Result<()> {
+        self.check_job_name()?;
+        probe_dir(&self.path).await?;
+        probe_dir(&format!("{}/{}", self.storage_config.path, CHUNK_DIR)).await
+    }
+
+    // partial chunks in chunks/<prefix>/ and partial indexes of this job
+    async fn cleanup_orphans(&self) -> eyre::Result<u64> {
+        let chunk_dir = format!("{}/{}", self.storage_config.path, CHUNK_DIR);
+        Ok(remove_orphaned_files(&chunk_dir, 1, |x| {
+            x.ends_with(&format!(".{}", PARTIAL_EXTENSION)) || is_probe_file(x)
+        })
+        .await?
+            + remove_orphaned_files(&self.path, 0, |x| {
+                x.ends_with(&format!(".{}", PARTIAL_EXTENSION)) || is_probe_file(x)
+            })
+            .await?)
+    }
+
+    async fn list(&self, filter: BackupObjectFilter) -> eyre::Result<Vec<RestorePoint>> {
+        let mut entries = tokio::fs::read_dir(&self.path).await?;
+        let mut restore_points = vec![];
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path().to_string_lossy().to_string();
+            if !path.ends_with(&format!(".{}", INDEX_EXTENSION)) {
+                continue;
+            }
+
+            let index = Self::read_index(&path).await?;
+            if !filter.matches(&index.backup_object) {
+                continue;
+            }
+
+            // the size is the one of the export, its chunks may be shared with other backups
+            let mut restore_point = RestorePoint::new(self.get_name(), index.backup_object);
+            restore_point.add_artifact(RestorePointArtifactKind::Data, path, Some(index.size));
+            restore_point.checksum = Some(index.checksum);
+            restore_point.pinned = index.pinned;
+
+            restore_points.push(restore_point);
+        }
+
+        Ok(restore_points)
+    }
+
+    async fn rotate(
+        &self,
+        filter: BackupObjectFilter,
+        retention: Option<u32>,
+        dry_run: DryRun,
+    ) -> eyre::Result<Vec<String>> {
+        let restore_points = self.list(filter).await?;
+
+        let mut deleted = vec![];
+        for restore_point in expired_restore_points(
+            restore_points,
+            retention.unwrap_or(self.storage_config.retention),
+            self.storage_config.delete_protection_days,
+        ) {
+            if !dry_run.is_enabled() {
+                self.delete(&restore_point).await?;
+            }
+            deleted.push(restore_point.id);
+        }
+
+        if !dry_run.is_enabled() {
+            self.collect_garbage().await?;
+        }
+
+        Ok(deleted)
+    }
+
+    // unreferenced chunks are removed by the next rotation
+    async fn delete(&self, restore_point: &RestorePoint) -> eyre::Result<()> {
+        debug!("Deleting restore point {}", restore_point.id);
+        for artifact in &restore_point.artifacts {
+            debug!("Deleting {}", artifact.location);
+            tokio::fs::remove_file(&artifact.location).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn set_pinned(&self, restore_point: &RestorePoint, pinned: bool) -> eyre::Result<()> {
+        let index_path = restore_point
+            .get_artifact(RestorePointArtifactKind::Data)
+            .ok_or_else(|| eyre::eyre!("Restore point {} has no index", restore_point.id))?;
+        let mut index = Self::read_index(&index_path.location).await?;
+        index.pinned = pinned;
+        Self::write_index(&index_path.location, &index).await
+    }
+
+    // the chunks are written to a pipe by a background task, a broken pipe ends it
+    async fn open_restore_stream(&self, restore_point: &RestorePoint) -> eyre::Result<StdioStream> {
+        let index_path = restore_point
+            .get_artifact(RestorePointArtifactKind::Data)
+            .ok_or_else(|| eyre::eyre!("Restore point {} has no index", restore_point.id))?;
+        let index = Self::read_index(&index_path.location).await?;
+
+        let (mut writer, reader) = tokio::io::duplex(4 * 1024 * 1024);
+        let storage = self.clone();
+        tokio::spawn(async move {
+            for chunk in &index.chunks {
+                let data = match storage.read_chunk(chunk).await {
+                    Ok(data) => data,
+                    Err(e) => {
+                        error!("Failed to read chunk of restore point: {}", e);
+                        return;
+                    }
+                };
+                if writer.write_all(&data).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Box::new(reader))
+    }
+
+    async fn verify(&self, restore_point: &RestorePoint) -> eyre::Result<()> {
+        let expected = restore_point.checksum.as_ref().ok_or_else(|| {
+            eyre::eyre!(
+                "No checksum recorded for restore point {}",
+                restore_point.id
+            )
+        })?;
+        let index_path = restore_point
+            .get_artifact(RestorePointArtifactKind::Data)
+            .ok_or_else(|| eyre::eyre!("Restore point {} has no index", restore_point.id))?;
+        let index = Self::read_index(&index_path.location).await?;
+
+        let mut hasher = Sha256::new();
+        for chunk in &index.chunks {
+            hasher.update(self.read_chunk(chunk).await?);
+        }
+        let actual = hex::encode(hasher.finalize());
+
+        if &actual != expected {
+            return Err(eyre::eyre!(
+                "Checksum mismatch: expected {}, got {}",
+                expected,
+                actual
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn handle_stdio_stream(
+        &self,
+        backup_object: BackupObject,
+        stdout_stream: StdioStream,
+        stderr_stream: StdioStream,
+    ) -> eyre::Result<()> {
+        let chunk_size = self.storage_config.chunk_size;
+        if !(256 * 1024..=4 * 1024 * 1024).contains(&chunk_size) {
+            return Err(eyre::eyre!(
+                "chunk_size has to be between 256 KiB and 4 MiB, got {}",
+                chunk_size
+            ));
+        }
+
+        let _lock = self.lock(false).await?;
+
+        const BUFFER_SIZE: usize = 1024 * 1024 * 10;
+        let mut stdout_hashing = HashingReader::new(tokio::io::BufReader::with_capacity(
+            BUFFER_SIZE,
+            stdout_stream,
+        ));
+        let mut stderr_buffered = tokio::io::BufReader::new(stderr_stream);
+
+        let mut chunks = vec![];
+        let mut size = 0;
+        let mut written = 0;
+        let mut new_chunks = 0;
+
+        {
+            let mut chunker = AsyncStreamCDC::new(
+                &mut stdout_hashing,
+                chunk_size / 4,
+                chunk_size,
+                chunk_size * 4,
+            );
+            let stream = chunker.as_stream();
+            tokio::pin!(stream);
+
+            while let Some(chunk) = stream.next().await {
+                let chunk =
+                    chunk.map_err(|e| eyre::eyre!("Failed to read export stream: {:?}", e))?;
+                let id = hex::encode(Sha256::digest(&chunk.data));
+
+                let chunk_written = self.write_chunk(&id, &chunk.data).await?;
+                if chunk_written > 0 {
+                    new_chunks += 1;
+                    written += chunk_written;
+                }
+
+                size += chunk.length as u64;
+                chunks.push(ChunkRef {
+                    id,
+                    size: chunk.length as u64,
+                });
+            }
+        }
+
+        // check stderr for errors, the chunks written so far are removed with the next rotation
+        let mut stderr = Vec::new();
+        stderr_buffered.read_to_end(&mut stderr).await?;
+        if !stderr.is_empty() {
+            let stderr = String::from_utf8_lossy(&stderr);
+            return Err(eyre::eyre!(
+                "Error encountered in stderr output: {}",
+                stderr
+            ));
+        }
+
+        info!(
+            "Stored {} chunks, {} new ({:.1} MiB written for {:.1} MiB of export)",
+            chunks.len(),
+            new_chunks,
+            written as f64 / 1024.0 / 1024.0,
+            size as f64 / 1024.0 / 1024.0
+        );
+
+        // the index is written last, a backup only exists once all of its chunks do
+        let index = ChunkIndex {
+            backup_object: BackupObject {
+                size: Some(size),
+                ..backup_object.clone()
+            },
+            checksum: stdout_hashing.finalize(),
+            size,
+            chunks,
+            pinned: false,
+            config: Some(ConfigSnapshot::new(&self.job_config, &self.storage_config)),
+        };
+        Self::write_index(&self.index_path(&backup_object), &index).await?;
+
+        Ok(())
+    }
+}
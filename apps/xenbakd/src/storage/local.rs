@@ -1,7 +1,10 @@
-use std::str::FromStr;
+use std::process::Stdio;
 
+use async_compression::{zstd::CParameter, Level};
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::Command as AsyncCommand;
+use tracing::debug;
 
 use crate::{
     config::{JobConfig, LocalStorageConfig},
@@ -9,9 +12,20 @@ use crate::{
 };
 
 use super::{
-    BackupObject, BackupObjectFilter, CompressionType, StorageHandler, StorageStatus, StorageType,
+    checksum::{sha256_of_reader, HashingReader, CHECKSUM_EXTENSION},
+    child_stream::ChildStream,
+    encode_name_component,
+    manifest::{BackupManifest, ConfigSnapshot, MANIFEST_EXTENSION},
+    orphans::remove_orphaned_files,
+    probe::{is_probe_file, probe_dir},
+    restore_point::{expired_restore_points, RestorePoint, RestorePointArtifactKind},
+    BackupObject, BackupObjectFilter, CompressionType, DryRun, EncryptionType, StdioStream,
+    StorageHandler, StorageStatus, StorageType,
 };
 
+/// extension of backup files which are still being written
+pub const PARTIAL_EXTENSION: &str = "partial";
+
 #[derive(Debug, Clone)]
 pub struct LocalStorage {
     pub path: String,
@@ -30,25 +44,15 @@ impl LocalStorage {
         }
     }
 
-    pub fn file_name_to_backup_object(&self, file_name: String) -> crate::storage::BackupObject {
-        let parts: Vec<&str> = file_name.split("__").collect();
-        if parts.len() != 4 {
-            panic!("Invalid backup object name");
-        }
-
-        let xen_host = parts[0];
-        let job_type = JobType::from_str(parts[1]).unwrap();
-        let vm_name = parts[2];
-        let time_stamp = chrono::DateTime::parse_from_rfc3339(parts[3].split(".").next().unwrap())
-            .unwrap()
-            .to_utc();
-
-        crate::storage::BackupObject {
-            job_type,
-            xen_host: xen_host.to_string(),
-            vm_name: vm_name.to_string(),
-            time_stamp,
-            size: None,
+    /// directory new backups of the backup object's VM are written to
+    pub fn backup_object_to_dir(&self, backup_object: &BackupObject) -> String {
+        match self.storage_config.layout {
+            LocalStorageLayout::Flat => self.path.clone(),
+            LocalStorageLayout::PerVm => format!(
+                "{}/{}",
+                self.path,
+                encode_name_component(&backup_object.vm_name)
+            ),
         }
     }
 
@@ -56,61 +60,144 @@ impl LocalStorage {
         &self,
         backup_object: crate::storage::BackupObject,
     ) -> String {
-        let base_name = format!(
-            "{}__{}__{}__{}",
-            backup_object.xen_host,
-            backup_object.job_type.to_string(),
-            backup_object.vm_name,
-            backup_object.time_stamp.to_rfc3339()
-        );
+        let base_name = backup_object.to_base_name();
 
         let base_extension = match backup_object.job_type {
+            // differential and VDI jobs store their disks in a xenbakd archive instead of an XVA
+            JobType::VmBackup if self.job_config.differential.is_some() => "xdiff",
             JobType::VmBackup => "xva",
+            JobType::VdiBackup => "xdiff",
+            JobType::PoolMetadata => "db",
+            // maintenance and replication jobs don't create backups
+            JobType::BorgMaintenance | JobType::Replication | JobType::Coverage => "bin",
         };
 
-        if self.storage_config.compression.is_none() {
-            return format!("{}.{}", base_name, base_extension);
-        } else {
-            return format!(
-                "{}.{}.{}",
-                base_name,
-                base_extension,
-                self.storage_config
-                    .compression
-                    .as_ref()
-                    .unwrap()
-                    .to_extension()
-            );
-        };
+        let mut file_name = format!("{}.{}", base_name, base_extension);
+
+        if let Some(compression) = &self.storage_config.compression {
+            file_name = format!("{}.{}", file_name, compression.to_extension());
+        }
+
+        if let Some(encryption) = &self.storage_config.encryption {
+            file_name = format!("{}.{}", file_name, encryption.to_extension());
+        }
+
+        file_name
     }
-}
 
-#[async_trait::async_trait]
-impl StorageHandler for LocalStorage {
-    async fn status(&self) -> eyre::Result<StorageStatus> {
-        todo!()
+    /// path of the manifest sidecar file belonging to a backup object
+    pub fn backup_object_to_manifest_path(&self, backup_object: BackupObject) -> String {
+        format!(
+            "{}/{}.{}",
+            self.backup_object_to_dir(&backup_object),
+            self.backup_object_to_file_name(backup_object),
+            MANIFEST_EXTENSION
+        )
     }
 
-    fn get_job_config(&self) -> JobConfig {
-        self.job_config.clone()
+    /// path of the checksum sidecar file belonging to a backup object
+    pub fn backup_object_to_checksum_path(&self, backup_object: BackupObject) -> String {
+        format!(
+            "{}/{}.{}",
+            self.backup_object_to_dir(&backup_object),
+            self.backup_object_to_file_name(backup_object),
+            CHECKSUM_EXTENSION
+        )
     }
 
-    fn get_storage_type(&self) -> StorageType {
-        self.storage_type.clone()
+    /// wraps the sink into the configured compression encoder
+    fn compression_writer(
+        &self,
+        sink: Box<dyn AsyncWrite + Unpin + Send>,
+    ) -> Box<dyn AsyncWrite + Unpin + Send> {
+        let level = match self.storage_config.compression_level {
+            Some(level) => Level::Precise(level),
+            None => Level::Default,
+        };
+
+        match self.storage_config.compression {
+            Some(LocalCompressionType::Zstd) => {
+                let mut params = vec![];
+                if let Some(threads) = self.storage_config.compression_threads {
+                    params.push(CParameter::nb_workers(threads));
+                }
+                Box::new(
+                    async_compression::tokio::write::ZstdEncoder::with_quality_and_params(
+                        sink, level, &params,
+                    ),
+                )
+            }
+            Some(LocalCompressionType::Gzip) => Box::new(
+                async_compression::tokio::write::GzipEncoder::with_quality(sink, level),
+            ),
+            Some(LocalCompressionType::Xz) => Box::new(
+                async_compression::tokio::write::XzEncoder::with_quality(sink, level),
+            ),
+            Some(LocalCompressionType::Bzip2) => Box::new(
+                async_compression::tokio::write::BzEncoder::with_quality(sink, level),
+            ),
+            Some(LocalCompressionType::Lz4) => Box::new(
+                async_compression::tokio::write::Lz4Encoder::with_quality(sink, level),
+            ),
+            None => sink,
+        }
     }
 
-    async fn initialize(&self) -> eyre::Result<()> {
-        let path = format!("{}/{}", self.storage_config.path, self.job_config.name);
-        tokio::fs::create_dir_all(&path).await?;
-        Ok(())
+    /// opens a stored backup file and returns a reader yielding the decrypted and decompressed export
+    pub async fn open_backup_stream(
+        &self,
+        path: &str,
+    ) -> eyre::Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let file = tokio::fs::File::open(path).await?;
+
+        // pipe the file through the decryption command, if the storage is encrypted. a failed
+        // decryption fails the stream with the command's error output
+        let source: Box<dyn AsyncRead + Unpin + Send> = match &self.storage_config.encryption {
+            Some(encryption) => {
+                let child = encryption
+                    .decrypt_cmd()?
+                    .stdin(Stdio::from(file.into_std().await))
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()?;
+                ChildStream::wrap(child, "decryption")?
+            }
+            None => Box::new(file),
+        };
+
+        let source = tokio::io::BufReader::new(source);
+
+        let reader: Box<dyn AsyncRead + Unpin + Send> = match self.storage_config.compression {
+            Some(LocalCompressionType::Zstd) => {
+                Box::new(async_compression::tokio::bufread::ZstdDecoder::new(source))
+            }
+            Some(LocalCompressionType::Gzip) => {
+                Box::new(async_compression::tokio::bufread::GzipDecoder::new(source))
+            }
+            Some(LocalCompressionType::Xz) => {
+                Box::new(async_compression::tokio::bufread::XzDecoder::new(source))
+            }
+            Some(LocalCompressionType::Bzip2) => {
+                Box::new(async_compression::tokio::bufread::BzDecoder::new(source))
+            }
+            Some(LocalCompressionType::Lz4) => {
+                Box::new(async_compression::tokio::bufread::Lz4Decoder::new(source))
+            }
+            None => Box::new(source),
+        };
+
+        Ok(reader)
     }
 
-    async fn list(
+    /// collects the restore points of the backup files directly inside a directory
+    async fn list_dir(
         &self,
-        filter: BackupObjectFilter,
-    ) -> eyre::Result<Vec<crate::storage::BackupObject>> {
-        let mut paths = tokio::fs::read_dir(&self.path).await?;
-        let mut backup_objects: Vec<BackupObject> = vec![];
+        dir: &str,
+        filter: &BackupObjectFilter,
+        restore_points: &mut Vec<RestorePoint>,
+    ) -> eyre::Result<()> {
+        let mut paths = tokio::fs::read_dir(dir).await?;
 
         while let Some(entry) = paths.next_entry().await? {
             let metadata = entry.metadata().await?;
@@ -120,98 +207,232 @@ impl StorageHandler for LocalStorage {
                     eyre::eyre!("Failed to convert OsString to String: {:?}", os_string)
                 })?;
 
-                let parts: Vec<&str> = file_name.split("__").collect();
-                if parts.len() != 4 {
-                    return Err(eyre::eyre!("Invalid backup object name"));
+                // checksum and manifest sidecars belong to their backup file, partial files
+                // are exports which are still running or were interrupted
+                if file_name.ends_with(&format!(".{}", CHECKSUM_EXTENSION))
+                    || file_name.ends_with(&format!(".{}", MANIFEST_EXTENSION))
+                    || file_name.ends_with(&format!(".{}", PARTIAL_EXTENSION))
+                    || is_probe_file(&file_name)
+                {
+                    continue;
                 }
 
-                let backup_object = self.file_name_to_backup_object(file_name);
+                // the manifest takes precedence over the values parsed from the file name
+                let manifest_path = format!("{}/{}.{}", dir, file_name, MANIFEST_EXTENSION);
+                let manifest = BackupManifest::read(&manifest_path).await?;
+                let pinned = manifest.as_ref().is_some_and(|x| x.pinned);
+                let mut backup_object =
+                    BackupObject::from_name_with_extension(&file_name, manifest)?;
 
                 // apply filter
-                if let Some(xen_host) = filter.xen_host.clone() {
-                    if !xen_host.contains(&backup_object.xen_host) {
-                        continue;
-                    }
+                if !filter.matches(&backup_object) {
+                    continue;
                 }
 
-                if let Some(job_type) = filter.job_type.clone() {
-                    if !job_type.contains(&backup_object.job_type) {
-                        continue;
-                    }
+                if backup_object.size.is_none() {
+                    backup_object.size = Some(metadata.len());
                 }
 
-                if let Some(vm_name) = filter.vm_name.clone() {
-                    if !vm_name.contains(&backup_object.vm_name) {
-                        continue;
-                    }
+                let mut restore_point = RestorePoint::new(self.get_name(), backup_object.clone());
+                restore_point.pinned = pinned;
+                restore_point.add_artifact(
+                    RestorePointArtifactKind::Data,
+                    format!("{}/{}", dir, file_name),
+                    Some(metadata.len()),
+                );
+
+                // collect the sidecars which exist for this backup. they are looked up next
+                // to the backup file, which may be stored in a different layout than the current one
+                if let Ok(metadata) = tokio::fs::metadata(&manifest_path).await {
+                    restore_point.add_artifact(
+                        RestorePointArtifactKind::Manifest,
+                        manifest_path,
+                        Some(metadata.len()),
+                    );
                 }
 
-                if let Some(time_stamp) = filter.time_stamp.clone() {
-                    if let Some(start) = time_stamp.0 {
-                        if let Some(end) = time_stamp.1 {
-                            if !(start <= backup_object.time_stamp
-                                && backup_object.time_stamp <= end)
-                            {
-                                continue;
-                            }
-                        } else {
-                            if !(start <= backup_object.time_stamp) {
-                                continue;
-                            }
-                        }
-                    } else {
-                        if let Some(end) = time_stamp.1 {
-                            if !(backup_object.time_stamp <= end) {
-                                continue;
-                            }
-                        }
-                    }
+                let checksum_path = format!("{}/{}.{}", dir, file_name, CHECKSUM_EXTENSION);
+                if let Ok(checksum) = tokio::fs::read_to_string(&checksum_path).await {
+                    restore_point.checksum = checksum.split_whitespace().next().map(String::from);
+                    restore_point.add_artifact(
+                        RestorePointArtifactKind::Checksum,
+                        checksum_path,
+                        Some(checksum.len() as u64),
+                    );
                 }
 
-                backup_objects.push(backup_object);
+                restore_points.push(restore_point);
             }
         }
 
-        Ok(backup_objects)
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl StorageHandler for LocalStorage {
+    async fn status(&self) -> eyre::Result<StorageStatus> {
+        let restore_points = self.list(BackupObjectFilter::default()).await?;
+
+        Ok(StorageStatus {
+            free_space: fs2::available_space(&self.path)?,
+            total_space: fs2::total_space(&self.path)?,
+            used_space: restore_points.iter().map(|x| x.size()).sum(),
+            backup_count: restore_points.len() as u32,
+        })
+    }
+
+    fn get_retention_count(&self) -> u32 {
+        self.storage_config.retention
     }
 
-    async fn rotate(&self, filter: BackupObjectFilter) -> eyre::Result<()> {
-        let backup_objects = self.list(filter).await?;
+    fn get_delete_protection_days(&self) -> Option<u32> {
+        self.storage_config.delete_protection_days
+    }
+
+    fn get_job_config(&self) -> JobConfig {
+        self.job_config.clone()
+    }
 
-        let mut vm_job_type_map: std::collections::HashMap<String, Vec<BackupObject>> =
-            std::collections::HashMap::new();
+    fn get_name(&self) -> String {
+        self.storage_config.name.clone()
+    }
 
-        for backup_object in backup_objects {
-            let key = format!(
-                "{}__{}__{}",
-                backup_object.xen_host,
-                backup_object.job_type.to_string(),
-                backup_object.vm_name
-            );
+    fn get_storage_type(&self) -> StorageType {
+        self.storage_type.clone()
+    }
+
+    fn supports_chains(&self) -> bool {
+        true
+    }
+
+    async fn initialize(&self) -> eyre::Result<()> {
+        let path = format!("{}/{}", self.storage_config.path, self.job_config.name);
+        tokio::fs::create_dir_all(&path).await?;
+        Ok(())
+    }
 
-            if let Some(backup_objects) = vm_job_type_map.get_mut(&key) {
-                backup_objects.push(backup_object);
-            } else {
-                vm_job_type_map.insert(key, vec![backup_object]);
+    async fn probe(&self) -> eyre::Result<()> {
+        probe_dir(&self.path).await
+    }
+
+    // partial files of interrupted exports, also inside per-VM subdirectories
+    async fn cleanup_orphans(&self) -> eyre::Result<u64> {
+        remove_orphaned_files(&self.path, 1, |x| {
+            x.ends_with(&format!(".{}", PARTIAL_EXTENSION)) || is_probe_file(x)
+        })
+        .await
+    }
+
+    async fn list(&self, filter: BackupObjectFilter) -> eyre::Result<Vec<RestorePoint>> {
+        let mut restore_points: Vec<RestorePoint> = vec![];
+        self.list_dir(&self.path, &filter, &mut restore_points)
+            .await?;
+
+        // backups in per-VM subdirectories are listed regardless of the configured layout,
+        // so switching the layout doesn't orphan existing backups
+        let mut paths = tokio::fs::read_dir(&self.path).await?;
+        while let Some(entry) = paths.next_entry().await? {
+            if entry.metadata().await?.is_dir() {
+                let dir = format!("{}/{}", self.path, entry.file_name().to_string_lossy());
+                self.list_dir(&dir, &filter, &mut restore_points).await?;
+            }
+        }
+
+        Ok(restore_points)
+    }
+
+    async fn rotate(
+        &self,
+        filter: BackupObjectFilter,
+        retention: Option<u32>,
+        dry_run: DryRun,
+    ) -> eyre::Result<Vec<String>> {
+        let restore_points = self.list(filter).await?;
+
+        let mut deleted = vec![];
+        for restore_point in expired_restore_points(
+            restore_points,
+            retention.unwrap_or(self.storage_config.retention),
+            self.storage_config.delete_protection_days,
+        ) {
+            if !dry_run.is_enabled() {
+                self.delete(&restore_point).await?;
             }
+            deleted.push(restore_point.id);
         }
 
-        // keep the last N backups
-        for (_key, mut backup_objects) in vm_job_type_map {
-            backup_objects.sort_by(|a, b| b.time_stamp.cmp(&a.time_stamp));
+        Ok(deleted)
+    }
 
-            if backup_objects.len() > self.storage_config.retention as usize {
-                let to_delete = &backup_objects[self.storage_config.retention as usize..];
+    async fn delete(&self, restore_point: &RestorePoint) -> eyre::Result<()> {
+        debug!("Deleting restore point {}", restore_point.id);
+        for artifact in &restore_point.artifacts {
+            debug!("Deleting {}", artifact.location);
+            tokio::fs::remove_file(&artifact.location).await?;
+        }
 
-                for backup_object in to_delete {
-                    let full_path = format!(
-                        "{}/{}",
-                        self.path,
-                        self.backup_object_to_file_name(backup_object.clone()),
-                    );
-                    tokio::fs::remove_file(full_path).await?;
-                }
+        Ok(())
+    }
+
+    // the pin is stored in the manifest, backups from before manifests existed get one
+    async fn set_pinned(&self, restore_point: &RestorePoint, pinned: bool) -> eyre::Result<()> {
+        let manifest_path = match restore_point.get_artifact(RestorePointArtifactKind::Manifest) {
+            Some(manifest) => manifest.location.clone(),
+            None => {
+                let data = restore_point
+                    .get_artifact(RestorePointArtifactKind::Data)
+                    .ok_or_else(|| eyre::eyre!("Restore point {} has no data", restore_point.id))?;
+                format!("{}.{}", data.location, MANIFEST_EXTENSION)
             }
+        };
+
+        let mut manifest = match BackupManifest::read(&manifest_path).await? {
+            Some(manifest) => manifest,
+            None => BackupManifest::from_backup_object(
+                &restore_point.backup_object,
+                self.storage_config
+                    .compression
+                    .as_ref()
+                    .map(|x| x.to_cli_arg()),
+                self.storage_config
+                    .encryption
+                    .as_ref()
+                    .map(|x| x.to_extension()),
+                restore_point.checksum.clone(),
+            ),
+        };
+        manifest.pinned = pinned;
+        manifest.write(&manifest_path).await
+    }
+
+    async fn open_restore_stream(&self, restore_point: &RestorePoint) -> eyre::Result<StdioStream> {
+        let data = restore_point
+            .get_artifact(RestorePointArtifactKind::Data)
+            .ok_or_else(|| eyre::eyre!("Restore point {} has no data", restore_point.id))?;
+        self.open_backup_stream(&data.location).await
+    }
+
+    async fn verify(&self, restore_point: &RestorePoint) -> eyre::Result<()> {
+        let expected = restore_point.checksum.as_ref().ok_or_else(|| {
+            eyre::eyre!(
+                "No checksum recorded for restore point {}",
+                restore_point.id
+            )
+        })?;
+
+        let data = restore_point
+            .get_artifact(RestorePointArtifactKind::Data)
+            .ok_or_else(|| eyre::eyre!("Restore point {} has no data", restore_point.id))?;
+        let reader = self.open_backup_stream(&data.location).await?;
+        let actual = sha256_of_reader(reader).await?;
+
+        if &actual != expected {
+            return Err(eyre::eyre!(
+                "Checksum mismatch: expected {}, got {}",
+                expected,
+                actual
+            ));
         }
 
         Ok(())
@@ -221,37 +442,73 @@ impl StorageHandler for LocalStorage {
     async fn handle_stdio_stream(
         &self,
         backup_object: BackupObject,
-        stdout_stream: tokio::process::ChildStdout,
-        stderr_stream: tokio::process::ChildStderr,
+        stdout_stream: StdioStream,
+        stderr_stream: StdioStream,
     ) -> eyre::Result<()> {
         // get full path for the file and create a handle
+        let dir = self.backup_object_to_dir(&backup_object);
+        tokio::fs::create_dir_all(&dir).await?;
         let full_path = format!(
             "{}/{}",
-            self.path,
+            dir,
             self.backup_object_to_file_name(backup_object.clone())
         );
 
+        // the export is written to a partial file first and only renamed once it's complete
+        let partial_path = format!("{}.{}", full_path, PARTIAL_EXTENSION);
+
         let result = async {
             // create file and get file handle
-            let mut file = tokio::fs::File::create(&full_path).await?;
+            let file = tokio::fs::File::create(&partial_path).await?;
 
             // create a buffered stream reader for smoother I/O
             const BUFFER_SIZE: usize = 1024 * 1024 * 10;
-            let mut stdout_buffered =
-                tokio::io::BufReader::with_capacity(BUFFER_SIZE, stdout_stream);
+            let mut stdout_buffered = HashingReader::new(tokio::io::BufReader::with_capacity(
+                BUFFER_SIZE,
+                stdout_stream,
+            ));
             let mut stderr_buffered = tokio::io::BufReader::new(stderr_stream);
 
-            match self.storage_config.compression {
-                Some(LocalCompressionType::Zstd) => {
-                    let mut zstd = async_compression::tokio::write::ZstdEncoder::new(file);
-                    tokio::io::copy(&mut stdout_buffered, &mut zstd).await?;
-                }
-                Some(LocalCompressionType::Gzip) => {
-                    let mut gzip = async_compression::tokio::write::GzipEncoder::new(file);
-                    tokio::io::copy(&mut stdout_buffered, &mut gzip).await?;
+            // if encryption is enabled, the encryption command writes to the file and we write to its stdin
+            let (sink, encryptor): (Box<dyn AsyncWrite + Unpin + Send>, _) =
+                match &self.storage_config.encryption {
+                    Some(encryption) => {
+                        let mut child = encryption
+                            .encrypt_cmd()
+                            .stdin(Stdio::piped())
+                            .stdout(Stdio::from(file.into_std().await))
+                            .stderr(Stdio::piped())
+                            .kill_on_drop(true)
+                            .spawn()?;
+                        (Box::new(child.stdin.take().unwrap()), Some(child))
+                    }
+                    None => (Box::new(file), None),
+                };
+
+            let mut writer = self.compression_writer(sink);
+
+            // not using tokio::io::copy, its flushes whenever the export stalls break the
+            // xz and bzip2 encoders
+            let mut buffer = vec![0; 64 * 1024];
+            loop {
+                let read = stdout_buffered.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
                 }
-                None => {
-                    tokio::io::copy(&mut stdout_buffered, &mut file).await?;
+                writer.write_all(&buffer[..read]).await?;
+            }
+
+            // finish the compression frame and close the encryption command's stdin
+            writer.shutdown().await?;
+            drop(writer);
+
+            if let Some(encryptor) = encryptor {
+                let output = encryptor.wait_with_output().await?;
+                if !output.status.success() {
+                    return Err(eyre::eyre!(
+                        "Encryption command failed: {}",
+                        String::from_utf8_lossy(&output.stderr)
+                    ));
                 }
             }
 
@@ -266,12 +523,47 @@ impl StorageHandler for LocalStorage {
                 ));
             }
 
+            // write the checksum of the export stream next to the backup
+            let checksum = stdout_buffered.finalize();
+            tokio::fs::write(
+                self.backup_object_to_checksum_path(backup_object.clone()),
+                format!("{}\n", checksum),
+            )
+            .await?;
+
+            // ... as well as the manifest describing the backup
+            let mut backup_object = backup_object.clone();
+            backup_object.size = Some(tokio::fs::metadata(&partial_path).await?.len());
+            let mut manifest = BackupManifest::from_backup_object(
+                &backup_object,
+                self.storage_config
+                    .compression
+                    .as_ref()
+                    .map(|x| x.to_cli_arg()),
+                self.storage_config
+                    .encryption
+                    .as_ref()
+                    .map(|x| x.to_extension()),
+                Some(checksum),
+            );
+            manifest.config = Some(ConfigSnapshot::new(&self.job_config, &self.storage_config));
+            manifest
+                .write(&self.backup_object_to_manifest_path(backup_object.clone()))
+                .await?;
+
+            tokio::fs::rename(&partial_path, &full_path).await?;
+
             Ok::<(), eyre::Error>(())
         }
         .await;
 
         if let Err(e) = result {
-            tokio::fs::remove_file(full_path).await?;
+            let _ = tokio::fs::remove_file(partial_path).await;
+            let _ =
+                tokio::fs::remove_file(self.backup_object_to_checksum_path(backup_object.clone()))
+                    .await;
+            let _ =
+                tokio::fs::remove_file(self.backup_object_to_manifest_path(backup_object)).await;
             return Err(e.wrap_err("Failed to write to file"));
         }
 
@@ -279,6 +571,19 @@ impl StorageHandler for LocalStorage {
     }
 }
 
+/// how backup files are arranged inside the job's directory
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+pub enum LocalStorageLayout {
+    /// all backups directly inside the job's directory
+    #[default]
+    #[serde(rename = "flat")]
+    Flat,
+    /// a subdirectory per VM inside the job's directory
+    #[serde(rename = "per_vm")]
+    PerVm,
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LocalStorageRetention {
     pub daily: u32,
@@ -293,6 +598,12 @@ pub enum LocalCompressionType {
     Gzip,
     #[serde(rename = "zstd")]
     Zstd,
+    #[serde(rename = "xz")]
+    Xz,
+    #[serde(rename = "bzip2")]
+    Bzip2,
+    #[serde(rename = "lz4")]
+    Lz4,
 }
 
 impl CompressionType for LocalCompressionType {
@@ -300,6 +611,9 @@ impl CompressionType for LocalCompressionType {
         match self {
             LocalCompressionType::Gzip => "gz".to_string(),
             LocalCompressionType::Zstd => "zst".to_string(),
+            LocalCompressionType::Xz => "xz".to_string(),
+            LocalCompressionType::Bzip2 => "bz2".to_string(),
+            LocalCompressionType::Lz4 => "lz4".to_string(),
         }
     }
 
@@ -307,6 +621,9 @@ impl CompressionType for LocalCompressionType {
         match extension {
             "gz" => Ok(LocalCompressionType::Gzip),
             "zst" => Ok(LocalCompressionType::Zstd),
+            "xz" => Ok(LocalCompressionType::Xz),
+            "bz2" => Ok(LocalCompressionType::Bzip2),
+            "lz4" => Ok(LocalCompressionType::Lz4),
             _ => Err(eyre::eyre!("Invalid compression extension")),
         }
     }
@@ -315,6 +632,116 @@ impl CompressionType for LocalCompressionType {
         match self {
             LocalCompressionType::Gzip => "gzip".to_string(),
             LocalCompressionType::Zstd => "zstd".to_string(),
+            LocalCompressionType::Xz => "xz".to_string(),
+            LocalCompressionType::Bzip2 => "bzip2".to_string(),
+            LocalCompressionType::Lz4 => "lz4".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum LocalEncryptionType {
+    #[serde(rename = "age")]
+    Age {
+        #[serde(default = "default_age_binary_path")]
+        binary_path: String,
+        recipients: Vec<String>,
+        identity_file: Option<String>,
+    },
+    #[serde(rename = "gpg")]
+    Gpg {
+        #[serde(default = "default_gpg_binary_path")]
+        binary_path: String,
+        key_ids: Vec<String>,
+        homedir: Option<String>,
+        /// encrypts to the keys without checking their validity, for keys imported without ownertrust
+        #[serde(default)]
+        trust_all_keys: bool,
+    },
+}
+
+fn default_age_binary_path() -> String {
+    "age".into()
+}
+
+fn default_gpg_binary_path() -> String {
+    "gpg".into()
+}
+
+fn gpg_base_cmd(binary_path: &str, homedir: &Option<String>) -> AsyncCommand {
+    let mut cmd = AsyncCommand::new(binary_path);
+    cmd.arg("--batch").arg("--yes").arg("--quiet");
+    if let Some(homedir) = homedir {
+        cmd.arg("--homedir").arg(homedir);
+    }
+    cmd
+}
+
+impl EncryptionType for LocalEncryptionType {
+    fn to_extension(&self) -> String {
+        match self {
+            LocalEncryptionType::Age { .. } => "age".to_string(),
+            LocalEncryptionType::Gpg { .. } => "gpg".to_string(),
+        }
+    }
+
+    fn encrypt_cmd(&self) -> AsyncCommand {
+        match self {
+            LocalEncryptionType::Age {
+                binary_path,
+                recipients,
+                ..
+            } => {
+                let mut cmd = AsyncCommand::new(binary_path);
+                cmd.arg("--encrypt");
+                for recipient in recipients {
+                    cmd.arg("--recipient").arg(recipient);
+                }
+                cmd
+            }
+            LocalEncryptionType::Gpg {
+                binary_path,
+                key_ids,
+                homedir,
+                trust_all_keys,
+            } => {
+                let mut cmd = gpg_base_cmd(binary_path, homedir);
+                if *trust_all_keys {
+                    cmd.arg("--trust-model").arg("always");
+                }
+                cmd.arg("--encrypt");
+                for key_id in key_ids {
+                    cmd.arg("--recipient").arg(key_id);
+                }
+                cmd
+            }
+        }
+    }
+
+    fn decrypt_cmd(&self) -> eyre::Result<AsyncCommand> {
+        match self {
+            LocalEncryptionType::Age {
+                binary_path,
+                identity_file,
+                ..
+            } => {
+                let identity_file = identity_file
+                    .as_ref()
+                    .ok_or_else(|| eyre::eyre!("No age identity file configured for decryption"))?;
+                let mut cmd = AsyncCommand::new(binary_path);
+                cmd.arg("--decrypt").arg("--identity").arg(identity_file);
+                Ok(cmd)
+            }
+            LocalEncryptionType::Gpg {
+                binary_path,
+                homedir,
+                ..
+            } => {
+                let mut cmd = gpg_base_cmd(binary_path, homedir);
+                cmd.arg("--decrypt");
+                Ok(cmd)
+            }
         }
     }
 }
@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{redact_secrets, JobConfig},
+    jobs::JobType,
+};
+
+use super::BackupObject;
+
+/// file extension of manifest sidecar files
+pub const MANIFEST_EXTENSION: &str = "manifest.json";
+
+/// effective job and storage configuration at the time of the backup, secrets redacted
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ConfigSnapshot {
+    pub job: serde_json::Value,
+    pub storage: serde_json::Value,
+}
+
+impl ConfigSnapshot {
+    pub fn new(job_config: &JobConfig, storage_config: &impl Serialize) -> Self {
+        let mut snapshot = ConfigSnapshot {
+            job: serde_json::to_value(job_config).unwrap_or_default(),
+            storage: serde_json::to_value(storage_config).unwrap_or_default(),
+        };
+        redact_secrets(&mut snapshot.job);
+        redact_secrets(&mut snapshot.storage);
+        snapshot
+    }
+}
+
+/// metadata written next to each backup object
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BackupManifest {
+    pub job_type: JobType,
+    pub vm_uuid: Option<String>,
+    pub vm_name: String,
+    pub xen_host: String,
+    pub snapshot_time: chrono::DateTime<chrono::Utc>,
+    pub size: Option<u64>,
+    pub compression: Option<String>,
+    pub encryption: Option<String>,
+    pub checksum: Option<String>,
+    pub xenbakd_version: String,
+    /// pinned backups are never deleted by the rotation
+    #[serde(default)]
+    pub pinned: bool,
+    /// configuration the backup was made with, missing for backups of older versions
+    #[serde(default)]
+    pub config: Option<ConfigSnapshot>,
+    /// restore point a differential backup is based on
+    #[serde(default)]
+    pub base: Option<String>,
+    /// pool alias of the xen host
+    #[serde(default)]
+    pub pool: Option<String>,
+}
+
+impl BackupManifest {
+    pub fn from_backup_object(
+        backup_object: &BackupObject,
+        compression: Option<String>,
+        encryption: Option<String>,
+        checksum: Option<String>,
+    ) -> Self {
+        BackupManifest {
+            job_type: backup_object.job_type.clone(),
+            vm_uuid: backup_object.vm_uuid.clone(),
+            vm_name: backup_object.vm_name.clone(),
+            xen_host: backup_object.xen_host.clone(),
+            snapshot_time: backup_object.time_stamp,
+            size: backup_object.size,
+            compression,
+            encryption,
+            checksum,
+            xenbakd_version: env!("CARGO_PKG_VERSION").to_string(),
+            pinned: false,
+            config: None,
+            base: backup_object.base.clone(),
+            pool: backup_object.pool.clone(),
+        }
+    }
+
+    pub async fn read(path: &str) -> eyre::Result<Option<BackupManifest>> {
+        match tokio::fs::read(path).await {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn write(&self, path: &str) -> eyre::Result<()> {
+        tokio::fs::write(path, serde_json::to_vec_pretty(self)?).await?;
+        Ok(())
+    }
+}
@@ -1,31 +1,120 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
 use crate::{config::JobConfig, jobs::JobType};
 
+use self::{manifest::BackupManifest, restore_point::RestorePoint};
+
 pub mod borg;
+pub mod borg_lock;
+pub mod checksum;
+pub mod child_stream;
+pub mod chunked;
 pub mod local;
+pub mod manifest;
+pub mod orphans;
+pub mod pin;
+pub mod plugin;
+pub mod probe;
+pub mod progress;
+pub mod remote;
+pub mod restore_point;
 
 #[async_trait::async_trait]
 pub trait StorageHandler: Send + Sync {
     fn get_storage_type(&self) -> StorageType;
     fn get_job_config(&self) -> JobConfig;
+    fn get_name(&self) -> String;
     async fn status(&self) -> eyre::Result<StorageStatus>;
+    /// number of backups per VM kept by the retention policy
+    fn get_retention_count(&self) -> u32;
+    /// backups younger than this number of days must never be deleted
+    fn get_delete_protection_days(&self) -> Option<u32>;
+    /// whether the rotation keeps the bases of differential restore points, see `BackupObject::base`.
+    /// differential backups fall back to full exports on other storages
+    fn supports_chains(&self) -> bool {
+        false
+    }
     async fn initialize(&self) -> eyre::Result<()>;
-    async fn list(&self, filter: BackupObjectFilter) -> eyre::Result<Vec<BackupObject>>;
-    async fn rotate(&self, filter: BackupObjectFilter) -> eyre::Result<()>;
+    /// checks that the initialized storage can be written to, read from and deleted from.
+    /// plugins are expected to check their backend on initialization
+    async fn probe(&self) -> eyre::Result<()> {
+        Ok(())
+    }
+    /// deletes temporary files left behind by crashed runs, returns the number of reclaimed bytes.
+    /// storages handled by an agent or a plugin take care of their own temporary files
+    async fn cleanup_orphans(&self) -> eyre::Result<u64> {
+        Ok(0)
+    }
+    async fn list(&self, filter: BackupObjectFilter) -> eyre::Result<Vec<RestorePoint>>;
+    /// deletes backups exceeding the retention policy. `retention` replaces the storage's own
+    /// policy with a number of kept backups. returns the ids of the deleted restore points, on a
+    /// dry run the ones which would be deleted, without deleting anything
+    async fn rotate(
+        &self,
+        filter: BackupObjectFilter,
+        retention: Option<u32>,
+        dry_run: DryRun,
+    ) -> eyre::Result<Vec<String>>;
+    /// deletes a single restore point, regardless of the retention policy
+    async fn delete(&self, restore_point: &RestorePoint) -> eyre::Result<()>;
+    /// pins or unpins a restore point, pinned ones are never deleted by the rotation
+    async fn set_pinned(&self, restore_point: &RestorePoint, pinned: bool) -> eyre::Result<()>;
+    /// re-reads a stored backup and validates it against the checksum recorded at backup time
+    async fn verify(&self, restore_point: &RestorePoint) -> eyre::Result<()>;
+    /// opens the stored backup as the plain export stream, as it was received from `xe vm-export`
+    async fn open_restore_stream(
+        &self,
+        _restore_point: &RestorePoint,
+    ) -> eyre::Result<StdioStream> {
+        Err(eyre::eyre!(
+            "Restoring is not supported on storage '{}'",
+            self.get_name()
+        ))
+    }
+    /// looks up the restore point of a stored backup object
+    async fn get_restore_point(&self, backup_object: &BackupObject) -> eyre::Result<RestorePoint> {
+        let mut filter = backup_object.to_filter();
+        filter.time_stamp = Some((
+            Some(backup_object.time_stamp),
+            Some(backup_object.time_stamp),
+        ));
+        let id = backup_object.to_base_name();
+
+        self.list(filter)
+            .await?
+            .into_iter()
+            .find(|x| x.id == id)
+            .ok_or_else(|| eyre::eyre!("Restore point {} not found on storage", id))
+    }
     async fn handle_stdio_stream(
         &self,
         backup_object: BackupObject,
-        stdout_stream: tokio::process::ChildStdout,
-        stderr_stream: tokio::process::ChildStderr,
+        stdout_stream: StdioStream,
+        stderr_stream: StdioStream,
     ) -> eyre::Result<()>;
 }
 
+/// stdout or stderr of an export, either from a local xe process or received by an agent
+pub type StdioStream = Box<dyn tokio::io::AsyncRead + Unpin + Send>;
+
 pub trait CompressionType: Sized {
     fn to_extension(&self) -> String;
+    #[allow(dead_code)]
     fn from_extension(extension: &str) -> eyre::Result<Self>;
     fn to_cli_arg(&self) -> String;
 }
 
-#[derive(Debug, Clone)]
+/// an encryption provider which is run as an external command, reading from stdin and writing to stdout
+pub trait EncryptionType {
+    fn to_extension(&self) -> String;
+    fn encrypt_cmd(&self) -> tokio::process::Command;
+    fn decrypt_cmd(&self) -> eyre::Result<tokio::process::Command>;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StorageStatus {
     pub free_space: u64,
     pub total_space: u64,
@@ -33,15 +122,69 @@ pub struct StorageStatus {
     pub backup_count: u32,
 }
 
-#[derive(Debug, Clone)]
+/// inclusive time range, either end may be open
+pub type TimeStampRange = (
+    Option<chrono::DateTime<chrono::Utc>>,
+    Option<chrono::DateTime<chrono::Utc>>,
+);
+
+/// whether a rotation deletes the expired backups or only reports them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DryRun {
+    #[default]
+    Disabled,
+    Enabled,
+}
+
+impl DryRun {
+    pub fn is_enabled(&self) -> bool {
+        *self == DryRun::Enabled
+    }
+}
+
+impl From<bool> for DryRun {
+    fn from(enabled: bool) -> DryRun {
+        match enabled {
+            true => DryRun::Enabled,
+            false => DryRun::Disabled,
+        }
+    }
+}
+
+/// logs the restore points deleted by a rotation, or which it would delete on a dry run
+pub fn log_rotation(storage_name: &str, deleted: &[String], dry_run: DryRun) {
+    if deleted.is_empty() {
+        return;
+    }
+    match dry_run {
+        DryRun::Enabled => info!(
+            "Rotation would delete {} restore point(s) on storage '{}' (dry run): {}",
+            deleted.len(),
+            storage_name,
+            deleted.join(", ")
+        ),
+        DryRun::Disabled => info!(
+            "Rotation deleted {} restore point(s) on storage '{}': {}",
+            deleted.len(),
+            storage_name,
+            deleted.join(", ")
+        ),
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BackupObjectFilter {
     pub job_type: Option<Vec<JobType>>,
     pub xen_host: Option<Vec<String>>,
     pub vm_name: Option<Vec<String>>,
-    pub time_stamp: Option<(
-        Option<chrono::DateTime<chrono::Utc>>,
-        Option<chrono::DateTime<chrono::Utc>>,
-    )>,
+    /// matched instead of the name for backup objects which know their VM's uuid, so renamed
+    /// VMs keep their backups
+    #[serde(default)]
+    pub vm_uuid: Option<Vec<String>>,
+    /// only matches backup objects labelled with one of these pools
+    #[serde(default)]
+    pub pool: Option<Vec<String>>,
+    pub time_stamp: Option<TimeStampRange>,
 }
 
 impl BackupObjectFilter {
@@ -50,44 +193,287 @@ impl BackupObjectFilter {
             job_type: Some(vec![backup_object.job_type]),
             xen_host: Some(vec![backup_object.xen_host]),
             vm_name: Some(vec![backup_object.vm_name]),
+            vm_uuid: backup_object.vm_uuid.map(|x| vec![x]),
+            pool: None,
             time_stamp: Some((None, Some(backup_object.time_stamp))),
         }
     }
+
+    /// checks whether a backup object matches all set criteria of the filter
+    pub fn matches(&self, backup_object: &BackupObject) -> bool {
+        if let Some(xen_host) = &self.xen_host {
+            if !xen_host.contains(&backup_object.xen_host) {
+                return false;
+            }
+        }
+
+        if let Some(job_type) = &self.job_type {
+            if !job_type.contains(&backup_object.job_type) {
+                return false;
+            }
+        }
+
+        if let Some(pool) = &self.pool {
+            if !backup_object
+                .pool
+                .as_ref()
+                .is_some_and(|x| pool.contains(x))
+            {
+                return false;
+            }
+        }
+
+        match (&self.vm_uuid, &backup_object.vm_uuid) {
+            (Some(vm_uuids), Some(vm_uuid)) => {
+                if !vm_uuids.contains(vm_uuid) {
+                    return false;
+                }
+            }
+            _ => {
+                if let Some(vm_name) = &self.vm_name {
+                    if !vm_name.contains(&backup_object.vm_name) {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        if let Some((start, end)) = self.time_stamp {
+            if start.is_some_and(|start| backup_object.time_stamp < start) {
+                return false;
+            }
+            if end.is_some_and(|end| backup_object.time_stamp > end) {
+                return false;
+            }
+        }
+
+        true
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BackupObject {
     pub job_type: JobType,
+    pub vm_uuid: Option<String>,
     pub vm_name: String,
     pub xen_host: String,
     pub time_stamp: chrono::DateTime<chrono::Utc>,
     pub size: Option<u64>,
+    /// id of the restore point a differential backup is based on, `None` for full backups
+    #[serde(default)]
+    pub base: Option<String>,
+    /// pool alias of the xen host, see `XenConfig::pool`. unknown for backups without manifest
+    #[serde(default)]
+    pub pool: Option<String>,
 }
 
 impl BackupObject {
     pub fn new(
         job_type: JobType,
+        vm_uuid: Option<String>,
         vm_name: String,
         xen_host: String,
         time_stamp: chrono::DateTime<chrono::Utc>,
-        _size: Option<u64>,
+        size: Option<u64>,
     ) -> Self {
         BackupObject {
             job_type,
+            vm_uuid,
             vm_name,
             xen_host,
             time_stamp,
-            size: None,
+            size,
+            base: None,
+            pool: None,
         }
     }
 
+    /// parses a backup object from a file or archive name (`host__type__vm__timestamp[.ext...]`).
+    /// if a manifest is available, its values take precedence over the ones parsed from the name.
+    pub fn from_name_with_extension(
+        name: &str,
+        manifest: Option<BackupManifest>,
+    ) -> eyre::Result<Self> {
+        if let Some(manifest) = manifest {
+            return Ok(BackupObject {
+                job_type: manifest.job_type,
+                vm_uuid: manifest.vm_uuid,
+                vm_name: manifest.vm_name,
+                xen_host: manifest.xen_host,
+                time_stamp: manifest.snapshot_time,
+                size: manifest.size,
+                base: manifest.base,
+                pool: manifest.pool,
+            });
+        }
+
+        let parts: Vec<&str> = name.split("__").collect();
+        if parts.len() != 4 {
+            return Err(eyre::eyre!("Invalid backup object name: {}", name));
+        }
+
+        let xen_host = decode_name_component(parts[0])?;
+        let job_type = JobType::from_str(parts[1])?;
+        let vm_name = decode_name_component(parts[2])?;
+        let time_stamp =
+            chrono::DateTime::parse_from_rfc3339(parts[3].split('.').next().unwrap_or_default())?
+                .to_utc();
+
+        Ok(BackupObject {
+            job_type,
+            vm_uuid: None,
+            xen_host: xen_host.to_string(),
+            vm_name: vm_name.to_string(),
+            time_stamp,
+            size: None,
+            base: None,
+            pool: None,
+        })
+    }
+
+    /// name shared by all file and archive names of the backup (`host__type__vm__timestamp`),
+    /// host and VM name encoded by `encode_name_component`
+    pub fn to_base_name(&self) -> String {
+        format!(
+            "{}__{}__{}__{}",
+            encode_name_component(&self.xen_host),
+            self.job_type,
+            encode_name_component(&self.vm_name),
+            self.time_stamp.to_rfc3339()
+        )
+    }
+
     pub fn to_filter(&self) -> BackupObjectFilter {
         BackupObjectFilter::from_backup_object(self.clone())
     }
 }
 
+/// percent-encodes characters of a name which would break backup identifiers: the `__`
+/// separator, path separators, glob characters, control characters and anything non-ASCII.
+/// names without such characters, e.g. `my_vm 01`, stay as they are
+pub fn encode_name_component(name: &str) -> String {
+    let bytes = name.as_bytes();
+    let mut encoded = String::with_capacity(name.len());
+
+    for (i, &byte) in bytes.iter().enumerate() {
+        // single underscores are kept, splitting on `__` has to stay unambiguous
+        let unsafe_underscore = byte == b'_'
+            && (i == 0 || i == bytes.len() - 1 || bytes[i - 1] == b'_' || bytes[i + 1] == b'_');
+        let safe = match byte {
+            b'_' => !unsafe_underscore,
+            b'%' | b'/' | b'\\' | b'*' | b'?' | b'[' | b']' | b':' | b'"' | b'<' | b'>' | b'|' => {
+                false
+            }
+            _ => byte.is_ascii_graphic() || byte == b' ',
+        };
+
+        match safe {
+            true => encoded.push(byte as char),
+            false => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+/// reverses `encode_name_component`
+pub fn decode_name_component(encoded: &str) -> eyre::Result<String> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = encoded
+                .get(i + 1..i + 3)
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+                .ok_or_else(|| eyre::eyre!("Invalid encoding in name: {}", encoded))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Ok(String::from_utf8(decoded)?)
+}
+
 #[derive(Debug, Clone)]
 pub enum StorageType {
     Local,
     Borg,
+    Chunked,
+    Remote,
+    Plugin,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_component_round_trip() {
+        for name in [
+            "my_vm 01",
+            "db__primary",
+            "_leading",
+            "trailing_",
+            "___",
+            "_",
+            "100% full",
+            "%41",
+            "vm/with:glob*[chars]?",
+            "Größe äöü 日本",
+            "",
+        ] {
+            let encoded = encode_name_component(name);
+            assert!(
+                !encoded.contains("__"),
+                "{:?} encoded as {:?}",
+                name,
+                encoded
+            );
+            assert!(!encoded.starts_with('_') && !encoded.ends_with('_'));
+            assert!(encoded.is_ascii());
+            assert_eq!(decode_name_component(&encoded).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn name_component_keeps_plain_names() {
+        assert_eq!(encode_name_component("my_vm 01"), "my_vm 01");
+        assert_eq!(encode_name_component("db__primary"), "db%5F%5Fprimary");
+        assert_eq!(encode_name_component("_vm_"), "%5Fvm%5F");
+        assert_eq!(encode_name_component("100%"), "100%25");
+        assert_eq!(encode_name_component("ä"), "%C3%A4");
+    }
+
+    #[test]
+    fn base_name_round_trip() {
+        let backup_object = BackupObject {
+            job_type: JobType::VmBackup,
+            vm_uuid: None,
+            vm_name: "_db__primary_".into(),
+            xen_host: "xen_01".into(),
+            time_stamp: chrono::DateTime::parse_from_rfc3339("2026-10-17T03:00:00+00:00")
+                .unwrap()
+                .to_utc(),
+            size: None,
+            base: None,
+            pool: None,
+        };
+        let parsed =
+            BackupObject::from_name_with_extension(&backup_object.to_base_name(), None).unwrap();
+        assert_eq!(parsed.vm_name, backup_object.vm_name);
+        assert_eq!(parsed.xen_host, backup_object.xen_host);
+        assert_eq!(parsed.time_stamp, backup_object.time_stamp);
+    }
+
+    #[test]
+    fn decode_rejects_invalid_escapes() {
+        assert!(decode_name_component("%4").is_err());
+        assert!(decode_name_component("%ZZ").is_err());
+        assert!(decode_name_component("%FF").is_err());
+    }
 }
@@ -0,0 +1,82 @@
+use std::{path::PathBuf, sync::Arc};
+
+use tracing::{debug, info, warn};
+
+use super::StorageHandler;
+
+/// temporary files which haven't been written to for this long belong to crashed or killed runs,
+/// running exports keep modifying theirs
+pub const ORPHAN_MIN_AGE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// deletes files matching the predicate which haven't been modified within `ORPHAN_MIN_AGE`,
+/// descending up to `max_depth` directory levels. returns the number of reclaimed bytes
+pub async fn remove_orphaned_files(
+    dir: &str,
+    max_depth: usize,
+    is_temporary: impl Fn(&str) -> bool,
+) -> eyre::Result<u64> {
+    let mut reclaimed = 0;
+    let mut dirs = vec![(PathBuf::from(dir), 0)];
+
+    while let Some((dir, depth)) = dirs.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            // the directory is only created on the first backup
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_dir() {
+                if depth < max_depth {
+                    dirs.push((entry.path(), depth + 1));
+                }
+                continue;
+            }
+
+            if !is_temporary(&entry.file_name().to_string_lossy()) {
+                continue;
+            }
+
+            let age = metadata.modified()?.elapsed().unwrap_or_default();
+            if age < ORPHAN_MIN_AGE {
+                debug!(
+                    "Keeping temporary file {}, it was modified {}s ago",
+                    entry.path().display(),
+                    age.as_secs()
+                );
+                continue;
+            }
+
+            info!(
+                "Deleting orphaned temporary file {} ({:.1} MiB)",
+                entry.path().display(),
+                metadata.len() as f64 / 1024.0 / 1024.0
+            );
+            tokio::fs::remove_file(entry.path()).await?;
+            reclaimed += metadata.len();
+        }
+    }
+
+    Ok(reclaimed)
+}
+
+/// removes the orphaned temporary files of all given storages, logging what was reclaimed
+pub async fn cleanup_storages(storage_handlers: &[Arc<dyn StorageHandler>]) {
+    for storage_handler in storage_handlers {
+        match storage_handler.cleanup_orphans().await {
+            Ok(0) => {}
+            Ok(reclaimed) => info!(
+                "Reclaimed {:.1} MiB of orphaned temporary files on storage '{}'",
+                reclaimed as f64 / 1024.0 / 1024.0,
+                storage_handler.get_name()
+            ),
+            Err(e) => warn!(
+                "Failed to clean up orphaned temporary files on storage '{}': {}",
+                storage_handler.get_name(),
+                e
+            ),
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use tracing::info;
+
+use crate::{cli::PinSubCommand, config::AppConfig};
+
+use super::restore_point::find_restore_points;
+
+/// pins or unpins the restore point with the given id on the storages of all enabled jobs
+pub async fn set_pinned(config: &AppConfig, pin: &PinSubCommand, pinned: bool) -> eyre::Result<()> {
+    let found = find_restore_points(
+        config,
+        &pin.restore_point,
+        pin.job.as_deref(),
+        pin.storage.as_deref(),
+    )
+    .await?;
+
+    for (storage_handler, restore_point) in found {
+        storage_handler.set_pinned(&restore_point, pinned).await?;
+        info!(
+            "{} restore point {} on storage '{}'",
+            if pinned { "Pinned" } else { "Unpinned" },
+            restore_point.id,
+            storage_handler.get_name()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,354 @@
+use std::{process::Stdio, str::FromStr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command as AsyncCommand};
+use tracing::{debug, info};
+use xenbak_storage_plugin::{
+    frame::{read_message, write_frame, write_message, FrameKind, DATA_FRAME_SIZE},
+    BackupInfo, PluginRequest, PluginResponse, PROTOCOL_VERSION,
+};
+
+use crate::{
+    config::{JobConfig, PluginStorageConfig},
+    jobs::JobType,
+};
+
+use super::{
+    checksum::HashingReader,
+    restore_point::{expired_restore_points, RestorePoint, RestorePointArtifactKind},
+    BackupObject, BackupObjectFilter, DryRun, StdioStream, StorageHandler, StorageStatus,
+    StorageType,
+};
+
+/// a running plugin process which completed the handshake
+struct PluginSession {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+/// a storage handled by an external plugin executable, see the `xenbak-storage-plugin` crate
+#[derive(Debug, Clone)]
+pub struct PluginStorage {
+    pub storage_type: StorageType,
+    pub storage_config: PluginStorageConfig,
+    pub job_config: JobConfig,
+}
+
+impl PluginStorage {
+    pub fn new(storage_config: PluginStorageConfig, job_config: JobConfig) -> Self {
+        PluginStorage {
+            storage_type: StorageType::Plugin,
+            storage_config,
+            job_config,
+        }
+    }
+
+    async fn spawn(&self) -> eyre::Result<PluginSession> {
+        let mut child = AsyncCommand::new(&self.storage_config.command)
+            .args(&self.storage_config.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut session = PluginSession {
+            stdin: child.stdin.take().unwrap(),
+            stdout: child.stdout.take().unwrap(),
+            child,
+        };
+
+        write_message(
+            &mut session.stdin,
+            &PluginRequest::Hello {
+                version: PROTOCOL_VERSION,
+                options: self.storage_config.options.clone(),
+            },
+        )
+        .await?;
+
+        match read_message(&mut session.stdout).await? {
+            PluginResponse::Hello { version } if version == PROTOCOL_VERSION => Ok(session),
+            PluginResponse::Hello { version } => Err(eyre::eyre!(
+                "Plugin '{}' speaks protocol version {}, expected {}",
+                self.storage_config.command,
+                version,
+                PROTOCOL_VERSION
+            )),
+            response => Err(self.response_error(response)),
+        }
+    }
+
+    /// reads the plugin's response and waits for it to exit
+    async fn finish(&self, mut session: PluginSession) -> eyre::Result<PluginResponse> {
+        drop(session.stdin);
+        let response = read_message(&mut session.stdout).await?;
+        session.child.wait().await?;
+
+        match response {
+            PluginResponse::Error(_) => Err(self.response_error(response)),
+            response => Ok(response),
+        }
+    }
+
+    async fn request(&self, request: PluginRequest) -> eyre::Result<PluginResponse> {
+        let mut session = self.spawn().await?;
+        write_message(&mut session.stdin, &request).await?;
+        self.finish(session).await
+    }
+
+    fn response_error(&self, response: PluginResponse) -> eyre::Error {
+        match response {
+            PluginResponse::Error(message) => eyre::eyre!(
+                "Plugin '{}' failed: {}",
+                self.storage_config.command,
+                message
+            ),
+            response => eyre::eyre!("Unexpected response from plugin: {:?}", response),
+        }
+    }
+
+    fn backup_object_to_info(&self, backup_object: &BackupObject) -> BackupInfo {
+        BackupInfo {
+            name: backup_object.to_base_name(),
+            job_type: backup_object.job_type.to_string(),
+            vm_uuid: backup_object.vm_uuid.clone(),
+            vm_name: backup_object.vm_name.clone(),
+            xen_host: backup_object.xen_host.clone(),
+            time_stamp: backup_object.time_stamp,
+            size: backup_object.size,
+            checksum: None,
+        }
+    }
+
+    fn info_to_restore_point(&self, info: BackupInfo) -> eyre::Result<RestorePoint> {
+        let backup_object = BackupObject::new(
+            JobType::from_str(&info.job_type)?,
+            info.vm_uuid,
+            info.vm_name,
+            info.xen_host,
+            info.time_stamp,
+            info.size,
+        );
+
+        let mut restore_point = RestorePoint::new(self.get_name(), backup_object);
+        restore_point.add_artifact(
+            RestorePointArtifactKind::Data,
+            format!("{}:{}", self.storage_config.name, info.name),
+            info.size,
+        );
+        restore_point.checksum = info.checksum;
+
+        Ok(restore_point)
+    }
+}
+
+#[async_trait::async_trait]
+impl StorageHandler for PluginStorage {
+    async fn status(&self) -> eyre::Result<StorageStatus> {
+        let status = match self
+            .request(PluginRequest::Status {
+                job: self.job_config.name.clone(),
+            })
+            .await?
+        {
+            PluginResponse::Status(status) => status,
+            response => return Err(self.response_error(response)),
+        };
+
+        Ok(StorageStatus {
+            free_space: status.free_space,
+            total_space: status.total_space,
+            used_space: status.used_space,
+            backup_count: self.list(BackupObjectFilter::default()).await?.len() as u32,
+        })
+    }
+
+    fn get_retention_count(&self) -> u32 {
+        self.storage_config.retention
+    }
+
+    fn get_delete_protection_days(&self) -> Option<u32> {
+        self.storage_config.delete_protection_days
+    }
+
+    fn get_job_config(&self) -> JobConfig {
+        self.job_config.clone()
+    }
+
+    fn get_name(&self) -> String {
+        self.storage_config.name.clone()
+    }
+
+    fn get_storage_type(&self) -> StorageType {
+        self.storage_type.clone()
+    }
+
+    async fn initialize(&self) -> eyre::Result<()> {
+        match self
+            .request(PluginRequest::Initialize {
+                job: self.job_config.name.clone(),
+            })
+            .await?
+        {
+            PluginResponse::Done => Ok(()),
+            response => Err(self.response_error(response)),
+        }
+    }
+
+    async fn list(&self, filter: BackupObjectFilter) -> eyre::Result<Vec<RestorePoint>> {
+        let backups = match self
+            .request(PluginRequest::List {
+                job: self.job_config.name.clone(),
+            })
+            .await?
+        {
+            PluginResponse::List(backups) => backups,
+            response => return Err(self.response_error(response)),
+        };
+
+        let mut restore_points = vec![];
+        for info in backups {
+            // skip backups which were not created by xenbakd
+            let Ok(restore_point) = self.info_to_restore_point(info) else {
+                continue;
+            };
+
+            if filter.matches(&restore_point.backup_object) {
+                restore_points.push(restore_point);
+            }
+        }
+
+        Ok(restore_points)
+    }
+
+    async fn rotate(
+        &self,
+        filter: BackupObjectFilter,
+        retention: Option<u32>,
+        dry_run: DryRun,
+    ) -> eyre::Result<Vec<String>> {
+        let restore_points = self.list(filter).await?;
+
+        let mut deleted = vec![];
+        for restore_point in expired_restore_points(
+            restore_points,
+            retention.unwrap_or(self.storage_config.retention),
+            self.storage_config.delete_protection_days,
+        ) {
+            if !dry_run.is_enabled() {
+                self.delete(&restore_point).await?;
+            }
+            deleted.push(restore_point.id);
+        }
+
+        Ok(deleted)
+    }
+
+    async fn delete(&self, restore_point: &RestorePoint) -> eyre::Result<()> {
+        debug!("Deleting restore point {}", restore_point.id);
+        match self
+            .request(PluginRequest::Delete {
+                job: self.job_config.name.clone(),
+                name: restore_point.id.clone(),
+            })
+            .await?
+        {
+            PluginResponse::Done => Ok(()),
+            response => Err(self.response_error(response)),
+        }
+    }
+
+    // the plugin protocol has no way to store the pin
+    async fn set_pinned(&self, _restore_point: &RestorePoint, _pinned: bool) -> eyre::Result<()> {
+        Err(eyre::eyre!("Pinning is not supported on plugin storages"))
+    }
+
+    async fn verify(&self, restore_point: &RestorePoint) -> eyre::Result<()> {
+        let mut backup = self.backup_object_to_info(&restore_point.backup_object);
+        backup.checksum = restore_point.checksum.clone();
+
+        match self
+            .request(PluginRequest::Verify {
+                job: self.job_config.name.clone(),
+                backup,
+            })
+            .await?
+        {
+            PluginResponse::Done => Ok(()),
+            response => Err(self.response_error(response)),
+        }
+    }
+
+    async fn handle_stdio_stream(
+        &self,
+        backup_object: BackupObject,
+        stdout_stream: StdioStream,
+        mut stderr_stream: StdioStream,
+    ) -> eyre::Result<()> {
+        info!(
+            "Streaming export to plugin '{}'",
+            self.storage_config.command
+        );
+
+        let mut session = self.spawn().await?;
+        write_message(
+            &mut session.stdin,
+            &PluginRequest::Store {
+                job: self.job_config.name.clone(),
+                backup: self.backup_object_to_info(&backup_object),
+            },
+        )
+        .await?;
+
+        let stderr = async {
+            let mut stderr = Vec::new();
+            stderr_stream.read_to_end(&mut stderr).await?;
+            Ok::<Vec<u8>, eyre::Error>(stderr)
+        };
+
+        let mut stdout_hashing = HashingReader::new(stdout_stream);
+        let stdin = &mut session.stdin;
+        let upload = async {
+            let mut buffer = vec![0; DATA_FRAME_SIZE];
+            loop {
+                let read = stdout_hashing.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                write_frame(stdin, FrameKind::Data, &buffer[..read]).await?;
+            }
+            Ok::<(), eyre::Error>(())
+        };
+
+        let (stderr, upload) = tokio::join!(stderr, upload);
+
+        // the plugin may have stopped reading because it failed, its response carries the reason
+        if let Err(e) = upload {
+            return match self.finish(session).await {
+                Err(plugin_error) => Err(plugin_error),
+                Ok(_) => Err(e),
+            };
+        }
+
+        let stderr = stderr?;
+        match stderr.is_empty() {
+            true => {
+                let checksum = stdout_hashing.finalize();
+                write_frame(&mut session.stdin, FrameKind::End, checksum.as_bytes()).await?
+            }
+            false => write_frame(&mut session.stdin, FrameKind::Stderr, &stderr).await?,
+        }
+        session.stdin.flush().await?;
+
+        match self.finish(session).await? {
+            PluginResponse::Done if stderr.is_empty() => Ok(()),
+            PluginResponse::Done => Err(eyre::eyre!(
+                "Error encountered in stderr output: {}",
+                String::from_utf8_lossy(&stderr)
+            )),
+            response => Err(self.response_error(response)),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use tracing::{debug, error};
+
+use super::StorageHandler;
+
+/// file name prefix of probe files, followed by the pid of the probing process. leftovers of
+/// killed runs are skipped by the listings and removed by the orphan cleanup
+const PROBE_FILE_PREFIX: &str = ".xenbakd-probe";
+
+/// whether the file was written by `probe_dir`
+pub fn is_probe_file(file_name: &str) -> bool {
+    file_name.starts_with(PROBE_FILE_PREFIX)
+}
+
+/// writes a small file to the directory, reads it back and deletes it again. catches unmounted,
+/// read-only or full filesystems before anything is exported
+pub async fn probe_dir(dir: &str) -> eyre::Result<()> {
+    let path = format!("{}/{}-{}", dir, PROBE_FILE_PREFIX, std::process::id());
+    let content = format!("xenbakd probe {}", chrono::Utc::now().to_rfc3339());
+
+    tokio::fs::write(&path, &content)
+        .await
+        .map_err(|e| eyre::eyre!("Failed to write probe file {}: {}", path, e))?;
+    let read = tokio::fs::read_to_string(&path)
+        .await
+        .map_err(|e| eyre::eyre!("Failed to read probe file {}: {}", path, e))?;
+    tokio::fs::remove_file(&path)
+        .await
+        .map_err(|e| eyre::eyre!("Failed to delete probe file {}: {}", path, e))?;
+
+    if read != content {
+        return Err(eyre::eyre!("Probe file {} was read back corrupted", path));
+    }
+
+    Ok(())
+}
+
+/// probes all given storages, returns an error message for every storage which failed
+pub async fn probe_storages(storage_handlers: &[Arc<dyn StorageHandler>]) -> Vec<String> {
+    let mut errors = vec![];
+
+    for storage_handler in storage_handlers {
+        debug!("Probing storage '{}'", storage_handler.get_name());
+        if let Err(e) = storage_handler.probe().await {
+            let message = format!(
+                "Storage '{}' failed the health check: {}",
+                storage_handler.get_name(),
+                e
+            );
+            error!("{}", message);
+            errors.push(message);
+        }
+    }
+
+    errors
+}
@@ -0,0 +1,173 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::{info, Instrument};
+
+use super::StdioStream;
+
+/// how often the progress of a running export is logged
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(30);
+const MIB: f64 = 1024.0 * 1024.0;
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// counts the bytes read from the wrapped stream
+struct CountingReader {
+    inner: StdioStream,
+    bytes: Arc<AtomicU64>,
+}
+
+impl AsyncRead for CountingReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - filled) as u64;
+        self.bytes.fetch_add(read, Ordering::Relaxed);
+        result
+    }
+}
+
+/// size and duration of a finished export
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportSummary {
+    pub bytes: u64,
+    /// seconds
+    pub duration: f64,
+}
+
+impl ExportSummary {
+    /// bytes per second
+    pub fn throughput(&self) -> f64 {
+        if self.duration > 0.0 {
+            self.bytes as f64 / self.duration
+        } else {
+            0.0
+        }
+    }
+
+    /// adds up the exports of an object to several storages
+    pub fn add(&mut self, other: ExportSummary) {
+        self.bytes += other.bytes;
+        self.duration += other.duration;
+    }
+}
+
+/// follows the bytes of an export going to a storage and logs its throughput and ETA, so a slow
+/// export can be told from a hung one. the ETA is based on the estimated size of the export
+pub struct ExportProgress {
+    bytes: Arc<AtomicU64>,
+    started: tokio::time::Instant,
+    logger: tokio::task::JoinHandle<()>,
+}
+
+impl ExportProgress {
+    /// wraps the export stream, returns the stream to hand to the storage
+    pub fn start(
+        stream: StdioStream,
+        estimated_size: Option<u64>,
+    ) -> (ExportProgress, StdioStream) {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let started = tokio::time::Instant::now();
+
+        let logger_bytes = bytes.clone();
+        let logger = tokio::spawn(
+            async move {
+                let mut interval = tokio::time::interval_at(
+                    started + PROGRESS_LOG_INTERVAL,
+                    PROGRESS_LOG_INTERVAL,
+                );
+                loop {
+                    interval.tick().await;
+                    let summary = ExportSummary {
+                        bytes: logger_bytes.load(Ordering::Relaxed),
+                        duration: started.elapsed().as_secs_f64(),
+                    };
+                    info!("{}", format_progress(&summary, estimated_size));
+                }
+            }
+            .in_current_span(),
+        );
+
+        let stream = Box::new(CountingReader {
+            inner: stream,
+            bytes: bytes.clone(),
+        });
+        (
+            ExportProgress {
+                bytes,
+                started,
+                logger,
+            },
+            stream,
+        )
+    }
+
+    pub fn finish(self) -> ExportSummary {
+        ExportSummary {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            duration: self.started.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+impl Drop for ExportProgress {
+    fn drop(&mut self) {
+        self.logger.abort();
+    }
+}
+
+fn format_progress(summary: &ExportSummary, estimated_size: Option<u64>) -> String {
+    let throughput = summary.throughput();
+    let exported = format!(
+        "Exported {} at {:.1} MiB/s",
+        format_size(summary.bytes),
+        throughput / MIB
+    );
+
+    match estimated_size {
+        Some(estimated) if summary.bytes >= estimated => format!(
+            "{}, beyond the estimate of ~{}",
+            exported,
+            format_size(estimated)
+        ),
+        // the estimate is the used disk space, compressed or sparse exports end early
+        Some(estimated) => format!(
+            "{} ({:.0}% of ~{}), ETA {}",
+            exported,
+            summary.bytes as f64 / estimated as f64 * 100.0,
+            format_size(estimated),
+            match throughput > 0.0 {
+                true => format_duration(((estimated - summary.bytes) as f64 / throughput) as u64),
+                false => "unknown".to_string(),
+            }
+        ),
+        None => exported,
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    if bytes as f64 >= GIB {
+        format!("{:.2} GiB", bytes as f64 / GIB)
+    } else {
+        format!("{:.1} MiB", bytes as f64 / MIB)
+    }
+}
+
+fn format_duration(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
@@ -0,0 +1,290 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, rustls::ServerName};
+use tracing::{debug, info};
+
+use crate::{
+    agent::{
+        protocol::{
+            read_message, write_frame, write_message, AgentOperation, AgentRequest, AgentResponse,
+            FrameKind, DATA_FRAME_SIZE,
+        },
+        tls,
+    },
+    config::{JobConfig, RemoteStorageConfig},
+};
+
+use super::{
+    restore_point::RestorePoint, BackupObject, BackupObjectFilter, DryRun, StdioStream,
+    StorageHandler, StorageStatus, StorageType,
+};
+
+/// a storage which is handled by an agent (`xenbakd agent`) running next to it
+#[derive(Debug, Clone)]
+pub struct RemoteStorage {
+    pub storage_type: StorageType,
+    pub storage_config: RemoteStorageConfig,
+    pub job_config: JobConfig,
+    // only known once the agent reported it during initialization
+    retention_count: Arc<AtomicU32>,
+    delete_protection_days: Arc<AtomicU32>,
+}
+
+impl RemoteStorage {
+    pub fn new(storage_config: RemoteStorageConfig, job_config: JobConfig) -> Self {
+        RemoteStorage {
+            storage_type: StorageType::Remote,
+            storage_config,
+            job_config,
+            retention_count: Arc::new(AtomicU32::new(0)),
+            delete_protection_days: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    async fn connect(&self) -> eyre::Result<TlsStream<TcpStream>> {
+        let server_name = match &self.storage_config.server_name {
+            Some(server_name) => server_name.as_str(),
+            None => self
+                .storage_config
+                .address
+                .rsplit_once(':')
+                .map(|(host, _)| host)
+                .unwrap_or(&self.storage_config.address),
+        };
+
+        let connector = tls::connector(&self.storage_config.ca_cert)?;
+        let stream = TcpStream::connect(&self.storage_config.address).await?;
+        Ok(connector
+            .connect(ServerName::try_from(server_name)?, stream)
+            .await?)
+    }
+
+    fn build_request(&self, operation: AgentOperation) -> AgentRequest {
+        AgentRequest {
+            token: self.storage_config.token.clone(),
+            storage: self.storage_config.remote_storage.clone(),
+            job: self.job_config.name.clone(),
+            differential: self.job_config.differential.is_some(),
+            operation,
+        }
+    }
+
+    async fn request(&self, operation: AgentOperation) -> eyre::Result<AgentResponse> {
+        let mut stream = self.connect().await?;
+        write_message(&mut stream, &self.build_request(operation)).await?;
+
+        match read_message(&mut stream).await? {
+            AgentResponse::Error(message) => Err(eyre::eyre!(
+                "Agent {} failed: {}",
+                self.storage_config.address,
+                message
+            )),
+            response => Ok(response),
+        }
+    }
+}
+
+fn unexpected_response(response: AgentResponse) -> eyre::Error {
+    eyre::eyre!("Unexpected response from agent: {:?}", response)
+}
+
+#[async_trait::async_trait]
+impl StorageHandler for RemoteStorage {
+    async fn status(&self) -> eyre::Result<StorageStatus> {
+        match self.request(AgentOperation::Status).await? {
+            AgentResponse::Status(status) => Ok(status),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    fn get_retention_count(&self) -> u32 {
+        self.retention_count.load(Ordering::Relaxed)
+    }
+
+    fn get_delete_protection_days(&self) -> Option<u32> {
+        match self.delete_protection_days.load(Ordering::Relaxed) {
+            0 => None,
+            days => Some(days),
+        }
+    }
+
+    fn get_job_config(&self) -> JobConfig {
+        self.job_config.clone()
+    }
+
+    fn get_name(&self) -> String {
+        self.storage_config.name.clone()
+    }
+
+    fn get_storage_type(&self) -> StorageType {
+        self.storage_type.clone()
+    }
+
+    async fn initialize(&self) -> eyre::Result<()> {
+        match self.request(AgentOperation::Initialize).await? {
+            AgentResponse::Initialized {
+                retention_count,
+                delete_protection_days,
+            } => {
+                self.retention_count
+                    .store(retention_count, Ordering::Relaxed);
+                self.delete_protection_days
+                    .store(delete_protection_days.unwrap_or(0), Ordering::Relaxed);
+                Ok(())
+            }
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    async fn probe(&self) -> eyre::Result<()> {
+        match self.request(AgentOperation::Probe).await? {
+            AgentResponse::Done => Ok(()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    async fn list(&self, filter: BackupObjectFilter) -> eyre::Result<Vec<RestorePoint>> {
+        match self.request(AgentOperation::List { filter }).await? {
+            AgentResponse::List(restore_points) => Ok(restore_points),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    async fn rotate(
+        &self,
+        filter: BackupObjectFilter,
+        retention: Option<u32>,
+        dry_run: DryRun,
+    ) -> eyre::Result<Vec<String>> {
+        match self
+            .request(AgentOperation::Rotate {
+                filter,
+                retention,
+                simulate: dry_run.is_enabled(),
+            })
+            .await?
+        {
+            AgentResponse::Rotated(deleted) => Ok(deleted),
+            // older agents don't report the deleted restore points
+            AgentResponse::Done => Ok(vec![]),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    async fn delete(&self, restore_point: &RestorePoint) -> eyre::Result<()> {
+        match self
+            .request(AgentOperation::Delete {
+                restore_point: restore_point.clone(),
+            })
+            .await?
+        {
+            AgentResponse::Done => Ok(()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    async fn set_pinned(&self, restore_point: &RestorePoint, pinned: bool) -> eyre::Result<()> {
+        match self
+            .request(AgentOperation::SetPinned {
+                restore_point: restore_point.clone(),
+                pinned,
+            })
+            .await?
+        {
+            AgentResponse::Done => Ok(()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    async fn verify(&self, restore_point: &RestorePoint) -> eyre::Result<()> {
+        match self
+            .request(AgentOperation::Verify {
+                restore_point: restore_point.clone(),
+            })
+            .await?
+        {
+            AgentResponse::Done => Ok(()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    async fn handle_stdio_stream(
+        &self,
+        backup_object: BackupObject,
+        mut stdout_stream: StdioStream,
+        mut stderr_stream: StdioStream,
+    ) -> eyre::Result<()> {
+        info!(
+            "Streaming export to agent {} (storage '{}')",
+            self.storage_config.address, self.storage_config.remote_storage
+        );
+
+        let mut stream = self.connect().await?;
+        write_message(
+            &mut stream,
+            &self.build_request(AgentOperation::Store { backup_object }),
+        )
+        .await?;
+
+        let stderr = async {
+            let mut stderr = Vec::new();
+            stderr_stream.read_to_end(&mut stderr).await?;
+            Ok::<Vec<u8>, eyre::Error>(stderr)
+        };
+
+        let upload = async {
+            let mut buffer = vec![0; DATA_FRAME_SIZE];
+            let mut total = 0;
+            loop {
+                let read = stdout_stream.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                write_frame(&mut stream, FrameKind::Data, &buffer[..read]).await?;
+                total += read;
+            }
+            Ok::<usize, eyre::Error>(total)
+        };
+
+        let (stderr, upload) = tokio::join!(stderr, upload);
+
+        // if the agent aborted the transfer, its response carries the reason
+        let sent = match upload {
+            Ok(sent) => sent,
+            Err(e) => {
+                return match read_message(&mut stream).await {
+                    Ok(AgentResponse::Error(message)) => Err(eyre::eyre!(
+                        "Agent {} failed: {}",
+                        self.storage_config.address,
+                        message
+                    )),
+                    _ => Err(e),
+                }
+            }
+        };
+
+        debug!("Sent {} bytes to agent", sent);
+
+        let stderr = stderr?;
+        match stderr.is_empty() {
+            true => write_frame(&mut stream, FrameKind::End, &[]).await?,
+            false => write_frame(&mut stream, FrameKind::Stderr, &stderr).await?,
+        }
+        stream.flush().await?;
+
+        match read_message(&mut stream).await? {
+            AgentResponse::Done => Ok(()),
+            AgentResponse::Error(message) => Err(eyre::eyre!(
+                "Agent {} failed: {}",
+                self.storage_config.address,
+                message
+            )),
+            response => Err(unexpected_response(response)),
+        }
+    }
+}
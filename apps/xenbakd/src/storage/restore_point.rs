@@ -0,0 +1,302 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::config::AppConfig;
+
+use super::{BackupObject, BackupObjectFilter, StorageHandler};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestorePointArtifactKind {
+    /// the exported data itself
+    Data,
+    /// the manifest sidecar describing the backup
+    Manifest,
+    /// the checksum sidecar of the export stream
+    Checksum,
+}
+
+/// a single stored object belonging to a restore point
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestorePointArtifact {
+    pub kind: RestorePointArtifactKind,
+    /// storage specific location, e.g. a file path or a borg archive
+    pub location: String,
+    pub size: Option<u64>,
+}
+
+/// groups everything stored for one backup of a VM on one storage under a single id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestorePoint {
+    pub id: String,
+    pub storage_name: String,
+    pub backup_object: BackupObject,
+    pub artifacts: Vec<RestorePointArtifact>,
+    /// sha256 checksum of the export stream, recorded at backup time
+    pub checksum: Option<String>,
+    /// pinned restore points are never deleted by the rotation
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+impl RestorePoint {
+    pub fn new(storage_name: String, backup_object: BackupObject) -> Self {
+        RestorePoint {
+            id: backup_object.to_base_name(),
+            storage_name,
+            backup_object,
+            artifacts: vec![],
+            checksum: None,
+            pinned: false,
+        }
+    }
+
+    pub fn add_artifact(
+        &mut self,
+        kind: RestorePointArtifactKind,
+        location: String,
+        size: Option<u64>,
+    ) {
+        self.artifacts.push(RestorePointArtifact {
+            kind,
+            location,
+            size,
+        });
+    }
+
+    pub fn get_artifact(&self, kind: RestorePointArtifactKind) -> Option<&RestorePointArtifact> {
+        self.artifacts.iter().find(|x| x.kind == kind)
+    }
+
+    /// total size of all artifacts with a known size
+    pub fn size(&self) -> u64 {
+        self.artifacts.iter().filter_map(|x| x.size).sum()
+    }
+}
+
+/// whether a differential restore point of the list is based on the restore point
+pub fn is_chain_base(restore_point: &RestorePoint, restore_points: &[RestorePoint]) -> bool {
+    restore_points
+        .iter()
+        .any(|x| x.backup_object.base.as_ref() == Some(&restore_point.id))
+}
+
+/// returns the restore points exceeding the retention count, counted per host, job type and VM.
+/// restore points younger than `delete_protection_days` are never returned. pinned restore points
+/// are neither returned nor counted towards the retention. bases of kept differential restore
+/// points are never returned either
+pub fn expired_restore_points(
+    restore_points: Vec<RestorePoint>,
+    retention: u32,
+    delete_protection_days: Option<u32>,
+) -> Vec<RestorePoint> {
+    let bases: Vec<(String, Option<String>)> = restore_points
+        .iter()
+        .map(|x| (x.id.clone(), x.backup_object.base.clone()))
+        .collect();
+    let mut vm_job_type_map: HashMap<String, Vec<RestorePoint>> = HashMap::new();
+
+    for restore_point in restore_points {
+        if restore_point.pinned {
+            debug!("Keeping pinned restore point {}", restore_point.id);
+            continue;
+        }
+
+        let backup_object = &restore_point.backup_object;
+        // backups which know their VM's uuid are grouped by it, so a renamed VM keeps one retention
+        let key = format!(
+            "{}__{}__{}",
+            backup_object.xen_host,
+            backup_object.job_type,
+            backup_object
+                .vm_uuid
+                .as_ref()
+                .unwrap_or(&backup_object.vm_name)
+        );
+        vm_job_type_map.entry(key).or_default().push(restore_point);
+    }
+
+    // keep the last N backups
+    let mut expired = vec![];
+    for (_key, mut restore_points) in vm_job_type_map {
+        restore_points.sort_by_key(|b| std::cmp::Reverse(b.backup_object.time_stamp));
+        expired.extend(restore_points.into_iter().skip(retention as usize));
+    }
+
+    if let Some(delete_protection_days) = delete_protection_days {
+        let protected_since =
+            chrono::Utc::now() - chrono::Duration::days(delete_protection_days as i64);
+        expired.retain(|restore_point| {
+            if restore_point.backup_object.time_stamp <= protected_since {
+                return true;
+            }
+            warn!(
+                "Not deleting restore point {}, it is within the delete protection window of {} days",
+                restore_point.id, delete_protection_days
+            );
+            false
+        });
+    }
+
+    // a differential restore point needs its whole chain, down to the full backup
+    loop {
+        let expired_ids: HashSet<&String> = expired.iter().map(|x| &x.id).collect();
+        let needed: HashSet<&String> = bases
+            .iter()
+            .filter(|(id, _)| !expired_ids.contains(id))
+            .filter_map(|(_, base)| base.as_ref())
+            .collect();
+        let count = expired.len();
+        expired.retain(|restore_point| {
+            if !needed.contains(&restore_point.id) {
+                return true;
+            }
+            debug!(
+                "Keeping restore point {}, differential restore points are based on it",
+                restore_point.id
+            );
+            false
+        });
+        if expired.len() == count {
+            return expired;
+        }
+    }
+}
+
+/// looks up the restore point with the given id on the storages of all enabled jobs, optionally
+/// limited to a job and a storage. storages shared by several jobs are only searched once
+pub async fn find_restore_points(
+    config: &AppConfig,
+    id: &str,
+    job: Option<&str>,
+    storage: Option<&str>,
+) -> eyre::Result<Vec<(Arc<dyn StorageHandler>, RestorePoint)>> {
+    let mut searched: Vec<String> = vec![];
+    let mut found = vec![];
+
+    for job_config in config.jobs.iter().filter(|x| x.enabled) {
+        if job.is_some_and(|x| x != job_config.name) {
+            continue;
+        }
+
+        for storage_handler in job_config.get_storages(config.storage.clone()) {
+            let storage_name = storage_handler.get_name();
+            if storage.is_some_and(|x| x != storage_name) || searched.contains(&storage_name) {
+                continue;
+            }
+            searched.push(storage_name);
+
+            let restore_point = storage_handler
+                .list(BackupObjectFilter::default())
+                .await?
+                .into_iter()
+                .find(|x| x.id == id);
+            if let Some(restore_point) = restore_point {
+                found.push((storage_handler, restore_point));
+            }
+        }
+    }
+
+    if found.is_empty() {
+        return Err(eyre::eyre!("Restore point {} not found on any storage", id));
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::JobType;
+
+    fn restore_point(days_ago: i64, base: Option<&RestorePoint>) -> RestorePoint {
+        let backup_object = BackupObject {
+            job_type: JobType::VmBackup,
+            vm_uuid: Some("uuid".into()),
+            vm_name: "vm".into(),
+            xen_host: "xen".into(),
+            time_stamp: chrono::Utc::now() - chrono::Duration::days(days_ago),
+            size: None,
+            base: base.map(|x| x.id.clone()),
+            pool: None,
+        };
+        RestorePoint::new("storage".into(), backup_object)
+    }
+
+    fn expired_ids(
+        restore_points: &[RestorePoint],
+        retention: u32,
+        delete_protection_days: Option<u32>,
+    ) -> Vec<String> {
+        let mut ids: Vec<_> =
+            expired_restore_points(restore_points.to_vec(), retention, delete_protection_days)
+                .into_iter()
+                .map(|x| x.id)
+                .collect();
+        ids.sort();
+        ids
+    }
+
+    fn sorted_ids(restore_points: &[&RestorePoint]) -> Vec<String> {
+        let mut ids: Vec<_> = restore_points.iter().map(|x| x.id.clone()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn keeps_the_chain_of_a_kept_differential() {
+        let full = restore_point(3, None);
+        let diff1 = restore_point(2, Some(&full));
+        let diff2 = restore_point(1, Some(&diff1));
+
+        assert!(expired_ids(&[full, diff1, diff2], 1, None).is_empty());
+    }
+
+    #[test]
+    fn expires_a_chain_once_nothing_depends_on_it() {
+        let full = restore_point(4, None);
+        let diff1 = restore_point(3, Some(&full));
+        let diff2 = restore_point(2, Some(&diff1));
+        let new_full = restore_point(1, None);
+
+        assert_eq!(
+            expired_ids(
+                &[full.clone(), diff1.clone(), diff2.clone(), new_full],
+                1,
+                None
+            ),
+            sorted_ids(&[&full, &diff1, &diff2])
+        );
+    }
+
+    #[test]
+    fn keeps_pinned_differential_and_its_base() {
+        let full = restore_point(4, None);
+        let mut diff = restore_point(3, Some(&full));
+        diff.pinned = true;
+        let old_full = restore_point(2, None);
+        let new_full = restore_point(1, None);
+
+        // the pinned restore point doesn't count towards the retention
+        assert_eq!(
+            expired_ids(&[full, diff, old_full.clone(), new_full], 1, None),
+            sorted_ids(&[&old_full])
+        );
+    }
+
+    #[test]
+    fn keeps_restore_points_within_delete_protection() {
+        let oldest = restore_point(10, None);
+        let protected = restore_point(2, None);
+        let newest = restore_point(1, None);
+
+        assert_eq!(
+            expired_ids(&[oldest.clone(), protected, newest], 1, Some(5)),
+            sorted_ids(&[&oldest])
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    os::unix::net::UnixDatagram,
+    sync::mpsc,
+};
+
+use tracing::{field::Field, Event, Level, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::config::{SyslogConfig, SyslogTransport};
+
+/// messages waiting for the writer, further ones are dropped so logging never blocks
+const QUEUE_SIZE: usize = 1024;
+
+/// sends the events as RFC 5424 messages to a syslog server. the messages are written by a
+/// thread of their own, a slow or unreachable server doesn't hold up the daemon
+pub struct SyslogLayer {
+    sender: mpsc::SyncSender<String>,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    pid: u32,
+}
+
+impl SyslogLayer {
+    pub fn new(config: &SyslogConfig) -> eyre::Result<Self> {
+        let facility = facility(&config.facility)
+            .ok_or_else(|| eyre::eyre!("Unknown syslog facility '{}'", config.facility))?;
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|x| x.trim().to_string())
+            .unwrap_or_else(|_| "-".into());
+
+        let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_SIZE);
+        let mut writer = SyslogWriter {
+            transport: config.transport,
+            address: config.address.clone(),
+            connection: None,
+            failing: false,
+        };
+        std::thread::Builder::new()
+            .name("syslog".into())
+            .spawn(move || {
+                for message in receiver {
+                    writer.write(&message);
+                }
+            })?;
+
+        Ok(SyslogLayer {
+            sender,
+            facility,
+            hostname,
+            app_name: config.app_name.clone(),
+            pid: std::process::id(),
+        })
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SyslogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let severity = match *event.metadata().level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        let message = format!(
+            "<{}>1 {} {} {} {} - - {}: {}{}",
+            self.facility * 8 + severity,
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            self.hostname,
+            self.app_name,
+            self.pid,
+            event.metadata().target(),
+            visitor.message,
+            visitor.fields
+        );
+        // a full queue means the server doesn't keep up, the message is lost
+        let _ = self.sender.try_send(message);
+    }
+}
+
+/// the message of an event followed by its other fields as `key=value`
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl tracing::field::Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => {
+                let _ = write!(self.fields, " {}={}", name, value);
+            }
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            name => {
+                let _ = write!(self.fields, " {}={:?}", name, value);
+            }
+        }
+    }
+}
+
+enum Connection {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+struct SyslogWriter {
+    transport: SyslogTransport,
+    address: String,
+    connection: Option<Connection>,
+    /// only the first of consecutive failures is reported
+    failing: bool,
+}
+
+impl SyslogWriter {
+    fn connect(&self) -> std::io::Result<Connection> {
+        Ok(match self.transport {
+            SyslogTransport::Unix => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(&self.address)?;
+                Connection::Unix(socket)
+            }
+            SyslogTransport::Udp => {
+                let address = self.address.to_socket_addrs()?.next().ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::NotFound, "address not resolved")
+                })?;
+                let socket = match address.is_ipv6() {
+                    true => UdpSocket::bind("[::]:0")?,
+                    false => UdpSocket::bind("0.0.0.0:0")?,
+                };
+                socket.connect(address)?;
+                Connection::Udp(socket)
+            }
+            SyslogTransport::Tcp => {
+                let stream = TcpStream::connect(&self.address)?;
+                stream.set_write_timeout(Some(std::time::Duration::from_secs(10)))?;
+                Connection::Tcp(stream)
+            }
+        })
+    }
+
+    fn send(&mut self, message: &str) -> std::io::Result<()> {
+        if self.connection.is_none() {
+            self.connection = Some(self.connect()?);
+        }
+        match self.connection.as_mut().unwrap() {
+            Connection::Unix(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Connection::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Connection::Tcp(stream) => {
+                stream.write_all(format!("{} {}", message.len(), message).as_bytes())
+            }
+        }
+    }
+
+    /// reconnects once if the message can't be sent, e.g. after a restart of the server
+    fn write(&mut self, message: &str) {
+        let result = self.send(message).or_else(|_| {
+            self.connection = None;
+            self.send(message)
+        });
+        match result {
+            Ok(()) => self.failing = false,
+            Err(e) => {
+                self.connection = None;
+                // the log can't be used, it would end up here again
+                if !self.failing {
+                    eprintln!(
+                        "Failed to send log messages to syslog at {}: {}",
+                        self.address, e
+                    );
+                }
+                self.failing = true;
+            }
+        }
+    }
+}
+
+fn facility(name: &str) -> Option<u8> {
+    Some(match name {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        name => match name.strip_prefix("local")?.parse::<u8>().ok()? {
+            n @ 0..=7 => 16 + n,
+            _ => return None,
+        },
+    })
+}
@@ -0,0 +1,67 @@
+use std::os::{
+    linux::net::SocketAddrExt,
+    unix::{fs::MetadataExt, net::UnixDatagram},
+};
+
+use tracing::debug;
+
+/// sends a state to the service manager, see sd_notify(3). does nothing unless systemd started
+/// the daemon with `Type=notify` or `NotifyAccess`
+pub fn notify(state: &str) {
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let result = (|| -> std::io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        // a leading @ names a socket in the abstract namespace
+        match path.strip_prefix('@') {
+            Some(name) => socket.send_to_addr(
+                state.as_bytes(),
+                &std::os::unix::net::SocketAddr::from_abstract_name(name)?,
+            )?,
+            None => socket.send_to(state.as_bytes(), &path)?,
+        };
+        Ok(())
+    })();
+    if let Err(e) = result {
+        debug!("Failed to notify systemd via {}: {}", path, e);
+    }
+}
+
+/// the scheduler is running
+pub fn ready() {
+    notify("READY=1");
+}
+
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// one-line status shown by `systemctl status`
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+/// interval the watchdog has to be pinged in, if `WatchdogSec` is set for the daemon
+pub fn watchdog_interval() -> Option<std::time::Duration> {
+    // the watchdog may be meant for another process of the service
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid != std::process::id().to_string() {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| std::time::Duration::from_micros(usec))
+}
+
+/// whether stdout is connected to the journal, see `JOURNAL_STREAM` in systemd.exec(5)
+pub fn stdout_is_journal() -> bool {
+    let Ok(stream) = std::env::var("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Ok(stdout) = std::fs::metadata("/proc/self/fd/1") else {
+        return false;
+    };
+    stream == format!("{}:{}", stdout.dev(), stdout.ino())
+}
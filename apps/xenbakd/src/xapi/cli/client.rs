@@ -1,53 +1,312 @@
-use std::{process::Stdio, sync::Arc};
+use std::{
+    process::Stdio,
+    sync::{atomic::Ordering, Arc, OnceLock},
+};
 
-use tokio::process::Command as AsyncCommand;
+use tokio::{
+    process::{Child, Command as AsyncCommand},
+    sync::OnceCell,
+};
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    config::XenConfig,
-    storage::{local::LocalCompressionType, CompressionType, StorageHandler},
-    xapi::{error::XApiCliError, SnapshotType, UUIDs, UUID, VM},
+    config::{XApiTransport, XePasswordMode, XenConfig},
+    storage::{
+        local::LocalCompressionType,
+        progress::{ExportProgress, ExportSummary},
+        CompressionType, StdioStream, StorageHandler,
+    },
+    xapi::{
+        error::{XApiCliError, XApiErrorKind, XApiParseError},
+        master::{self, PoolMaster},
+        rpc::client::XApiRpcClient,
+        task::{self, XApiTask},
+        version::{HostFeature, HostVersion},
+        ExportCompression, PowerOperation, PowerState, SnapshotType, UUIDs, VmSizeEstimate, UUID,
+        VM,
+    },
 };
 
-use super::FromCliOutput;
+use super::{
+    credentials::CredentialsFile,
+    timeout::{InactivityTimeout, OutputTimeout},
+    FromCliOutput,
+};
 
-#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct XApiCliClient {
     config: XenConfig,
+    /// handles VM queries and snapshots if the host is configured with `api = "xmlrpc"`
+    rpc: Option<XApiRpcClient>,
+    /// version of the pool master, queried once and shared by all clones. `None` if it couldn't
+    /// be determined
+    version: Arc<OnceCell<Option<HostVersion>>>,
+    /// written on the first remote `xe` command and shared by all clones. `None` if it couldn't
+    /// be written
+    credentials: Arc<OnceLock<Option<CredentialsFile>>>,
+    /// address of the pool master, shared with the xmlrpc client
+    master: PoolMaster,
+}
+
+// clients are told apart by their host, the shared session and version don't matter
+impl PartialEq for XApiCliClient {
+    fn eq(&self, other: &Self) -> bool {
+        self.config == other.config
+    }
+}
+
+impl Eq for XApiCliClient {}
+
+impl std::hash::Hash for XApiCliClient {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.config.hash(state);
+    }
 }
 
 impl XApiCliClient {
     pub fn new(config: XenConfig) -> Self {
-        XApiCliClient { config }
+        let master = PoolMaster::new(&config.server);
+        let rpc = match config.api {
+            XApiTransport::Cli => None,
+            XApiTransport::XmlRpc => Some(XApiRpcClient::new(config.clone(), master.clone())),
+        };
+        XApiCliClient {
+            config,
+            rpc,
+            version: Arc::new(OnceCell::new()),
+            credentials: Arc::new(OnceLock::new()),
+            master,
+        }
+    }
+
+    /// returns the product brand and version of the pool master, queried on the first call
+    pub async fn get_version(&self) -> Option<HostVersion> {
+        self.version
+            .get_or_init(|| async {
+                match self.query_version().await {
+                    Ok(Some(version)) => {
+                        info!("Xen host '{}' runs {}", self.config.name, version);
+                        Some(version)
+                    }
+                    Ok(None) => {
+                        warn!(
+                            "Failed to parse the version of xen host '{}'",
+                            self.config.name
+                        );
+                        None
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to query the version of xen host '{}': {}",
+                            self.config.name, e
+                        );
+                        None
+                    }
+                }
+            })
+            .await
+            .clone()
+    }
+
+    async fn query_version(&self) -> Result<Option<HostVersion>, XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            let (brand, version) = rpc.get_software_version().await?;
+            return Ok(HostVersion::new(&brand, &version));
+        }
+
+        let pool = self
+            .list_uuids("pool", &[])
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(XApiCliError::XApiParseError(XApiParseError::EmptyOutput))?;
+        let master = self.get_param("pool", &pool, "master").await?;
+        let software_version = self.get_param("host", &master, "software-version").await?;
+
+        Ok(HostVersion::from_cli_output(&software_version))
+    }
+
+    /// fails if the host's version doesn't support the feature. hosts of unknown version are
+    /// assumed to support it, `xe` reports the failure then
+    pub async fn require(&self, feature: HostFeature) -> Result<(), XApiCliError> {
+        match self.get_version().await {
+            Some(version) if !version.supports(feature) => {
+                Err(XApiCliError::Unsupported(feature, version))
+            }
+            _ => Ok(()),
+        }
     }
 
     pub fn get_config(&self) -> &XenConfig {
         &self.config
     }
 
+    /// ends the xmlrpc session, hosts only keep a limited number of sessions per user
+    pub async fn logout(&self) {
+        if let Some(rpc) = &self.rpc {
+            if let Err(e) = rpc.logout().await {
+                warn!(
+                    "Failed to log out of XAPI of host '{}': {}",
+                    self.config.name, e
+                );
+            }
+        }
+    }
+
     pub fn get_base_command(&self) -> AsyncCommand {
         let mut command = AsyncCommand::new("xe");
 
-        if self.config.server == "localhost" || self.config.server == "127.0.0.1" {
+        let server = self.master.get();
+        if server == "localhost" || server == "127.0.0.1" {
             command.arg("-s").arg("127.0.0.1");
         } else {
-            command
-                .arg("-s")
-                .arg(&self.config.server)
-                .arg("-u")
-                .arg(&self.config.username)
-                .arg("-pw")
-                .arg(&self.config.password);
+            command.arg("-s").arg(&server);
+            match self.config.xe_password {
+                XePasswordMode::Argument => {
+                    command
+                        .arg("-u")
+                        .arg(&self.config.username)
+                        .arg("-pw")
+                        .arg(self.config.get_password());
+                }
+                XePasswordMode::File => {
+                    // without credentials xe fails with an authentication error
+                    if let Some(credentials) = self.get_credentials_file() {
+                        command.arg("-pwf").arg(credentials.path());
+                    }
+                }
+            }
         }
 
         command
     }
 
+    /// the file is rewritten once the password changes, e.g. after it was rotated in the
+    /// password file
+    fn get_credentials_file(&self) -> Option<&CredentialsFile> {
+        let password = self.config.get_password();
+        let credentials = self
+            .credentials
+            .get_or_init(
+                || match CredentialsFile::create(&self.config.username, &password) {
+                    Ok(credentials) => Some(credentials),
+                    Err(e) => {
+                        error!(
+                            "Failed to write xe credentials file for xen host '{}': {}",
+                            self.config.name, e
+                        );
+                        None
+                    }
+                },
+            )
+            .as_ref()?;
+        // the file keeps the old password, the host rejects it with a meaningful error
+        if let Err(e) = credentials.update(&self.config.username, &password) {
+            error!(
+                "Failed to rewrite xe credentials file for xen host '{}': {}",
+                self.config.name, e
+            );
+        }
+        Some(credentials)
+    }
+
+    /// talks to the given address from now on, for both `xe` and the xmlrpc api
+    pub async fn switch_master(&self, address: &str) {
+        self.master.set(address);
+        if let Some(rpc) = &self.rpc {
+            rpc.switch_master(address).await;
+        }
+    }
+
+    /// checks the connectivity of the host and follows a pool failover. a `HOST_IS_SLAVE` error
+    /// leads to the new master, if the master is unreachable the configured server and the
+    /// fallback servers are tried in turn
+    pub async fn connect(&self) -> Result<(), XApiCliError> {
+        let error = match self.ping_master().await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let current = self.master.get();
+        let candidates = std::iter::once(&self.config.server)
+            .chain(&self.config.fallback_servers)
+            .filter(|x| **x != current);
+        for candidate in candidates {
+            debug!(
+                "Trying '{}' as pool master of xen host '{}'",
+                candidate, self.config.name
+            );
+            self.switch_master(candidate).await;
+            match self.ping_master().await {
+                Ok(()) => {
+                    warn!(
+                        "Pool master '{}' of xen host '{}' failed ({}), switched to '{}'",
+                        current,
+                        self.config.name,
+                        error,
+                        self.master.get()
+                    );
+                    return Ok(());
+                }
+                Err(e) => debug!("'{}' failed as well: {}", candidate, e),
+            }
+        }
+
+        self.switch_master(&current).await;
+        Err(error)
+    }
+
+    /// pings the current master, following a `HOST_IS_SLAVE` error once
+    async fn ping_master(&self) -> Result<(), XApiCliError> {
+        match self.ping().await {
+            Err(XApiCliError::CommandFailed(stderr))
+                if XApiErrorKind::from_stderr(&stderr) == XApiErrorKind::HostIsSlave =>
+            {
+                let Some(address) = master::parse_master_address(&stderr) else {
+                    return Err(XApiCliError::CommandFailed(stderr));
+                };
+                warn!(
+                    "'{}' is no longer the pool master of xen host '{}', switching to '{}'",
+                    self.master.get(),
+                    self.config.name,
+                    address
+                );
+                self.switch_master(&address).await;
+                self.ping().await
+            }
+            result => result,
+        }
+    }
+
+    /// cheap query which fails if the host is unreachable or rejects the credentials
+    pub async fn ping(&self) -> Result<(), XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            let timeout = self.config.command_timeout;
+            if timeout == 0 {
+                return Ok(rpc.ping().await?);
+            }
+            return match tokio::time::timeout(std::time::Duration::from_secs(timeout), rpc.ping())
+                .await
+            {
+                Ok(result) => Ok(result?),
+                Err(_) => Err(XApiCliError::Timeout("'host.get_all'".into(), timeout)),
+            };
+        }
+
+        self.list_uuids("host", &[]).await?;
+        Ok(())
+    }
+
     /// filter by tags and return UUIDs
     pub async fn filter_vms_by_tag(
         &self,
         tags: Vec<String>,
         excluded_tags: Vec<String>,
     ) -> Result<Vec<VM>, XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            return rpc.filter_vms_by_tag(tags, excluded_tags).await;
+        }
+
         // get VM UUIDs with the specified tags
         let mut tagged_uuids: Vec<String> = vec![];
 
@@ -60,7 +319,7 @@ impl XApiCliClient {
                 .arg("is-a-snapshot=false")
                 .arg("is-control-domain=false")
                 .arg("--minimal")
-                .output()
+                .output_timeout(self.config.command_timeout)
                 .await?;
 
             if tagged_uuid_output.status.success() {
@@ -75,7 +334,7 @@ impl XApiCliClient {
         }
 
         // get VM UUIDs with the excluded tags
-        let mut excluded_uuids: Vec<String> = vec![];
+        let excluded_uuids: Vec<String> = vec![];
 
         for excluded_tag in &excluded_tags {
             let excluded_uuid_output = self
@@ -86,7 +345,7 @@ impl XApiCliClient {
                 .arg("is-a-snapshot=false")
                 .arg("is-control-domain=false")
                 .arg("--minimal")
-                .output()
+                .output_timeout(self.config.command_timeout)
                 .await?;
 
             if excluded_uuid_output.status.success() {
@@ -116,14 +375,164 @@ impl XApiCliClient {
         Ok(vms)
     }
 
+    /// returns all VMs of the pool, without templates, snapshots and control domains
+    pub async fn list_vms(&self) -> Result<Vec<VM>, XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            return rpc.list_vms().await;
+        }
+
+        let uuids = self
+            .list_uuids(
+                "vm",
+                &[
+                    "is-a-template=false",
+                    "is-a-snapshot=false",
+                    "is-control-domain=false",
+                ],
+            )
+            .await?;
+        let mut vms = vec![];
+        for uuid in uuids {
+            vms.push(self.get_vm_by_uuid(&uuid).await?);
+        }
+
+        Ok(vms)
+    }
+
+    /// returns the uuid and name-label of the host's pool
+    pub async fn get_pool(&self) -> Result<(UUID, String), XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            return rpc.get_pool().await;
+        }
+
+        let pool = self
+            .list_uuids("pool", &[])
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(XApiCliError::XApiParseError(XApiParseError::EmptyOutput))?;
+        let name_label = self.get_param("pool", &pool, "name-label").await?;
+
+        Ok((pool, name_label))
+    }
+
+    /// dumps the pool database to the storage, like `xe pool-dump-database`
+    pub async fn pool_dump_database_to_storage(
+        &self,
+        storage_handler: Arc<dyn StorageHandler>,
+        backup_object: crate::storage::BackupObject,
+    ) -> eyre::Result<()> {
+        if let Some(rpc) = &self.rpc {
+            let (stdout, stderr, _) = rpc
+                .open_database_dump()
+                .await
+                .map_err(|e| XApiCliError::ExportFailure(e.kind(), e.to_string()))?;
+            self.handle_export_stream(storage_handler, backup_object, stdout, stderr, None)
+                .await?;
+            return Ok(());
+        }
+
+        // xe only writes the dump to a file, it's small enough to be kept in the temp dir
+        let dump = async_tempfile::TempFile::new().await?;
+        let output = self
+            .get_base_command()
+            .arg("pool-dump-database")
+            .arg(format!("file-name={}", dump.file_path().display()))
+            .output_timeout(self.config.command_timeout)
+            .await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(XApiCliError::ExportFailure(
+                XApiErrorKind::from_stderr(&stderr),
+                stderr.into(),
+            )
+            .into());
+        }
+
+        let stdout = Box::new(tokio::fs::File::open(dump.file_path()).await?);
+        self.handle_export_stream(
+            storage_handler,
+            backup_object,
+            stdout,
+            Box::new(tokio::io::empty()),
+            None,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// returns the UUIDs of the user VDIs carrying one of the tags or stored on one of the SRs,
+    /// without the ones carrying an excluded tag
+    pub async fn filter_vdis(
+        &self,
+        tags: &[String],
+        excluded_tags: &[String],
+        srs: &[UUID],
+    ) -> Result<UUIDs, XApiCliError> {
+        let base_filters = ["is-a-snapshot=false", "type=User", "managed=true"];
+        let mut uuids: UUIDs = vec![];
+
+        let filters = tags
+            .iter()
+            .filter(|x| !x.is_empty())
+            .map(|x| format!("tags:contains={}", x))
+            .chain(srs.iter().map(|x| format!("sr-uuid={}", x)));
+        for filter in filters {
+            for uuid in self
+                .list_uuids("vdi", &[&[filter.as_str()], &base_filters[..]].concat())
+                .await?
+            {
+                if !uuids.contains(&uuid) {
+                    uuids.push(uuid);
+                }
+            }
+        }
+
+        for excluded_tag in excluded_tags.iter().filter(|x| !x.is_empty()) {
+            let excluded = self
+                .list_uuids("vdi", &[&format!("tags:contains={}", excluded_tag)])
+                .await?;
+            uuids.retain(|x| !excluded.contains(x));
+        }
+
+        Ok(uuids)
+    }
+
+    /// snapshots a single VDI, returns the UUID of the snapshot
+    pub async fn vdi_snapshot(&self, vdi: &UUID) -> Result<UUID, XApiCliError> {
+        let output = self
+            .get_base_command()
+            .arg("vdi-snapshot")
+            .arg("uuid=".to_owned() + vdi)
+            .output_timeout(self.config.snapshot_timeout)
+            .await?;
+
+        if output.status.success() {
+            Ok(UUID::from_cli_output(&String::from_utf8_lossy(
+                &output.stdout,
+            ))?)
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::SnapshotFailure(
+                XApiErrorKind::from_stderr(&stderr),
+                stderr.into(),
+            ))
+        }
+    }
+
     /// returns a list of the VMs snapshots
     pub async fn get_snapshots(&self, vm: &VM) -> Result<Vec<VM>, XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            return rpc.get_snapshots(vm).await;
+        }
+
         let output = self
             .get_base_command()
             .arg("snapshot-list")
             .arg("snapshot-of=".to_owned() + &vm.uuid)
             .arg("--minimal")
-            .output()
+            .output_timeout(self.config.command_timeout)
             .await?;
 
         if output.status.success() {
@@ -132,17 +541,25 @@ impl XApiCliClient {
             let mut snapshots: Vec<VM> = vec![];
             let uuids = UUIDs::from_cli_output(&stdout)?;
             for uuid in &uuids {
-                let snapshot = self.get_vm_by_uuid(&uuid).await?;
+                let snapshot = self.get_vm_by_uuid(uuid).await?;
                 snapshots.push(snapshot);
             }
             Ok(snapshots)
         } else {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(XApiCliError::CommandFailed(stderr.into()));
+            Err(XApiCliError::CommandFailed(stderr.into()))
         }
     }
 
     pub async fn snapshot(&self, vm: &VM, snapshot_type: SnapshotType) -> Result<VM, XApiCliError> {
+        if let SnapshotType::_Quiesced = snapshot_type {
+            self.require(HostFeature::QuiescedSnapshot).await?;
+        }
+
+        if let Some(rpc) = &self.rpc {
+            return rpc.snapshot(vm, snapshot_type).await;
+        }
+
         let mut command = self.get_base_command();
 
         match snapshot_type {
@@ -152,15 +569,21 @@ impl XApiCliClient {
                     .arg("vm=".to_owned() + &vm.uuid)
                     .arg("new-name-label=xenbakd-snapshot");
             }
-            SnapshotType::_Memory => {
+            SnapshotType::Memory => {
                 command
                     .arg("vm-checkpoint")
                     .arg("vm=".to_owned() + &vm.uuid)
                     .arg("new-name-label=xenbakd-snapshot");
             }
+            SnapshotType::_Quiesced => {
+                command
+                    .arg("vm-snapshot-with-quiesce")
+                    .arg("vm=".to_owned() + &vm.uuid)
+                    .arg("new-name-label=xenbakd-snapshot");
+            }
         }
 
-        let output = command.output().await?;
+        let output = command.output_timeout(self.config.snapshot_timeout).await?;
 
         if output.status.success() {
             let stdout = String::from_utf8_lossy(&output.stdout);
@@ -168,42 +591,68 @@ impl XApiCliClient {
             self.get_vm_by_uuid(&uuid).await
         } else {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(XApiCliError::SnapshotFailure(stderr.into()));
+            Err(XApiCliError::SnapshotFailure(
+                XApiErrorKind::from_stderr(&stderr),
+                stderr.into(),
+            ))
         }
     }
 
     pub async fn set_snapshot_name(&self, snapshot: &VM, name: &str) -> Result<VM, XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            return rpc.set_name_label(snapshot, name).await;
+        }
+
         let output = self
             .get_base_command()
             .arg("snapshot-param-set")
             .arg("uuid=".to_owned() + &snapshot.uuid)
             .arg("name-label=".to_owned() + name)
-            .output()
+            .output_timeout(self.config.command_timeout)
             .await?;
 
         if output.status.success() {
             Ok(self.get_vm_by_uuid(&snapshot.uuid).await?)
         } else {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(XApiCliError::CommandFailed(stderr.into()));
+            Err(XApiCliError::CommandFailed(stderr.into()))
         }
     }
 
+    /// deletes the snapshot with its disks, and the suspend image of a memory snapshot
     pub async fn delete_snapshot_by_uuid(&self, snapshot: &UUID) -> Result<(), XApiCliError> {
+        // memory snapshots keep the memory image in a VDI without VBD
+        let suspend_vdi = self
+            .get_param("snapshot", snapshot, "suspend-VDI-uuid")
+            .await
+            .ok()
+            .filter(|x| !x.is_empty() && !x.starts_with('<'));
+
         let output = self
             .get_base_command()
             .arg("snapshot-uninstall")
-            .arg("uuid=".to_owned() + &snapshot)
+            .arg("uuid=".to_owned() + snapshot)
             .arg("force=true")
-            .output()
+            .output_timeout(self.config.snapshot_timeout)
             .await?;
 
-        if output.status.success() {
-            Ok(())
-        } else {
+        if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
             return Err(XApiCliError::CommandFailed(stderr.into()));
         }
+
+        if let Some(suspend_vdi) = suspend_vdi {
+            if !self
+                .list_uuids("vdi", &[&format!("uuid={}", suspend_vdi)])
+                .await?
+                .is_empty()
+            {
+                debug!("Destroying suspend image {} of snapshot", suspend_vdi);
+                self.destroy("vdi", &suspend_vdi).await?;
+            }
+        }
+
+        Ok(())
     }
 
     // xe vm-export uuid=<VM_UUID> filename= | ssh <other_server> xe vm-import filename=/dev/stdin
@@ -212,28 +661,184 @@ impl XApiCliClient {
         vm: &VM,
         storage_handler: Arc<dyn StorageHandler>,
         backup_object: crate::storage::BackupObject,
-    ) -> eyre::Result<()> {
-        let mut command = self.get_base_command();
+        estimated_size: Option<u64>,
+        compression: Option<ExportCompression>,
+    ) -> eyre::Result<ExportSummary> {
+        if compression == Some(ExportCompression::Zstd) {
+            self.require(HostFeature::ZstdExport).await?;
+        }
+        // name-label of the task xe creates for the export
+        let task_name = format!("Export of VM: {}", vm.uuid);
+        // the export holds the used blocks of the disks
+        let estimated_size = match estimated_size {
+            Some(estimated_size) => Some(estimated_size),
+            None => self.get_vm_disk_usage(vm).await.ok(),
+        };
 
-        command
-            .arg("vm-export")
-            .arg("vm=".to_owned() + &vm.uuid)
-            .arg("filename=");
+        if let Some(rpc) = &self.rpc {
+            let task = rpc
+                .create_task(&task_name)
+                .await
+                .map_err(|e| XApiCliError::ExportFailure(e.kind(), e.to_string()))?;
+            let result = task::track(self, &task_name, async {
+                let (stdout, stderr, size) = rpc
+                    .open_export(vm, &task, compression)
+                    .await
+                    .map_err(|e| XApiCliError::ExportFailure(e.kind(), e.to_string()))?;
+                if let Some(size) = size {
+                    debug!(
+                        "Host announced an export size of {:.1} MiB",
+                        size as f64 / 1024.0 / 1024.0
+                    );
+                }
 
-        let mut child = command
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .kill_on_drop(true)
-            .spawn()?;
+                self.handle_export_stream(
+                    storage_handler,
+                    backup_object,
+                    stdout,
+                    stderr,
+                    size.or(estimated_size),
+                )
+                .await
+            })
+            .await;
+            if let Err(e) = rpc.destroy_task(&task).await {
+                debug!("Failed to destroy task {}: {}", task, e);
+            }
+
+            return result;
+        }
 
-        let stdout = child.stdout.take().unwrap();
-        let stderr = child.stderr.take().unwrap();
+        task::track(self, &task_name, async {
+            let mut command = self.get_base_command();
 
-        storage_handler
-            .handle_stdio_stream(backup_object, stdout, stderr)
+            command
+                .arg("vm-export")
+                .arg("vm=".to_owned() + &vm.uuid)
+                .arg("filename=");
+            if let Some(compression) = compression {
+                command.arg("compress=".to_owned() + compression.to_cli_arg());
+            }
+
+            let mut child = command
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+
+            let stdout = Box::new(child.stdout.take().unwrap());
+            let stderr = Box::new(child.stderr.take().unwrap());
+            let summary = self
+                .handle_export_stream(
+                    storage_handler,
+                    backup_object,
+                    stdout,
+                    stderr,
+                    estimated_size,
+                )
+                .await?;
+
+            let status = child.wait().await?;
+            if !status.success() {
+                return Err(XApiCliError::ExportFailure(
+                    XApiErrorKind::Unknown,
+                    format!("'xe vm-export' exited with {}", status),
+                )
+                .into());
+            }
+
+            Ok(summary)
+        })
+        .await
+    }
+
+    /// returns the newest task with the name-label
+    pub async fn find_task(&self, name_label: &str) -> Result<Option<XApiTask>, XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            return Ok(rpc.find_task(name_label).await?);
+        }
+
+        let Some(task) = self
+            .list_uuids("task", &[&format!("name-label={}", name_label)])
+            .await?
+            .pop()
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(XApiTask {
+            progress: self
+                .get_param("task", &task, "progress")
+                .await?
+                .parse()
+                .unwrap_or_default(),
+            status: self.get_param("task", &task, "status").await?,
+            name_label: name_label.to_string(),
+            reference: task,
+        }))
+    }
+
+    pub async fn cancel_task(&self, task: &XApiTask) -> Result<(), XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            return Ok(rpc.cancel_task(task).await?);
+        }
+
+        let output = self
+            .get_base_command()
+            .arg("task-cancel")
+            .arg("uuid=".to_owned() + &task.reference)
+            .output_timeout(self.config.command_timeout)
             .await?;
 
-        Ok(())
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
+    }
+
+    /// hands the export over to the storage, classifying failures by the error output. the
+    /// progress is logged against the estimated size of the export
+    pub async fn handle_export_stream(
+        &self,
+        storage_handler: Arc<dyn StorageHandler>,
+        backup_object: crate::storage::BackupObject,
+        stdout: StdioStream,
+        stderr: StdioStream,
+        estimated_size: Option<u64>,
+    ) -> eyre::Result<ExportSummary> {
+        let inactivity_timeout = self.config.export_inactivity_timeout;
+        let (stdout, timed_out) = match inactivity_timeout {
+            0 => (stdout, Default::default()),
+            _ => InactivityTimeout::wrap(stdout, inactivity_timeout),
+        };
+        let (progress, stdout) = ExportProgress::start(stdout, estimated_size);
+        if let Err(e) = storage_handler
+            .handle_stdio_stream(backup_object, stdout, stderr)
+            .await
+        {
+            if timed_out.load(Ordering::Relaxed) {
+                return Err(
+                    XApiCliError::Timeout("Idle export".to_string(), inactivity_timeout).into(),
+                );
+            }
+            let message = format!("{:#}", e);
+            return Err(
+                XApiCliError::ExportFailure(XApiErrorKind::from_stderr(&message), message).into(),
+            );
+        }
+
+        let summary = progress.finish();
+        info!(
+            "Exported {:.1} MiB to storage '{}' in {:.1} seconds ({:.1} MiB/s)",
+            summary.bytes as f64 / 1024.0 / 1024.0,
+            storage_handler.get_name(),
+            summary.duration,
+            summary.throughput() / 1024.0 / 1024.0
+        );
+
+        Ok(summary)
     }
 
     pub async fn _vm_export_to_file(
@@ -250,6 +855,9 @@ impl XApiCliClient {
             .arg("vm=".to_owned() + &vm.uuid);
 
         if let Some(compress) = compress {
+            if compress == LocalCompressionType::Zstd {
+                self.require(HostFeature::ZstdExport).await?;
+            }
             command.arg("compress=".to_owned() + &compress.to_cli_arg());
         }
 
@@ -259,54 +867,666 @@ impl XApiCliClient {
             Ok(())
         } else {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(XApiCliError::CommandFailed(stderr.into()));
+            Err(XApiCliError::CommandFailed(stderr.into()))
         }
     }
 
     pub async fn _dynamic_command(&self, args: Vec<&str>) -> Result<String, XApiCliError> {
-        let output = self.get_base_command().args(args).output().await?;
+        let output = self
+            .get_base_command()
+            .args(args)
+            .output_timeout(self.config.command_timeout)
+            .await?;
 
         if output.status.success() {
             let stdout = String::from_utf8_lossy(&output.stdout);
             Ok(stdout.into())
         } else {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(XApiCliError::CommandFailed(stderr.into()));
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
+    }
+
+    /// adds the tag to the VM, adding an existing tag changes nothing
+    pub async fn add_vm_tag(&self, vm: &VM, tag: &str) -> Result<(), XApiCliError> {
+        let output = self
+            .get_base_command()
+            .arg("vm-param-add")
+            .arg("uuid=".to_owned() + &vm.uuid)
+            .arg("param-name=tags")
+            .arg(format!("param-key={}", tag))
+            .output_timeout(self.config.command_timeout)
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
         }
     }
 
     pub async fn set_snapshot_param_not_template(&self, snapshot: &VM) -> Result<VM, XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            return rpc.set_is_a_template(snapshot, false).await;
+        }
+
         let output = self
             .get_base_command()
             .arg("snapshot-param-set")
             .arg("is-a-template=false")
             .arg("uuid=".to_owned() + &snapshot.uuid)
-            .output()
+            .output_timeout(self.config.command_timeout)
             .await?;
 
         if output.status.success() {
             Ok(self.get_vm_by_uuid(&snapshot.uuid).await?)
         } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
+    }
+
+    /// returns the VBDs of the VM's disks and their VDIs, empty drives are left out
+    async fn list_vm_disk_vbds(&self, vm_uuid: &str) -> Result<(UUIDs, UUIDs), XApiCliError> {
+        let output = self
+            .get_base_command()
+            .arg("vbd-list")
+            .arg("vm-uuid=".to_owned() + vm_uuid)
+            .arg("type=Disk")
+            .arg("params=uuid,vdi-uuid")
+            .output_timeout(self.config.command_timeout)
+            .await?;
+
+        if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
             return Err(XApiCliError::CommandFailed(stderr.into()));
         }
+
+        // one record per VBD, `uuid` comes before `vdi-uuid`
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut vbds = vec![];
+        let mut vdis = vec![];
+        let mut vbd = None;
+        for line in stdout.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            match key.trim().split(' ').next().unwrap_or_default() {
+                "uuid" => vbd = Some(value.trim().to_string()),
+                "vdi-uuid" => {
+                    if let (Some(vbd), Ok(vdi)) = (vbd.take(), UUID::from_cli_output(value)) {
+                        vbds.push(vbd);
+                        vdis.push(vdi);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok((vbds, vdis))
+    }
+
+    /// returns the UUIDs of the VDIs attached to the VM as disks, the ones queried along with
+    /// the VM if there are any
+    pub async fn get_vm_disk_vdis(&self, vm: &VM) -> Result<UUIDs, XApiCliError> {
+        if !vm.vdis.is_empty() {
+            return Ok(vm.vdis.clone());
+        }
+
+        let output = self
+            .get_base_command()
+            .arg("vbd-list")
+            .arg("vm-uuid=".to_owned() + &vm.uuid)
+            .arg("type=Disk")
+            .arg("params=vdi-uuid")
+            .arg("--minimal")
+            .output_timeout(self.config.command_timeout)
+            .await?;
+
+        if output.status.success() {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            if stdout.trim().is_empty() {
+                return Ok(vec![]);
+            }
+            Ok(UUIDs::from_cli_output(&stdout)?)
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
+    }
+
+    /// returns the userdevice and VDI of every disk attached to the VM
+    pub async fn get_vm_disks(&self, vm: &VM) -> Result<Vec<(String, UUID)>, XApiCliError> {
+        let mut disks = vec![];
+        for vbd in self
+            .list_uuids("vbd", &[&format!("vm-uuid={}", vm.uuid), "type=Disk"])
+            .await?
+        {
+            let vdi = self.get_param("vbd", &vbd, "vdi-uuid").await?;
+            // empty drives have no VDI
+            if vdi.is_empty() || vdi == "<not in database>" {
+                continue;
+            }
+            disks.push((self.get_param("vbd", &vbd, "userdevice").await?, vdi));
+        }
+        disks.sort();
+
+        Ok(disks)
+    }
+
+    /// returns the VDIs of the VM which wait for a coalesce: their VHD parent has no other child
+    /// left, which is the case after a snapshot was deleted until the garbage collector of the SR
+    /// merged them. SRs without VHD chains never report any
+    pub async fn get_vm_coalescing_vdis(&self, vm: &VM) -> Result<UUIDs, XApiCliError> {
+        let mut coalescing = vec![];
+
+        for vdi in self.get_vm_disk_vdis(vm).await? {
+            // e.g. `vhd-parent: <uuid>; vhd-blocks: ...`
+            let sm_config = self.get_param("vdi", &vdi, "sm-config").await?;
+            let Some(parent) = sm_config
+                .split(';')
+                .filter_map(|x| x.split_once(':'))
+                .find(|(key, _)| key.trim() == "vhd-parent")
+                .map(|(_, value)| value.trim().to_string())
+            else {
+                continue;
+            };
+
+            let children = self
+                .list_uuids("vdi", &[&format!("sm-config:vhd-parent={}", parent)])
+                .await?;
+            if children.len() <= 1 {
+                coalescing.push(vdi);
+            }
+        }
+
+        Ok(coalescing)
+    }
+
+    /// returns the space the VM's disks physically use on their SRs, an upper bound of the export size
+    pub async fn get_vm_disk_usage(&self, vm: &VM) -> Result<u64, XApiCliError> {
+        Ok(self.estimate_vm_size(vm).await?.physical_utilisation)
+    }
+
+    /// sums up the virtual size and physical utilisation of the VM's disks
+    pub async fn estimate_vm_size(&self, vm: &VM) -> Result<VmSizeEstimate, XApiCliError> {
+        let mut estimate = VmSizeEstimate::default();
+
+        for vdi in self.get_vm_disk_vdis(vm).await? {
+            estimate.virtual_size += self.get_vdi_size_param(&vdi, "virtual-size").await?;
+            estimate.physical_utilisation += self
+                .get_vdi_size_param(&vdi, "physical-utilisation")
+                .await?;
+        }
+
+        Ok(estimate)
+    }
+
+    async fn get_vdi_size_param(&self, vdi: &UUID, param: &str) -> Result<u64, XApiCliError> {
+        let output = self
+            .get_base_command()
+            .arg("vdi-param-get")
+            .arg("uuid=".to_owned() + vdi)
+            .arg("param-name=".to_owned() + param)
+            .output_timeout(self.config.command_timeout)
+            .await?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(XApiCliError::CommandFailed(stderr.into()));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(stdout.trim().parse::<u64>().map_err(|_| {
+            XApiParseError::GenericParseError(format!(
+                "Invalid {} of VDI {}: {}",
+                param, vdi, stdout
+            ))
+        })?)
+    }
+
+    /// returns the UUIDs of the SRs the VM's disks are stored on
+    pub async fn get_vm_disk_srs(&self, vm: &VM) -> Result<UUIDs, XApiCliError> {
+        let mut srs = UUIDs::new();
+
+        for vdi in self.get_vm_disk_vdis(vm).await? {
+            let output = self
+                .get_base_command()
+                .arg("vdi-param-get")
+                .arg("uuid=".to_owned() + &vdi)
+                .arg("param-name=sr-uuid")
+                .output_timeout(self.config.command_timeout)
+                .await?;
+
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(XApiCliError::CommandFailed(stderr.into()));
+            }
+
+            let sr = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if !srs.contains(&sr) {
+                srs.push(sr);
+            }
+        }
+
+        Ok(srs)
+    }
+
+    /// returns the space physically used on the SR
+    pub async fn get_sr_physical_utilisation(&self, sr: &UUID) -> Result<u64, XApiCliError> {
+        let output = self
+            .get_base_command()
+            .arg("sr-param-get")
+            .arg("uuid=".to_owned() + sr)
+            .arg("param-name=physical-utilisation")
+            .output_timeout(self.config.command_timeout)
+            .await?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(XApiCliError::CommandFailed(stderr.into()));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout.trim().parse::<u64>().map_err(|_| {
+            XApiParseError::GenericParseError(format!(
+                "Invalid physical-utilisation of SR {}: {}",
+                sr, stdout
+            ))
+            .into()
+        })
+    }
+
+    /// returns the operations currently running on the VM (e.g. `pool_migrate`, `snapshot`)
+    pub async fn get_vm_current_operations(&self, vm: &VM) -> Result<Vec<String>, XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            return rpc.get_vm_current_operations(vm).await;
+        }
+
+        let output = self
+            .get_base_command()
+            .arg("vm-param-get")
+            .arg("uuid=".to_owned() + &vm.uuid)
+            .arg("param-name=current-operations")
+            .output_timeout(self.config.command_timeout)
+            .await?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(XApiCliError::CommandFailed(stderr.into()));
+        }
+
+        // map of task references to operations: `OpaqueRef:...: pool_migrate; OpaqueRef:...: snapshot`
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(stdout
+            .trim()
+            .split(';')
+            .filter_map(|x| x.rsplit(':').next())
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect())
+    }
+
+    pub async fn get_vm_power_state(&self, vm: &VM) -> Result<PowerState, XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            return rpc.get_vm_power_state(vm).await;
+        }
+
+        Ok(self
+            .get_param("vm", &vm.uuid, "power-state")
+            .await?
+            .parse()?)
+    }
+
+    /// shuts down, suspends, starts or resumes the VM, aborted after the timeout in seconds
+    pub async fn vm_power_operation(
+        &self,
+        vm: &VM,
+        operation: PowerOperation,
+        timeout: u64,
+    ) -> Result<(), XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            if timeout == 0 {
+                return rpc.vm_power_operation(vm, operation).await;
+            }
+            return match tokio::time::timeout(
+                std::time::Duration::from_secs(timeout),
+                rpc.vm_power_operation(vm, operation),
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(_) => Err(XApiCliError::Timeout(
+                    format!("VM {} of '{}'", operation, vm.name_label),
+                    timeout,
+                )),
+            };
+        }
+
+        let mut command = self.get_base_command();
+        command
+            .arg(operation.xe_command())
+            .arg("uuid=".to_owned() + &vm.uuid);
+        if matches!(operation, PowerOperation::HardShutdown) {
+            command.arg("force=true");
+        }
+        let output = command.output_timeout(timeout).await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
     }
 
     pub async fn get_vm_by_uuid(&self, vm_uuid: &str) -> Result<VM, XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            return rpc.get_vm_by_uuid(vm_uuid).await;
+        }
+
         let output = self
             .get_base_command()
             .arg("vm-param-list")
             .arg("uuid=".to_owned() + vm_uuid)
-            .output()
+            .output_timeout(self.config.command_timeout)
             .await?;
 
         if output.status.success() {
             let stdout = String::from_utf8_lossy(&output.stdout);
-            let vm = VM::from_cli_output(&stdout)?;
+            let mut vm = VM::from_cli_output(&stdout)?;
+            (vm.vbds, vm.vdis) = self.list_vm_disk_vbds(vm_uuid).await?;
             Ok(vm)
         } else {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(XApiCliError::CommandFailed(stderr.into()));
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
+    }
+
+    /// returns a single parameter of an object, e.g. `vbd-param-get uuid=... param-name=userdevice`
+    pub async fn get_param(
+        &self,
+        class: &str,
+        uuid: &str,
+        param: &str,
+    ) -> Result<String, XApiCliError> {
+        // saves the login of a separate `xe` for the most common queries
+        if let Some(rpc) = &self.rpc {
+            if let Some(value) = rpc.get_param(class, uuid, param).await? {
+                return Ok(value);
+            }
+        }
+
+        let output = self
+            .get_base_command()
+            .arg(format!("{}-param-get", class))
+            .arg("uuid=".to_owned() + uuid)
+            .arg("param-name=".to_owned() + param)
+            .output_timeout(self.config.command_timeout)
+            .await?;
+
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
+    }
+
+    /// sets a single parameter of an object, e.g. `snapshot-param-set uuid=... other-config:key=value`
+    pub async fn set_param(
+        &self,
+        class: &str,
+        uuid: &str,
+        param: &str,
+        value: &str,
+    ) -> Result<(), XApiCliError> {
+        if let Some(rpc) = &self.rpc {
+            if rpc.set_param(class, uuid, param, value).await? {
+                return Ok(());
+            }
+        }
+
+        let output = self
+            .get_base_command()
+            .arg(format!("{}-param-set", class))
+            .arg("uuid=".to_owned() + uuid)
+            .arg(format!("{}={}", param, value))
+            .output_timeout(self.config.command_timeout)
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
+    }
+
+    /// lists the UUIDs of the objects of a class matching the given `key=value` filters
+    pub async fn list_uuids(&self, class: &str, filters: &[&str]) -> Result<UUIDs, XApiCliError> {
+        let output = self
+            .get_base_command()
+            .arg(format!("{}-list", class))
+            .args(filters)
+            .arg("--minimal")
+            .output_timeout(self.config.command_timeout)
+            .await?;
+
+        if output.status.success() {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            if stdout.trim().is_empty() {
+                return Ok(vec![]);
+            }
+            Ok(UUIDs::from_cli_output(&stdout)?)
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
+    }
+
+    /// resolves an object given by UUID or name-label to its UUID
+    pub async fn resolve_uuid(&self, class: &str, uuid_or_name: &str) -> eyre::Result<UUID> {
+        if !self
+            .list_uuids(class, &[&format!("uuid={}", uuid_or_name)])
+            .await
+            .unwrap_or_default()
+            .is_empty()
+        {
+            return Ok(uuid_or_name.to_string());
+        }
+
+        match self
+            .list_uuids(class, &[&format!("name-label={}", uuid_or_name)])
+            .await?
+            .as_slice()
+        {
+            [uuid] => Ok(uuid.clone()),
+            [] => Err(eyre::eyre!("No {} named '{}' found", class, uuid_or_name)),
+            _ => Err(eyre::eyre!(
+                "Multiple {}s named '{}' found, use the uuid instead",
+                class,
+                uuid_or_name
+            )),
+        }
+    }
+
+    /// imports an XVA from the stream, returns the UUID of the imported VM.
+    /// `preserve` keeps the MAC addresses of the VIFs
+    pub async fn vm_import(
+        &self,
+        mut stream: crate::storage::StdioStream,
+        sr: Option<&UUID>,
+        preserve: bool,
+    ) -> eyre::Result<UUID> {
+        let mut command = self.get_base_command();
+        command.arg("vm-import").arg("filename=/dev/stdin");
+        if let Some(sr) = sr {
+            command.arg("sr-uuid=".to_owned() + sr);
+        }
+        if preserve {
+            command.arg("preserve=true");
+        }
+
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        let copied = tokio::io::copy(&mut stream, &mut stdin).await;
+        drop(stdin);
+        let output = child.wait_with_output().await?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(XApiCliError::CommandFailed(stderr.into()).into());
+        }
+        copied?;
+
+        // a vApp export contains several VMs, the first one is the one which was backed up
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        UUIDs::from_cli_output(&stdout)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| eyre::eyre!("'xe vm-import' returned no VM"))
+    }
+
+    /// starts `xe vm-export` of a VM as XVA to stdout, e.g. to pipe it into the import of
+    /// another pool
+    pub fn open_vm_export(
+        &self,
+        vm: &VM,
+        compression: Option<ExportCompression>,
+    ) -> Result<Child, XApiCliError> {
+        let mut command = self.get_base_command();
+        command
+            .arg("vm-export")
+            .arg("vm=".to_owned() + &vm.uuid)
+            .arg("filename=");
+        if let Some(compression) = compression {
+            command.arg("compress=".to_owned() + compression.to_cli_arg());
+        }
+
+        Ok(command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?)
+    }
+
+    /// destroys a halted VM including its disks. the disks may take a while to delete, so it
+    /// isn't limited by the command timeout
+    pub async fn vm_uninstall(&self, vm: &UUID) -> Result<(), XApiCliError> {
+        let output = self
+            .get_base_command()
+            .arg("vm-uninstall")
+            .arg("uuid=".to_owned() + vm)
+            .arg("force=true")
+            .output()
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
+    }
+
+    /// starts `xe vdi-export` of a VDI as VHD to stdout. with a base, only the blocks which
+    /// differ from the base VDI are exported
+    pub fn open_vdi_export(&self, vdi: &UUID, base: Option<&UUID>) -> Result<Child, XApiCliError> {
+        let mut command = self.get_base_command();
+        command
+            .arg("vdi-export")
+            .arg("uuid=".to_owned() + vdi)
+            .arg("format=vhd")
+            .arg("filename=");
+        if let Some(base) = base {
+            command.arg("base=".to_owned() + base);
+        }
+
+        Ok(command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?)
+    }
+
+    /// starts `xe vdi-import` of a VHD from stdin into an existing VDI. blocks missing in the
+    /// VHD are left untouched, so differential VHDs can be applied on top of a full one
+    pub fn open_vdi_import(&self, vdi: &UUID) -> Result<Child, XApiCliError> {
+        Ok(self
+            .get_base_command()
+            .arg("vdi-import")
+            .arg("uuid=".to_owned() + vdi)
+            .arg("format=vhd")
+            .arg("filename=/dev/stdin")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?)
+    }
+
+    /// copies a VDI to another SR, returns the UUID of the copy. the copy takes as long as the
+    /// disk is large, so it isn't limited by the command timeout
+    pub async fn vdi_copy(&self, vdi: &UUID, sr: &UUID) -> Result<UUID, XApiCliError> {
+        let output = self
+            .get_base_command()
+            .arg("vdi-copy")
+            .arg("uuid=".to_owned() + vdi)
+            .arg("sr-uuid=".to_owned() + sr)
+            .output()
+            .await?;
+
+        if output.status.success() {
+            Ok(UUID::from_cli_output(&String::from_utf8_lossy(
+                &output.stdout,
+            ))?)
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
+    }
+
+    /// creates an object, e.g. `vbd-create vm-uuid=... vdi-uuid=...`, returns its UUID
+    pub async fn create(&self, class: &str, params: &[String]) -> Result<UUID, XApiCliError> {
+        let output = self
+            .get_base_command()
+            .arg(format!("{}-create", class))
+            .args(params)
+            .output_timeout(self.config.command_timeout)
+            .await?;
+
+        if output.status.success() {
+            Ok(UUID::from_cli_output(&String::from_utf8_lossy(
+                &output.stdout,
+            ))?)
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
+        }
+    }
+
+    /// destroys an object, e.g. `vif-destroy uuid=...`
+    pub async fn destroy(&self, class: &str, uuid: &UUID) -> Result<(), XApiCliError> {
+        let output = self
+            .get_base_command()
+            .arg(format!("{}-destroy", class))
+            .arg("uuid=".to_owned() + uuid)
+            .output_timeout(self.config.command_timeout)
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(XApiCliError::CommandFailed(stderr.into()))
         }
     }
 }
@@ -0,0 +1,80 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use tracing::{debug, warn};
+
+/// file passed to `xe -pwf`, holding the username on the first and the password on the second
+/// line. only readable by xenbakd and removed once the last client using it is dropped
+#[derive(Debug)]
+pub struct CredentialsFile {
+    path: PathBuf,
+    /// password the file currently holds
+    password: Mutex<String>,
+}
+
+/// writes the credentials to a new file only readable by xenbakd
+fn write_new(path: &Path, username: &str, password: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(format!("{}\n{}\n", username, password).as_bytes())
+}
+
+impl CredentialsFile {
+    pub fn create(username: &str, password: &str) -> std::io::Result<CredentialsFile> {
+        let path = std::env::temp_dir().join(format!("xenbakd-xe-{}.pwf", uuid::Uuid::new_v4()));
+        // remove the file again if writing fails
+        let credentials = CredentialsFile {
+            path,
+            password: Mutex::new(password.to_string()),
+        };
+        write_new(&credentials.path, username, password)?;
+        debug!("Wrote xe credentials file '{}'", credentials.path.display());
+        Ok(credentials)
+    }
+
+    /// rewrites the file if the password changed. the new file replaces the old one at once,
+    /// `xe` commands which are reading it get either the old or the new credentials
+    pub fn update(&self, username: &str, password: &str) -> std::io::Result<()> {
+        let mut current = self.password.lock().unwrap();
+        if *current == password {
+            return Ok(());
+        }
+
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(format!(".{}", uuid::Uuid::new_v4()));
+        let temp_path = PathBuf::from(temp_path);
+        if let Err(e) = write_new(&temp_path, username, password)
+            .and_then(|_| std::fs::rename(&temp_path, &self.path))
+        {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        debug!("Rewrote xe credentials file '{}'", self.path.display());
+        *current = password.to_string();
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for CredentialsFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove xe credentials file '{}': {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
@@ -1,16 +1,18 @@
-use crate::xapi::error::{XApiError, XApiParseError};
+use crate::xapi::error::XApiParseError;
 
-use super::{error::XApiCliError, parse_timestamp, UUIDs, UUID, VM};
+use super::{parse_timestamp, UUIDs, UUID, VM};
 use std::str::FromStr;
 
 pub mod client;
+pub mod credentials;
+pub mod timeout;
 
 pub trait FromCliOutput: Sized {
     fn from_cli_output(output: &str) -> Result<Self, XApiParseError>;
 }
 
 impl FromCliOutput for VM {
-    /// create a new VM struct from `xe vm-param-list` stdout
+    /// create a new VM struct from `xe vm-param-list` stdout, without its VBDs and VDIs
     fn from_cli_output(output: &str) -> Result<VM, XApiParseError> {
         let output = output.trim();
         let mut vm = VM::default();
@@ -33,6 +35,20 @@ impl FromCliOutput for VM {
                 "snapshot-time" => {
                     vm.snapshot_time = parse_timestamp(value)?;
                 }
+                "power-state" => vm.power_state = value.parse().ok(),
+                "memory-static-max" => vm.memory = value.parse().unwrap_or_default(),
+                "VCPUs-at-startup" => vm.vcpus = value.parse().unwrap_or_default(),
+                "tags" => {
+                    vm.tags = value
+                        .split(',')
+                        .map(|x| x.trim().to_string())
+                        .filter(|x| !x.is_empty())
+                        .collect()
+                }
+                // `<not in database>` if the VM isn't running
+                "resident-on" => {
+                    vm.resident_on = UUID::from_cli_output(value).ok();
+                }
                 _ => {}
             }
         }
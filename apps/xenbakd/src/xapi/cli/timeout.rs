@@ -0,0 +1,112 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    process::Output,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    process::Command,
+};
+
+use crate::{storage::StdioStream, xapi::error::XApiCliError};
+
+/// runs `xe` commands with a timeout in seconds, 0 waits forever. the process is killed when
+/// the timeout elapses
+pub trait OutputTimeout {
+    fn output_timeout(
+        &mut self,
+        timeout: u64,
+    ) -> impl Future<Output = Result<Output, XApiCliError>> + Send;
+}
+
+impl OutputTimeout for Command {
+    async fn output_timeout(&mut self, timeout: u64) -> Result<Output, XApiCliError> {
+        // the subcommand without the connection flags, which include the password
+        let subcommand = {
+            let mut args = self.as_std().get_args().map(|x| x.to_string_lossy());
+            let mut subcommand = String::new();
+            while let Some(arg) = args.next() {
+                if arg.starts_with('-') {
+                    args.next();
+                } else {
+                    subcommand = arg.to_string();
+                    break;
+                }
+            }
+            subcommand
+        };
+
+        self.kill_on_drop(true);
+        if timeout == 0 {
+            return Ok(self.output().await?);
+        }
+        match tokio::time::timeout(Duration::from_secs(timeout), self.output()).await {
+            Ok(output) => Ok(output?),
+            Err(_) => Err(XApiCliError::Timeout(
+                format!("'xe {}'", subcommand),
+                timeout,
+            )),
+        }
+    }
+}
+
+/// fails the export stream once it sent no data for the timeout, a hung export would block its
+/// job forever otherwise
+pub struct InactivityTimeout {
+    inner: StdioStream,
+    timeout: Duration,
+    deadline: Pin<Box<tokio::time::Sleep>>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl InactivityTimeout {
+    /// wraps the stream, the flag is set once the timeout elapsed
+    pub fn wrap(inner: StdioStream, timeout: u64) -> (StdioStream, Arc<AtomicBool>) {
+        let timeout = Duration::from_secs(timeout);
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let stream = Box::new(InactivityTimeout {
+            inner,
+            timeout,
+            deadline: Box::pin(tokio::time::sleep(timeout)),
+            timed_out: timed_out.clone(),
+        });
+
+        (stream, timed_out)
+    }
+}
+
+impl AsyncRead for InactivityTimeout {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() > filled {
+                    let deadline = tokio::time::Instant::now() + self.timeout;
+                    self.deadline.as_mut().reset(deadline);
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => match self.deadline.as_mut().poll(cx) {
+                Poll::Ready(_) => {
+                    self.timed_out.store(true, Ordering::Relaxed);
+                    Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("export sent no data for {} seconds", self.timeout.as_secs()),
+                    )))
+                }
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::storage::StdioStream;
+
+use super::cli::client::XApiCliClient;
+
+/// first line of a differential archive
+const MAGIC: &[u8] = b"xenbakd-diff-archive 1\n";
+const CHUNK_SIZE: usize = 1024 * 1024;
+/// upper bound for a disk header, it only holds a few uuids and names
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// a disk of a differential archive. its VHD follows the header and only holds the blocks changed
+/// since `base_vdi_uuid` if that's set.
+///
+/// the archive starts with `MAGIC`, followed by every disk as a length-prefixed JSON header and
+/// its VHD in length-prefixed chunks. a zero-length chunk ends the disk, a zero-length header
+/// ends the archive. lengths are u32 big-endian
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffDisk {
+    pub userdevice: String,
+    pub vdi_uuid: String,
+    pub base_vdi_uuid: Option<String>,
+    pub name_label: String,
+    pub virtual_size: u64,
+    pub sr_uuid: String,
+    pub sr_name: String,
+}
+
+/// exports the disks into a differential archive. returns the archive stream and a stream
+/// yielding the error output, like the stdout and stderr of `xe vm-export`
+pub fn write_archive(
+    xapi_client: XApiCliClient,
+    disks: Vec<DiffDisk>,
+) -> (StdioStream, StdioStream) {
+    let (mut data_writer, data_reader) = tokio::io::duplex(CHUNK_SIZE);
+    let (mut error_writer, error_reader) = tokio::io::duplex(64 * 1024);
+
+    tokio::spawn(async move {
+        let result = write_disks(&xapi_client, &disks, &mut data_writer).await;
+        // the storage reads the errors once the archive has ended
+        drop(data_writer);
+        if let Err(e) = result {
+            let _ = error_writer.write_all(format!("{:#}", e).as_bytes()).await;
+        }
+    });
+
+    (Box::new(data_reader), Box::new(error_reader))
+}
+
+async fn write_disks(
+    xapi_client: &XApiCliClient,
+    disks: &[DiffDisk],
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> eyre::Result<()> {
+    writer.write_all(MAGIC).await?;
+
+    for disk in disks {
+        let header = serde_json::to_vec(disk)?;
+        writer.write_u32(header.len() as u32).await?;
+        writer.write_all(&header).await?;
+
+        let mut child = xapi_client.open_vdi_export(&disk.vdi_uuid, disk.base_vdi_uuid.as_ref())?;
+        let mut stdout = child.stdout.take().unwrap();
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let read = stdout.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            writer.write_u32(read as u32).await?;
+            writer.write_all(&buffer[..read]).await?;
+        }
+
+        let output = child.wait_with_output().await?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() || !stderr.trim().is_empty() {
+            // classified by the handler of the export stream
+            return Err(eyre::eyre!(
+                "'xe vdi-export' of disk {} failed ({}): {}",
+                disk.userdevice,
+                output.status,
+                stderr
+            ));
+        }
+        writer.write_u32(0).await?;
+    }
+
+    writer.write_u32(0).await?;
+    writer.shutdown().await?;
+
+    Ok(())
+}
+
+/// checks whether the stream is a differential archive. returns the stream from its start again
+pub async fn is_diff_archive(mut stream: StdioStream) -> eyre::Result<(bool, StdioStream)> {
+    let mut head = vec![0u8; MAGIC.len()];
+    stream.read_exact(&mut head).await?;
+    let is_diff_archive = head == MAGIC;
+
+    let stream: Box<dyn AsyncRead + Unpin + Send> =
+        Box::new(std::io::Cursor::new(head).chain(stream));
+    Ok((is_diff_archive, stream))
+}
+
+/// reads the disks of a differential archive, each disk has to be copied before the next one is read
+pub struct DiffArchiveReader {
+    stream: StdioStream,
+}
+
+impl DiffArchiveReader {
+    pub async fn new(mut stream: StdioStream) -> eyre::Result<DiffArchiveReader> {
+        let mut head = vec![0u8; MAGIC.len()];
+        stream.read_exact(&mut head).await?;
+        if head != MAGIC {
+            return Err(eyre::eyre!("Not a differential archive"));
+        }
+
+        Ok(DiffArchiveReader { stream })
+    }
+
+    /// returns the header of the next disk, `None` at the end of the archive
+    pub async fn next_disk(&mut self) -> eyre::Result<Option<DiffDisk>> {
+        let size = self.stream.read_u32().await? as usize;
+        if size == 0 {
+            return Ok(None);
+        }
+        if size > MAX_HEADER_SIZE {
+            return Err(eyre::eyre!(
+                "Invalid disk header in differential archive ({} bytes)",
+                size
+            ));
+        }
+
+        let mut header = vec![0u8; size];
+        self.stream.read_exact(&mut header).await?;
+        Ok(Some(serde_json::from_slice(&header)?))
+    }
+
+    /// copies the VHD of the current disk to the writer, returns the number of bytes
+    pub async fn copy_disk(&mut self, writer: &mut (impl AsyncWrite + Unpin)) -> eyre::Result<u64> {
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut copied = 0;
+        loop {
+            let size = self.stream.read_u32().await? as usize;
+            if size == 0 {
+                break;
+            }
+            if size > CHUNK_SIZE {
+                return Err(eyre::eyre!(
+                    "Invalid chunk in differential archive ({} bytes)",
+                    size
+                ));
+            }
+            self.stream.read_exact(&mut buffer[..size]).await?;
+            writer.write_all(&buffer[..size]).await?;
+            copied += size as u64;
+        }
+        writer.flush().await?;
+
+        Ok(copied)
+    }
+}
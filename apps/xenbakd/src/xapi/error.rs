@@ -1,5 +1,140 @@
+use serde::Serialize;
 use thiserror::Error;
 
+use super::version::{HostFeature, HostVersion};
+
+/// classification of `xe` failures, derived from the command's stderr output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum XApiErrorKind {
+    /// the storage repository has no space left
+    SrFull,
+    /// the storage backend reported an error
+    SrBackendFailure,
+    /// the VM is in the wrong power state for the operation
+    VmBadPowerState,
+    /// another operation on the VM/VDI is in progress
+    OperationInProgress,
+    /// the operation is not allowed in the current state (e.g. blocked operations)
+    OperationNotAllowed,
+    /// the VDI is in use by another operation or VM
+    VdiInUse,
+    /// invalid credentials
+    AuthenticationFailed,
+    /// the host could not be reached or is offline
+    HostUnreachable,
+    /// the host is a pool slave and the pool master has to be used instead
+    HostIsSlave,
+    /// the referenced object doesn't exist (anymore)
+    HandleInvalid,
+    /// the export/snapshot stream was interrupted
+    StreamInterrupted,
+    Unknown,
+}
+
+impl XApiErrorKind {
+    pub fn from_stderr(stderr: &str) -> XApiErrorKind {
+        let stderr = stderr.to_lowercase();
+        let contains_any = |patterns: &[&str]| patterns.iter().any(|x| stderr.contains(x));
+
+        if contains_any(&["sr_full", "insufficient space", "no space left"]) {
+            XApiErrorKind::SrFull
+        } else if contains_any(&["sr_backend_failure"]) {
+            XApiErrorKind::SrBackendFailure
+        } else if contains_any(&["vm_bad_power_state", "bad power state"]) {
+            XApiErrorKind::VmBadPowerState
+        } else if contains_any(&["other_operation_in_progress", "operation_in_progress"]) {
+            XApiErrorKind::OperationInProgress
+        } else if contains_any(&["operation_not_allowed", "operation_blocked"]) {
+            XApiErrorKind::OperationNotAllowed
+        } else if contains_any(&["vdi_in_use", "vdi_is_in_use"]) {
+            XApiErrorKind::VdiInUse
+        } else if contains_any(&[
+            "session_authentication_failed",
+            "authentication failed",
+            "permission denied",
+        ]) {
+            XApiErrorKind::AuthenticationFailed
+        } else if contains_any(&["host_is_slave"]) {
+            XApiErrorKind::HostIsSlave
+        } else if contains_any(&[
+            "host_offline",
+            "connection refused",
+            "unable to contact server",
+            "no route to host",
+            "connection timed out",
+        ]) {
+            XApiErrorKind::HostUnreachable
+        } else if contains_any(&["handle_invalid", "uuid_invalid"]) {
+            XApiErrorKind::HandleInvalid
+        } else if contains_any(&[
+            "broken pipe",
+            "connection reset",
+            "unexpected eof",
+            "stream interrupted",
+        ]) {
+            XApiErrorKind::StreamInterrupted
+        } else {
+            XApiErrorKind::Unknown
+        }
+    }
+
+    /// classifies the status of a failed request to one of the host's HTTP handlers
+    pub fn from_http_status(status: reqwest::StatusCode) -> XApiErrorKind {
+        match status {
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                XApiErrorKind::AuthenticationFailed
+            }
+            reqwest::StatusCode::NOT_FOUND => XApiErrorKind::HandleInvalid,
+            _ => XApiErrorKind::Unknown,
+        }
+    }
+
+    /// the likely cause of the failure and what to do about it
+    pub fn hint(&self) -> Option<&'static str> {
+        Some(match self {
+            XApiErrorKind::SrFull => "the SR has no space left for the snapshot, free some space or move disks to another SR",
+            XApiErrorKind::SrBackendFailure => "the storage backend of the SR failed, check SMlog on the host",
+            XApiErrorKind::VmBadPowerState => "the VM was started or shut down during the backup",
+            XApiErrorKind::OperationInProgress => "another operation, e.g. a migration or another backup, was running on the VM",
+            XApiErrorKind::OperationNotAllowed => "the operation is blocked on the VM (blocked-operations) or not allowed in its current state",
+            XApiErrorKind::VdiInUse => "another snapshot/export holds the disk",
+            XApiErrorKind::AuthenticationFailed => "the host rejected the credentials, check username and password",
+            XApiErrorKind::HostUnreachable => "the host couldn't be reached, check that it's online",
+            XApiErrorKind::HostIsSlave => "the host is a pool member, configure the pool master instead",
+            XApiErrorKind::HandleInvalid => "the VM or disk was deleted during the backup",
+            XApiErrorKind::StreamInterrupted => "the export stream broke off, e.g. by a network interruption or a stalled storage",
+            XApiErrorKind::Unknown => return None,
+        })
+    }
+
+    /// the hint as suffix of error messages, empty if there is none
+    fn hint_suffix(&self) -> String {
+        self.hint()
+            .map(|x| format!(" (hint: {})", x))
+            .unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for XApiErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            XApiErrorKind::SrFull => "SR full",
+            XApiErrorKind::SrBackendFailure => "SR backend failure",
+            XApiErrorKind::VmBadPowerState => "bad power state",
+            XApiErrorKind::OperationInProgress => "operation in progress",
+            XApiErrorKind::OperationNotAllowed => "operation not allowed",
+            XApiErrorKind::VdiInUse => "VDI in use",
+            XApiErrorKind::AuthenticationFailed => "authentication failed",
+            XApiErrorKind::HostUnreachable => "host unreachable",
+            XApiErrorKind::HostIsSlave => "host is pool member",
+            XApiErrorKind::HandleInvalid => "invalid handle",
+            XApiErrorKind::StreamInterrupted => "stream interrupted",
+            XApiErrorKind::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Error)]
 pub enum XApiParseError {
@@ -13,18 +148,69 @@ pub enum XApiParseError {
 
 #[derive(Debug, Error)]
 pub enum XApiCliError {
-    #[error("Failed to create snapshot: {0}")]
-    SnapshotFailure(String),
+    #[error("Failed to create snapshot [{0}]: {1}{}", .0.hint_suffix())]
+    SnapshotFailure(XApiErrorKind, String),
+    #[error("Failed to export VM [{0}]: {1}{}", .0.hint_suffix())]
+    ExportFailure(XApiErrorKind, String),
     #[error("'xe' command could not be executed: {0}")]
     CommandExecutionError(#[from] tokio::io::Error),
     #[error("'xe' cli-command failed: {0}")]
     CommandFailed(String),
     #[error("Failed to parse cli stdout to struct: {0}")]
     XApiParseError(#[from] XApiParseError),
+    #[error(transparent)]
+    RpcFailed(#[from] XApiRpcError),
+    #[error("{0} is not supported on this host version ({1})")]
+    Unsupported(HostFeature, HostVersion),
+    #[error("{0} timed out after {1} seconds and was aborted")]
+    Timeout(String, u64),
+}
+
+impl XApiCliError {
+    /// returns the classification of snapshot and export failures
+    pub fn kind(&self) -> Option<XApiErrorKind> {
+        match self {
+            XApiCliError::SnapshotFailure(kind, _) | XApiCliError::ExportFailure(kind, _) => {
+                Some(*kind)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum XApiRpcError {
+    #[error("XAPI request failed: {0}")]
+    RequestFailed(#[from] reqwest::Error),
+    #[error("Invalid XML-RPC response: {0}")]
+    InvalidResponse(String),
+    #[error("XAPI call failed [{0}]: {1}")]
+    Failure(XApiErrorKind, String),
 }
 
+impl XApiRpcError {
+    /// the session expired or was logged out, e.g. after a restart of the toolstack
+    pub fn is_session_invalid(&self) -> bool {
+        matches!(self, XApiRpcError::Failure(_, message) if message.starts_with("SESSION_INVALID"))
+    }
+
+    pub fn kind(&self) -> XApiErrorKind {
+        match self {
+            XApiRpcError::RequestFailed(e) => match e.status() {
+                Some(status) => XApiErrorKind::from_http_status(status),
+                None => XApiErrorKind::HostUnreachable,
+            },
+            XApiRpcError::InvalidResponse(_) => XApiErrorKind::Unknown,
+            XApiRpcError::Failure(kind, _) => *kind,
+        }
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Error, Debug)]
 pub enum XApiError {
     #[error("CLI Error: {0}")]
     XApiCliError(#[from] XApiCliError),
+    #[error("RPC Error: {0}")]
+    XApiRpcError(#[from] XApiRpcError),
 }
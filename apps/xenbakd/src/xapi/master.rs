@@ -0,0 +1,41 @@
+use std::sync::{Arc, RwLock};
+
+/// address of the pool master the clients of a host talk to. starts out as the configured
+/// server and moves along after a pool failover, shared by all clones of a client
+#[derive(Debug, Clone)]
+pub struct PoolMaster {
+    address: Arc<RwLock<String>>,
+}
+
+impl PoolMaster {
+    pub fn new(server: &str) -> Self {
+        PoolMaster {
+            address: Arc::new(RwLock::new(server.to_string())),
+        }
+    }
+
+    pub fn get(&self) -> String {
+        self.address.read().unwrap().clone()
+    }
+
+    pub fn set(&self, address: &str) {
+        *self.address.write().unwrap() = address.to_string();
+    }
+}
+
+/// extracts the address of the new master from a `HOST_IS_SLAVE` error. the xmlrpc api puts it
+/// right after the error code, `xe` prints it as `master: <address>`
+pub fn parse_master_address(message: &str) -> Option<String> {
+    let mut words = message.split_whitespace();
+    if words.any(|x| x.eq_ignore_ascii_case("host_is_slave")) {
+        if let Some(address) = words.find(|x| !x.ends_with(':')) {
+            return Some(address.trim_matches(['[', ']', ',']).to_string());
+        }
+    }
+
+    message.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        let value = value.trim();
+        (key.to_lowercase().contains("master") && !value.is_empty()).then(|| value.to_string())
+    })
+}
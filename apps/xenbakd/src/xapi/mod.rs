@@ -1,9 +1,18 @@
 use chrono::Utc;
+use serde::{Deserialize, Serialize};
 
 use self::error::XApiParseError;
 
 pub mod cli;
+pub mod diff_archive;
 pub mod error;
+pub mod master;
+pub mod preflight;
+pub mod rpc;
+pub mod task;
+pub mod version;
+pub mod xmlrpc;
+pub mod xva;
 
 pub fn parse_timestamp(timestamp: &str) -> Result<chrono::DateTime<chrono::Utc>, XApiParseError> {
     let naive = chrono::NaiveDateTime::parse_from_str(timestamp, "%Y%m%dT%H:%M:%S%Z")?;
@@ -11,6 +20,7 @@ pub fn parse_timestamp(timestamp: &str) -> Result<chrono::DateTime<chrono::Utc>,
     Ok(utc)
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub type UUID = String;
 pub type UUIDs = Vec<UUID>;
 
@@ -23,25 +33,150 @@ pub struct VM {
     pub is_default_template: bool,
     pub is_a_snapshot: bool,
     pub snapshot_time: chrono::DateTime<chrono::Utc>,
+    /// power state at the time the VM was queried
+    pub power_state: Option<PowerState>,
+    /// static maximum of the memory in bytes
+    pub memory: u64,
+    /// number of VCPUs the VM boots with
+    pub vcpus: u32,
+    pub tags: Vec<String>,
+    /// host the VM runs on, `None` if it isn't running
+    pub resident_on: Option<UUID>,
+    /// VBDs of the VM's disks, CD drives are left out
+    pub vbds: UUIDs,
+    /// VDIs attached to those VBDs
+    pub vdis: UUIDs,
 }
 
-#[derive(Debug, Clone)]
+/// size of the disks of a VM, queried before its backup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct VmSizeEstimate {
+    /// bytes the guest sees, the size of a raw or fully allocated export
+    pub virtual_size: u64,
+    /// bytes allocated on the SRs, an upper bound of the XVA export
+    pub physical_utilisation: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub enum SnapshotType {
+    #[default]
+    #[serde(rename = "basic")]
     Normal,
-    _Memory,
+    /// `vm-checkpoint`, the snapshot includes the memory of a running VM
+    #[serde(rename = "memory")]
+    Memory,
+    #[serde(skip)]
+    _Quiesced,
+}
+
+/// compression of the XVA applied by the host during `xe vm-export`, instead of by xenbakd
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportCompression {
+    Gzip,
+    /// needs 8.1 or newer
+    Zstd,
+}
+
+impl ExportCompression {
+    /// value of `compress=` and the `use_compression` query of the export handler, older hosts
+    /// only know `true` for gzip
+    pub fn to_cli_arg(self) -> &'static str {
+        match self {
+            ExportCompression::Gzip => "true",
+            ExportCompression::Zstd => "zstd",
+        }
+    }
 }
 
-impl Default for SnapshotType {
-    fn default() -> Self {
-        Self::Normal
+impl std::fmt::Display for ExportCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportCompression::Gzip => write!(f, "gzip"),
+            ExportCompression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// power state of a VM, lowercase as printed by xe, the xmlrpc api capitalizes them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerState {
+    Running,
+    Halted,
+    Paused,
+    Suspended,
+}
+
+impl std::str::FromStr for PowerState {
+    type Err = XApiParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "running" => Ok(PowerState::Running),
+            "halted" => Ok(PowerState::Halted),
+            "paused" => Ok(PowerState::Paused),
+            "suspended" => Ok(PowerState::Suspended),
+            _ => Err(XApiParseError::GenericParseError(format!(
+                "Invalid power state: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// operations changing the power state of a VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerOperation {
+    /// clean shutdown through the guest
+    Shutdown,
+    /// powers the VM off without asking the guest, like pulling the plug
+    HardShutdown,
+    Suspend,
+    Start,
+    Resume,
+}
+
+impl PowerOperation {
+    pub fn xe_command(&self) -> &'static str {
+        match self {
+            PowerOperation::Shutdown | PowerOperation::HardShutdown => "vm-shutdown",
+            PowerOperation::Suspend => "vm-suspend",
+            PowerOperation::Start => "vm-start",
+            PowerOperation::Resume => "vm-resume",
+        }
+    }
+}
+
+impl std::fmt::Display for PowerOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PowerOperation::Shutdown => write!(f, "shutdown"),
+            PowerOperation::HardShutdown => write!(f, "hard shutdown"),
+            PowerOperation::Suspend => write!(f, "suspend"),
+            PowerOperation::Start => write!(f, "start"),
+            PowerOperation::Resume => write!(f, "resume"),
+        }
+    }
+}
+
+impl std::fmt::Display for PowerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PowerState::Running => write!(f, "running"),
+            PowerState::Halted => write!(f, "halted"),
+            PowerState::Paused => write!(f, "paused"),
+            PowerState::Suspended => write!(f, "suspended"),
+        }
     }
 }
 
-impl ToString for SnapshotType {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for SnapshotType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            SnapshotType::Normal => "basic".to_string(),
-            SnapshotType::_Memory => "memory".to_string(),
+            SnapshotType::Normal => write!(f, "basic"),
+            SnapshotType::Memory => write!(f, "memory"),
+            SnapshotType::_Quiesced => write!(f, "quiesced"),
         }
     }
 }
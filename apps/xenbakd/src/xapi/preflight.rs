@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use super::cli::client::XApiCliClient;
+
+/// result of the connectivity check of a xen host
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostStatus {
+    Reachable,
+    /// the host couldn't be reached or rejected the credentials
+    Unreachable(String),
+}
+
+impl std::fmt::Display for HostStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostStatus::Reachable => write!(f, "reachable"),
+            HostStatus::Unreachable(e) => write!(f, "unreachable ({})", e),
+        }
+    }
+}
+
+/// checks all hosts at once, so a single unreachable host only costs one command timeout. clients
+/// of a pool which failed over are switched to the new master. returns the reachable clients and
+/// the status of every host by name
+pub async fn check_hosts(
+    clients: Vec<XApiCliClient>,
+) -> (Vec<XApiCliClient>, BTreeMap<String, HostStatus>) {
+    let handles: Vec<_> = clients
+        .into_iter()
+        .map(|client| {
+            tokio::spawn(async move {
+                debug!(
+                    "Checking connectivity of xen host '{}'",
+                    client.get_config().name
+                );
+                let status = match client.connect().await {
+                    Ok(()) => HostStatus::Reachable,
+                    Err(e) => {
+                        warn!(
+                            "Xen host '{}' failed the connectivity check: {}",
+                            client.get_config().name,
+                            e
+                        );
+                        HostStatus::Unreachable(e.to_string())
+                    }
+                };
+                (client, status)
+            })
+        })
+        .collect();
+
+    let mut reachable = vec![];
+    let mut host_status = BTreeMap::new();
+    for handle in handles {
+        let (client, status) = match handle.await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("Connectivity check panicked: {}", e);
+                continue;
+            }
+        };
+        if status == HostStatus::Reachable {
+            reachable.push(client.clone());
+        }
+        host_status.insert(client.get_config().name.clone(), status);
+    }
+
+    (reachable, host_status)
+}
@@ -0,0 +1,709 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tracing::{debug, warn};
+
+use crate::{
+    config::XenConfig,
+    storage::StdioStream,
+    xapi::{
+        error::{XApiCliError, XApiErrorKind, XApiRpcError},
+        master::{self, PoolMaster},
+        task::XApiTask,
+        xmlrpc::{method_call, parse_response, XmlRpcValue},
+        ExportCompression, PowerOperation, PowerState, SnapshotType, VM,
+    },
+};
+
+use super::FromXmlRpc;
+
+/// reference of a field which points to no object
+const NULL_REF: &str = "OpaqueRef:NULL";
+
+/// data of the export buffered between the http connection and the storage
+const EXPORT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// idle connections are kept open this long, so the calls of a job run share them
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// `xe` parameters answered over the session instead of a separate `xe` login, as
+/// `(xe class, xe parameter, api class, field, api class of the referenced object)`.
+/// references are resolved to uuids like `xe` does
+const PARAMS: &[(&str, &str, &str, &str, Option<&str>)] = &[
+    ("vm", "name-label", "VM", "name_label", None),
+    ("snapshot", "name-label", "VM", "name_label", None),
+    (
+        "snapshot",
+        "suspend-VDI-uuid",
+        "VM",
+        "suspend_VDI",
+        Some("VDI"),
+    ),
+    ("vdi", "name-label", "VDI", "name_label", None),
+    ("vdi", "virtual-size", "VDI", "virtual_size", None),
+    (
+        "vdi",
+        "physical-utilisation",
+        "VDI",
+        "physical_utilisation",
+        None,
+    ),
+    ("vdi", "snapshot-time", "VDI", "snapshot_time", None),
+    ("vdi", "tags", "VDI", "tags", None),
+    ("vdi", "sr-uuid", "VDI", "SR", Some("SR")),
+    ("vdi", "snapshot-of", "VDI", "snapshot_of", Some("VDI")),
+    ("sr", "name-label", "SR", "name_label", None),
+    (
+        "sr",
+        "physical-utilisation",
+        "SR",
+        "physical_utilisation",
+        None,
+    ),
+    ("vbd", "vdi-uuid", "VBD", "VDI", Some("VDI")),
+    ("vbd", "userdevice", "VBD", "userdevice", None),
+    ("pool", "name-label", "pool", "name_label", None),
+    ("pool", "master", "pool", "master", Some("host")),
+    ("pool", "default-SR", "pool", "default_SR", Some("SR")),
+];
+
+/// api class of the `xe` classes whose `other-config` can be set over the session
+const OTHER_CONFIG_CLASSES: &[(&str, &str)] = &[
+    ("vm", "VM"),
+    ("snapshot", "VM"),
+    ("vdi", "VDI"),
+    ("sr", "SR"),
+];
+
+/// formats a field like `xe` prints it, sets are separated by `, `
+fn to_cli_value(value: &XmlRpcValue) -> String {
+    match value {
+        XmlRpcValue::String(value) => value.clone(),
+        // older hosts leave out the timezone
+        XmlRpcValue::DateTime(value) => match value.ends_with('Z') {
+            true => value.clone(),
+            false => format!("{}Z", value),
+        },
+        XmlRpcValue::Int(value) => value.to_string(),
+        XmlRpcValue::Boolean(value) => value.to_string(),
+        XmlRpcValue::Double(value) => value.to_string(),
+        XmlRpcValue::Array(values) => values
+            .iter()
+            .map(to_cli_value)
+            .collect::<Vec<_>>()
+            .join(", "),
+        XmlRpcValue::Struct(members) => {
+            let mut members: Vec<_> = members
+                .iter()
+                .map(|(key, value)| format!("{}: {}", key, to_cli_value(value)))
+                .collect();
+            members.sort();
+            members.join("; ")
+        }
+    }
+}
+
+/// talks to XAPI's XML-RPC interface over HTTPS, without a local `xe`. the session and the
+/// connections are created on the first call and shared by all clones for the whole job run
+#[derive(Debug, Clone)]
+pub struct XApiRpcClient {
+    config: XenConfig,
+    http: reqwest::Client,
+    session: Arc<Mutex<Option<String>>>,
+    master: PoolMaster,
+}
+
+// clients are told apart by their host, like the cli client
+impl PartialEq for XApiRpcClient {
+    fn eq(&self, other: &Self) -> bool {
+        self.config == other.config
+    }
+}
+
+impl Eq for XApiRpcClient {}
+
+impl std::hash::Hash for XApiRpcClient {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.config.hash(state);
+    }
+}
+
+impl XApiRpcClient {
+    pub fn new(config: XenConfig, master: PoolMaster) -> Self {
+        // hosts use self-signed certificates unless one was installed
+        let http = reqwest::ClientBuilder::new()
+            .user_agent(format!("xenbakd/{}", env!("CARGO_PKG_VERSION")))
+            .danger_accept_invalid_certs(!config.verify_tls)
+            .pool_idle_timeout(IDLE_CONNECTION_TIMEOUT)
+            .tcp_keepalive(IDLE_CONNECTION_TIMEOUT)
+            .build()
+            .unwrap();
+
+        XApiRpcClient {
+            config,
+            http,
+            session: Arc::new(Mutex::new(None)),
+            master,
+        }
+    }
+
+    pub fn get_url(&self) -> String {
+        format!("https://{}:{}/", self.master.get(), self.config.port)
+    }
+
+    /// talks to the given address from now on, the session of the old master isn't valid there
+    pub async fn switch_master(&self, address: &str) {
+        self.master.set(address);
+        *self.session.lock().await = None;
+    }
+
+    /// follows a `HOST_IS_SLAVE` error to the new pool master, returns whether it did
+    async fn follow_master(&self, error: &XApiRpcError) -> bool {
+        let XApiRpcError::Failure(XApiErrorKind::HostIsSlave, message) = error else {
+            return false;
+        };
+        let Some(address) = master::parse_master_address(message) else {
+            return false;
+        };
+        warn!(
+            "'{}' is no longer the pool master of xen host '{}', switching to '{}'",
+            self.master.get(),
+            self.config.name,
+            address
+        );
+        self.switch_master(&address).await;
+        true
+    }
+
+    /// sends a single XML-RPC call, without a session
+    async fn rpc(&self, method: &str, params: &[XmlRpcValue]) -> Result<XmlRpcValue, XApiRpcError> {
+        let body = self
+            .http
+            .post(self.get_url())
+            .header("Content-Type", "text/xml")
+            .body(method_call(method, params))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        parse_response(&body)
+    }
+
+    async fn login(&self) -> Result<String, XApiRpcError> {
+        debug!("Logging in to XAPI of host '{}'", self.config.name);
+        let session = self
+            .rpc(
+                "session.login_with_password",
+                &[
+                    self.config.username.as_str().into(),
+                    self.config.get_password().as_str().into(),
+                    "1.0".into(),
+                    "xenbakd".into(),
+                ],
+            )
+            .await?;
+
+        session
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| XApiRpcError::InvalidResponse("login returned no session".into()))
+    }
+
+    /// ends the session, the next call logs in again
+    pub async fn logout(&self) -> Result<(), XApiRpcError> {
+        let Some(session) = self.session.lock().await.take() else {
+            return Ok(());
+        };
+        self.rpc("session.logout", &[session.as_str().into()])
+            .await?;
+        Ok(())
+    }
+
+    /// logs in if needed and lists the pool's hosts
+    pub async fn ping(&self) -> Result<(), XApiRpcError> {
+        self.call("host.get_all", &[]).await?;
+        Ok(())
+    }
+
+    /// returns the current session, logging in if there is none
+    async fn get_session(&self) -> Result<String, XApiRpcError> {
+        let mut session = self.session.lock().await;
+        match session.as_ref() {
+            Some(session) => Ok(session.clone()),
+            None => Ok(session.insert(self.login().await?).clone()),
+        }
+    }
+
+    /// calls an API method with the session as first parameter, logging in again once if the
+    /// session expired and following the pool master once if it moved
+    pub async fn call(
+        &self,
+        method: &str,
+        params: &[XmlRpcValue],
+    ) -> Result<XmlRpcValue, XApiRpcError> {
+        let mut retried = false;
+        let mut redirected = false;
+        loop {
+            let result = match self.get_session().await {
+                Ok(session) => {
+                    let mut session_params = vec![XmlRpcValue::String(session)];
+                    session_params.extend_from_slice(params);
+                    self.rpc(method, &session_params).await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(e) if e.is_session_invalid() && !retried => {
+                    debug!("XAPI session of host '{}' expired", self.config.name);
+                    *self.session.lock().await = None;
+                    retried = true;
+                }
+                Err(e) if !redirected && self.follow_master(&e).await => {
+                    redirected = true;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// returns a parameter as `xe <class>-param-get` would, `None` if the parameter isn't
+    /// mapped to the api and has to be queried with `xe`
+    pub async fn get_param(
+        &self,
+        class: &str,
+        uuid: &str,
+        param: &str,
+    ) -> Result<Option<String>, XApiRpcError> {
+        let Some((_, _, api_class, field, ref_class)) = PARAMS
+            .iter()
+            .find(|(cli_class, cli_param, ..)| *cli_class == class && *cli_param == param)
+        else {
+            return Ok(None);
+        };
+
+        let object_ref = self
+            .call(&format!("{}.get_by_uuid", api_class), &[uuid.into()])
+            .await?;
+        let value = self
+            .call(
+                &format!("{}.get_{}", api_class, field),
+                std::slice::from_ref(&object_ref),
+            )
+            .await?;
+
+        let Some(ref_class) = ref_class else {
+            return Ok(Some(to_cli_value(&value)));
+        };
+        match value.as_str() {
+            Some(NULL_REF) | None => Ok(Some("<not in database>".to_string())),
+            Some(value_ref) => {
+                let value_uuid = self
+                    .call(&format!("{}.get_uuid", ref_class), &[value_ref.into()])
+                    .await?;
+                Ok(Some(to_cli_value(&value_uuid)))
+            }
+        }
+    }
+
+    /// sets a parameter as `xe <class>-param-set` would, returns false if the parameter isn't
+    /// mapped to the api and has to be set with `xe`. only `other-config:<key>` is mapped
+    pub async fn set_param(
+        &self,
+        class: &str,
+        uuid: &str,
+        param: &str,
+        value: &str,
+    ) -> Result<bool, XApiRpcError> {
+        let Some(key) = param.strip_prefix("other-config:") else {
+            return Ok(false);
+        };
+        let Some((_, api_class)) = OTHER_CONFIG_CLASSES.iter().find(|(x, _)| *x == class) else {
+            return Ok(false);
+        };
+
+        let object_ref = self
+            .call(&format!("{}.get_by_uuid", api_class), &[uuid.into()])
+            .await?;
+        // adding fails if the key exists, removing a missing key doesn't
+        self.call(
+            &format!("{}.remove_from_other_config", api_class),
+            &[object_ref.clone(), key.into()],
+        )
+        .await?;
+        self.call(
+            &format!("{}.add_to_other_config", api_class),
+            &[object_ref, key.into(), value.into()],
+        )
+        .await?;
+
+        Ok(true)
+    }
+
+    async fn get_vm_ref(&self, vm_uuid: &str) -> Result<String, XApiRpcError> {
+        let vm_ref = self.call("VM.get_by_uuid", &[vm_uuid.into()]).await?;
+        Ok(vm_ref.as_str().unwrap_or_default().to_string())
+    }
+
+    async fn get_vm_record(&self, vm_ref: &str) -> Result<VM, XApiCliError> {
+        let record = self.call("VM.get_record", &[vm_ref.into()]).await?;
+        self.vm_from_record(&record).await
+    }
+
+    /// parses a VM record and resolves its host and disk references to uuids
+    async fn vm_from_record(&self, record: &XmlRpcValue) -> Result<VM, XApiCliError> {
+        let mut vm = VM::from_xml_rpc(record)?;
+
+        let resident_on = record.get_str("resident_on");
+        if resident_on != NULL_REF {
+            let host = self.call("host.get_uuid", &[resident_on.into()]).await?;
+            vm.resident_on = host.as_str().map(String::from);
+        }
+
+        for vbd_ref in record.get("VBDs").map(|x| x.as_array()).unwrap_or_default() {
+            let vbd = self
+                .call("VBD.get_record", std::slice::from_ref(vbd_ref))
+                .await?;
+            // empty drives have no VDI
+            let vdi_ref = vbd.get_str("VDI");
+            if vbd.get_str("type") != "Disk" || vdi_ref == NULL_REF {
+                continue;
+            }
+            let vdi = self.call("VDI.get_uuid", &[vdi_ref.into()]).await?;
+            vm.vbds.push(vbd.get_str("uuid").to_string());
+            vm.vdis.push(vdi.as_str().unwrap_or_default().to_string());
+        }
+
+        Ok(vm)
+    }
+
+    /// returns the VMs having any of the tags and none of the excluded tags
+    pub async fn filter_vms_by_tag(
+        &self,
+        tags: Vec<String>,
+        excluded_tags: Vec<String>,
+    ) -> Result<Vec<VM>, XApiCliError> {
+        let records = self.call("VM.get_all_records", &[]).await?;
+        let XmlRpcValue::Struct(records) = records else {
+            return Ok(vec![]);
+        };
+
+        let mut vms = vec![];
+        for record in records.values() {
+            let flag = |key: &str| record.get(key).and_then(|x| x.as_bool()).unwrap_or(false);
+            if flag("is_a_template") || flag("is_a_snapshot") || flag("is_control_domain") {
+                continue;
+            }
+
+            let vm_tags: Vec<&str> = record
+                .get("tags")
+                .map(|x| x.as_array())
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|x| x.as_str())
+                .collect();
+            if !tags.iter().any(|x| vm_tags.contains(&x.as_str()))
+                || excluded_tags.iter().any(|x| vm_tags.contains(&x.as_str()))
+            {
+                continue;
+            }
+
+            vms.push(self.vm_from_record(record).await?);
+        }
+
+        Ok(vms)
+    }
+
+    /// returns all VMs, without templates, snapshots and control domains
+    pub async fn list_vms(&self) -> Result<Vec<VM>, XApiCliError> {
+        let records = self.call("VM.get_all_records", &[]).await?;
+        let XmlRpcValue::Struct(records) = records else {
+            return Ok(vec![]);
+        };
+
+        let mut vms = vec![];
+        for record in records.values() {
+            let flag = |key: &str| record.get(key).and_then(|x| x.as_bool()).unwrap_or(false);
+            if flag("is_a_template") || flag("is_a_snapshot") || flag("is_control_domain") {
+                continue;
+            }
+            vms.push(self.vm_from_record(record).await?);
+        }
+
+        Ok(vms)
+    }
+
+    pub async fn get_vm_by_uuid(&self, vm_uuid: &str) -> Result<VM, XApiCliError> {
+        let vm_ref = self.get_vm_ref(vm_uuid).await?;
+        self.get_vm_record(&vm_ref).await
+    }
+
+    /// returns a list of the VMs snapshots
+    pub async fn get_snapshots(&self, vm: &VM) -> Result<Vec<VM>, XApiCliError> {
+        let vm_ref = self.get_vm_ref(&vm.uuid).await?;
+        let snapshot_refs = self
+            .call("VM.get_snapshots", &[vm_ref.as_str().into()])
+            .await?;
+
+        let mut snapshots = vec![];
+        for snapshot_ref in snapshot_refs.as_array() {
+            snapshots.push(
+                self.get_vm_record(snapshot_ref.as_str().unwrap_or_default())
+                    .await?,
+            );
+        }
+        Ok(snapshots)
+    }
+
+    pub async fn snapshot(&self, vm: &VM, snapshot_type: SnapshotType) -> Result<VM, XApiCliError> {
+        let method = match snapshot_type {
+            SnapshotType::Normal => "VM.snapshot",
+            SnapshotType::Memory => "VM.checkpoint",
+            SnapshotType::_Quiesced => "VM.snapshot_with_quiesce",
+        };
+
+        let result = async {
+            let vm_ref = self.get_vm_ref(&vm.uuid).await?;
+            self.call(method, &[vm_ref.as_str().into(), "xenbakd-snapshot".into()])
+                .await
+        }
+        .await;
+
+        match result {
+            Ok(snapshot_ref) => {
+                self.get_vm_record(snapshot_ref.as_str().unwrap_or_default())
+                    .await
+            }
+            Err(e) => Err(XApiCliError::SnapshotFailure(e.kind(), e.to_string())),
+        }
+    }
+
+    pub async fn set_name_label(&self, vm: &VM, name: &str) -> Result<VM, XApiCliError> {
+        let vm_ref = self.get_vm_ref(&vm.uuid).await?;
+        self.call("VM.set_name_label", &[vm_ref.as_str().into(), name.into()])
+            .await?;
+        self.get_vm_record(&vm_ref).await
+    }
+
+    pub async fn set_is_a_template(&self, vm: &VM, value: bool) -> Result<VM, XApiCliError> {
+        let vm_ref = self.get_vm_ref(&vm.uuid).await?;
+        self.call(
+            "VM.set_is_a_template",
+            &[vm_ref.as_str().into(), value.into()],
+        )
+        .await?;
+        self.get_vm_record(&vm_ref).await
+    }
+
+    /// returns the operations currently running on the VM (e.g. `pool_migrate`, `snapshot`)
+    pub async fn get_vm_current_operations(&self, vm: &VM) -> Result<Vec<String>, XApiCliError> {
+        let vm_ref = self.get_vm_ref(&vm.uuid).await?;
+        // map of task references to operations
+        let operations = self
+            .call("VM.get_current_operations", &[vm_ref.as_str().into()])
+            .await?;
+
+        Ok(match operations {
+            XmlRpcValue::Struct(operations) => operations
+                .values()
+                .filter_map(|x| x.as_str())
+                .map(String::from)
+                .collect(),
+            _ => vec![],
+        })
+    }
+
+    pub async fn get_vm_power_state(&self, vm: &VM) -> Result<PowerState, XApiCliError> {
+        let vm_ref = self.get_vm_ref(&vm.uuid).await?;
+        let power_state = self
+            .call("VM.get_power_state", &[vm_ref.as_str().into()])
+            .await?;
+
+        Ok(power_state.as_str().unwrap_or_default().parse()?)
+    }
+
+    pub async fn vm_power_operation(
+        &self,
+        vm: &VM,
+        operation: PowerOperation,
+    ) -> Result<(), XApiCliError> {
+        let vm_ref = self.get_vm_ref(&vm.uuid).await?;
+        let vm_ref: XmlRpcValue = vm_ref.as_str().into();
+        // start and resume neither pause the VM nor force the operation
+        let (method, params) = match operation {
+            PowerOperation::Shutdown => ("VM.clean_shutdown", vec![vm_ref]),
+            PowerOperation::HardShutdown => ("VM.hard_shutdown", vec![vm_ref]),
+            PowerOperation::Suspend => ("VM.suspend", vec![vm_ref]),
+            PowerOperation::Start => ("VM.start", vec![vm_ref, false.into(), false.into()]),
+            PowerOperation::Resume => ("VM.resume", vec![vm_ref, false.into(), false.into()]),
+        };
+        self.call(method, &params).await?;
+
+        Ok(())
+    }
+
+    /// returns the uuid and name-label of the host's pool
+    pub async fn get_pool(&self) -> Result<(String, String), XApiCliError> {
+        let records = self.call("pool.get_all_records", &[]).await?;
+        let XmlRpcValue::Struct(records) = records else {
+            return Err(XApiRpcError::InvalidResponse("no pool returned".into()).into());
+        };
+        let pool = records
+            .values()
+            .next()
+            .ok_or_else(|| XApiRpcError::InvalidResponse("no pool returned".into()))?;
+
+        Ok((
+            pool.get_str("uuid").to_string(),
+            pool.get_str("name_label").to_string(),
+        ))
+    }
+
+    /// returns the product brand and version of the pool master
+    pub async fn get_software_version(&self) -> Result<(String, String), XApiCliError> {
+        let records = self.call("pool.get_all_records", &[]).await?;
+        let XmlRpcValue::Struct(records) = records else {
+            return Err(XApiRpcError::InvalidResponse("no pool returned".into()).into());
+        };
+        let master = records
+            .values()
+            .next()
+            .map(|x| x.get_str("master").to_string())
+            .ok_or_else(|| XApiRpcError::InvalidResponse("no pool returned".into()))?;
+        let software_version = self
+            .call("host.get_software_version", &[master.as_str().into()])
+            .await?;
+
+        Ok((
+            software_version.get_str("product_brand").to_string(),
+            software_version.get_str("product_version").to_string(),
+        ))
+    }
+
+    /// creates a task for an HTTP handler to report its progress on, it has to be destroyed afterwards
+    pub async fn create_task(&self, name_label: &str) -> Result<String, XApiRpcError> {
+        let task = self
+            .call("task.create", &[name_label.into(), "".into()])
+            .await?;
+        Ok(task.as_str().unwrap_or_default().to_string())
+    }
+
+    pub async fn destroy_task(&self, task: &str) -> Result<(), XApiRpcError> {
+        self.call("task.destroy", &[task.into()]).await?;
+        Ok(())
+    }
+
+    /// returns the newest task with the name-label
+    pub async fn find_task(&self, name_label: &str) -> Result<Option<XApiTask>, XApiRpcError> {
+        let tasks = self
+            .call("task.get_by_name_label", &[name_label.into()])
+            .await?;
+        let Some(task) = tasks.as_array().last().and_then(|x| x.as_str()) else {
+            return Ok(None);
+        };
+        let record = self.call("task.get_record", &[task.into()]).await?;
+
+        Ok(Some(XApiTask {
+            reference: task.to_string(),
+            name_label: record.get_str("name_label").to_string(),
+            progress: record
+                .get("progress")
+                .and_then(|x| x.as_f64())
+                .unwrap_or_default(),
+            status: record.get_str("status").to_string(),
+        }))
+    }
+
+    pub async fn cancel_task(&self, task: &XApiTask) -> Result<(), XApiRpcError> {
+        self.call("task.cancel", &[task.reference.as_str().into()])
+            .await?;
+        Ok(())
+    }
+
+    /// streams the XVA of a VM or snapshot from the host's `/export` handler. returns the data
+    /// stream, an error stream which receives the error if the transfer breaks off, and the size
+    /// of the export if the host announced it
+    pub async fn open_export(
+        &self,
+        vm: &VM,
+        task: &str,
+        compression: Option<ExportCompression>,
+    ) -> Result<(StdioStream, StdioStream, Option<u64>), XApiRpcError> {
+        let mut query = vec![("uuid", vm.uuid.as_str()), ("task_id", task)];
+        if let Some(compression) = compression {
+            query.push(("use_compression", compression.to_cli_arg()));
+        }
+        self.open_download("export", &query).await
+    }
+
+    /// streams the pool database from the host's `/pool/xmldbdump` handler, like `xe pool-dump-database`
+    pub async fn open_database_dump(
+        &self,
+    ) -> Result<(StdioStream, StdioStream, Option<u64>), XApiRpcError> {
+        self.open_download("pool/xmldbdump", &[]).await
+    }
+
+    /// streams a download of one of the host's HTTP handlers, authenticated by the session. the
+    /// session id is part of the url, which is therefore removed from the errors
+    async fn open_download(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<(StdioStream, StdioStream, Option<u64>), XApiRpcError> {
+        let mut response = None;
+        // the session may have expired since the last call
+        for _ in 0..2 {
+            let session = self.get_session().await?;
+            let download = self
+                .http
+                .get(format!("{}{}", self.get_url(), path))
+                .query(query)
+                .query(&[("session_id", session.as_str())])
+                .send()
+                .await
+                .map_err(|e| e.without_url())?;
+            if download.status() == reqwest::StatusCode::UNAUTHORIZED {
+                *self.session.lock().await = None;
+                continue;
+            }
+            if !download.status().is_success() {
+                return Err(XApiRpcError::Failure(
+                    XApiErrorKind::from_http_status(download.status()),
+                    format!("/{} returned {}", path, download.status()),
+                ));
+            }
+            response = Some(download);
+            break;
+        }
+        let Some(mut response) = response else {
+            return Err(XApiRpcError::Failure(
+                XApiErrorKind::AuthenticationFailed,
+                format!("/{} was rejected, the session is not authorized", path),
+            ));
+        };
+        let size = response.content_length();
+
+        let (mut data_writer, data_reader) = tokio::io::duplex(EXPORT_BUFFER_SIZE);
+        let (mut error_writer, error_reader) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let result: eyre::Result<()> = async {
+                while let Some(chunk) = response.chunk().await.map_err(|e| e.without_url())? {
+                    data_writer.write_all(&chunk).await?;
+                }
+                Ok(())
+            }
+            .await;
+
+            // the storage reads the error stream once the data stream has ended
+            drop(data_writer);
+            if let Err(e) = result {
+                let _ = error_writer
+                    .write_all(format!("Export stream interrupted: {}", e).as_bytes())
+                    .await;
+            }
+        });
+
+        Ok((Box::new(data_reader), Box::new(error_reader), size))
+    }
+}
@@ -0,0 +1,55 @@
+use crate::xapi::error::XApiParseError;
+
+use super::{parse_timestamp, xmlrpc::XmlRpcValue, VM};
+
+pub mod client;
+
+pub trait FromXmlRpc: Sized {
+    fn from_xml_rpc(value: &XmlRpcValue) -> Result<Self, XApiParseError>;
+}
+
+impl FromXmlRpc for VM {
+    /// create a new VM struct from a `VM.get_record` result, without its host, VBDs and VDIs
+    fn from_xml_rpc(record: &XmlRpcValue) -> Result<VM, XApiParseError> {
+        let flag = |key: &str| record.get(key).and_then(|x| x.as_bool()).unwrap_or(false);
+
+        // older hosts leave out the timezone of the never-set snapshot time
+        let snapshot_time = record.get_str("snapshot_time");
+        let snapshot_time = match snapshot_time.ends_with('Z') {
+            true => parse_timestamp(snapshot_time)?,
+            false => parse_timestamp(&format!("{}Z", snapshot_time))?,
+        };
+
+        Ok(VM {
+            uuid: record.get_str("uuid").to_string(),
+            name_label: record.get_str("name_label").to_string(),
+            name_description: record.get_str("name_description").to_string(),
+            is_a_template: flag("is_a_template"),
+            is_default_template: flag("is_default_template"),
+            is_a_snapshot: flag("is_a_snapshot"),
+            snapshot_time,
+            power_state: record.get_str("power_state").parse().ok(),
+            // 64 bit integers are sent as strings
+            memory: record
+                .get_str("memory_static_max")
+                .parse()
+                .unwrap_or_default(),
+            vcpus: record
+                .get_str("VCPUs_at_startup")
+                .parse()
+                .unwrap_or_default(),
+            tags: record
+                .get("tags")
+                .map(|x| x.as_array())
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|x| x.as_str())
+                .map(String::from)
+                .collect(),
+            // references are resolved to uuids by the client
+            resident_on: None,
+            vbds: vec![],
+            vdis: vec![],
+        })
+    }
+}
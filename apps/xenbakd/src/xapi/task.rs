@@ -0,0 +1,103 @@
+use std::future::Future;
+
+use eyre::WrapErr;
+use tracing::{debug, info, warn};
+
+use super::cli::client::XApiCliClient;
+
+/// how often the task of a running operation is polled
+const TASK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// an XAPI task, as far as xenbakd follows it
+#[derive(Debug, Clone)]
+pub struct XApiTask {
+    /// uuid with the cli, opaque reference with the xmlrpc api
+    pub reference: String,
+    pub name_label: String,
+    /// 0.0 to 1.0
+    pub progress: f64,
+    /// `pending`, `success`, `failure`, `cancelling` or `cancelled`
+    pub status: String,
+}
+
+impl std::fmt::Display for XApiTask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "'{}' [{}] at {:.0}%",
+            self.name_label,
+            self.reference,
+            self.progress * 100.0
+        )
+    }
+}
+
+/// runs the operation while following the XAPI task it creates, found by the task's name-label.
+/// progress is logged, tasks without progress for `task_stall_timeout` seconds are cancelled and
+/// a failed operation names its task
+pub async fn track<T>(
+    xapi_client: &XApiCliClient,
+    name_label: &str,
+    operation: impl Future<Output = eyre::Result<T>>,
+) -> eyre::Result<T> {
+    let stall_timeout = xapi_client.get_config().task_stall_timeout;
+    let mut last_task: Option<XApiTask> = None;
+    // seconds without progress when the task was cancelled
+    let mut cancelled: Option<u64> = None;
+
+    // the task is polled next to the operation, which keeps streaming in the meantime
+    let watcher = async {
+        let mut interval = tokio::time::interval(TASK_POLL_INTERVAL);
+        let mut last_progress = tokio::time::Instant::now();
+        loop {
+            interval.tick().await;
+
+            let task = match xapi_client.find_task(name_label).await {
+                Ok(Some(task)) => task,
+                Ok(None) => continue,
+                Err(e) => {
+                    debug!("Failed to poll task '{}': {}", name_label, e);
+                    continue;
+                }
+            };
+
+            // only whole percents count as progress, the host updates large exports in tiny steps
+            let percent = |x: &XApiTask| (x.progress * 100.0) as u32;
+            if last_task.as_ref().map(percent) != Some(percent(&task)) {
+                info!("Task {}", task);
+                last_progress = tokio::time::Instant::now();
+            }
+
+            let stalled = last_progress.elapsed().as_secs();
+            if stall_timeout > 0
+                && stalled >= stall_timeout
+                && cancelled.is_none()
+                && task.status == "pending"
+            {
+                warn!(
+                    "Task {} made no progress for {} seconds, cancelling it",
+                    task, stalled
+                );
+                match xapi_client.cancel_task(&task).await {
+                    Ok(_) => cancelled = Some(stalled),
+                    Err(e) => warn!("Failed to cancel task {}: {}", task, e),
+                }
+            }
+            last_task = Some(task);
+        }
+    };
+
+    let result = tokio::select! {
+        result = operation => result,
+        _ = watcher => unreachable!(),
+    };
+
+    match (result, last_task, cancelled) {
+        (Err(e), Some(task), Some(stalled)) => Err(e).wrap_err(format!(
+            "XAPI task {} was cancelled after {} seconds without progress",
+            task, stalled
+        )),
+        (Err(e), Some(task), None) => Err(e).wrap_err(format!("XAPI task {} failed", task)),
+        (result, _, _) => result,
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+/// features which depend on the version of the pool master. XCP-ng and XenServer share their
+/// version numbers, so the brand doesn't matter
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostFeature {
+    /// `compress=zstd` of `xe vm-export`, since 8.1
+    ZstdExport,
+    /// changed block tracking and `xe vdi-export base=`, needed by differential backups, since 7.3
+    ChangedBlockTracking,
+    /// `xe vm-snapshot-with-quiesce`, removed together with the VSS provider in 8.1
+    QuiescedSnapshot,
+}
+
+impl std::fmt::Display for HostFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostFeature::ZstdExport => write!(f, "zstd export compression"),
+            HostFeature::ChangedBlockTracking => {
+                write!(f, "changed block tracking and differential exports")
+            }
+            HostFeature::QuiescedSnapshot => write!(f, "quiesced snapshots"),
+        }
+    }
+}
+
+/// product brand and version of a pool master, e.g. `XCP-ng 8.2.1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostVersion {
+    pub brand: String,
+    pub version: (u32, u32, u32),
+}
+
+impl HostVersion {
+    /// parses `product_version`, missing parts are 0
+    pub fn new(brand: &str, version: &str) -> Option<HostVersion> {
+        let mut parts = version.trim().split('.').map(|x| x.parse::<u32>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+        let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+
+        Some(HostVersion {
+            brand: brand.trim().to_string(),
+            version: (major, minor, patch),
+        })
+    }
+
+    /// parses the `software-version` map as printed by `xe host-param-get`,
+    /// e.g. `product_version: 8.2.1; product_brand: XCP-ng; ...`
+    pub fn from_cli_output(output: &str) -> Option<HostVersion> {
+        let software_version: HashMap<&str, &str> = output
+            .split(';')
+            .filter_map(|x| x.split_once(':'))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+
+        HostVersion::new(
+            software_version.get("product_brand").unwrap_or(&"unknown"),
+            software_version.get("product_version")?,
+        )
+    }
+
+    pub fn supports(&self, feature: HostFeature) -> bool {
+        match feature {
+            HostFeature::ZstdExport => self.version >= (8, 1, 0),
+            HostFeature::ChangedBlockTracking => self.version >= (7, 3, 0),
+            HostFeature::QuiescedSnapshot => self.version < (8, 1, 0),
+        }
+    }
+}
+
+impl std::fmt::Display for HostVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (major, minor, patch) = self.version;
+        write!(f, "{} {}.{}.{}", self.brand, major, minor, patch)
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use super::error::{XApiErrorKind, XApiRpcError};
+
+/// an XML-RPC value as used by XAPI and `ova.xml`. untyped values are strings
+#[derive(Debug, Clone, PartialEq)]
+pub enum XmlRpcValue {
+    String(String),
+    Int(i64),
+    Boolean(bool),
+    Double(f64),
+    /// XAPI's `dateTime.iso8601`, e.g. `20240101T10:00:00Z`
+    DateTime(String),
+    Struct(HashMap<String, XmlRpcValue>),
+    Array(Vec<XmlRpcValue>),
+}
+
+impl XmlRpcValue {
+    /// parses a `<value>` element
+    pub fn parse(node: roxmltree::Node) -> XmlRpcValue {
+        let Some(inner) = node.children().find(|x| x.is_element()) else {
+            // a value without type element is a string
+            return XmlRpcValue::String(node.text().unwrap_or_default().to_string());
+        };
+        let text = inner.text().unwrap_or_default();
+
+        match inner.tag_name().name() {
+            "struct" => XmlRpcValue::Struct(
+                inner
+                    .children()
+                    .filter(|x| x.has_tag_name("member"))
+                    .filter_map(|member| {
+                        let name = member.children().find(|x| x.has_tag_name("name"))?;
+                        let value = member.children().find(|x| x.has_tag_name("value"))?;
+                        Some((
+                            name.text().unwrap_or_default().to_string(),
+                            XmlRpcValue::parse(value),
+                        ))
+                    })
+                    .collect(),
+            ),
+            "array" => XmlRpcValue::Array(
+                inner
+                    .children()
+                    .filter(|x| x.has_tag_name("data"))
+                    .flat_map(|x| x.children().filter(|x| x.has_tag_name("value")))
+                    .map(XmlRpcValue::parse)
+                    .collect(),
+            ),
+            "int" | "i4" | "i8" => text
+                .trim()
+                .parse()
+                .map(XmlRpcValue::Int)
+                .unwrap_or_else(|_| XmlRpcValue::String(text.to_string())),
+            "boolean" => XmlRpcValue::Boolean(text.trim() == "1"),
+            "double" => text
+                .trim()
+                .parse()
+                .map(XmlRpcValue::Double)
+                .unwrap_or_else(|_| XmlRpcValue::String(text.to_string())),
+            "dateTime.iso8601" => XmlRpcValue::DateTime(text.trim().to_string()),
+            _ => XmlRpcValue::String(text.to_string()),
+        }
+    }
+
+    /// appends the value as `<value>` element
+    pub fn write_xml(&self, xml: &mut String) {
+        xml.push_str("<value>");
+        match self {
+            XmlRpcValue::String(value) => {
+                xml.push_str("<string>");
+                xml.push_str(&escape(value));
+                xml.push_str("</string>");
+            }
+            XmlRpcValue::Int(value) => xml.push_str(&format!("<int>{}</int>", value)),
+            XmlRpcValue::Boolean(value) => {
+                xml.push_str(&format!("<boolean>{}</boolean>", u8::from(*value)))
+            }
+            XmlRpcValue::Double(value) => xml.push_str(&format!("<double>{}</double>", value)),
+            XmlRpcValue::DateTime(value) => xml.push_str(&format!(
+                "<dateTime.iso8601>{}</dateTime.iso8601>",
+                escape(value)
+            )),
+            XmlRpcValue::Struct(members) => {
+                xml.push_str("<struct>");
+                for (name, value) in members {
+                    xml.push_str("<member><name>");
+                    xml.push_str(&escape(name));
+                    xml.push_str("</name>");
+                    value.write_xml(xml);
+                    xml.push_str("</member>");
+                }
+                xml.push_str("</struct>");
+            }
+            XmlRpcValue::Array(values) => {
+                xml.push_str("<array><data>");
+                for value in values {
+                    value.write_xml(xml);
+                }
+                xml.push_str("</data></array>");
+            }
+        }
+        xml.push_str("</value>");
+    }
+
+    pub fn get(&self, key: &str) -> Option<&XmlRpcValue> {
+        match self {
+            XmlRpcValue::Struct(members) => members.get(key),
+            _ => None,
+        }
+    }
+
+    /// returns the string member, or an empty string if it's missing or not a string
+    pub fn get_str(&self, key: &str) -> &str {
+        self.get(key).and_then(|x| x.as_str()).unwrap_or_default()
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            XmlRpcValue::String(value) | XmlRpcValue::DateTime(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            XmlRpcValue::Boolean(value) => Some(*value),
+            XmlRpcValue::String(value) => value.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            XmlRpcValue::Double(value) => Some(*value),
+            XmlRpcValue::Int(value) => Some(*value as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[XmlRpcValue] {
+        match self {
+            XmlRpcValue::Array(values) => values,
+            _ => &[],
+        }
+    }
+}
+
+impl From<&str> for XmlRpcValue {
+    fn from(value: &str) -> Self {
+        XmlRpcValue::String(value.to_string())
+    }
+}
+
+impl From<bool> for XmlRpcValue {
+    fn from(value: bool) -> Self {
+        XmlRpcValue::Boolean(value)
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// serializes a method call
+pub fn method_call(method: &str, params: &[XmlRpcValue]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?><methodCall><methodName>");
+    xml.push_str(&escape(method));
+    xml.push_str("</methodName><params>");
+    for param in params {
+        xml.push_str("<param>");
+        param.write_xml(&mut xml);
+        xml.push_str("</param>");
+    }
+    xml.push_str("</params></methodCall>");
+    xml
+}
+
+/// parses a method response. XAPI wraps every result into a struct with `Status` and either
+/// `Value` or `ErrorDescription`, the error description is a list like `["HANDLE_INVALID", "VM", ...]`
+pub fn parse_response(body: &str) -> Result<XmlRpcValue, XApiRpcError> {
+    let document = roxmltree::Document::parse(body)
+        .map_err(|e| XApiRpcError::InvalidResponse(e.to_string()))?;
+
+    let value = document
+        .descendants()
+        .find(|x| x.has_tag_name("value"))
+        .map(XmlRpcValue::parse)
+        .ok_or_else(|| XApiRpcError::InvalidResponse("response contains no value".into()))?;
+
+    if document.descendants().any(|x| x.has_tag_name("fault")) {
+        let message = value.get_str("faultString").to_string();
+        return Err(XApiRpcError::Failure(XApiErrorKind::Unknown, message));
+    }
+
+    match value.get_str("Status") {
+        "Success" => Ok(value
+            .get("Value")
+            .cloned()
+            .unwrap_or(XmlRpcValue::String(String::new()))),
+        _ => {
+            let description: Vec<&str> = value
+                .get("ErrorDescription")
+                .map(|x| x.as_array())
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|x| x.as_str())
+                .collect();
+            let message = description.join(" ");
+            Err(XApiRpcError::Failure(
+                XApiErrorKind::from_stderr(&message),
+                message,
+            ))
+        }
+    }
+}
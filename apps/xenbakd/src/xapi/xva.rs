@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::storage::StdioStream;
+
+use super::xmlrpc::XmlRpcValue;
+
+/// the `ova.xml` of an XVA is the first tar member and holds the VM's metadata
+const OVA_XML: &str = "ova.xml";
+const TAR_BLOCK_SIZE: usize = 512;
+/// upper bound for the metadata, a VM with hundreds of disks stays far below
+const MAX_OVA_XML_SIZE: usize = 64 * 1024 * 1024;
+/// disks are stored in blocks of 1 MiB, named by their index. blocks of zeros are left out
+const XVA_BLOCK_SIZE: u64 = 1024 * 1024;
+/// magic bytes of XVAs compressed by the host
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// a disk of the exported VM, with the SR it was stored on
+#[derive(Debug, Clone)]
+pub struct XvaDisk {
+    pub userdevice: String,
+    /// id of the VDI in the XVA, its blocks are stored in the `<id>/` directory
+    pub vdi_ref: String,
+    pub name_label: String,
+    pub virtual_size: u64,
+    pub sr_uuid: String,
+    pub sr_name: String,
+}
+
+/// a network interface of the exported VM, with the network it was attached to
+#[derive(Debug, Clone)]
+pub struct XvaVif {
+    pub device: String,
+    pub network_uuid: String,
+    pub network_name: String,
+}
+
+/// disks and VIFs of the exported VM, as recorded in the XVA's `ova.xml`
+#[derive(Debug, Clone, Default)]
+pub struct XvaMetadata {
+    pub disks: Vec<XvaDisk>,
+    pub vifs: Vec<XvaVif>,
+}
+
+impl XvaMetadata {
+    pub fn from_ova_xml(ova_xml: &str) -> eyre::Result<XvaMetadata> {
+        let document = roxmltree::Document::parse(ova_xml)?;
+        let root = XmlRpcValue::parse(document.root_element());
+        let Some(XmlRpcValue::Array(objects)) = root.get("objects") else {
+            return Err(eyre::eyre!("ova.xml contains no objects"));
+        };
+
+        // objects reference each other by their id, e.g. `Ref:12`
+        let mut by_class: HashMap<&str, HashMap<&str, &XmlRpcValue>> = HashMap::new();
+        for object in objects {
+            let Some(snapshot) = object.get("snapshot") else {
+                continue;
+            };
+            by_class
+                .entry(object.get_str("class"))
+                .or_default()
+                .insert(object.get_str("id"), snapshot);
+        }
+        let lookup = |class: &str, id: &str| by_class.get(class).and_then(|x| x.get(id)).copied();
+
+        let mut metadata = XvaMetadata::default();
+        for vbd in by_class.get("VBD").into_iter().flat_map(|x| x.values()) {
+            if vbd.get_str("type") != "Disk" {
+                continue;
+            }
+            let Some(vdi) = lookup("VDI", vbd.get_str("VDI")) else {
+                continue;
+            };
+            let sr = lookup("SR", vdi.get_str("SR"));
+            metadata.disks.push(XvaDisk {
+                userdevice: vbd.get_str("userdevice").to_string(),
+                vdi_ref: vbd.get_str("VDI").to_string(),
+                name_label: vdi.get_str("name_label").to_string(),
+                // 64 bit integers are sent as strings
+                virtual_size: match vdi.get("virtual_size") {
+                    Some(XmlRpcValue::Int(size)) => *size as u64,
+                    _ => vdi.get_str("virtual_size").parse().unwrap_or_default(),
+                },
+                sr_uuid: sr
+                    .map(|x| x.get_str("uuid"))
+                    .unwrap_or_default()
+                    .to_string(),
+                sr_name: sr
+                    .map(|x| x.get_str("name_label"))
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+        }
+        for vif in by_class.get("VIF").into_iter().flat_map(|x| x.values()) {
+            let network = lookup("network", vif.get_str("network"));
+            metadata.vifs.push(XvaVif {
+                device: vif.get_str("device").to_string(),
+                network_uuid: network
+                    .map(|x| x.get_str("uuid"))
+                    .unwrap_or_default()
+                    .to_string(),
+                network_name: network
+                    .map(|x| x.get_str("name_label"))
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+        }
+
+        Ok(metadata)
+    }
+
+    /// reads the metadata from the head of an XVA stream. returns the metadata and a stream
+    /// yielding the complete XVA again, so it can still be imported. XVAs compressed by the host
+    /// are decompressed
+    pub async fn read_from_stream(stream: StdioStream) -> eyre::Result<(XvaMetadata, StdioStream)> {
+        let mut stream = decompress_stream(stream).await?;
+        let mut head = vec![0u8; TAR_BLOCK_SIZE];
+        stream.read_exact(&mut head).await?;
+
+        let name = String::from_utf8_lossy(&head[..100]);
+        if name.trim_end_matches('\0') != OVA_XML {
+            return Err(eyre::eyre!("Not an XVA, first member is not {}", OVA_XML));
+        }
+        let size = String::from_utf8_lossy(&head[124..136]);
+        let size = usize::from_str_radix(size.trim_matches(|c: char| c == '\0' || c == ' '), 8)
+            .map_err(|_| eyre::eyre!("Invalid tar header in XVA"))?;
+        if size > MAX_OVA_XML_SIZE {
+            return Err(eyre::eyre!(
+                "{} of XVA is too large ({} bytes)",
+                OVA_XML,
+                size
+            ));
+        }
+
+        // the member's content is padded to full blocks
+        let padded = size.div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE;
+        head.resize(TAR_BLOCK_SIZE + padded, 0);
+        stream.read_exact(&mut head[TAR_BLOCK_SIZE..]).await?;
+
+        let metadata = XvaMetadata::from_ova_xml(&String::from_utf8_lossy(
+            &head[TAR_BLOCK_SIZE..TAR_BLOCK_SIZE + size],
+        ))?;
+        let stream: Box<dyn AsyncRead + Unpin + Send> =
+            Box::new(std::io::Cursor::new(head).chain(stream));
+
+        Ok((metadata, stream))
+    }
+}
+
+/// detects an XVA compressed by the host (`export_compression`) by its magic bytes and wraps it
+/// into the matching decoder
+pub async fn decompress_stream(mut stream: StdioStream) -> eyre::Result<StdioStream> {
+    let mut magic = vec![0u8; ZSTD_MAGIC.len()];
+    stream.read_exact(&mut magic).await?;
+    let stream = tokio::io::BufReader::new(std::io::Cursor::new(magic.clone()).chain(stream));
+
+    if magic == ZSTD_MAGIC {
+        return Ok(Box::new(
+            async_compression::tokio::bufread::ZstdDecoder::new(stream),
+        ));
+    }
+    if magic.starts_with(&GZIP_MAGIC) {
+        return Ok(Box::new(
+            async_compression::tokio::bufread::GzipDecoder::new(stream),
+        ));
+    }
+
+    Ok(Box::new(stream))
+}
+
+/// reads the members of a tar stream one after another
+struct TarReader {
+    stream: StdioStream,
+    /// bytes of the current member's content and padding which weren't read yet
+    remaining: u64,
+}
+
+impl TarReader {
+    fn new(stream: StdioStream) -> Self {
+        TarReader {
+            stream,
+            remaining: 0,
+        }
+    }
+
+    /// skips the rest of the current member and returns the name and size of the next one,
+    /// `None` at the end of the archive
+    async fn next_member(&mut self) -> eyre::Result<Option<(String, u64)>> {
+        self.skip(self.remaining).await?;
+
+        let mut header = [0u8; TAR_BLOCK_SIZE];
+        match self.stream.read_exact(&mut header).await {
+            Ok(_) => {}
+            // the end-of-archive blocks are optional
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        if header.iter().all(|x| *x == 0) {
+            return Ok(None);
+        }
+
+        let field = |range: std::ops::Range<usize>| {
+            String::from_utf8_lossy(&header[range])
+                .trim_matches(|c: char| c == '\0' || c == ' ')
+                .to_string()
+        };
+        // ustar splits long names into a prefix and the name
+        let name = match field(345..500) {
+            prefix if prefix.is_empty() => field(0..100),
+            prefix => format!("{}/{}", prefix, field(0..100)),
+        };
+        let size = u64::from_str_radix(&field(124..136), 8)
+            .map_err(|_| eyre::eyre!("Invalid tar header in XVA"))?;
+
+        self.remaining = size.div_ceil(TAR_BLOCK_SIZE as u64) * TAR_BLOCK_SIZE as u64;
+        Ok(Some((name, size)))
+    }
+
+    /// copies the content of the current member
+    async fn copy_member<W: tokio::io::AsyncWrite + Unpin>(
+        &mut self,
+        size: u64,
+        writer: &mut W,
+    ) -> eyre::Result<()> {
+        let copied = tokio::io::copy(&mut (&mut self.stream).take(size), writer).await?;
+        if copied != size {
+            return Err(eyre::eyre!("XVA ended in the middle of a member"));
+        }
+        self.remaining -= size;
+        Ok(())
+    }
+
+    async fn skip(&mut self, size: u64) -> eyre::Result<()> {
+        let skipped =
+            tokio::io::copy(&mut (&mut self.stream).take(size), &mut tokio::io::sink()).await?;
+        if skipped != size {
+            return Err(eyre::eyre!("XVA ended in the middle of a member"));
+        }
+        self.remaining -= size;
+        Ok(())
+    }
+}
+
+/// extracts disks of an XVA stream into raw images, without importing the VM. `output` returns
+/// the path of the image of every disk to extract. blocks left out of the XVA stay sparse.
+/// returns the extracted disks with their image and the number of blocks written
+pub async fn extract_disks<F>(
+    stream: StdioStream,
+    output: F,
+) -> eyre::Result<Vec<(XvaDisk, String, u64)>>
+where
+    F: Fn(&XvaDisk) -> Option<String>,
+{
+    let (metadata, stream) = XvaMetadata::read_from_stream(stream).await?;
+
+    let mut images = HashMap::new();
+    let mut extracted = vec![];
+    for disk in metadata.disks {
+        let Some(path) = output(&disk) else {
+            continue;
+        };
+        let file = tokio::fs::File::create(&path).await?;
+        images.insert(disk.vdi_ref.clone(), (extracted.len(), file));
+        extracted.push((disk, path, 0));
+    }
+
+    let mut reader = TarReader::new(stream);
+    while let Some((name, size)) = reader.next_member().await? {
+        // blocks are stored as `<vdi>/<index>`, their checksums as `<index>.checksum` or
+        // `<index>.xxhash`
+        let Some((vdi_ref, index)) = name.rsplit_once('/') else {
+            continue;
+        };
+        let (Some((position, file)), Ok(index)) = (images.get_mut(vdi_ref), index.parse::<u64>())
+        else {
+            continue;
+        };
+
+        file.seek(std::io::SeekFrom::Start(index * XVA_BLOCK_SIZE))
+            .await?;
+        reader.copy_member(size, file).await?;
+        extracted[*position].2 += 1;
+    }
+
+    for (disk, _, _) in &extracted {
+        let (_, file) = images.get_mut(&disk.vdi_ref).unwrap();
+        file.flush().await?;
+        file.set_len(disk.virtual_size).await?;
+    }
+
+    Ok(extracted)
+}
@@ -0,0 +1,87 @@
+//! minimal plugin storing backups as plain files in the directory given by the `path` option,
+//! with the backup info in a json file next to each backup
+
+use std::path::PathBuf;
+
+use tokio::io::AsyncRead;
+use xenbak_storage_plugin::{serve, BackupInfo, StoragePlugin, StorageStatus};
+
+struct DirectoryPlugin {
+    path: PathBuf,
+}
+
+impl DirectoryPlugin {
+    fn job_dir(&self, job: &str) -> PathBuf {
+        self.path.join(job)
+    }
+}
+
+#[async_trait::async_trait]
+impl StoragePlugin for DirectoryPlugin {
+    async fn initialize(&self, job: &str) -> eyre::Result<()> {
+        tokio::fs::create_dir_all(self.job_dir(job)).await?;
+        Ok(())
+    }
+
+    async fn status(&self, _job: &str) -> eyre::Result<StorageStatus> {
+        Ok(StorageStatus::default())
+    }
+
+    async fn list(&self, job: &str) -> eyre::Result<Vec<BackupInfo>> {
+        let mut backups = vec![];
+        let mut entries = tokio::fs::read_dir(self.job_dir(job)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_some_and(|x| x == "json") {
+                backups.push(serde_json::from_slice(
+                    &tokio::fs::read(entry.path()).await?,
+                )?);
+            }
+        }
+        Ok(backups)
+    }
+
+    async fn delete(&self, job: &str, name: &str) -> eyre::Result<()> {
+        let _ = tokio::fs::remove_file(self.job_dir(job).join(format!("{}.json", name))).await;
+        tokio::fs::remove_file(self.job_dir(job).join(name)).await?;
+        Ok(())
+    }
+
+    async fn store(
+        &self,
+        job: &str,
+        backup: &BackupInfo,
+        data: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> eyre::Result<()> {
+        let mut file = tokio::fs::File::create(self.job_dir(job).join(&backup.name)).await?;
+        tokio::io::copy(data, &mut file).await?;
+        Ok(())
+    }
+
+    async fn commit(&self, job: &str, backup: &BackupInfo) -> eyre::Result<()> {
+        let mut backup = backup.clone();
+        backup.size = Some(
+            tokio::fs::metadata(self.job_dir(job).join(&backup.name))
+                .await?
+                .len(),
+        );
+        tokio::fs::write(
+            self.job_dir(job).join(format!("{}.json", backup.name)),
+            serde_json::to_vec(&backup)?,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    serve(|options| {
+        let path = options["path"]
+            .as_str()
+            .ok_or_else(|| eyre::eyre!("Option 'path' is required"))?;
+        Ok(DirectoryPlugin {
+            path: PathBuf::from(path),
+        })
+    })
+    .await
+}
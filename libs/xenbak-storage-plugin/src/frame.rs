@@ -0,0 +1,81 @@
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// maximum payload size of a single frame
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// size of the data frames an export stream is split into
+pub const DATA_FRAME_SIZE: usize = 1024 * 1024;
+
+/// every frame is sent as `[kind: u8][length: u32 big endian][payload]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// a json encoded request or response
+    Message = 0,
+    /// a chunk of the export stream
+    Data = 1,
+    /// the export failed, the payload contains the error output. terminates the export stream
+    Stderr = 2,
+    /// the export stream completed successfully
+    End = 3,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = eyre::Error;
+
+    fn try_from(value: u8) -> eyre::Result<FrameKind> {
+        match value {
+            0 => Ok(FrameKind::Message),
+            1 => Ok(FrameKind::Data),
+            2 => Ok(FrameKind::Stderr),
+            3 => Ok(FrameKind::End),
+            _ => Err(eyre::eyre!("Invalid frame kind: {}", value)),
+        }
+    }
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    kind: FrameKind,
+    payload: &[u8],
+) -> eyre::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(eyre::eyre!("Frame exceeds maximum size"));
+    }
+
+    writer.write_u8(kind as u8).await?;
+    writer.write_u32(payload.len() as u32).await?;
+    writer.write_all(payload).await?;
+    Ok(())
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> eyre::Result<(FrameKind, Vec<u8>)> {
+    let kind = FrameKind::try_from(reader.read_u8().await?)?;
+    let length = reader.read_u32().await? as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(eyre::eyre!("Frame exceeds maximum size"));
+    }
+
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).await?;
+    Ok((kind, payload))
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin, T: Serialize>(
+    writer: &mut W,
+    message: &T,
+) -> eyre::Result<()> {
+    write_frame(writer, FrameKind::Message, &serde_json::to_vec(message)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_message<R: AsyncRead + Unpin, T: DeserializeOwned>(
+    reader: &mut R,
+) -> eyre::Result<T> {
+    match read_frame(reader).await? {
+        (FrameKind::Message, payload) => Ok(serde_json::from_slice(&payload)?),
+        (kind, _) => Err(eyre::eyre!("Expected a message frame, got {:?}", kind)),
+    }
+}
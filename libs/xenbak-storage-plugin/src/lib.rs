@@ -0,0 +1,264 @@
+//! Interface for external xenbakd storage plugins.
+//!
+//! A plugin is an executable which is started by xenbakd for every storage operation. xenbakd
+//! talks to it over stdin/stdout using length-prefixed frames (see [`frame`]), everything written
+//! to stderr ends up in xenbakd's log. Every session starts with a [`PluginRequest::Hello`]
+//! carrying the protocol version and the plugin options from the xenbakd config, followed by a
+//! single operation.
+//!
+//! Plugin authors implement [`StoragePlugin`] and hand it to [`serve`]:
+//!
+//! ```no_run
+//! use xenbak_storage_plugin::{serve, BackupInfo, StoragePlugin, StorageStatus};
+//!
+//! struct MyPlugin;
+//!
+//! #[async_trait::async_trait]
+//! impl StoragePlugin for MyPlugin {
+//!     async fn initialize(&self, _job: &str) -> eyre::Result<()> { Ok(()) }
+//!     async fn status(&self, _job: &str) -> eyre::Result<StorageStatus> { Ok(StorageStatus::default()) }
+//!     async fn list(&self, _job: &str) -> eyre::Result<Vec<BackupInfo>> { Ok(vec![]) }
+//!     async fn delete(&self, _job: &str, _name: &str) -> eyre::Result<()> { Ok(()) }
+//!     async fn store(
+//!         &self,
+//!         _job: &str,
+//!         _backup: &BackupInfo,
+//!         _data: &mut (dyn tokio::io::AsyncRead + Unpin + Send),
+//!     ) -> eyre::Result<()> {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! #[tokio::main(flavor = "current_thread")]
+//! async fn main() -> eyre::Result<()> {
+//!     serve(|_options| Ok(MyPlugin)).await
+//! }
+//! ```
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWriteExt, DuplexStream};
+
+use self::frame::{read_frame, read_message, write_message, FrameKind, DATA_FRAME_SIZE};
+
+pub mod frame;
+
+/// version of the protocol, bumped on every incompatible change
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// a backup as stored by a plugin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupInfo {
+    /// unique name of the backup within a job (`host__type__vm__timestamp`)
+    pub name: String,
+    pub job_type: String,
+    pub vm_uuid: Option<String>,
+    pub vm_name: String,
+    pub xen_host: String,
+    pub time_stamp: chrono::DateTime<chrono::Utc>,
+    pub size: Option<u64>,
+    /// sha256 checksum of the export stream, only known once the stream is complete
+    pub checksum: Option<String>,
+}
+
+/// space information of a storage, 0 if unknown
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StorageStatus {
+    pub free_space: u64,
+    pub total_space: u64,
+    pub used_space: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PluginRequest {
+    Hello {
+        version: u32,
+        options: serde_json::Value,
+    },
+    Initialize {
+        job: String,
+    },
+    Status {
+        job: String,
+    },
+    List {
+        job: String,
+    },
+    Delete {
+        job: String,
+        name: String,
+    },
+    Verify {
+        job: String,
+        backup: BackupInfo,
+    },
+    /// followed by data frames, terminated by an end frame carrying the checksum or a stderr frame
+    Store {
+        job: String,
+        backup: BackupInfo,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PluginResponse {
+    Hello { version: u32 },
+    Status(StorageStatus),
+    List(Vec<BackupInfo>),
+    Done,
+    Error(String),
+}
+
+#[async_trait::async_trait]
+pub trait StoragePlugin: Send + Sync {
+    /// prepares the storage for a job, called before every backup run
+    async fn initialize(&self, job: &str) -> eyre::Result<()>;
+    async fn status(&self, job: &str) -> eyre::Result<StorageStatus>;
+    async fn list(&self, job: &str) -> eyre::Result<Vec<BackupInfo>>;
+    async fn delete(&self, job: &str, name: &str) -> eyre::Result<()>;
+    /// stores the export stream, reading it to its end
+    async fn store(
+        &self,
+        job: &str,
+        backup: &BackupInfo,
+        data: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> eyre::Result<()>;
+    /// called after the export finished successfully, `backup.checksum` is set. if the export
+    /// failed, the stored backup is deleted instead
+    async fn commit(&self, _job: &str, _backup: &BackupInfo) -> eyre::Result<()> {
+        Ok(())
+    }
+    /// re-reads a stored backup and validates it against its checksum
+    async fn verify(&self, _job: &str, _backup: &BackupInfo) -> eyre::Result<()> {
+        Err(eyre::eyre!("Verification is not supported by this plugin"))
+    }
+}
+
+/// runs a plugin session on stdin/stdout. `init` receives the plugin options from the xenbakd
+/// config and creates the plugin.
+pub async fn serve<P, F>(init: F) -> eyre::Result<()>
+where
+    P: StoragePlugin,
+    F: FnOnce(serde_json::Value) -> eyre::Result<P>,
+{
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+
+    let options = match read_message(&mut stdin).await? {
+        PluginRequest::Hello { version, options } if version == PROTOCOL_VERSION => options,
+        PluginRequest::Hello { version, .. } => {
+            let message = format!(
+                "Unsupported protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            );
+            write_message(&mut stdout, &PluginResponse::Error(message.clone())).await?;
+            return Err(eyre::eyre!(message));
+        }
+        _ => return Err(eyre::eyre!("Expected hello request")),
+    };
+
+    let plugin = match init(options) {
+        Ok(plugin) => plugin,
+        Err(e) => {
+            write_message(&mut stdout, &PluginResponse::Error(format!("{:#}", e))).await?;
+            return Err(e);
+        }
+    };
+    write_message(
+        &mut stdout,
+        &PluginResponse::Hello {
+            version: PROTOCOL_VERSION,
+        },
+    )
+    .await?;
+
+    let request: PluginRequest = read_message(&mut stdin).await?;
+    let response = match handle_request(&plugin, request, &mut stdin).await {
+        Ok(response) => response,
+        Err(e) => PluginResponse::Error(format!("{:#}", e)),
+    };
+    write_message(&mut stdout, &response).await?;
+    stdout.shutdown().await?;
+
+    Ok(())
+}
+
+async fn handle_request<P: StoragePlugin, R: AsyncRead + Unpin + Send>(
+    plugin: &P,
+    request: PluginRequest,
+    stream: &mut R,
+) -> eyre::Result<PluginResponse> {
+    match request {
+        PluginRequest::Hello { .. } => Err(eyre::eyre!("Unexpected hello request")),
+        PluginRequest::Initialize { job } => {
+            plugin.initialize(&job).await?;
+            Ok(PluginResponse::Done)
+        }
+        PluginRequest::Status { job } => Ok(PluginResponse::Status(plugin.status(&job).await?)),
+        PluginRequest::List { job } => Ok(PluginResponse::List(plugin.list(&job).await?)),
+        PluginRequest::Delete { job, name } => {
+            plugin.delete(&job, &name).await?;
+            Ok(PluginResponse::Done)
+        }
+        PluginRequest::Verify { job, backup } => {
+            plugin.verify(&job, &backup).await?;
+            Ok(PluginResponse::Done)
+        }
+        PluginRequest::Store { job, mut backup } => {
+            let (writer, mut reader) = tokio::io::duplex(DATA_FRAME_SIZE * 4);
+
+            // the reader is dropped as soon as the plugin returns, so the frames of a failed
+            // store are drained instead of blocking on the full duplex buffer
+            let (job_ref, backup_ref) = (&job, &backup);
+            let store = async move {
+                let result = plugin.store(job_ref, backup_ref, &mut reader).await;
+                drop(reader);
+                result
+            };
+
+            let (store_result, stream_result) = tokio::join!(store, forward_frames(stream, writer));
+
+            let checksum = match (store_result, stream_result) {
+                (Ok(()), Ok(checksum)) => checksum,
+                (Err(e), _) | (_, Err(e)) => {
+                    // the export or the plugin failed, discard what has been stored
+                    let _ = plugin.delete(&job, &backup.name).await;
+                    return Err(e);
+                }
+            };
+
+            backup.checksum = Some(checksum);
+            plugin.commit(&job, &backup).await?;
+            Ok(PluginResponse::Done)
+        }
+    }
+}
+
+/// writes data frames into the plugin's reader until the stream ends, returns the checksum
+async fn forward_frames<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    writer: DuplexStream,
+) -> eyre::Result<String> {
+    let mut writer = Some(writer);
+
+    loop {
+        match read_frame(stream).await? {
+            (FrameKind::Data, payload) => {
+                // if the plugin stopped reading, the rest of the stream is drained
+                if let Some(w) = writer.as_mut() {
+                    if w.write_all(&payload).await.is_err() {
+                        writer = None;
+                    }
+                }
+            }
+            (FrameKind::End, payload) => return Ok(String::from_utf8(payload)?),
+            (FrameKind::Stderr, payload) => {
+                return Err(eyre::eyre!(
+                    "Export failed: {}",
+                    String::from_utf8_lossy(&payload)
+                ))
+            }
+            (FrameKind::Message, _) => {
+                return Err(eyre::eyre!("Unexpected message during export stream"))
+            }
+        }
+    }
+}